@@ -0,0 +1,86 @@
+/*
+ * C view of the boot information handed to the viceOS kernel, for bootloader stubs written in C
+ * or assembly.
+ *
+ * Mirrors BootInfo, FramebufferInfo, MemoryMapEntry, Architecture and MemoryType in
+ * kernel/src/bootinfo.rs and kernel/src/mem/mod.rs. Those are #[repr(C)] and their layout is
+ * locked by compile-time assertions next to them, so a change on either side that isn't made on
+ * the other fails the kernel build or the assertions below.
+ *
+ * Pointers and sizes are spelled as 64-bit integers, the layout is that of the 64-bit kernel even
+ * when the stub filling it in is 32-bit code.
+ */
+
+#ifndef VICEOS_BOOTINFO_H
+#define VICEOS_BOOTINFO_H
+
+#include <stdint.h>
+
+enum vice_architecture {
+    VICE_ARCH_X86 = 0,
+    VICE_ARCH_X86_64 = 1,
+    VICE_ARCH_ARM32 = 2,
+    VICE_ARCH_ARM64 = 3,
+    VICE_ARCH_UNKNOWN = 255,
+};
+
+enum vice_memory_type {
+    VICE_MEMORY_AVAILABLE = 1,
+    VICE_MEMORY_RESERVED = 2,
+    VICE_MEMORY_ACPI_RECLAIMABLE = 3,
+    VICE_MEMORY_ACPI_NVS = 4,
+    VICE_MEMORY_BAD = 5,
+    VICE_MEMORY_KERNEL = 6,
+    VICE_MEMORY_BOOTLOADER = 7,
+    VICE_MEMORY_FRAMEBUFFER = 8,
+    VICE_MEMORY_PAGE_TABLE = 9,
+};
+
+struct vice_memory_map_entry {
+    uint64_t base;
+    uint64_t length;
+    uint32_t mem_type; /* enum vice_memory_type */
+    uint32_t _padding;
+};
+
+struct vice_framebuffer_info {
+    uint64_t address;
+    uint32_t width;
+    uint32_t height;
+    uint32_t pitch;
+    uint8_t bpp;
+    uint8_t red_shift;
+    uint8_t green_shift;
+    uint8_t blue_shift;
+    uint8_t red_mask;
+    uint8_t green_mask;
+    uint8_t blue_mask;
+    uint8_t _padding[5];
+};
+
+struct vice_boot_info {
+    uint64_t magic;
+    uint64_t memory_map; /* const struct vice_memory_map_entry * */
+    uint64_t memory_map_entries;
+    struct vice_framebuffer_info framebuffer;
+    uint32_t arch; /* enum vice_architecture */
+    uint32_t _padding;
+    uint64_t kernel_start;
+    uint64_t kernel_end;
+    uint64_t initrd_start;
+    uint64_t initrd_end;
+    uint64_t cmdline; /* const uint8_t *, not NUL terminated */
+    uint64_t cmdline_len;
+    /* Physical address of the ACPI RSDP copy handed over by the bootloader (0 if none) */
+    uint64_t rsdp;
+    /* Physical address of the EFI system table when booted via UEFI (0 if none) */
+    uint64_t efi_system_table;
+    /* Physical address of the VBE controller info block copied by the bootloader (0 if none) */
+    uint64_t vbe_info;
+};
+
+_Static_assert(sizeof(struct vice_memory_map_entry) == 24, "vice_memory_map_entry layout");
+_Static_assert(sizeof(struct vice_framebuffer_info) == 32, "vice_framebuffer_info layout");
+_Static_assert(sizeof(struct vice_boot_info) == 136, "vice_boot_info layout");
+
+#endif /* VICEOS_BOOTINFO_H */
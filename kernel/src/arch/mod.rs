@@ -10,6 +10,29 @@ pub fn init(boot_info: &BootInfo) {
     x86_64::init(boot_info);
 }
 
+/// Initialize architecture-specific components that need memory management
+pub fn init_late() {
+    x86_64::init_late();
+}
+
+/// Spin for at least `us` microseconds
+#[inline]
+pub fn delay_us(us: u64) {
+    x86_64::delay::delay_us(us);
+}
+
+/// Spin for at least `ms` milliseconds
+#[inline]
+pub fn delay_ms(ms: u64) {
+    x86_64::delay::delay_ms(ms);
+}
+
+/// Physical address of the active top-level page table, without the flag bits CR3 also holds
+#[inline]
+pub fn current_cr3() -> u64 {
+    x86_64::read_cr3() & !0xFFF
+}
+
 /// Disable interrupts
 #[inline(always)]
 pub fn disable_interrupts() {
@@ -34,6 +57,15 @@ pub fn halt() {
     }
 }
 
+/// Enable interrupts and halt until the next one arrives. `sti` only takes effect after the
+/// instruction following it, so nothing can be delivered between the two and get missed.
+#[inline(always)]
+pub fn enable_interrupts_and_halt() {
+    unsafe {
+        core::arch::asm!("sti; hlt", options(nomem, nostack));
+    }
+}
+
 /// Check if interrupts are enabled
 #[inline(always)]
 pub fn interrupts_enabled() -> bool {
@@ -9,12 +9,20 @@
 //! Currently the timer interrupt overlaps with the PIT (Programmable Interval Timer) interrupt, so
 //! we need to remap the PICs to avoid conflicts.
 
-use crate::arch::x86_64::{cpuid, rdmsr, wrmsr};
+use crate::arch::x86_64::{cpu, cpuid, rdmsr, wrmsr};
+use core::sync::atomic::{AtomicBool, Ordering};
 use log;
 
 /// APIC base MSR
 const IA32_APIC_BASE_MSR: u32 = 0x1B;
 
+/// APIC base MSR bits
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+
+/// In x2APIC mode register `offset` lives in MSR `X2APIC_MSR_BASE + offset / 16`
+const X2APIC_MSR_BASE: u32 = 0x800;
+
 /// APIC register offsets
 mod regs {
     pub const ID: u32 = 0x020;
@@ -38,6 +46,14 @@ mod regs {
 /// Local APIC base address (default)
 static mut APIC_BASE: u64 = 0xFEE00000;
 
+/// Whether registers are accessed through MSRs (x2APIC) rather than MMIO (xAPIC)
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// Whether the local APIC runs in x2APIC mode
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
 /// Check if APIC is available
 pub fn is_available() -> bool {
     let (_, _, _, edx) = cpuid(1);
@@ -58,21 +74,39 @@ pub fn init() -> bool {
 
         log::debug!("APIC base address: {:#x}", APIC_BASE);
 
-        // Enable the APIC
-        wrmsr(IA32_APIC_BASE_MSR, base | (1 << 11)); // Set the APIC Global Enable bit
+        // Enable the APIC, in x2APIC mode if the CPU has it. x2APIC can only be entered from
+        // an enabled xAPIC, so the global enable bit has to be set first.
+        wrmsr(IA32_APIC_BASE_MSR, base | APIC_BASE_GLOBAL_ENABLE);
+
+        if cpu::features().x2apic {
+            wrmsr(
+                IA32_APIC_BASE_MSR,
+                base | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE,
+            );
+            X2APIC.store(true, Ordering::Relaxed);
+        }
 
         // Enable the Spurious Interrupt Vector Register (SVR).
-        let svr = rdmsr(IA32_APIC_BASE_MSR + regs::SVR / 0x10);
+        let svr = read_reg(regs::SVR);
         let _ = svr;
     }
 
-    log::debug!("APIC initialized: ID={}, version={:#x}", get_id(), get_version());
+    log::debug!(
+        "APIC initialized ({}): ID={}, version={:#x}",
+        if is_x2apic() { "x2APIC" } else { "xAPIC" },
+        get_id(),
+        get_version()
+    );
 
     true
 }
 
 /// Read APIC register
 fn read_reg(offset: u32) -> u32 {
+    if is_x2apic() {
+        return rdmsr(X2APIC_MSR_BASE + offset / 0x10) as u32;
+    }
+
     unsafe {
         let addr = (APIC_BASE + offset as u64) as *const u32;
         core::ptr::read_volatile(addr)
@@ -81,20 +115,45 @@ fn read_reg(offset: u32) -> u32 {
 
 /// Write APIC register
 fn write_reg(offset: u32, value: u32) {
+    if is_x2apic() {
+        wrmsr(X2APIC_MSR_BASE + offset / 0x10, value as u64);
+        return;
+    }
+
     unsafe {
         let addr = (APIC_BASE + offset as u64) as *mut u32;
         core::ptr::write_volatile(addr, value);
     }
 }
 
+/// Write the Interrupt Command Register. x2APIC has it as a single 64-bit MSR with a 32-bit
+/// destination, xAPIC splits it in two with an 8-bit destination, and writing the low half sends.
+fn write_icr(destination: u32, low: u32) {
+    if is_x2apic() {
+        wrmsr(
+            X2APIC_MSR_BASE + regs::ICR_LOW / 0x10,
+            ((destination as u64) << 32) | low as u64,
+        );
+        return;
+    }
+
+    write_reg(regs::ICR_HIGH, destination << 24);
+    write_reg(regs::ICR_LOW, low);
+}
+
 /// Send End of Interrupt
 pub fn send_eoi() {
     write_reg(regs::EOI, 0);
 }
 
 /// Get APIC ID
-pub fn get_id() -> u8 {
-    ((read_reg(regs::ID) >> 24) & 0xFF) as u8
+pub fn get_id() -> u32 {
+    // x2APIC IDs are the full 32-bit register, xAPIC IDs are its top byte
+    if is_x2apic() {
+        return read_reg(regs::ID);
+    }
+
+    (read_reg(regs::ID) >> 24) & 0xFF
 }
 
 /// Get APIC version
@@ -138,19 +197,16 @@ pub fn timer_current() -> u32 {
 }
 
 /// Send Inter-Processor Interrupt (IPI)
-pub fn send_ipi(apic_id: u8, vector: u8) {
-    write_reg(regs::ICR_HIGH, (apic_id as u32) << 24);
-    write_reg(regs::ICR_LOW, vector as u32);
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    write_icr(apic_id, vector as u32);
 }
 
 /// Send Init IPI to all processors
 pub fn send_init_ipi_all() {
-    write_reg(regs::ICR_HIGH, 0);
-    write_reg(regs::ICR_LOW, 0xC4500);
+    write_icr(0, 0xC4500);
 }
 
 /// Send Startup IPI to all processors
 pub fn send_startup_ipi_all(vector: u8) {
-    write_reg(regs::ICR_HIGH, 0);
-    write_reg(regs::ICR_LOW, 0xC4600 | (vector as u32));
+    write_icr(0, 0xC4600 | (vector as u32));
 }
@@ -0,0 +1,75 @@
+//! Frame-pointer based stack walking
+//!
+//! With frame pointers enabled every function prologue does `push rbp; mov rbp, rsp`, so RBP
+//! always points at a pair of `[saved rbp, return address]`. Following the saved RBPs gives us the
+//! chain of return addresses without needing any unwind tables.
+//!
+//! This is best-effort: it's used from fault and panic paths, so every frame is sanity-checked
+//! before it's dereferenced and the walk simply stops at the first one that looks wrong.
+
+use crate::arch::paging;
+
+/// Stop after this many frames, in case the chain loops or is corrupted
+const MAX_FRAMES: usize = 32;
+
+/// Walk the stack starting at `rbp`, calling `f(depth, return_address)` for each frame
+pub fn walk(mut rbp: u64, mut f: impl FnMut(usize, u64)) {
+    for depth in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+
+        // Both the saved RBP and the return address must be readable
+        if paging::translate(rbp).is_none() || paging::translate(rbp + 8).is_none() {
+            break;
+        }
+
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+
+        f(depth, ret);
+
+        // The stack grows down, so callers' frames must be at higher addresses
+        if next <= rbp {
+            break;
+        }
+
+        rbp = next;
+    }
+}
+
+/// Log a backtrace starting at `rbp`
+pub fn log_from(rbp: u64) {
+    log::error!("Backtrace:");
+    walk(rbp, |depth, ret| log::error!("  #{:<2} {:#018x}", depth, ret));
+}
+
+/// Log a backtrace of the caller
+#[inline(always)]
+pub fn log_current() {
+    log_from(current_rbp());
+}
+
+/// Fill `frames` with the caller's return addresses, returning how many there were
+#[inline(always)]
+pub fn collect_current(frames: &mut [u64]) -> usize {
+    let mut count = 0;
+    walk(current_rbp(), |depth, ret| {
+        if let Some(frame) = frames.get_mut(depth) {
+            *frame = ret;
+            count = depth + 1;
+        }
+    });
+    count
+}
+
+#[inline(always)]
+fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+    }
+    rbp
+}
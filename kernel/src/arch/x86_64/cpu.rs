@@ -0,0 +1,218 @@
+//! CPU identification and feature detection
+//!
+//! CPUID is queried once and cached, so code that wants to use an optional instruction (RDRAND,
+//! x2APIC MSRs, ...) can cheaply check `features()` first instead of issuing CPUID every time.
+
+use crate::arch::x86_64::{cpuid, cpuid_count};
+use spin::Once;
+
+/// Optional CPU features the kernel knows how to make use of
+#[derive(Debug, Clone, Copy)]
+pub struct CpuFeatures {
+    /// Time Stamp Counter (leaf 1 EDX bit 4)
+    pub tsc: bool,
+    /// Page Attribute Table (leaf 1 EDX bit 16)
+    pub pat: bool,
+    /// On-chip local APIC (leaf 1 EDX bit 9)
+    pub apic: bool,
+    /// x2APIC mode (leaf 1 ECX bit 21)
+    pub x2apic: bool,
+    /// RDRAND instruction (leaf 1 ECX bit 30)
+    pub rdrand: bool,
+    /// RDSEED instruction (leaf 7 EBX bit 18)
+    pub rdseed: bool,
+}
+
+impl CpuFeatures {
+    fn detect() -> Self {
+        let (max_leaf, _, _, _) = cpuid(0);
+        let (_, _, ecx1, edx1) = cpuid(1);
+        let ebx7 = if max_leaf >= 7 { cpuid_count(7, 0).1 } else { 0 };
+
+        Self {
+            tsc: edx1 & (1 << 4) != 0,
+            pat: edx1 & (1 << 16) != 0,
+            apic: edx1 & (1 << 9) != 0,
+            x2apic: ecx1 & (1 << 21) != 0,
+            rdrand: ecx1 & (1 << 30) != 0,
+            rdseed: ebx7 & (1 << 18) != 0,
+        }
+    }
+}
+
+/// Vendor and model strings reported by CPUID
+pub struct CpuIdentity {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+}
+
+impl CpuIdentity {
+    fn detect() -> Self {
+        let (_, ebx, ecx, edx) = cpuid(0);
+
+        // The vendor string is spread over EBX, EDX, ECX in that order
+        let mut vendor = [0; 12];
+        for (i, reg) in [ebx, edx, ecx].into_iter().enumerate() {
+            vendor[i * 4..i * 4 + 4].copy_from_slice(&reg.to_le_bytes());
+        }
+
+        // The brand string takes leaves 0x80000002-0x80000004, 16 bytes each
+        let mut brand = [0; 48];
+        let (max_ext_leaf, _, _, _) = cpuid(0x8000_0000);
+        if max_ext_leaf >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let (a, b, c, d) = cpuid(leaf);
+                for (j, reg) in [a, b, c, d].into_iter().enumerate() {
+                    let at = i * 16 + j * 4;
+                    brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+
+        Self { vendor, brand }
+    }
+
+    /// e.g. "GenuineIntel" or "AuthenticAMD"
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Marketing name of the CPU, empty if the CPU doesn't report one
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(self.brand.len());
+        core::str::from_utf8(&self.brand[..len])
+            .unwrap_or("unknown")
+            .trim()
+    }
+}
+
+/// How the boot CPU's package is split into cores and hardware threads
+#[derive(Debug, Clone, Copy)]
+pub struct CpuTopology {
+    /// Logical processors in the whole machine: the MADT's count when ACPI lists the CPUs,
+    /// otherwise one package's worth
+    pub logical_cpus: u32,
+    /// Logical processors (hardware threads) per package
+    pub logical_per_package: u32,
+    pub cores_per_package: u32,
+    /// Hardware threads per core, 1 without SMT
+    pub threads_per_core: u32,
+    /// Enabled processors in the MADT, `None` without one
+    pub madt_cpus: Option<u32>,
+}
+
+impl CpuTopology {
+    /// Whether the MADT agrees with CPUID, i.e. lists a whole number of packages. Firmware that
+    /// disables some cores or threads breaks this without being wrong.
+    pub fn is_consistent(&self) -> bool {
+        self.madt_cpus
+            .is_none_or(|cpus| cpus != 0 && cpus % self.logical_per_package == 0)
+    }
+}
+
+/// The part of the topology CPUID describes: logical processors per package and threads per core
+#[derive(Clone, Copy)]
+struct PackageTopology {
+    logical: u32,
+    threads_per_core: u32,
+}
+
+impl PackageTopology {
+    fn detect() -> Self {
+        let (max_leaf, _, _, _) = cpuid(0);
+        let topology = if max_leaf >= 0xB {
+            Self::from_extended_leaf()
+        } else {
+            None
+        };
+
+        topology.unwrap_or_else(|| Self::from_legacy_leaves(max_leaf))
+    }
+
+    /// Leaf 0x0B walks the levels from SMT upwards, EBX[15:0] counting the logical processors
+    /// at each one
+    fn from_extended_leaf() -> Option<Self> {
+        const SMT: u32 = 1;
+        const CORE: u32 = 2;
+
+        let (mut threads_per_core, mut logical) = (None, None);
+        for level in 0..8 {
+            let (_, ebx, ecx, _) = cpuid_count(0xB, level);
+            let count = ebx & 0xFFFF;
+            match (ecx >> 8) & 0xFF {
+                0 => break,
+                SMT => threads_per_core = Some(count),
+                CORE => logical = Some(count),
+                _ => {}
+            }
+        }
+
+        // Some hypervisors report the levels with zero counts
+        let threads_per_core = threads_per_core.filter(|&n| n != 0)?;
+        let logical = logical.filter(|&n| n >= threads_per_core)?;
+        Some(Self {
+            logical,
+            threads_per_core,
+        })
+    }
+
+    /// Leaf 1 EBX[23:16] is the logical processor count (when EDX.HTT is set) and leaf 4
+    /// EAX[31:26] + 1 the core count. Only Intel has leaf 4, elsewhere every logical processor
+    /// counts as its own core.
+    fn from_legacy_leaves(max_leaf: u32) -> Self {
+        let (_, ebx1, _, edx1) = cpuid(1);
+        let logical = if edx1 & (1 << 28) != 0 {
+            ((ebx1 >> 16) & 0xFF).max(1)
+        } else {
+            1
+        };
+
+        let (eax4, _, _, _) = if max_leaf >= 4 {
+            cpuid_count(4, 0)
+        } else {
+            (0, 0, 0, 0)
+        };
+        // EAX[4:0] is the cache type, 0 if the leaf describes nothing
+        let cores = if eax4 & 0x1F != 0 {
+            (eax4 >> 26) + 1
+        } else {
+            logical
+        };
+
+        Self {
+            logical,
+            threads_per_core: (logical / cores.clamp(1, logical)).max(1),
+        }
+    }
+}
+
+static FEATURES: Once<CpuFeatures> = Once::new();
+static IDENTITY: Once<CpuIdentity> = Once::new();
+static PACKAGE: Once<PackageTopology> = Once::new();
+
+/// Get the (cached) feature set of the boot CPU
+pub fn features() -> &'static CpuFeatures {
+    FEATURES.call_once(CpuFeatures::detect)
+}
+
+/// Get the (cached) vendor and brand of the boot CPU
+pub fn identity() -> &'static CpuIdentity {
+    IDENTITY.call_once(CpuIdentity::detect)
+}
+
+/// Topology of the boot CPU from CPUID (leaf 0x0B, or leaves 0x04 and 0x01 on older CPUs),
+/// combined with the MADT's processor count once ACPI is up
+pub fn topology() -> CpuTopology {
+    let package = *PACKAGE.call_once(PackageTopology::detect);
+    let madt_cpus = crate::drivers::acpi::madt_cpu_count();
+
+    CpuTopology {
+        logical_cpus: madt_cpus
+            .filter(|&cpus| cpus != 0)
+            .unwrap_or(package.logical),
+        logical_per_package: package.logical,
+        cores_per_package: package.logical / package.threads_per_core,
+        threads_per_core: package.threads_per_core,
+        madt_cpus,
+    }
+}
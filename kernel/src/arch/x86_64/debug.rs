@@ -0,0 +1,183 @@
+//! Hardware breakpoints and watchpoints through the debug registers.
+//! DR0-DR3 hold up to four linear addresses, DR7 enables them and sets what kind of access and
+//! how many bytes each one covers, and DR6 reports which of them fired.
+
+/// What kind of access a watchpoint triggers on.
+/// x86 can't watch reads alone, so `ReadWrite` is the closest to a read watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Execute = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11,
+}
+
+impl WatchKind {
+    fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0b00 => Some(Self::Execute),
+            0b01 => Some(Self::Write),
+            0b11 => Some(Self::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+const SLOTS: usize = 4;
+
+/// DR6 bits B0-B3: which breakpoint conditions were met
+const DR6_HITS: u64 = 0xF;
+/// DR6 BS: the trap came from single-stepping (RFLAGS.TF)
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+/// DR6 with no conditions recorded (reserved bits read as 1)
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+fn read_dr(n: usize) -> u64 {
+    let value: u64;
+    unsafe {
+        match n {
+            0 => core::arch::asm!("mov {}, dr0", out(reg) value, options(nomem, nostack)),
+            1 => core::arch::asm!("mov {}, dr1", out(reg) value, options(nomem, nostack)),
+            2 => core::arch::asm!("mov {}, dr2", out(reg) value, options(nomem, nostack)),
+            3 => core::arch::asm!("mov {}, dr3", out(reg) value, options(nomem, nostack)),
+            6 => core::arch::asm!("mov {}, dr6", out(reg) value, options(nomem, nostack)),
+            7 => core::arch::asm!("mov {}, dr7", out(reg) value, options(nomem, nostack)),
+            _ => unreachable!("no debug register {}", n),
+        }
+    }
+    value
+}
+
+fn write_dr(n: usize, value: u64) {
+    unsafe {
+        match n {
+            0 => core::arch::asm!("mov dr0, {}", in(reg) value, options(nomem, nostack)),
+            1 => core::arch::asm!("mov dr1, {}", in(reg) value, options(nomem, nostack)),
+            2 => core::arch::asm!("mov dr2, {}", in(reg) value, options(nomem, nostack)),
+            3 => core::arch::asm!("mov dr3, {}", in(reg) value, options(nomem, nostack)),
+            6 => core::arch::asm!("mov dr6, {}", in(reg) value, options(nomem, nostack)),
+            7 => core::arch::asm!("mov dr7, {}", in(reg) value, options(nomem, nostack)),
+            _ => unreachable!("no debug register {}", n),
+        }
+    }
+}
+
+/// DR7 local enable bit for a slot
+fn enable_bit(slot: usize) -> u64 {
+    1 << (slot * 2)
+}
+
+/// Shift of a slot's 4-bit R/W + LEN field in DR7
+fn field_shift(slot: usize) -> usize {
+    16 + slot * 4
+}
+
+/// DR7 LEN encoding, note that 8 bytes is 0b10 and 4 bytes is 0b11
+fn len_bits(len: usize) -> Option<u64> {
+    match len {
+        1 => Some(0b00),
+        2 => Some(0b01),
+        8 => Some(0b10),
+        4 => Some(0b11),
+        _ => None,
+    }
+}
+
+fn len_from_bits(bits: u64) -> usize {
+    match bits {
+        0b00 => 1,
+        0b01 => 2,
+        0b10 => 8,
+        _ => 4,
+    }
+}
+
+/// Watch `len` (1, 2, 4 or 8) bytes at `addr`, which must be aligned to `len`.
+/// Execute breakpoints must use a length of 1. Returns the slot to pass to `clear_watchpoint`.
+pub fn set_watchpoint(addr: u64, len: usize, kind: WatchKind) -> Result<usize, &'static str> {
+    let len_field = len_bits(len).ok_or("Watchpoint length must be 1, 2, 4 or 8 bytes")?;
+
+    if kind == WatchKind::Execute && len != 1 {
+        return Err("Execute breakpoints must have a length of 1");
+    }
+    if !addr.is_multiple_of(len as u64) {
+        return Err("Watchpoint address must be aligned to its length");
+    }
+
+    let dr7 = read_dr(7);
+    let slot = (0..SLOTS)
+        .find(|&slot| dr7 & enable_bit(slot) == 0)
+        .ok_or("All four debug registers are in use")?;
+
+    write_dr(slot, addr);
+
+    let shift = field_shift(slot);
+    let field = (len_field << 2) | kind as u64;
+    let dr7 = (dr7 & !(0xF << shift)) | (field << shift) | enable_bit(slot);
+    write_dr(7, dr7);
+
+    log::debug!(
+        "Watchpoint {} set: {:?} of {} bytes at {:#x}",
+        slot,
+        kind,
+        len,
+        addr
+    );
+
+    Ok(slot)
+}
+
+/// Disable a watchpoint set by `set_watchpoint`
+pub fn clear_watchpoint(slot: usize) {
+    if slot >= SLOTS {
+        return;
+    }
+
+    write_dr(7, read_dr(7) & !enable_bit(slot) & !(0xF << field_shift(slot)));
+    write_dr(slot, 0);
+}
+
+/// Called from the #DB handler: report which watchpoints fired.
+/// Returns `None` if the trap wasn't caused by one, otherwise whether an execute breakpoint fired,
+/// in which case the caller must set RFLAGS.RF so returning doesn't immediately trap again.
+pub(super) fn report_hits(rip: u64) -> Option<bool> {
+    let hits = read_dr(6) & DR6_HITS;
+    if hits == 0 {
+        return None;
+    }
+
+    let dr7 = read_dr(7);
+    let mut execute = false;
+
+    for slot in (0..SLOTS).filter(|&slot| hits & (1 << slot) != 0) {
+        let field = (dr7 >> field_shift(slot)) & 0xF;
+        let kind = WatchKind::from_bits(field & 0b11);
+        let len = len_from_bits(field >> 2);
+
+        execute |= kind == Some(WatchKind::Execute);
+
+        // Data watchpoints trap after the access, so RIP is the instruction following it
+        log::warn!(
+            "Watchpoint {} hit: {:?} of {} bytes at {:#x}, RIP={:#018x}",
+            slot,
+            kind,
+            len,
+            read_dr(slot),
+            rip
+        );
+    }
+
+    write_dr(6, DR6_CLEAR);
+
+    Some(execute)
+}
+
+/// Called from the #DB handler: whether the trap was a single step, clearing the condition
+pub(super) fn take_single_step() -> bool {
+    let dr6 = read_dr(6);
+    if dr6 & DR6_SINGLE_STEP == 0 {
+        return false;
+    }
+
+    write_dr(6, dr6 & !DR6_SINGLE_STEP);
+    true
+}
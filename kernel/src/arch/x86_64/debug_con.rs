@@ -0,0 +1,42 @@
+//! Bochs/QEMU debug console: every byte written to port 0xE9 shows up on the host (QEMU needs
+//! `-debugcon stdio` or similar).
+//!
+//! Needs no setup at all, so it works before the IDT, serial or anything else exists. Real
+//! hardware has nothing on the port and ignores the writes.
+
+use crate::arch::x86_64::outb;
+use core::fmt::Write;
+
+const PORT: u16 = 0xE9;
+
+/// Writes straight to the debug console port. Stateless, so there's nothing to lock and output
+/// from several CPUs may interleave.
+pub struct DebugCon;
+
+impl Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            outb(PORT, byte);
+        }
+        Ok(())
+    }
+}
+
+/// Backend of `dbg_print!`
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    let _ = DebugCon.write_fmt(args);
+}
+
+/// Print to the debug console (supports `format_args!` syntax), usable from the first
+/// instruction of `_start64`
+#[macro_export]
+macro_rules! dbg_print {
+    ($($arg:tt)*) => ($crate::arch::x86_64::debug_con::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! dbg_println {
+    () => ($crate::dbg_print!("\n"));
+    ($($arg:tt)*) => ($crate::dbg_print!("{}\n", format_args!($($arg)*)));
+}
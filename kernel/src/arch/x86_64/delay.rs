@@ -0,0 +1,112 @@
+//! Busy-wait delays.
+//!
+//! The TSC is calibrated against PIT channel 2 at boot, after which delays spin on RDTSC. Before
+//! that (or without a TSC, or if calibration fails) they fall back to the HPET, and failing that
+//! to port 0x80 writes, which take roughly a microsecond each on any PC.
+
+use crate::arch::x86_64::{cpu, inb, outb, rdtsc};
+use crate::drivers::hpet;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// TSC ticks per microsecond, 0 until calibrated
+static TSC_PER_US: AtomicU64 = AtomicU64::new(0);
+
+/// PIT ports
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Port B of the keyboard controller, which gates PIT channel 2
+const PORT_B: u16 = 0x61;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const CALIBRATION_MS: u64 = 10;
+/// Port B reads to wait for the countdown before giving up on it. A port read takes about a
+/// microsecond, so this is roughly a second, where 10 ms is expected.
+const CALIBRATION_MAX_POLLS: u32 = 1_000_000;
+
+/// Unused port, a write to it takes about a microsecond
+const IO_DELAY_PORT: u16 = 0x80;
+
+/// Wait a tiny amount of time (~1 us) by writing to an unused port. Good enough to give slow
+/// devices like the PIC time to settle between commands, works before any timer is set up.
+#[inline]
+pub fn io_wait() {
+    outb(IO_DELAY_PORT, 0);
+}
+
+/// Measure the TSC frequency against a 10 ms one-shot countdown on PIT channel 2. Without a TSC,
+/// or if the countdown never finishes (some machines have no channel 2, or don't wire its output
+/// to port B), the TSC stays uncalibrated and delays use the fallbacks.
+pub fn calibrate() {
+    if !cpu::features().tsc {
+        log::warn!("No TSC, delays fall back to the HPET or port I/O");
+        return;
+    }
+
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    // Gate channel 2 off with the speaker disconnected while programming it
+    let port_b = inb(PORT_B) & !0x03;
+    outb(PORT_B, port_b);
+
+    // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+    outb(PIT_COMMAND, 0b1011_0000);
+    outb(PIT_CHANNEL2, count as u8);
+    outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+    // Raising the gate starts the countdown, bit 5 of port B goes high when it reaches zero
+    outb(PORT_B, port_b | 0x01);
+    let start = rdtsc();
+    let finished = (0..CALIBRATION_MAX_POLLS).any(|_| inb(PORT_B) & 0x20 != 0);
+    let elapsed = rdtsc() - start;
+
+    outb(PORT_B, port_b);
+
+    if !finished {
+        log::warn!("PIT channel 2 never counted down, TSC left uncalibrated");
+        return;
+    }
+
+    let per_us = elapsed / (CALIBRATION_MS * 1000);
+    if per_us == 0 {
+        log::warn!("TSC calibration failed ({} ticks in {} ms)", elapsed, CALIBRATION_MS);
+        return;
+    }
+
+    TSC_PER_US.store(per_us, Ordering::Relaxed);
+    log::debug!("TSC calibrated: {} MHz", per_us);
+}
+
+/// TSC ticks per microsecond, `None` until the TSC is calibrated
+pub fn tsc_per_us() -> Option<u64> {
+    match TSC_PER_US.load(Ordering::Relaxed) {
+        0 => None,
+        per_us => Some(per_us),
+    }
+}
+
+/// Spin for at least `us` microseconds
+pub fn delay_us(us: u64) {
+    let per_us = TSC_PER_US.load(Ordering::Relaxed);
+    if per_us != 0 {
+        let ticks = us * per_us;
+        let start = rdtsc();
+        while rdtsc().wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    if hpet::sleep_ns(us * 1000) {
+        return;
+    }
+
+    for _ in 0..us {
+        io_wait();
+    }
+}
+
+/// Spin for at least `ms` milliseconds
+pub fn delay_ms(ms: u64) {
+    delay_us(ms * 1000);
+}
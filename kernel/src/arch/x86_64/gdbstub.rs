@@ -0,0 +1,566 @@
+//! GDB remote stub on COM2, enabled by the `gdb` command line flag.
+//!
+//! Speaks enough of the remote serial protocol for `target remote` to read and write registers
+//! and memory, set software breakpoints, single-step and continue. The stub takes over on a
+//! breakpoint, after a single step, or when GDB sends Ctrl-C, and keeps the CPU with interrupts
+//! disabled until GDB resumes it. The kernel breaks in right after the stub is set up, so GDB can
+//! attach before anything else runs.
+//!
+//! With QEMU, give COM2 a socket (`make gdbstub` does) and `target remote :4321` from GDB.
+
+use crate::BootInfo;
+use crate::arch::x86_64::serial::{self, Serial};
+use crate::arch::x86_64::{idt, idt::InterruptFrame, paging, read_cr0, write_cr0};
+use crate::mem::PAGE_SIZE;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const PORT: u16 = serial::COM2;
+const IRQ: u8 = 3;
+
+/// Largest packet either side sends, advertised to GDB as `PacketSize` (in hex)
+const PACKET_SIZE: usize = 0x400;
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xCC;
+/// GDB's interrupt request, sent outside of any packet
+const CTRL_C: u8 = 0x03;
+
+/// CR0.WP: when set, the kernel can't write to read-only pages either
+const CR0_WP: u64 = 1 << 16;
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// Signal numbers GDB expects in stop replies
+mod signal {
+    pub const SIGINT: u8 = 2;
+    pub const SIGTRAP: u8 = 5;
+}
+
+/// Registers in GDB's amd64 order start with these 17 of 8 bytes, followed by EFLAGS and six
+/// segment registers of 4
+const GPR_COUNT: usize = 17;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    /// The byte the `int3` replaced
+    original: u8,
+}
+
+/// Why the stub took over, reported to GDB in the stop reply
+#[derive(Clone, Copy)]
+struct Stop {
+    signal: u8,
+    /// Stopped on one of our breakpoints, RIP has already been moved back onto it
+    swbreak: bool,
+}
+
+struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// Whether GDB resumed the kernel and is waiting to hear why it stopped
+    attached: bool,
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+}
+
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    breakpoints: [None; MAX_BREAKPOINTS],
+    attached: false,
+    packet: [0; PACKET_SIZE],
+    reply: Reply::new(),
+});
+
+/// Whether the stub is set up, i.e. `gdb` was on the command line and COM2 works
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Set up COM2 for GDB if the command line asks for it, then break in
+pub fn init(boot_info: &BootInfo) {
+    if !boot_info.cmdline_flag("gdb") {
+        return;
+    }
+
+    let port = Serial::new(PORT);
+    if let Err(e) = port.init() {
+        log::warn!("{} on COM2, GDB stub disabled", e);
+        return;
+    }
+    port.enable_rx_interrupt();
+
+    ENABLED.store(true, Ordering::Relaxed);
+    idt::unmask_irq(IRQ);
+
+    log::info!("GDB stub listening on COM2, waiting for GDB to attach");
+    unsafe { core::arch::asm!("int3") };
+}
+
+/// #BP: take over if the stub is enabled, otherwise leave the breakpoint to the caller
+pub(super) fn handle_breakpoint(frame: &mut InterruptFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+
+    let mut stub = lock();
+
+    // `int3` traps after itself, so RIP is one past the breakpoint
+    let addr = frame.rip.wrapping_sub(1);
+    let swbreak = stub.breakpoints.iter().flatten().any(|bp| bp.addr == addr);
+    if swbreak {
+        frame.rip = addr;
+    }
+
+    stub.serve(
+        frame,
+        Stop {
+            signal: signal::SIGTRAP,
+            swbreak,
+        },
+    );
+    true
+}
+
+/// #DB after a single step: take over if the stub is enabled
+pub(super) fn handle_single_step(frame: &mut InterruptFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+
+    lock().serve(
+        frame,
+        Stop {
+            signal: signal::SIGTRAP,
+            swbreak: false,
+        },
+    );
+    true
+}
+
+/// COM2 interrupt: stop the kernel if GDB sent Ctrl-C, anything else is noise between packets
+pub(super) fn handle_interrupt(frame: &mut InterruptFrame) {
+    let port = Serial::new(PORT);
+    let mut interrupted = false;
+    while let Some(byte) = port.read_byte() {
+        interrupted |= byte == CTRL_C;
+    }
+
+    if interrupted && is_enabled() {
+        lock().serve(
+            frame,
+            Stop {
+                signal: signal::SIGINT,
+                swbreak: false,
+            },
+        );
+    }
+}
+
+fn lock() -> spin::MutexGuard<'static, Stub> {
+    // Only a breakpoint inside the stub itself could find it locked
+    STUB.try_lock()
+        .unwrap_or_else(|| crate::bug!("GDB stub re-entered"))
+}
+
+impl Stub {
+    /// Tell GDB why the kernel stopped (if it's waiting to hear) and handle its commands until it
+    /// resumes the kernel
+    fn serve(&mut self, frame: &mut InterruptFrame, stop: Stop) {
+        let Stub {
+            breakpoints,
+            attached,
+            packet,
+            reply,
+        } = self;
+
+        if *attached {
+            reply.stop(stop);
+            reply.send();
+        }
+
+        loop {
+            let len = receive(packet);
+            let (command, args) = match packet[..len].split_first() {
+                Some((&command, args)) => (command, args),
+                None => (0, &[][..]),
+            };
+
+            reply.clear();
+            match command {
+                b'?' => reply.stop(stop),
+                b'g' => reply.registers(frame),
+                b'G' => match write_registers(frame, args) {
+                    Some(()) => reply.ok(),
+                    None => reply.error(0x16),
+                },
+                b'm' => match parse_range(args) {
+                    Some((addr, len)) => reply.memory(addr, len, breakpoints),
+                    None => reply.error(0x16),
+                },
+                b'M' => match write_memory(args, breakpoints) {
+                    Some(()) => reply.ok(),
+                    None => reply.error(0x0E),
+                },
+                b'c' | b's' => {
+                    if let Some(addr) = parse_hex(args) {
+                        frame.rip = addr;
+                    }
+                    if command == b's' {
+                        frame.rflags |= RFLAGS_TF;
+                    } else {
+                        frame.rflags &= !RFLAGS_TF;
+                    }
+
+                    *attached = true;
+                    // Time spent stopped doesn't count as a hang
+                    crate::watchdog::kick();
+                    return;
+                }
+                b'Z' | b'z' => match breakpoint_command(command == b'Z', args, breakpoints) {
+                    Some(true) => reply.ok(),
+                    Some(false) => reply.error(0x0E),
+                    // Other breakpoint kinds aren't supported, which an empty reply says
+                    None => {}
+                },
+                b'D' | b'k' => {
+                    for bp in breakpoints.iter_mut().filter_map(Option::take) {
+                        write_text(bp.addr, bp.original);
+                    }
+                    frame.rflags &= !RFLAGS_TF;
+                    *attached = false;
+
+                    if command == b'D' {
+                        reply.ok();
+                        reply.send();
+                    }
+                    crate::watchdog::kick();
+                    return;
+                }
+                b'H' => reply.ok(),
+                b'q' if args.starts_with(b"Supported") => reply.push_str("PacketSize=400;swbreak+"),
+                b'q' if args == b"Attached" => reply.push_str("1"),
+                // Anything else is unsupported, which an empty reply says
+                _ => {}
+            }
+            reply.send();
+        }
+    }
+}
+
+/// Reply being built, sent with `send`
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    const fn new() -> Self {
+        Self {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    fn push_hex_byte(&mut self, byte: u8) {
+        hex_byte(byte)
+            .into_iter()
+            .for_each(|digit| self.push(digit));
+    }
+
+    /// `value` as hex in target (little endian) byte order
+    fn push_le(&mut self, value: u64, size: usize) {
+        value.to_le_bytes()[..size]
+            .iter()
+            .for_each(|&byte| self.push_hex_byte(byte));
+    }
+
+    fn ok(&mut self) {
+        self.push_str("OK");
+    }
+
+    fn error(&mut self, errno: u8) {
+        self.push(b'E');
+        self.push_hex_byte(errno);
+    }
+
+    fn stop(&mut self, stop: Stop) {
+        self.push(b'T');
+        self.push_hex_byte(stop.signal);
+        if stop.swbreak {
+            self.push_str("swbreak:;");
+        }
+    }
+
+    fn registers(&mut self, f: &InterruptFrame) {
+        for value in gprs(f) {
+            self.push_le(value, 8);
+        }
+
+        // EFLAGS, CS, SS, then DS, ES, FS and GS, which the kernel leaves at zero
+        for value in [f.rflags, f.cs, f.ss, 0, 0, 0, 0] {
+            self.push_le(value, 4);
+        }
+    }
+
+    /// `len` bytes at `addr`, with our breakpoints showing the bytes they replaced
+    fn memory(
+        &mut self,
+        addr: u64,
+        len: usize,
+        breakpoints: &[Option<Breakpoint>; MAX_BREAKPOINTS],
+    ) {
+        let len = len.min(PACKET_SIZE / 2);
+        if !is_mapped(addr, len) {
+            self.error(0x0E);
+            return;
+        }
+
+        for at in addr..addr + len as u64 {
+            let byte = match breakpoints.iter().flatten().find(|bp| bp.addr == at) {
+                Some(bp) => bp.original,
+                None => unsafe { core::ptr::read_volatile(at as *const u8) },
+            };
+            self.push_hex_byte(byte);
+        }
+    }
+
+    fn send(&self) {
+        let body = &self.buf[..self.len];
+        let checksum = body.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let port = Serial::new(PORT);
+
+        // Resend until GDB acknowledges it with `+`
+        loop {
+            port.write_byte(b'$');
+            body.iter().for_each(|&byte| port.write_byte(byte));
+            port.write_byte(b'#');
+            hex_byte(checksum)
+                .into_iter()
+                .for_each(|digit| port.write_byte(digit));
+
+            match read_byte() {
+                b'+' => return,
+                b'-' => continue,
+                // GDB went away or reset the connection, don't wait on it forever
+                _ => return,
+            }
+        }
+    }
+}
+
+/// General purpose registers and RIP, in GDB's order
+fn gprs(f: &InterruptFrame) -> [u64; GPR_COUNT] {
+    [
+        f.rax, f.rbx, f.rcx, f.rdx, f.rsi, f.rdi, f.rbp, f.rsp, f.r8, f.r9, f.r10, f.r11, f.r12,
+        f.r13, f.r14, f.r15, f.rip,
+    ]
+}
+
+/// `G`: load the general purpose registers, RIP and EFLAGS. Segment registers are left alone.
+fn write_registers(f: &mut InterruptFrame, hex: &[u8]) -> Option<()> {
+    if hex.len() < (GPR_COUNT * 8 + 4) * 2 {
+        return None;
+    }
+
+    let le = |index: usize, size: usize| {
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes[..size].iter_mut().enumerate() {
+            *byte = parse_hex_byte(&hex[(index + i) * 2..])?;
+        }
+        Some(u64::from_le_bytes(bytes))
+    };
+
+    let mut gprs = [0u64; GPR_COUNT];
+    for (i, value) in gprs.iter_mut().enumerate() {
+        *value = le(i * 8, 8)?;
+    }
+    let rflags = le(GPR_COUNT * 8, 4)?;
+
+    [
+        f.rax, f.rbx, f.rcx, f.rdx, f.rsi, f.rdi, f.rbp, f.rsp, f.r8, f.r9, f.r10, f.r11, f.r12,
+        f.r13, f.r14, f.r15, f.rip,
+    ] = gprs;
+    f.rflags = rflags;
+
+    Some(())
+}
+
+/// `M addr,len:bytes`. Writing over one of our breakpoints updates the byte it restores instead.
+fn write_memory(
+    args: &[u8],
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+) -> Option<()> {
+    let colon = args.iter().position(|&byte| byte == b':')?;
+    let (addr, len) = parse_range(&args[..colon])?;
+    let data = &args[colon + 1..];
+
+    if data.len() != len * 2 || !is_mapped(addr, len) {
+        return None;
+    }
+
+    for (i, at) in (addr..addr + len as u64).enumerate() {
+        let byte = parse_hex_byte(&data[i * 2..])?;
+        match breakpoints.iter_mut().flatten().find(|bp| bp.addr == at) {
+            Some(bp) => bp.original = byte,
+            None => write_text(at, byte),
+        }
+    }
+
+    Some(())
+}
+
+/// `Z0,addr,kind` or `z0,addr,kind`. `None` for kinds other than software breakpoints, otherwise
+/// whether it worked.
+fn breakpoint_command(
+    insert: bool,
+    args: &[u8],
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+) -> Option<bool> {
+    let args = args.strip_prefix(b"0,")?;
+    let comma = args.iter().position(|&byte| byte == b',')?;
+    let addr = parse_hex(&args[..comma])?;
+
+    let existing = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|bp| bp.addr == addr));
+
+    if !insert {
+        if let Some(bp) = existing.and_then(Option::take) {
+            write_text(bp.addr, bp.original);
+        }
+        return Some(true);
+    }
+
+    if existing.is_some() {
+        return Some(true);
+    }
+    if !is_mapped(addr, 1) {
+        return Some(false);
+    }
+    let Some(slot) = breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+        return Some(false);
+    };
+
+    let original = unsafe { core::ptr::read_volatile(addr as *const u8) };
+    write_text(addr, INT3);
+    *slot = Some(Breakpoint { addr, original });
+
+    Some(true)
+}
+
+/// Whether every byte of `[addr, addr + len)` is mapped
+fn is_mapped(addr: u64, len: usize) -> bool {
+    let Some(end) = addr.checked_add(len as u64) else {
+        return false;
+    };
+
+    let first_page = addr & !(PAGE_SIZE as u64 - 1);
+    (first_page..end)
+        .step_by(PAGE_SIZE)
+        .all(|page| paging::translate(page).is_some())
+}
+
+/// Write a byte even if it's in read-only kernel text
+fn write_text(addr: u64, byte: u8) {
+    let cr0 = read_cr0();
+    write_cr0(cr0 & !CR0_WP);
+    unsafe { core::ptr::write_volatile(addr as *mut u8, byte) };
+    write_cr0(cr0);
+}
+
+fn read_byte() -> u8 {
+    let port = Serial::new(PORT);
+    loop {
+        if let Some(byte) = port.read_byte() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for a packet with a good checksum and return the length of its body in `buf`.
+/// Packets are acknowledged as they arrive, bad ones are asked for again.
+fn receive(buf: &mut [u8; PACKET_SIZE]) -> usize {
+    let port = Serial::new(PORT);
+
+    loop {
+        // Acks and Ctrl-C that arrive while stopped mean nothing
+        while read_byte() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum = 0u8;
+        let complete = loop {
+            match read_byte() {
+                b'#' => break true,
+                b'$' => break false,
+                byte => {
+                    if len == buf.len() {
+                        break false;
+                    }
+                    buf[len] = byte;
+                    len += 1;
+                    checksum = checksum.wrapping_add(byte);
+                }
+            }
+        };
+
+        let expected = parse_hex_byte(&[read_byte(), read_byte()]);
+        if complete && expected == Some(checksum) {
+            port.write_byte(b'+');
+            return len;
+        }
+        port.write_byte(b'-');
+    }
+}
+
+fn hex_byte(byte: u8) -> [u8; 2] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    [DIGITS[(byte >> 4) as usize], DIGITS[(byte & 0xF) as usize]]
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex_byte(hex: &[u8]) -> Option<u8> {
+    Some(hex_digit(*hex.first()?)? << 4 | hex_digit(*hex.get(1)?)?)
+}
+
+/// A big endian hex number of up to 16 digits, as GDB writes addresses and lengths
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter()
+        .try_fold(0u64, |value, &c| Some(value << 4 | hex_digit(c)? as u64))
+}
+
+/// `addr,len`
+fn parse_range(args: &[u8]) -> Option<(u64, usize)> {
+    let comma = args.iter().position(|&byte| byte == b',')?;
+    let addr = parse_hex(&args[..comma])?;
+    let len = parse_hex(&args[comma + 1..])? as usize;
+    Some((addr, len))
+}
@@ -13,6 +13,9 @@
 
 use core::mem::size_of;
 
+use crate::arch::paging;
+use crate::mem::PAGE_SIZE;
+
 use bitflags::bitflags;
 use log;
 
@@ -242,33 +245,111 @@ pub struct Gdt {
     null: GdtEntry,        // Null segment (required, but unused)
     kernel_code: GdtEntry, // Kernel code segment
     kernel_data: GdtEntry, // Kernel data segment
-    user_code: GdtEntry,   // User code segment
     user_data: GdtEntry,   // User data segment
+    user_code: GdtEntry,   // User code segment
     tss_entry: TssEntry,   // TSS takes up 2 entries
 }
 
+// These are all read by the CPU, which expects exactly these layouts
+const _: () = {
+    use core::mem::offset_of;
+
+    assert!(size_of::<GdtEntry>() == 8);
+    assert!(offset_of!(GdtEntry, access) == 5);
+    assert!(offset_of!(GdtEntry, granularity) == 6);
+    assert!(size_of::<TssEntry>() == 16);
+    assert!(offset_of!(TssEntry, flags1) == 5);
+    assert!(offset_of!(TssEntry, base_upper) == 8);
+    assert!(size_of::<GdtDescriptor>() == 10);
+
+    assert!(size_of::<TaskStateSegment>() == 104);
+    assert!(offset_of!(TaskStateSegment, rsps) == 4);
+    assert!(offset_of!(TaskStateSegment, ists) == 36);
+    assert!(offset_of!(TaskStateSegment, io_map_base) == 102);
+
+    // The selectors index straight into this
+    assert!(offset_of!(Gdt, kernel_code) == KERNEL_CODE_SELECTOR as usize);
+    assert!(offset_of!(Gdt, kernel_data) == KERNEL_DATA_SELECTOR as usize);
+    assert!(offset_of!(Gdt, user_data) == (USER_DATA_SELECTOR & !3) as usize);
+    assert!(offset_of!(Gdt, user_code) == (USER_CODE_SELECTOR & !3) as usize);
+    assert!(offset_of!(Gdt, tss_entry) == TSS_SELECTOR as usize);
+    assert!(size_of::<Gdt>() == 7 * 8);
+};
+
 static mut GDT: Gdt = Gdt {
     null: GdtEntry::null(),
     kernel_code: GdtEntry::code(),
     kernel_data: GdtEntry::data(),
-    user_code: GdtEntry::user_code(),
     user_data: GdtEntry::user_data(),
+    user_code: GdtEntry::user_code(),
     tss_entry: TssEntry::null(), // Will be initialized later
 };
 
 static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
+/// A stack with an extra page below it. Once paging is up the guard page is unmapped, so running
+/// off the bottom of the stack page faults instead of silently corrupting whatever sits below it.
+#[repr(C, align(4096))]
+struct GuardedStack<const N: usize> {
+    guard: [u8; PAGE_SIZE],
+    stack: [u8; N],
+}
+
+impl<const N: usize> GuardedStack<N> {
+    const fn new() -> Self {
+        Self {
+            guard: [0; PAGE_SIZE],
+            stack: [0; N],
+        }
+    }
+
+    fn guard_addr(&self) -> u64 {
+        self.guard.as_ptr() as u64
+    }
+
+    /// Lowest usable address of the stack
+    fn bottom(&self) -> u64 {
+        self.stack.as_ptr() as u64
+    }
+
+    /// Initial stack pointer (the stack grows down from here)
+    fn top(&self) -> u64 {
+        self.stack.as_ptr() as u64 + N as u64
+    }
+}
+
 /// Kernel stack for syscalls and interrupts
-static mut KERNEL_STACK: [u8; 32768] = [0; 32768]; // 32KB, used for kernel mode stack during syscalls and interrupts
-static mut IST_STACK0: [u8; 16384] = [0; 16384]; // Used for double faults and stuff
+static mut KERNEL_STACK: GuardedStack<32768> = GuardedStack::new(); // 32KB, used for kernel mode stack during syscalls and interrupts
+static mut IST_STACK0: GuardedStack<16384> = GuardedStack::new(); // Used for double faults and stuff
+
+unsafe extern "C" {
+    /// Guard page below the boot stack (the stack `_start64` runs on), see boot_stub.asm
+    static boot_stack_guard: u8;
+}
+
+/// Size of the boot stack, must match boot_stub.asm
+const BOOT_STACK_SIZE: u64 = 65536;
 
 /// Segment selectors
+/// User data comes before user code because SYSRET loads SS from STAR[63:48] + 8 and CS from
+/// STAR[63:48] + 16.
 pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
 pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
-pub const USER_CODE_SELECTOR: u16 = 0x18 | 3;
-pub const USER_DATA_SELECTOR: u16 = 0x20 | 3;
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
 pub const TSS_SELECTOR: u16 = 0x28;
 
+/// Top of the boot thread's stack for entering the kernel from user mode, other threads bring
+/// their own
+pub fn kernel_stack_top() -> u64 {
+    unsafe { KERNEL_STACK.top() }
+}
+
+/// Have the CPU switch to the stack ending at `top` when it enters the kernel from user mode
+pub fn set_kernel_stack(top: u64) {
+    unsafe { TSS.rsps[0] = top };
+}
+
 pub fn init() {
     log::trace!("Initializing GDT...");
 
@@ -280,8 +361,8 @@ pub fn init() {
         let tss_size = (size_of::<TaskStateSegment>() - 1) as u16;
 
         // Set kernel SP
-        TSS.rsps[0] = (&KERNEL_STACK[KERNEL_STACK.len() - 1] as *const u8) as u64;
-        TSS.ists[0] = (&IST_STACK0[IST_STACK0.len() - 1] as *const u8) as u64;
+        TSS.rsps[0] = KERNEL_STACK.top();
+        TSS.ists[0] = IST_STACK0.top();
 
         // Set TSS entry in GDT
         GDT.tss_entry = TssEntry::new(tss_addr, tss_size);
@@ -317,6 +398,49 @@ pub fn init() {
     }
 }
 
+/// Guard pages of every kernel stack, as (stack name, guard page address)
+fn stack_guards() -> [(&'static str, u64); 3] {
+    unsafe {
+        [
+            ("boot", &raw const boot_stack_guard as u64),
+            ("kernel", KERNEL_STACK.guard_addr()),
+            ("IST0", IST_STACK0.guard_addr()),
+        ]
+    }
+}
+
+/// Every kernel stack, as (stack name, bottom, top)
+pub fn stack_bounds() -> [(&'static str, u64, u64); 3] {
+    unsafe {
+        let boot_bottom = &raw const boot_stack_guard as u64 + PAGE_SIZE as u64;
+        [
+            ("boot", boot_bottom, boot_bottom + BOOT_STACK_SIZE),
+            ("kernel", KERNEL_STACK.bottom(), KERNEL_STACK.top()),
+            ("IST0", IST_STACK0.bottom(), IST_STACK0.top()),
+        ]
+    }
+}
+
+/// Unmap the guard page below each kernel stack.
+/// Splitting the huge identity mapping needs the frame allocator, so this has to run after
+/// memory management is initialized.
+pub fn install_stack_guards() {
+    for (name, guard) in stack_guards() {
+        match paging::unmap_page(guard) {
+            Ok(_) => log::debug!("Installed {} stack guard page at {:#x}", name, guard),
+            Err(e) => log::warn!("Failed to install {} stack guard page at {:#x}: {}", name, guard, e),
+        }
+    }
+}
+
+/// If `addr` lies in one of the stack guard pages, return the name of the stack that overflowed
+pub fn stack_guard_hit(addr: u64) -> Option<&'static str> {
+    stack_guards()
+        .into_iter()
+        .find(|&(_, guard)| (guard..guard + PAGE_SIZE as u64).contains(&addr))
+        .map(|(name, _)| name)
+}
+
 // helper functions
 fn load_gdt(gdt_descriptor: &GdtDescriptor) {
     unsafe {
@@ -3,11 +3,21 @@
 //! entries that correspond to vectors 0-255, which can be used for hardware interrupts, software
 //! interrupts, and exceptions.
 
-use crate::arch::{self, x86_64::gdt::KERNEL_CODE_SELECTOR};
-use crate::drivers::keyboard;
+use crate::arch::x86_64::gdt::{
+    self, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR,
+};
+use crate::arch::x86_64::delay::io_wait;
+use crate::arch::x86_64::{inb, outb, percpu::PerCpu, rdmsr, wrmsr};
+use crate::arch::{self, x86_64::backtrace, x86_64::debug, x86_64::gdbstub};
+use crate::drivers::{acpi, ps2};
+use crate::mem::PAGE_SIZE;
+use crate::proc::scheduler;
+use crate::sync::IrqSpinlock;
 use log;
 
 use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
 
 /// IDT entry type
 /// An interrupt clears the IF flag, while a trap does not. This means that interrupts can be
@@ -79,10 +89,8 @@ struct Idt {
     entries: [IdtEntry; 256],
 }
 
-/// Global IDT instance
-static mut IDT: Idt = Idt {
-    entries: [IdtEntry::null(); 256],
-};
+/// The IDT the CPU uses, filled in by `init`
+static IDT: Once<Idt> = Once::new();
 
 // ISR definitions
 
@@ -91,28 +99,28 @@ static mut IDT: Idt = Idt {
 ///   r15..rax  (pushed by push_regs, low → high address)
 ///   rip / cs / rflags / rsp / ss  (pushed by CPU)
 #[repr(C)]
-struct InterruptFrame {
-    r15: u64,
-    r14: u64,
-    r13: u64,
-    r12: u64,
-    r11: u64,
-    r10: u64,
-    r9: u64,
-    r8: u64,
-    rbp: u64,
-    rdi: u64,
-    rsi: u64,
-    rdx: u64,
-    rcx: u64,
-    rbx: u64,
-    rax: u64,
+pub(super) struct InterruptFrame {
+    pub(super) r15: u64,
+    pub(super) r14: u64,
+    pub(super) r13: u64,
+    pub(super) r12: u64,
+    pub(super) r11: u64,
+    pub(super) r10: u64,
+    pub(super) r9: u64,
+    pub(super) r8: u64,
+    pub(super) rbp: u64,
+    pub(super) rdi: u64,
+    pub(super) rsi: u64,
+    pub(super) rdx: u64,
+    pub(super) rcx: u64,
+    pub(super) rbx: u64,
+    pub(super) rax: u64,
     // CPU-pushed
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-    rsp: u64,
-    ss: u64,
+    pub(super) rip: u64,
+    pub(super) cs: u64,
+    pub(super) rflags: u64,
+    pub(super) rsp: u64,
+    pub(super) ss: u64,
 }
 
 /// Same as `InterruptFrame` but with an error code between the saved regs and the CPU frame.
@@ -142,6 +150,34 @@ struct InterruptFrameWithError {
     ss: u64,
 }
 
+// The CPU reads IDT entries and the descriptor with these exact layouts, and the handler stubs
+// hand the stack pointer straight to code that reads it as one of the frames
+const _: () = {
+    use core::mem::offset_of;
+
+    assert!(size_of::<IdtEntry>() == 16);
+    assert!(offset_of!(IdtEntry, selector) == 2);
+    assert!(offset_of!(IdtEntry, ist) == 4);
+    assert!(offset_of!(IdtEntry, type_attr) == 5);
+    assert!(offset_of!(IdtEntry, offset_mid) == 6);
+    assert!(offset_of!(IdtEntry, offset_high) == 8);
+    assert!(size_of::<IdtDescriptor>() == 10);
+    assert!(size_of::<Idt>() == 256 * 16);
+
+    // 15 saved registers, then the 5 quadwords the CPU pushes
+    assert!(size_of::<InterruptFrame>() == 20 * 8);
+    assert!(offset_of!(InterruptFrame, rax) == 14 * 8);
+    assert!(offset_of!(InterruptFrame, rip) == 15 * 8);
+    assert!(offset_of!(InterruptFrame, ss) == 19 * 8);
+
+    // The error code sits between the saved registers and the CPU frame
+    assert!(size_of::<InterruptFrameWithError>() == 21 * 8);
+    assert!(offset_of!(InterruptFrameWithError, rax) == 14 * 8);
+    assert!(offset_of!(InterruptFrameWithError, error_code) == 15 * 8);
+    assert!(offset_of!(InterruptFrameWithError, rip) == 16 * 8);
+    assert!(offset_of!(InterruptFrameWithError, ss) == 20 * 8);
+};
+
 macro_rules! push_regs {
     () => {
         "push rax; push rbx; push rcx; push rdx;
@@ -160,6 +196,119 @@ macro_rules! pop_regs {
     };
 }
 
+/// `swapgs`, but only if the CPU came from (or is going back to) user mode, where the GS base is
+/// the user's and the per-CPU block waits in IA32_KERNEL_GS_BASE (see `percpu`). `$cs` is where
+/// the saved CS is relative to RSP: 8 on entry, 16 on entry with an error code, and 8 right
+/// before `iretq`.
+macro_rules! swapgs_if_user {
+    ($cs:literal) => {
+        concat!("test qword ptr [rsp + ", $cs, "], 3; jz 2f; swapgs; 2:")
+    };
+}
+
+/// Registers saved by `push_regs!`, in `InterruptFrame` field order (lowest address first).
+///
+/// The stack grows down, so the first register pushed ends up at the highest address: `push_regs!`
+/// has to push this list back to front (rax first), leaving RSP pointing at r15, the first field.
+/// `pop_regs!` undoes it front to back. Both macros and both frame structs are checked against
+/// this list below, so none of them can be reordered on its own.
+const SAVED_REGS: [&str; 15] = [
+    "r15", "r14", "r13", "r12", "r11", "r10", "r9", "r8", "rbp", "rdi", "rsi", "rdx", "rcx", "rbx",
+    "rax",
+];
+
+/// Whether `asm` is nothing but `mnemonic reg` instructions, separated by `;` or whitespace, for
+/// each of `regs` in order (or in reverse)
+const fn is_register_sequence(asm: &str, mnemonic: &str, regs: &[&str], reverse: bool) -> bool {
+    const fn is_separator(byte: u8) -> bool {
+        matches!(byte, b' ' | b'\t' | b'\n' | b';')
+    }
+
+    let asm = asm.as_bytes();
+    let mnemonic = mnemonic.as_bytes();
+    let mut i = 0;
+    let mut count = 0;
+
+    loop {
+        while i < asm.len() && is_separator(asm[i]) {
+            i += 1;
+        }
+        if i == asm.len() {
+            return count == regs.len();
+        }
+        if count == regs.len() {
+            return false;
+        }
+
+        let mut j = 0;
+        while j < mnemonic.len() {
+            if i + j >= asm.len() || asm[i + j] != mnemonic[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += mnemonic.len();
+        if i >= asm.len() || asm[i] != b' ' {
+            return false;
+        }
+        while i < asm.len() && asm[i] == b' ' {
+            i += 1;
+        }
+
+        let expected = if reverse {
+            regs[regs.len() - 1 - count]
+        } else {
+            regs[count]
+        }
+        .as_bytes();
+        let mut j = 0;
+        while i < asm.len() && !is_separator(asm[i]) {
+            if j >= expected.len() || asm[i] != expected[j] {
+                return false;
+            }
+            i += 1;
+            j += 1;
+        }
+        if j != expected.len() {
+            return false;
+        }
+
+        count += 1;
+    }
+}
+
+const _: () = {
+    use core::mem::offset_of;
+
+    assert!(is_register_sequence(push_regs!(), "push", &SAVED_REGS, true));
+    assert!(is_register_sequence(pop_regs!(), "pop", &SAVED_REGS, false));
+
+    // Field i of either frame is SAVED_REGS[i]
+    macro_rules! assert_saved_regs_order {
+        ($frame:ty: $($field:ident),*) => {
+            let mut i = 0;
+            $(
+                let name = stringify!($field).as_bytes();
+                let expected = SAVED_REGS[i].as_bytes();
+                assert!(offset_of!($frame, $field) == i * 8);
+                assert!(name.len() == expected.len());
+                let mut j = 0;
+                while j < name.len() {
+                    assert!(name[j] == expected[j]);
+                    j += 1;
+                }
+                i += 1;
+            )*
+            assert!(i == SAVED_REGS.len());
+        };
+    }
+
+    assert_saved_regs_order!(InterruptFrame:
+        r15, r14, r13, r12, r11, r10, r9, r8, rbp, rdi, rsi, rdx, rcx, rbx, rax);
+    assert_saved_regs_order!(InterruptFrameWithError:
+        r15, r14, r13, r12, r11, r10, r9, r8, rbp, rdi, rsi, rdx, rcx, rbx, rax);
+};
+
 #[inline(always)]
 fn halt() -> ! {
     log::error!("System halted.");
@@ -170,9 +319,12 @@ fn halt() -> ! {
 }
 
 macro_rules! exception_no_error {
-    ($name:ident, $msg:expr) => {
+    ($name:ident, $vector:expr, $msg:expr) => {
+        const _: () = assert!(!pushes_error_code($vector), concat!($msg, " pushes an error code"));
+
         paste::paste! {
             extern "C" fn [<$name _inner>](frame: *const InterruptFrame) -> ! {
+                count($vector);
                 let f = unsafe { &*frame };
                 log::error!(
                     concat!("Exception: ", $msg, "\n",
@@ -181,7 +333,7 @@ macro_rules! exception_no_error {
                             "  RAX={:#018x}  RBX={:#018x}  RCX={:#018x}  RDX={:#018x}\n",
                             "  RSI={:#018x}  RDI={:#018x}  RBP={:#018x}\n",
                             "  R8 ={:#018x}  R9 ={:#018x}  R10={:#018x}  R11={:#018x}\n",
-                            "  R12={:#018x}  R13={:#018x}  R14={:#018x}  R15={:#018x}\x1b[0m\n"),
+                            "  R12={:#018x}  R13={:#018x}  R14={:#018x}  R15={:#018x}\n"),
                     f.rip, f.cs, f.rflags,
                     f.rsp, f.ss,
                     f.rax, f.rbx, f.rcx, f.rdx,
@@ -195,10 +347,12 @@ macro_rules! exception_no_error {
             #[unsafe(naked)]
             extern "C" fn $name() {
                 core::arch::naked_asm!(
+                    swapgs_if_user!("8"),
                     push_regs!(),
                     "mov rdi, rsp",
                     "call {inner}",
                     pop_regs!(),
+                    swapgs_if_user!("8"),
                     "iretq",
                     inner = sym [<$name _inner>],
                 );
@@ -208,10 +362,16 @@ macro_rules! exception_no_error {
 }
 
 macro_rules! exception_with_error {
-    ($name:ident, $msg:expr) => {
+    ($name:ident, $vector:expr, $msg:expr) => {
+        const _: () = assert!(pushes_error_code($vector), concat!($msg, " pushes no error code"));
+
         paste::paste! {
             extern "C" fn [<$name _inner>](frame: *const InterruptFrameWithError) -> ! {
+                count($vector);
                 let f = unsafe { &*frame };
+                if has_selector_error($vector) {
+                    log_selector_error($msg, f.error_code);
+                }
                 log::error!(
                     concat!("Exception: ", $msg, "\n",
                             "  Error Code : {:#018x}\n",
@@ -220,7 +380,7 @@ macro_rules! exception_with_error {
                             "  RAX={:#018x}  RBX={:#018x}  RCX={:#018x}  RDX={:#018x}\n",
                             "  RSI={:#018x}  RDI={:#018x}  RBP={:#018x}\n",
                             "  R8 ={:#018x}  R9 ={:#018x}  R10={:#018x}  R11={:#018x}\n",
-                            "  R12={:#018x}  R13={:#018x}  R14={:#018x}  R15={:#018x}\x1b[0m\n"),
+                            "  R12={:#018x}  R13={:#018x}  R14={:#018x}  R15={:#018x}\n"),
                     f.error_code,
                     f.rip, f.cs, f.rflags,
                     f.rsp, f.ss,
@@ -235,11 +395,13 @@ macro_rules! exception_with_error {
             #[unsafe(naked)]
             extern "C" fn $name() {
                 core::arch::naked_asm!(
+                    swapgs_if_user!("16"),
                     push_regs!(),
                     "mov rdi, rsp",
                     "call {inner}",
                     pop_regs!(),
                     "add rsp, 8", // pop error code
+                    swapgs_if_user!("8"),
                     "iretq",
                     inner = sym [<$name _inner>],
                 );
@@ -248,22 +410,196 @@ macro_rules! exception_with_error {
     };
 }
 
+/// Whether the CPU pushes an error code for exception `vector`, and so whether its handler must
+/// use `exception_with_error!`/`InterruptFrameWithError`. A handler with the wrong frame reads
+/// every register one slot off and `iretq`s to garbage.
+///
+/// This is the authoritative list (Intel SDM Vol. 3A, table 6-1): #DF (8), #TS (10), #NP (11),
+/// #SS (12), #GP (13), #PF (14), #AC (17), #CP (21), #VC (29) and #SX (30). No other exception
+/// pushes one, and neither do IRQs or software interrupts.
+const fn pushes_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+
+/// A CPU exception handler stub and the vector it handles
+struct ExceptionHandler {
+    vector: u8,
+    entry: extern "C" fn(),
+}
+
+impl ExceptionHandler {
+    /// Only used to build `EXCEPTION_HANDLERS`, so a stub registered for a vector whose error code
+    /// it doesn't match fails to compile
+    const fn new(vector: u8, entry: extern "C" fn(), error_code: bool) -> Self {
+        assert!(vector < 32, "Not a CPU exception vector");
+        assert!(
+            pushes_error_code(vector) == error_code,
+            "Exception handler frame doesn't match whether the CPU pushes an error code"
+        );
+
+        Self { vector, entry }
+    }
+}
+
+/// Every CPU exception we handle. `true` marks stubs built for `InterruptFrameWithError`.
+const EXCEPTION_HANDLERS: [ExceptionHandler; 19] = [
+    ExceptionHandler::new(0, divide_error, false),
+    ExceptionHandler::new(1, debug, false),
+    ExceptionHandler::new(2, nmi, false),
+    ExceptionHandler::new(3, breakpoint, false),
+    ExceptionHandler::new(4, overflow, false),
+    ExceptionHandler::new(5, bound_range, false),
+    ExceptionHandler::new(6, invalid_opcode, false),
+    ExceptionHandler::new(7, device_not_available, false),
+    ExceptionHandler::new(DOUBLE_FAULT_VECTOR, double_fault, true),
+    ExceptionHandler::new(10, invalid_tss, true),
+    ExceptionHandler::new(11, segment_not_present, true),
+    ExceptionHandler::new(12, stack_segment, true),
+    ExceptionHandler::new(13, general_protection, true),
+    ExceptionHandler::new(14, page_fault, true),
+    ExceptionHandler::new(16, x87_fp_exception, false),
+    ExceptionHandler::new(17, alignment_check, true),
+    ExceptionHandler::new(18, machine_check, false),
+    ExceptionHandler::new(19, simd_fp_exception, false),
+    ExceptionHandler::new(20, virtualization, false),
+];
+
+/// #TS, #NP, #SS and #GP push an error code naming the segment selector involved
+fn has_selector_error(vector: u8) -> bool {
+    matches!(vector, 10..=13)
+}
+
+/// Decode a selector error code: bit 0 is set if the fault came from an external event (an
+/// interrupt), bits 1-2 say which table the index is in, bits 3-15 are the index
+fn log_selector_error(name: &str, code: u64) {
+    if code == 0 {
+        log::error!("{} not caused by a segment selector", name);
+        return;
+    }
+
+    let external = if code & 1 != 0 { " during delivery of an external event" } else { "" };
+    let index = (code >> 3) & 0x1FFF;
+
+    if code & 2 != 0 {
+        log::error!("{} referencing IDT vector {:#04x}{}", name, index, external);
+    } else {
+        let table = if code & 4 != 0 { "LDT" } else { "GDT" };
+        log::error!(
+            "{} referencing {} selector {:#06x} (index {}){}",
+            name,
+            table,
+            code & 0xFFF8,
+            index,
+            external
+        );
+    }
+}
+
+/// Interrupts taken per vector, bumped on entry to every handler
+static VECTOR_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// IRQ7/IRQ15s the PIC raised without a matching in-service bit
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[inline(always)]
+fn count(vector: u8) {
+    VECTOR_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of interrupts taken so far, indexed by vector
+pub fn stats() -> [u64; 256] {
+    core::array::from_fn(|i| VECTOR_COUNTS[i].load(Ordering::Relaxed))
+}
+
+/// Number of spurious PIC interrupts, these are also counted under vectors 0x27/0x2F
+pub fn spurious_count() -> u64 {
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
+
+/// Log every vector that has fired at least once
+pub fn log_stats() {
+    log::info!("Interrupt counters:");
+    for (vector, &n) in stats().iter().enumerate() {
+        if n != 0 {
+            log::info!("  {:#04x}: {}", vector, n);
+        }
+    }
+    log::info!("  spurious: {}", spurious_count());
+}
+
 static mut TIMER_TICKS: u64 = 0;
 
-extern "C" fn irq_common_handler(irq: u8) {
+/// The PIT is left at its power-on divisor, so it ticks at roughly 18.2 Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65_536;
+
+/// Number of timer interrupts since interrupts were enabled
+pub fn ticks() -> u64 {
+    unsafe { core::ptr::read_volatile(&raw const TIMER_TICKS) }
+}
+
+/// Milliseconds elapsed since interrupts were enabled, from the HPET when there is one
+pub fn uptime_ms() -> u64 {
+    match crate::drivers::hpet::now_ns() {
+        Some(ns) => ns / 1_000_000,
+        None => pit_uptime_ms(),
+    }
+}
+
+/// Milliseconds elapsed since interrupts were enabled, counted in PIT ticks
+pub fn pit_uptime_ms() -> u64 {
+    ticks() * PIT_DIVISOR * 1000 / PIT_FREQUENCY
+}
+
+/// Number of timer ticks that take at least `ms` milliseconds
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * PIT_FREQUENCY).div_ceil(PIT_DIVISOR * 1000)
+}
+
+extern "C" fn irq_common_handler(irq: u8, frame: *mut InterruptFrame) {
+    count(0x20 + irq);
+
+    if (irq == 7 || irq == 15) && is_spurious(irq) {
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        // The slave's spurious IRQ still came through the master's cascade line, which did get
+        // acknowledged, so only the master needs an EOI
+        if irq == 15 {
+            send_eoi(0);
+        }
+        return;
+    }
+
     match irq {
-        0 => unsafe {
-            TIMER_TICKS += 1;
+        0 => {
+            unsafe {
+                TIMER_TICKS += 1;
+
+                if TIMER_TICKS % 100 == 0 {
+                    log::trace!("Timer tick: {}", TIMER_TICKS);
+                }
+            }
 
-            if TIMER_TICKS % 100 == 0 {
-                log::trace!("Timer tick: {}", TIMER_TICKS);
+            if crate::watchdog::expired() {
+                watchdog_bite(unsafe { &*frame });
             }
-        },
+
+            crate::timer::run_due(ticks());
+            scheduler::tick();
+        }
         1 => {
-            keyboard::handle_interrupt();
+            ps2::handle_interrupt(ps2::Port::First);
+        }
+        3 if gdbstub::is_enabled() => {
+            gdbstub::handle_interrupt(unsafe { &mut *frame });
         }
         12 => {
-            log::trace!("Mouse interrupt");
+            ps2::handle_interrupt(ps2::Port::Second);
+        }
+        irq if acpi::sci_irq() == Some(irq) => {
+            acpi::handle_sci();
         }
         _ => {
             log::trace!("Received IRQ {}", irq);
@@ -271,6 +607,32 @@ extern "C" fn irq_common_handler(irq: u8) {
     }
 
     send_eoi(irq);
+
+    // Every thread has its own kernel stack, so user code can be switched away from too
+    scheduler::preempt_from_irq();
+}
+
+/// The watchdog wasn't kicked in time: report where the CPU was stuck and reset the machine
+fn watchdog_bite(f: &InterruptFrame) -> ! {
+    log::error!(
+        "Watchdog expired, the kernel appears to be hung\n\
+         RIP={:#018x}  CS={:#06x}  RFLAGS={:#018x}\n\
+         RSP={:#018x}  SS={:#06x}\n\
+         RAX={:#018x}  RBX={:#018x}  RCX={:#018x}  RDX={:#018x}\n\
+         RSI={:#018x}  RDI={:#018x}  RBP={:#018x}\n\
+         R8 ={:#018x}  R9 ={:#018x}  R10={:#018x}  R11={:#018x}\n\
+         R12={:#018x}  R13={:#018x}  R14={:#018x}  R15={:#018x}",
+        f.rip, f.cs, f.rflags,
+        f.rsp, f.ss,
+        f.rax, f.rbx, f.rcx, f.rdx,
+        f.rsi, f.rdi, f.rbp,
+        f.r8, f.r9, f.r10, f.r11,
+        f.r12, f.r13, f.r14, f.r15,
+    );
+    backtrace::log_from(f.rbp);
+
+    log::error!("Rebooting...");
+    crate::arch::x86_64::reboot();
 }
 
 macro_rules! irq_handler {
@@ -278,10 +640,13 @@ macro_rules! irq_handler {
         #[unsafe(naked)]
         extern "C" fn $name() {
             core::arch::naked_asm!(
+                swapgs_if_user!("8"),
                 push_regs!(),
                 "mov rdi, {irq}",
+                "mov rsi, rsp",
                 "call {handler}",
                 pop_regs!(),
+                swapgs_if_user!("8"),
                 "iretq",
                 irq = const $irq,
                 handler = sym irq_common_handler,
@@ -290,28 +655,218 @@ macro_rules! irq_handler {
     };
 }
 
-exception_no_error!(divide_error, "Divide Error");
-exception_no_error!(debug, "Debug");
-exception_no_error!(nmi, "NMI");
-exception_no_error!(breakpoint, "Breakpoint");
-exception_no_error!(overflow, "Overflow");
-exception_no_error!(bound_range, "Bound Range Exceeded");
-exception_no_error!(invalid_opcode, "Invalid Opcode");
-exception_no_error!(device_not_available, "Device Not Available");
-exception_no_error!(x87_fp_exception, "x87 FP Exception");
-exception_no_error!(simd_fp_exception, "SIMD FP Exception");
-exception_no_error!(virtualization, "Virtualization Exception");
-exception_no_error!(machine_check, "Machine Check");
-
-exception_with_error!(double_fault, "Double Fault");
-exception_with_error!(invalid_tss, "Invalid TSS");
-exception_with_error!(general_protection, "General Protection Fault");
-exception_with_error!(segment_not_present, "Segment Not Present");
-exception_with_error!(stack_segment, "Stack Segment Fault");
-exception_with_error!(alignment_check, "Alignment Check");
+exception_no_error!(divide_error, 0, "Divide Error");
+exception_no_error!(nmi, 2, "NMI");
+exception_no_error!(overflow, 4, "Overflow");
+exception_no_error!(bound_range, 5, "Bound Range Exceeded");
+exception_no_error!(invalid_opcode, 6, "Invalid Opcode");
+exception_no_error!(device_not_available, 7, "Device Not Available");
+exception_no_error!(x87_fp_exception, 16, "x87 FP Exception");
+exception_no_error!(simd_fp_exception, 19, "SIMD FP Exception");
+exception_no_error!(virtualization, 20, "Virtualization Exception");
+exception_no_error!(machine_check, 18, "Machine Check");
+
+exception_with_error!(invalid_tss, 10, "Invalid TSS");
+exception_with_error!(general_protection, 13, "General Protection Fault");
+exception_with_error!(segment_not_present, 11, "Segment Not Present");
+exception_with_error!(stack_segment, 12, "Stack Segment Fault");
+exception_with_error!(alignment_check, 17, "Alignment Check");
+
+/// Set in RFLAGS to suppress instruction breakpoints for one instruction after `iretq`
+const RFLAGS_RF: u64 = 1 << 16;
+
+// Dedicated breakpoint handler - the GDB stub takes over if it's enabled
+extern "C" fn breakpoint_inner(frame: *mut InterruptFrame) {
+    count(3);
+    let f = unsafe { &mut *frame };
+
+    if gdbstub::handle_breakpoint(f) {
+        return;
+    }
+
+    log::error!(
+        "Exception: Breakpoint\n  RIP={:#018x}  CS={:#06x}  RFLAGS={:#018x}\n  RSP={:#018x}  SS={:#06x}\n",
+        f.rip, f.cs, f.rflags, f.rsp, f.ss,
+    );
+    halt();
+}
+
+#[unsafe(naked)]
+extern "C" fn breakpoint() {
+    core::arch::naked_asm!(
+        swapgs_if_user!("8"),
+        push_regs!(),
+        "mov rdi, rsp",
+        "call {inner}",
+        pop_regs!(),
+        swapgs_if_user!("8"),
+        "iretq",
+        inner = sym breakpoint_inner,
+    );
+}
+
+// Dedicated debug handler - hardware watchpoints are reported and execution resumes
+extern "C" fn debug_inner(frame: *mut InterruptFrame) {
+    count(1);
+    let f = unsafe { &mut *frame };
+
+    // A single step the GDB stub asked for
+    if debug::take_single_step() && gdbstub::handle_single_step(f) {
+        return;
+    }
+
+    match debug::report_hits(f.rip) {
+        Some(true) => f.rflags |= RFLAGS_RF,
+        Some(false) => {}
+        None => {
+            log::error!(
+                "Exception: Debug\n  RIP={:#018x}  CS={:#06x}  RFLAGS={:#018x}\n  RSP={:#018x}  SS={:#06x}\n",
+                f.rip, f.cs, f.rflags, f.rsp, f.ss,
+            );
+            halt();
+        }
+    }
+}
+
+#[unsafe(naked)]
+extern "C" fn debug() {
+    core::arch::naked_asm!(
+        swapgs_if_user!("8"),
+        push_regs!(),
+        "mov rdi, rsp",
+        "call {inner}",
+        pop_regs!(),
+        swapgs_if_user!("8"),
+        "iretq",
+        inner = sym debug_inner,
+    );
+}
+
+// Dedicated double fault handler - checks whether a stack guard page was hit
+extern "C" fn double_fault_inner(frame: *const InterruptFrameWithError, cr2: u64) -> ! {
+    count(8);
+    let f = unsafe { &*frame };
+
+    // Overflowing a stack faults on the guard page, and the CPU then can't push the page fault
+    // frame onto that same stack, so it escalates straight to a double fault
+    if let Some(stack) = gdt::stack_guard_hit(cr2) {
+        log::error!("Kernel stack overflow: hit the {} stack guard page at {:#018x}", stack, cr2);
+    }
+
+    diagnose_double_fault(f);
+
+    log::error!(
+        "Exception: Double Fault\n\
+         Fault Addr : {cr2:#018x}\n\
+         Error Code : {ec:#018x}\n\
+         RIP={rip:#018x}  CS={cs:#06x}  RFLAGS={rfl:#018x}\n\
+         RSP={rsp:#018x}  SS={ss:#06x}\n\
+         RAX={rax:#018x}  RBX={rbx:#018x}  RCX={rcx:#018x}  RDX={rdx:#018x}\n\
+         RSI={rsi:#018x}  RDI={rdi:#018x}  RBP={rbp:#018x}\n\
+         R8 ={r8:#018x}  R9 ={r9:#018x}  R10={r10:#018x}  R11={r11:#018x}\n\
+         R12={r12:#018x}  R13={r13:#018x}  R14={r14:#018x}  R15={r15:#018x}\n",
+        cr2 = cr2,
+        ec = f.error_code,
+        rip = f.rip,
+        cs = f.cs,
+        rfl = f.rflags,
+        rsp = f.rsp,
+        ss = f.ss,
+        rax = f.rax,
+        rbx = f.rbx,
+        rcx = f.rcx,
+        rdx = f.rdx,
+        rsi = f.rsi,
+        rdi = f.rdi,
+        rbp = f.rbp,
+        r8 = f.r8,
+        r9 = f.r9,
+        r10 = f.r10,
+        r11 = f.r11,
+        r12 = f.r12,
+        r13 = f.r13,
+        r14 = f.r14,
+        r15 = f.r15,
+    );
+    halt();
+}
+
+/// How close to the bottom of a stack the interrupted RSP has to be to call it an overflow
+const STACK_OVERFLOW_SLACK: u64 = 512;
+
+/// Work out which stack the double fault came from and whether it ran out.
+///
+/// A #DF is nearly always either a stack overflow (the CPU can't push the frame for the first
+/// fault) or a fault while delivering another fault. The registers in the frame are from
+/// whatever was running when the second fault hit, the first fault's context is lost.
+fn diagnose_double_fault(f: &InterruptFrameWithError) {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+
+    let stacks = gdt::stack_bounds();
+    let find = |addr: u64| {
+        stacks
+            .iter()
+            .find(|&&(_, bottom, top)| (bottom - PAGE_SIZE as u64..=top).contains(&addr))
+    };
+
+    match find(rsp) {
+        Some(&("IST0", bottom, top)) => log::error!(
+            "Double fault handler on IST0 ({:#x}..{:#x}), RSP={:#x}, {} bytes used",
+            bottom,
+            top,
+            rsp,
+            top - rsp
+        ),
+        _ => log::error!(
+            "Double fault handler is NOT on IST0 (RSP={:#x}), the TSS or IDT entry is broken",
+            rsp
+        ),
+    }
+
+    match find(f.rsp) {
+        _ if f.cs & 3 != 0 => log::error!("Interrupted code was in user mode, RSP={:#x}", f.rsp),
+        Some(&(name, bottom, _)) if f.rsp < bottom + STACK_OVERFLOW_SLACK => log::error!(
+            "Interrupted RSP={:#x} is {} the bottom of the {} stack: looks like a stack overflow",
+            f.rsp,
+            if f.rsp < bottom { "below" } else { "at" },
+            name
+        ),
+        Some(&(name, bottom, top)) => log::error!(
+            "Interrupted RSP={:#x} is on the {} stack ({} of {} bytes used), not an overflow",
+            f.rsp,
+            name,
+            top - f.rsp,
+            top - bottom
+        ),
+        None => log::error!(
+            "Interrupted RSP={:#x} is not on any known kernel stack, it may be corrupt",
+            f.rsp
+        ),
+    }
+
+    log::error!("Note: the registers below are from the second fault, not the original one");
+}
+
+#[unsafe(naked)]
+extern "C" fn double_fault() {
+    core::arch::naked_asm!(
+        swapgs_if_user!("16"),
+        push_regs!(),
+        "mov rdi, rsp",   // arg1: frame pointer
+        "mov rsi, cr2",  // arg2: last page fault address
+        "call {inner}",
+        pop_regs!(),
+        "add rsp, 8",    // pop error code
+        swapgs_if_user!("8"),
+        "iretq",
+        inner = sym double_fault_inner,
+    );
+}
 
 // Dedicated page fault handler - reads CR2 and decodes the error code
 extern "C" fn page_fault_inner(frame: *const InterruptFrameWithError, cr2: u64) -> ! {
+    count(14);
     let f = unsafe { &*frame };
     let ec = f.error_code;
     let cause = if ec & (1 << 4) != 0 {
@@ -327,6 +882,11 @@ extern "C" fn page_fault_inner(frame: *const InterruptFrameWithError, cr2: u64)
         "page not present"
     };
     let mode = if ec & 4 != 0 { "user" } else { "kernel" };
+
+    if let Some(stack) = gdt::stack_guard_hit(cr2) {
+        log::error!("Kernel stack overflow: hit the {} stack guard page at {:#018x}", stack, cr2);
+    }
+
     log::error!(
         "Exception: Page Fault\n\
          Fault Addr : {cr2:#018x}\n\
@@ -336,7 +896,7 @@ extern "C" fn page_fault_inner(frame: *const InterruptFrameWithError, cr2: u64)
          RAX={rax:#018x}  RBX={rbx:#018x}  RCX={rcx:#018x}  RDX={rdx:#018x}\n\
          RSI={rsi:#018x}  RDI={rdi:#018x}  RBP={rbp:#018x}\n\
          R8 ={r8:#018x}  R9 ={r9:#018x}  R10={r10:#018x}  R11={r11:#018x}\n\
-         R12={r12:#018x}  R13={r13:#018x}  R14={r14:#018x}  R15={r15:#018x}\x1b[0m\n",
+         R12={r12:#018x}  R13={r13:#018x}  R14={r14:#018x}  R15={r15:#018x}\n",
         cr2 = cr2,
         ec = ec,
         mode = mode,
@@ -369,12 +929,14 @@ extern "C" fn page_fault_inner(frame: *const InterruptFrameWithError, cr2: u64)
 #[unsafe(naked)]
 extern "C" fn page_fault() {
     core::arch::naked_asm!(
+        swapgs_if_user!("16"),
         push_regs!(),
         "mov rdi, rsp",   // arg1: frame pointer
         "mov rsi, cr2",  // arg2: faulting address
         "call {inner}",
         pop_regs!(),
         "add rsp, 8",    // pop error code
+        swapgs_if_user!("8"),
         "iretq",
         inner = sym page_fault_inner,
     );
@@ -397,92 +959,241 @@ irq_handler!(irq13, 13u8);
 irq_handler!(irq14, 14u8);
 irq_handler!(irq15, 15u8);
 
+extern "C" fn syscall_inner(frame: *mut InterruptFrame) {
+    count(0x80);
+    let f = unsafe { &mut *frame };
+
+    // Each thread enters on its own kernel stack, so a syscall can block or be preempted
+    f.rax = crate::syscall::dispatch(f.rax, f.rdi, f.rsi, f.rdx, f.r10, f.r8) as u64;
+}
+
 #[unsafe(naked)]
 extern "C" fn syscall_handler() {
     core::arch::naked_asm!(
+        swapgs_if_user!("8"),
         push_regs!(),
-        // TODO: dispatch syscall
+        "mov rdi, rsp",
+        "call {inner}",
         pop_regs!(),
+        swapgs_if_user!("8"),
         "iretq",
+        inner = sym syscall_inner,
     );
 }
 
-pub fn init() {
-    log::trace!("Initializing IDT...");
+/// SYSCALL/SYSRET MSRs
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+
+/// EFER.SCE: enables SYSCALL/SYSRET
+const EFER_SCE: u64 = 1 << 0;
+
+/// RFLAGS.IF, the only flag user code starts out with (bit 1 always reads as set)
+const RFLAGS_IF: u64 = (1 << 9) | (1 << 1);
 
+/// RFLAGS bits cleared on SYSCALL entry: TF, IF, DF and AC
+const SYSCALL_RFLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+/// SYSCALL entry point. The CPU leaves the user RIP in RCX, RFLAGS in R11 and doesn't switch
+/// stacks, so switch to the running thread's kernel stack and build the same frame `int 0x80`
+/// would have, letting both paths share `syscall_inner`. Interrupts stay off until the user RSP
+/// is out of the per-CPU block, another thread's syscall would overwrite it.
+#[unsafe(naked)]
+extern "C" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{stack}]",
+        "push {user_ss}",
+        "push qword ptr gs:[{user_rsp}]",
+        "push r11",
+        "push {user_cs}",
+        "push rcx",
+        push_regs!(),
+        "sti",
+        "mov rdi, rsp",
+        "call {inner}",
+        "cli",
+        pop_regs!(),
+        // SYSRET with a non-canonical RIP faults in ring 0 with the user's RSP already loaded,
+        // IRETQ faults on the kernel stack instead. RCX and R11 are clobbered either way.
+        "mov rcx, [rsp]",
+        "mov r11, rcx",
+        "shl r11, 16",
+        "sar r11, 16",
+        "cmp r11, rcx",
+        "jne 4f",
+        "pop rcx",         // RIP
+        "add rsp, 8",      // CS
+        "pop r11",         // RFLAGS
+        "swapgs",
+        "pop rsp",         // user RSP
+        "sysretq",
+        "4:",
+        "swapgs",
+        "iretq",
+        user_rsp = const core::mem::offset_of!(PerCpu, user_rsp),
+        stack = const core::mem::offset_of!(PerCpu, syscall_stack),
+        user_ss = const USER_DATA_SELECTOR,
+        user_cs = const USER_CODE_SELECTOR,
+        inner = sym syscall_inner,
+    );
+}
+
+/// Leave the kernel for user mode at `rip`, with the stack at `rsp` and interrupts enabled. The GS
+/// bases are swapped on the way out, so user code gets its own GS base and the per-CPU block
+/// waits in IA32_KERNEL_GS_BASE for the next entry.
+pub fn enter_user(rip: u64, rsp: u64) -> ! {
     unsafe {
-        // CPU exceptions (0-31)
-        IDT.entries[0].set_handler(divide_error as *const () as u64);
-        IDT.entries[1].set_handler(debug as *const () as u64);
-        IDT.entries[2].set_handler(nmi as *const () as u64);
-        IDT.entries[3].set_handler(breakpoint as *const () as u64);
-        IDT.entries[4].set_handler(overflow as *const () as u64);
-        IDT.entries[5].set_handler(bound_range as *const () as u64);
-        IDT.entries[6].set_handler(invalid_opcode as *const () as u64);
-        IDT.entries[7].set_handler(device_not_available as *const () as u64);
-        IDT.entries[8] = IdtEntry::new(
-            double_fault as *const () as u64,
-            KERNEL_CODE_SELECTOR,
-            1,
-            GateType::Interrupt,
-            0,
-        );
-        IDT.entries[10].set_handler(invalid_tss as *const () as u64);
-        IDT.entries[11].set_handler(segment_not_present as *const () as u64);
-        IDT.entries[12].set_handler(stack_segment as *const () as u64);
-        IDT.entries[13].set_handler(general_protection as *const () as u64);
-        IDT.entries[14].set_handler(page_fault as *const () as u64);
-        IDT.entries[16].set_handler(x87_fp_exception as *const () as u64);
-        IDT.entries[17].set_handler(alignment_check as *const () as u64);
-        IDT.entries[18].set_handler(machine_check as *const () as u64);
-        IDT.entries[19].set_handler(simd_fp_exception as *const () as u64);
-        IDT.entries[20].set_handler(virtualization as *const () as u64);
-
-        // IRQs (32-47)
-        IDT.entries[32].set_handler(irq0 as *const () as u64); // Timer
-        IDT.entries[33].set_handler(irq1 as *const () as u64); // Keyboard
-        IDT.entries[34].set_handler(irq2 as *const () as u64);
-        IDT.entries[35].set_handler(irq3 as *const () as u64);
-        IDT.entries[36].set_handler(irq4 as *const () as u64);
-        IDT.entries[37].set_handler(irq5 as *const () as u64);
-        IDT.entries[38].set_handler(irq6 as *const () as u64);
-        IDT.entries[39].set_handler(irq7 as *const () as u64);
-        IDT.entries[40].set_handler(irq8 as *const () as u64);
-        IDT.entries[41].set_handler(irq9 as *const () as u64);
-        IDT.entries[42].set_handler(irq10 as *const () as u64);
-        IDT.entries[43].set_handler(irq11 as *const () as u64);
-        IDT.entries[44].set_handler(irq12 as *const () as u64);
-        IDT.entries[45].set_handler(irq13 as *const () as u64);
-        IDT.entries[46].set_handler(irq14 as *const () as u64);
-        IDT.entries[47].set_handler(irq15 as *const () as u64);
-
-        // Syscall interrupt
-        IDT.entries[0x80] = IdtEntry::new(
-            syscall_handler as *const () as u64,
-            KERNEL_CODE_SELECTOR,
-            0,
-            GateType::Trap,
-            3,
+        core::arch::asm!(
+            "cli",
+            "push {user_ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {user_cs}",
+            "push {rip}",
+            "swapgs",
+            // Leave nothing of the kernel's in the registers
+            "xor eax, eax; xor ebx, ebx; xor ecx, ecx; xor edx, edx",
+            "xor esi, esi; xor edi, edi; xor ebp, ebp",
+            "xor r8d, r8d; xor r9d, r9d; xor r10d, r10d; xor r11d, r11d",
+            "xor r12d, r12d; xor r13d, r13d; xor r14d, r14d; xor r15d, r15d",
+            "iretq",
+            rip = in(reg) rip,
+            rsp = in(reg) rsp,
+            user_ss = const USER_DATA_SELECTOR,
+            user_cs = const USER_CODE_SELECTOR,
+            rflags = const RFLAGS_IF,
+            options(noreturn),
         );
+    }
+}
 
-        // Load IDT
-        let idt_descriptor = IdtDescriptor {
-            size: (size_of::<Idt>() - 1) as u16,
-            offset: &IDT as *const _ as u64,
-        };
+/// Program the MSRs for the SYSCALL/SYSRET fast path, `int 0x80` keeps working alongside it
+fn init_fast_syscall() {
+    // SYSCALL loads CS from STAR[47:32] and SS from +8. SYSRET loads SS from STAR[63:48] + 8 and
+    // CS from +16, hence the base is the selector just before the user data segment.
+    let sysret_base = (USER_DATA_SELECTOR & !3) - 8;
+    let star = ((sysret_base as u64) << 48) | ((KERNEL_CODE_SELECTOR as u64) << 32);
+    debug_assert_eq!(KERNEL_CODE_SELECTOR + 8, KERNEL_DATA_SELECTOR);
+
+    wrmsr(IA32_STAR, star);
+    wrmsr(IA32_LSTAR, syscall_entry as *const () as u64);
+    wrmsr(IA32_FMASK, SYSCALL_RFLAGS_MASK);
+    wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_SCE);
+
+    log::debug!("SYSCALL/SYSRET enabled, entry at {:#x}", syscall_entry as *const () as u64);
+}
+
+/// Fill in every gate the kernel handles
+fn build() -> Idt {
+    let mut idt = Idt {
+        entries: [IdtEntry::null(); 256],
+    };
+
+    // CPU exceptions (0-31)
+    for handler in &EXCEPTION_HANDLERS {
+        let entry = handler.entry as *const () as u64;
+        if handler.vector == DOUBLE_FAULT_VECTOR {
+            // Runs on its own stack, the faulting one may be what's broken
+            idt.entries[handler.vector as usize] =
+                IdtEntry::new(entry, KERNEL_CODE_SELECTOR, 1, GateType::Interrupt, 0);
+        } else {
+            idt.entries[handler.vector as usize].set_handler(entry);
+        }
+    }
+
+    // IRQs (32-47)
+    let irqs = [
+        irq0, irq1, irq2, irq3, irq4, irq5, irq6, irq7, irq8, irq9, irq10, irq11, irq12, irq13,
+        irq14, irq15,
+    ];
+    for (irq, handler) in irqs.into_iter().enumerate() {
+        idt.entries[32 + irq].set_handler(handler as *const () as u64);
+    }
+
+    // Syscall interrupt
+    idt.entries[0x80] = IdtEntry::new(
+        syscall_handler as *const () as u64,
+        KERNEL_CODE_SELECTOR,
+        0,
+        GateType::Trap,
+        3,
+    );
+
+    idt
+}
+
+/// Base and limit of the IDT the CPU is using
+fn loaded_idt() -> (u64, u16) {
+    let mut descriptor = IdtDescriptor { size: 0, offset: 0 };
+    unsafe {
+        core::arch::asm!("sidt [{}]", in(reg) &mut descriptor, options(nostack));
+    }
+
+    (descriptor.offset, descriptor.size)
+}
 
+pub fn init() {
+    log::trace!("Initializing IDT...");
+
+    // Built once and never written again, so the CPU's copy can't change under it
+    let idt = IDT.call_once(build);
+    let expected = IdtDescriptor {
+        size: (size_of::<Idt>() - 1) as u16,
+        offset: idt as *const Idt as u64,
+    };
+
+    unsafe {
         core::arch::asm!(
             "lidt [{}]",
-            in(reg) &idt_descriptor,
+            in(reg) &expected,
             options(nostack)
         );
+    }
 
-        log::debug!("IDT loaded at {:#x}, size {} bytes", &IDT as *const _ as u64, core::mem::size_of::<Idt>());
+    // A descriptor with the wrong layout or address only shows up as a triple fault on the first
+    // interrupt, catch it while we can still say so
+    let (base, limit) = loaded_idt();
+    if (base, limit) == (expected.offset, expected.size) {
+        log::debug!(
+            "IDT loaded at {:#x}, size {} bytes",
+            base,
+            limit as usize + 1
+        );
+    } else {
+        log::error!(
+            "IDT did not load as expected: the CPU has base {:#x} limit {:#x}, wanted base {:#x} \
+             limit {:#x}",
+            base,
+            limit,
+            { expected.offset },
+            { expected.size }
+        );
+    }
 
-        init_pic();
+    init_pic();
+    init_fast_syscall();
 
-        log::debug!("IDT initialization complete");
-    }
+    log::debug!("IDT initialization complete");
+}
+
+const PIC1_CMD: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_CMD: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+/// Serializes access to the PICs. Programming them takes several writes in a row, and the mask
+/// registers are read-modify-write, so nothing else may talk to them in between.
+static PIC_LOCK: IrqSpinlock<()> = IrqSpinlock::new(());
+
+/// Write a PIC register and give the PIC time to take it in, older ones drop writes that come
+/// back to back
+fn pic_write(port: u16, value: u8) {
+    outb(port, value);
+    io_wait();
 }
 
 /// Initialize PIC (Programmable Interrupt Controller)
@@ -491,46 +1202,85 @@ pub fn init() {
 fn init_pic() {
     log::trace!("Initializing PIC, remapping IRQs to vectors 0x20-0x2F...");
 
-    use crate::arch::x86_64::{inb, outb};
-
-    const PIC1_CMD: u16 = 0x20;
-    const PIC1_DATA: u16 = 0x21;
-    const PIC2_CMD: u16 = 0xA0;
-    const PIC2_DATA: u16 = 0xA1;
-
-    // Save masks
-    let _mask1 = inb(PIC1_DATA);
-    let _mask2 = inb(PIC2_DATA);
+    let _pic = PIC_LOCK.lock();
 
     // ICW1: Initialize + ICW4 needed
-    outb(PIC1_CMD, 0x11);
-    outb(PIC2_CMD, 0x11);
+    pic_write(PIC1_CMD, 0x11);
+    pic_write(PIC2_CMD, 0x11);
 
     // ICW2: Vector offset
-    outb(PIC1_DATA, 0x20); // IRQs 0-7 -> interrupts 32-39
-    outb(PIC2_DATA, 0x28); // IRQs 8-15 -> interrupts 40-47
+    pic_write(PIC1_DATA, 0x20); // IRQs 0-7 -> interrupts 32-39
+    pic_write(PIC2_DATA, 0x28); // IRQs 8-15 -> interrupts 40-47
 
     // ICW3: Cascade identity
-    outb(PIC1_DATA, 0x04); // IRQ2 has slave
-    outb(PIC2_DATA, 0x02); // Slave identity
+    pic_write(PIC1_DATA, 0x04); // IRQ2 has slave
+    pic_write(PIC2_DATA, 0x02); // Slave identity
 
     // ICW4: 8086 mode
-    outb(PIC1_DATA, 0x01);
-    outb(PIC2_DATA, 0x01);
+    pic_write(PIC1_DATA, 0x01);
+    pic_write(PIC2_DATA, 0x01);
 
-    // Restore masks (enable all for now)
-    outb(PIC1_DATA, 0x00);
-    outb(PIC2_DATA, 0x00);
+    // Everything starts masked, each driver unmasks its own line once it's ready for interrupts.
+    // IRQ2 is the cascade from the slave and stays open, so slave lines only need their own bit.
+    pic_write(PIC1_DATA, !(1 << CASCADE_IRQ));
+    pic_write(PIC2_DATA, 0xFF);
 
     log::debug!("PIC initialized: IRQ0-7 -> INT 0x20-0x27, IRQ8-15 -> INT 0x28-0x2F");
 }
 
-pub fn send_eoi(irq: u8) {
-    use crate::arch::x86_64::outb;
+/// Master PIC line the slave is wired to
+const CASCADE_IRQ: u8 = 2;
 
-    const PIC1_CMD: u16 = 0x20;
-    const PIC2_CMD: u16 = 0xA0;
+/// Current IRQ mask, bit n set means IRQ n is masked
+pub fn irq_mask() -> u16 {
+    let _pic = PIC_LOCK.lock();
+    inb(PIC1_DATA) as u16 | (inb(PIC2_DATA) as u16) << 8
+}
+
+/// Set or clear `irq`'s bit in the mask register of the PIC it's on
+fn update_irq_mask(irq: u8, masked: bool) {
+    assert!(irq < 16, "IRQ {} doesn't exist on the PIC", irq);
+
+    let (port, bit) = if irq >= 8 {
+        (PIC2_DATA, irq - 8)
+    } else {
+        (PIC1_DATA, irq)
+    };
+
+    let _pic = PIC_LOCK.lock();
+    let mask = inb(port);
+    let mask = if masked {
+        mask | 1 << bit
+    } else {
+        mask & !(1 << bit)
+    };
+    pic_write(port, mask);
+}
 
+/// Stop `irq` from being delivered
+pub fn mask_irq(irq: u8) {
+    update_irq_mask(irq, true);
+}
+
+/// Let `irq` be delivered again
+pub fn unmask_irq(irq: u8) {
+    update_irq_mask(irq, false);
+}
+
+/// Whether an IRQ7/IRQ15 is spurious, i.e. the line dropped before the PIC could deliver it and
+/// its bit isn't set in the in-service register
+fn is_spurious(irq: u8) -> bool {
+    const OCW3_READ_ISR: u8 = 0x0B;
+
+    let cmd = if irq >= 8 { PIC2_CMD } else { PIC1_CMD };
+
+    let _pic = PIC_LOCK.lock();
+    outb(cmd, OCW3_READ_ISR);
+    inb(cmd) & 0x80 == 0
+}
+
+/// Acknowledge `irq`. Each write stands on its own, so this skips the PIC lock.
+pub fn send_eoi(irq: u8) {
     if irq >= 8 {
         outb(PIC2_CMD, 0x20);
     }
@@ -1,24 +1,42 @@
 pub mod apic;
+pub mod backtrace;
+pub mod cpu;
+pub mod debug;
+pub mod debug_con;
+pub mod delay;
+pub mod gdbstub;
 pub mod gdt;
 pub mod idt;
 pub mod paging;
+pub mod percpu;
+pub mod rand;
+pub mod registers;
 pub mod serial;
 
 use crate::BootInfo;
+use crate::drivers::ps2;
 use log;
 
-pub fn init(_: &BootInfo) {
-    // TODO: pit init
+pub fn init(boot_info: &BootInfo) {
     gdt::init();
+    percpu::init(0);
     idt::init();
     paging::init();
     serial::init();
+    delay::calibrate();
+    gdbstub::init(boot_info);
+    crate::timer::init();
 
     crate::arch::enable_interrupts();
 
     log::info!("Architecture initialized");
 }
 
+/// Architecture setup that depends on memory management being initialized
+pub fn init_late() {
+    gdt::install_stack_guards();
+}
+
 /// Read MSR (Model Specific Register)
 /// From here we can get data such as TSC (Time Stamp Counter), APIC base, etc.
 #[inline]
@@ -135,6 +153,11 @@ pub fn invlpg(addr: u64) {
 
 /// Get CPU features using CPUID
 pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    cpuid_count(leaf, 0)
+}
+
+/// CPUID for leaves that take a subleaf in ECX (e.g. leaf 7 or 0xB)
+pub fn cpuid_count(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
     let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
     unsafe {
         core::arch::asm!(
@@ -144,14 +167,41 @@ pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
             "pop rbx",
             inout("eax") leaf => eax,
             ebx_out = out(reg) ebx,
-            out("ecx") ecx,
+            inout("ecx") subleaf => ecx,
             out("edx") edx,
-            options(nomem, nostack)
+            options(nomem)
         );
     }
     (eax, ebx, ecx, edx)
 }
 
+/// FS base MSR, the thread pointer user programs use for thread-local storage.
+/// The kernel keeps its per-CPU data behind GS, so the two never conflict.
+const IA32_FS_BASE: u32 = 0xC000_0100;
+
+/// Set the FS segment base
+#[inline]
+pub fn set_fs_base(addr: u64) {
+    wrmsr(IA32_FS_BASE, addr);
+}
+
+/// Read the FS segment base
+#[inline]
+pub fn fs_base() -> u64 {
+    rdmsr(IA32_FS_BASE)
+}
+
+/// Read the Time Stamp Counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    ((high as u64) << 32) | (low as u64)
+}
+
 /// Read from port
 #[inline]
 pub fn inb(port: u16) -> u8 {
@@ -235,3 +285,19 @@ pub fn outl(port: u16, value: u32) {
         );
     }
 }
+
+/// Reset the machine
+/// Pulses the CPU reset line through the PS/2 controller, falling back to a triple fault if the
+/// controller doesn't respond.
+pub fn reboot() -> ! {
+    crate::arch::disable_interrupts();
+
+    // Not taking the controller lock: whoever holds it isn't going to get to finish anyway
+    let _ = ps2::write_command(ps2::cmd::PULSE_RESET);
+
+    // Still here, load an empty IDT so the next interrupt triple faults
+    let null_idt = [0u8; 10];
+    unsafe {
+        core::arch::asm!("lidt [{}]", "int3", in(reg) &null_idt, options(noreturn));
+    }
+}
@@ -1,4 +1,11 @@
+use crate::arch::x86_64::{cpu, read_cr3, wrmsr, write_cr3};
+use crate::mem::phys::{self, Frame};
+use crate::mem::{PAGE_SIZE, page_align_down};
+use crate::sync::IrqSpinlock;
+
+use core::sync::atomic::{AtomicBool, Ordering};
 use log;
+use spin::Mutex;
 
 /// Every PTE has flags
 /// These flags control how the page is accessed, whether it's present in memory, whether it's
@@ -14,8 +21,29 @@ pub mod flags {
     pub const HUGE_PAGE: u64 = 1 << 7;
     pub const GLOBAL: u64 = 1 << 8;
     pub const NO_EXECUTE: u64 = 1 << 63;
+
+    /// Once `init_pat` has run, PWT alone selects PAT entry 1, which we program as
+    /// write-combining instead of write-through
+    pub const WRITE_COMBINING: u64 = WRITE_THROUGH;
 }
 
+/// Page Attribute Table MSR
+const IA32_PAT_MSR: u32 = 0x277;
+
+/// PAT memory types
+const PAT_UC: u64 = 0x00; // Uncacheable
+const PAT_WC: u64 = 0x01; // Write-combining
+const PAT_WT: u64 = 0x04; // Write-through
+const PAT_WB: u64 = 0x06; // Write-back
+const PAT_UC_MINUS: u64 = 0x07; // Uncacheable, overridable by MTRRs
+
+/// Whether PAT entry 1 has been reprogrammed to write-combining
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+const HUGE_PAGE_SIZE: u64 = 0x200000;
+/// Everything below this is identity mapped
+const IDENTITY_MAPPED_END: u64 = 4 << 30;
+
 const ADDR_MASK: u64 = 0x000FFFFFFFFFF000;
 const FLAG_MASK: u64 = 0x8000000000000FFF;
 
@@ -165,6 +193,75 @@ static mut KPD: [PageTable; 4] = [
 /// PML4 table.
 static mut PAGE_TABLE_PHYS: u64 = 0;
 
+/// Held by everything that walks the page tables and changes them. Otherwise two threads could
+/// both find a table missing and each install their own, losing whatever the other mapped into
+/// it. Interrupts and preemption are off while it's held, so nothing that edits page tables may
+/// run from an interrupt handler.
+static PAGE_TABLES: IrqSpinlock<()> = IrqSpinlock::new(());
+
+/// Frames kept in reserve for new page tables
+const TABLE_POOL_SIZE: usize = 16;
+
+/// Zeroed frames for page tables, so that mapping a page doesn't have to reach into the frame
+/// allocator while a caller (e.g. the heap growing itself) is halfway through its own update.
+struct TablePool {
+    frames: [u64; TABLE_POOL_SIZE],
+    len: usize,
+}
+
+static TABLE_POOL: Mutex<TablePool> = Mutex::new(TablePool {
+    frames: [0; TABLE_POOL_SIZE],
+    len: 0,
+});
+
+/// Top up the page-table frame pool from the frame allocator, returning how many frames it now
+/// holds. Call this before taking locks that page-table edits will be made under.
+pub fn refill_table_pool() -> usize {
+    loop {
+        if TABLE_POOL.lock().len == TABLE_POOL_SIZE {
+            return TABLE_POOL_SIZE;
+        }
+
+        // The pool lock isn't held across the allocation, so the frame allocator is never entered
+        // with it taken
+        let Some(frame) = crate::mem::phys::alloc_zeroed_frame() else {
+            return TABLE_POOL.lock().len;
+        };
+
+        let mut pool = TABLE_POOL.lock();
+        if pool.len == TABLE_POOL_SIZE {
+            drop(pool);
+            crate::mem::phys::free_frame(frame);
+            return TABLE_POOL_SIZE;
+        }
+        let len = pool.len;
+        pool.frames[len] = frame;
+        pool.len += 1;
+    }
+}
+
+/// Take a zeroed frame for a new page table. The pool is refilled on the spot if it has run dry,
+/// which is safe as long as no frame allocator lock is held, but callers on hot paths should
+/// refill it up front.
+fn alloc_table_frame() -> Option<u64> {
+    let mut pool = TABLE_POOL.lock();
+    if pool.len > 0 {
+        pool.len -= 1;
+        return Some(pool.frames[pool.len]);
+    }
+    drop(pool);
+
+    log::debug!("Page-table frame pool ran dry, refilling it");
+    refill_table_pool();
+
+    let mut pool = TABLE_POOL.lock();
+    if pool.len == 0 {
+        return None;
+    }
+    pool.len -= 1;
+    Some(pool.frames[pool.len])
+}
+
 /// Initialize paging
 pub fn init() {
     log::trace!("Initializing paging...");
@@ -202,49 +299,148 @@ pub fn init() {
             pml4_addr
         );
     }
+
+    init_pat();
+}
+
+/// Program the PAT so that entry 1 (PWT=1, PCD=0) is write-combining.
+/// The power-on layout is WB, WT, UC-, UC repeated twice, we keep WT reachable through entry 5.
+fn init_pat() {
+    if !cpu::features().pat {
+        log::warn!("PAT not supported, write-combining mappings unavailable");
+        return;
+    }
+
+    let entries = [PAT_WB, PAT_WC, PAT_UC_MINUS, PAT_UC, PAT_WB, PAT_WT, PAT_UC_MINUS, PAT_UC];
+    let pat = entries
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, &ty)| pat | (ty << (i * 8)));
+
+    wrmsr(IA32_PAT_MSR, pat);
+
+    // Nothing should be cached under the old attributes
+    unsafe {
+        core::arch::asm!("wbinvd", options(nostack));
+    }
+    write_cr3(read_cr3());
+
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+    log::debug!("PAT programmed: {:#018x}", pat);
+}
+
+/// Whether `flags::WRITE_COMBINING` actually selects write-combining
+pub fn write_combining_available() -> bool {
+    PAT_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Set and clear flag bits on every existing mapping in `[start, start + len)`.
+/// 2 MiB pages that are only partly covered by the range are split first.
+pub fn update_range_flags(start: u64, len: u64, set: u64, clear: u64) -> Result<(), &'static str> {
+    let end = start.checked_add(len).ok_or("Range overflows the address space")?;
+    let mut addr = page_align_down(start);
+    let _tables = PAGE_TABLES.lock();
+
+    unsafe {
+        while addr < end {
+            let indices = VirtualAddress(addr).indices();
+
+            let pml4e = &KPML4[indices.pml4];
+            if !pml4e.is_present() {
+                return Err("PML4 entry not present");
+            }
+
+            let pdpt = pml4e.addr() as *mut PageTable;
+            let pdpte = &(*pdpt).entries[indices.pdpt];
+            if !pdpte.is_present() {
+                return Err("PDPT entry not present");
+            }
+            if pdpte.is_huge_page() {
+                return Err("1 GiB pages are not supported");
+            }
+
+            let pd = pdpte.addr() as *mut PageTable;
+            let pde = &mut (*pd).entries[indices.pd];
+            if !pde.is_present() {
+                return Err("PD entry not present");
+            }
+
+            if pde.is_huge_page() {
+                if addr.is_multiple_of(HUGE_PAGE_SIZE) && addr + HUGE_PAGE_SIZE <= end {
+                    pde.set_flags((pde.flags() | set) & !clear);
+                    addr += HUGE_PAGE_SIZE;
+                    continue;
+                }
+
+                split_huge_page(pde)?;
+            }
+
+            let pt = pde.addr() as *mut PageTable;
+            let pte = &mut (*pt).entries[indices.pt];
+            if !pte.is_present() {
+                return Err("PT entry not present");
+            }
+
+            pte.set_flags((pte.flags() | set) & !clear);
+            addr += PAGE_SIZE as u64;
+        }
+    }
+
+    write_cr3(read_cr3());
+
+    Ok(())
 }
 
-/// Map virt -> phys
+/// Map virt -> phys in the kernel's address space
+/// A 2 MiB huge page covering `virt` is split first, otherwise it would keep shadowing the new
+/// 4 KiB entry.
 pub fn map_page(virt: u64, phys: u64, flags: u64) -> Result<(), &'static str> {
+    map_page_in(kernel_cr3(), virt, phys, flags)
+}
+
+/// Map virt -> phys in the address space whose PML4 is at `cr3`, like `map_page`. The tables on
+/// the way down are made user-accessible if the page is, the CPU only lets user code through if
+/// every level allows it.
+pub fn map_page_in(cr3: u64, virt: u64, phys: u64, flags: u64) -> Result<(), &'static str> {
     let indices = VirtualAddress(virt).indices();
+    let _tables = PAGE_TABLES.lock();
+    let pml4 = own_pml4(cr3, indices.pml4)?;
+
+    // New tables allow everything, the page's own entry decides what it can be used for
+    let user = flags & flags::USER_ACCESSIBLE;
+    let table_flags = flags::PRESENT | flags::WRITABLE | user;
+    let allow_user = |entry: &mut PageTableEntry| entry.set_flags(entry.flags() | user);
 
     unsafe {
-        let pml4e = &mut KPML4[indices.pml4];
+        let pml4e = &mut (*pml4).entries[indices.pml4];
         if !pml4e.is_present() {
-            let pdpt_phys =
-                crate::mem::phys::alloc_frame().ok_or("Failed to allocate frame for PDPT")?;
-            *pml4e = PageTableEntry::new(pdpt_phys, flags::PRESENT | flags::WRITABLE);
-
-            // Zero the new table
-            let pdpt = pml4e.addr() as *mut PageTable;
-            core::ptr::write_bytes(pdpt, 0, 1);
+            let pdpt_phys = alloc_table_frame().ok_or("Failed to allocate frame for PDPT")?;
+            *pml4e = PageTableEntry::new(pdpt_phys, table_flags);
         }
+        allow_user(pml4e);
 
         let pdpt = pml4e.addr() as *mut PageTable;
         let pdpte = &mut (*pdpt).entries[indices.pdpt];
 
         if !pdpte.is_present() {
-            let pd_phys =
-                crate::mem::phys::alloc_frame().ok_or("Failed to allocate frame for PD")?;
-            *pdpte = PageTableEntry::new(pd_phys, flags::PRESENT | flags::WRITABLE);
-
-            // Zero the new table
-            let pd = pdpte.addr() as *mut PageTable;
-            core::ptr::write_bytes(pd, 0, 1);
+            let pd_phys = alloc_table_frame().ok_or("Failed to allocate frame for PD")?;
+            *pdpte = PageTableEntry::new(pd_phys, table_flags);
         }
+        if pdpte.is_huge_page() {
+            return Err("1 GiB pages are not supported");
+        }
+        allow_user(pdpte);
 
         let pd = pdpte.addr() as *mut PageTable;
         let pde = &mut (*pd).entries[indices.pd];
 
         if !pde.is_present() {
-            let pt_phys =
-                crate::mem::phys::alloc_frame().ok_or("Failed to allocate frame for PT")?;
-            *pde = PageTableEntry::new(pt_phys, flags::PRESENT | flags::WRITABLE);
-
-            // Zero the new table
-            let pt = pde.addr() as *mut PageTable;
-            core::ptr::write_bytes(pt, 0, 1);
+            let pt_phys = alloc_table_frame().ok_or("Failed to allocate frame for PT")?;
+            *pde = PageTableEntry::new(pt_phys, table_flags);
+        } else if pde.is_huge_page() {
+            split_huge_page(pde)?;
         }
+        allow_user(pde);
 
         let pt = pde.addr() as *mut PageTable;
         let pte = &mut (*pt).entries[indices.pt];
@@ -257,11 +453,46 @@ pub fn map_page(virt: u64, phys: u64, flags: u64) -> Result<(), &'static str> {
     Ok(())
 }
 
-fn unmap_page(virt: u64) -> Result<u64, &'static str> {
+/// Replace a 2 MiB huge PDE with a page table of 512 4 KiB entries that map the same range with
+/// the same flags, so that individual pages inside it can be changed. `PAGE_TABLES` must be held.
+unsafe fn split_huge_page(pde: &mut PageTableEntry) -> Result<(), &'static str> {
+    let pt_phys = alloc_table_frame().ok_or("Failed to allocate frame for split PT")?;
+    let pt = pt_phys as *mut PageTable;
+
+    let base = pde.addr();
+    let page_flags = pde.flags() & !flags::HUGE_PAGE;
+
+    unsafe {
+        for (i, entry) in (*pt).entries.iter_mut().enumerate() {
+            *entry = PageTableEntry::new(base + (i * 0x1000) as u64, page_flags);
+        }
+    }
+
+    *pde = PageTableEntry::new(
+        pt_phys,
+        flags::PRESENT | flags::WRITABLE | (page_flags & flags::USER_ACCESSIBLE),
+    );
+
+    // The whole 2 MiB range may be cached as a single huge TLB entry, flush everything
+    crate::arch::x86_64::write_cr3(crate::arch::x86_64::read_cr3());
+
+    Ok(())
+}
+
+/// Unmap virt from the kernel's address space, returning the physical address it was mapped to.
+/// A 2 MiB huge page covering `virt` is split first so only the one 4 KiB page goes away.
+pub fn unmap_page(virt: u64) -> Result<u64, &'static str> {
+    unmap_page_in(kernel_cr3(), virt)
+}
+
+/// Unmap virt from the address space whose PML4 is at `cr3`, like `unmap_page`
+pub fn unmap_page_in(cr3: u64, virt: u64) -> Result<u64, &'static str> {
     let indices = VirtualAddress(virt).indices();
+    let _tables = PAGE_TABLES.lock();
+    let pml4 = own_pml4(cr3, indices.pml4)?;
 
     unsafe {
-        let pml4_entry = &mut KPML4[indices.pml4];
+        let pml4_entry = &mut (*pml4).entries[indices.pml4];
         if !pml4_entry.is_present() {
             return Err("PML4 entry not present");
         }
@@ -273,11 +504,15 @@ fn unmap_page(virt: u64) -> Result<u64, &'static str> {
         }
 
         let pd = pdpt_entry.addr() as *mut PageTable;
-        let pd_entry = &(*pd).entries[indices.pd];
+        let pd_entry = &mut (*pd).entries[indices.pd];
         if !pd_entry.is_present() {
             return Err("PD entry not present");
         }
 
+        if pd_entry.is_huge_page() {
+            split_huge_page(pd_entry)?;
+        }
+
         let pt = pd_entry.addr() as *mut PageTable;
         let pt_entry = &mut (*pt).entries[indices.pt];
         if !pt_entry.is_present() {
@@ -293,15 +528,388 @@ fn unmap_page(virt: u64) -> Result<u64, &'static str> {
     }
 }
 
+/// A page mapped to a frame it owns. Dropping it unmaps the page and frees the frame.
+#[derive(Debug)]
+pub struct Mapping {
+    virt: u64,
+}
+
+impl Mapping {
+    /// Map `virt` to `frame`. On failure the frame is freed again.
+    pub fn new(virt: u64, frame: Frame, flags: u64) -> Result<Self, &'static str> {
+        map_page(virt, frame.addr(), flags)?;
+        frame.into_raw();
+        Ok(Self { virt })
+    }
+
+    /// Take ownership of an existing mapping
+    ///
+    /// # Safety
+    /// `virt` must be mapped to a frame that nothing else will unmap or free.
+    pub unsafe fn from_raw(virt: u64) -> Self {
+        Self { virt }
+    }
+
+    /// Give up ownership without unmapping, returning the virtual address
+    pub fn into_raw(self) -> u64 {
+        core::mem::ManuallyDrop::new(self).virt
+    }
+
+    /// Keep the page mapped forever
+    pub fn leak(self) -> u64 {
+        self.into_raw()
+    }
+
+    pub fn virt(&self) -> u64 {
+        self.virt
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        match unmap_page(self.virt) {
+            Ok(phys) => drop(unsafe { Frame::from_raw(phys) }),
+            Err(e) => log::warn!("Failed to unmap {:#x}: {}", self.virt, e),
+        }
+    }
+}
+
+/// What the page tables currently hold, see `stats()`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PagingStats {
+    /// Present 4 KiB page table entries
+    pub mapped_pages: usize,
+    /// Present 2 MiB page directory entries
+    pub huge_pages: usize,
+    /// Present 1 GiB PDPT entries
+    pub giant_pages: usize,
+    /// Frames used for page tables of every level, the PML4 included
+    pub table_frames: usize,
+}
+
+/// Walk the kernel's page tables and count what's mapped. The kernel's is the only address space
+/// so far, so this covers both halves. A PDPT that more than one PML4 entry points at (the
+/// identity map is aliased at the top of the address space) is only counted once.
+pub fn stats() -> PagingStats {
+    let mut stats = PagingStats {
+        table_frames: 1,
+        ..Default::default()
+    };
+
+    unsafe {
+        let pml4 = &KPML4;
+        for (i, pml4_entry) in pml4.entries.iter().enumerate() {
+            let aliased = pml4.entries[..i]
+                .iter()
+                .any(|earlier| earlier.is_present() && earlier.addr() == pml4_entry.addr());
+            if !pml4_entry.is_present() || aliased {
+                continue;
+            }
+
+            stats.table_frames += 1;
+            let pdpt = &*(pml4_entry.addr() as *const PageTable);
+            for pdpt_entry in pdpt.entries.iter().filter(|entry| entry.is_present()) {
+                if pdpt_entry.is_huge_page() {
+                    stats.giant_pages += 1;
+                    continue;
+                }
+
+                stats.table_frames += 1;
+                let pd = &*(pdpt_entry.addr() as *const PageTable);
+                for pd_entry in pd.entries.iter().filter(|entry| entry.is_present()) {
+                    if pd_entry.is_huge_page() {
+                        stats.huge_pages += 1;
+                        continue;
+                    }
+
+                    stats.table_frames += 1;
+                    let pt = &*(pd_entry.addr() as *const PageTable);
+                    let present = pt.entries.iter().filter(|entry| entry.is_present());
+                    stats.mapped_pages += present.count();
+                }
+            }
+        }
+    }
+
+    stats
+}
+
+/// Physical address of the kernel's PML4, the address space everything runs in until processes get
+/// their own
+pub fn kernel_cr3() -> u64 {
+    unsafe { PAGE_TABLE_PHYS }
+}
+
+/// The PML4 at `cr3`, as long as it's somewhere the kernel can read it
+fn reachable_table(cr3: u64) -> Result<u64, &'static str> {
+    let pml4 = cr3 & ADDR_MASK;
+    // Only the identity mapped low 4 GiB can be read before switching
+    if pml4 == 0 || pml4 >= IDENTITY_MAPPED_END {
+        return Err("Not a page table the kernel can reach");
+    }
+
+    Ok(pml4)
+}
+
+/// The PML4 at `cr3`, if the tables under its entry `index` may be changed through it. Another
+/// address space shares the kernel's tables wherever the kernel had something mapped when it was
+/// made, so changing them there would change the kernel and every other address space too.
+fn own_pml4(cr3: u64, index: usize) -> Result<*mut PageTable, &'static str> {
+    let pml4 = reachable_table(cr3)? as *mut PageTable;
+    let kernel = kernel_cr3() as *const PageTable;
+
+    if !core::ptr::eq(pml4, kernel) {
+        let (ours, theirs) = unsafe { (&(*kernel).entries[index], &(*pml4).entries[index]) };
+        if ours.is_present() && theirs.is_present() && theirs.addr() == ours.addr() {
+            return Err("Range is shared with the kernel's address space");
+        }
+    }
+
+    Ok(pml4)
+}
+
+/// Switch to the address space whose PML4 is at `cr3`, run `f` and switch back. Interrupts stay
+/// disabled throughout, so nothing else runs in the borrowed address space.
+///
+/// Fails unless `cr3` maps everything the kernel's PML4 does the same way, since the kernel (this
+/// code and its stack included) has to stay mapped while it's active.
+pub fn with_address_space<R>(cr3: u64, f: impl FnOnce() -> R) -> Result<R, &'static str> {
+    let pml4 = reachable_table(cr3)?;
+
+    let target = unsafe { &*(pml4 as *const PageTable) };
+    let kernel = unsafe { &KPML4 };
+    let shares_kernel = kernel
+        .entries
+        .iter()
+        .zip(&target.entries)
+        .all(|(ours, theirs)| {
+            !ours.is_present() || (theirs.is_present() && theirs.addr() == ours.addr())
+        });
+    if !shares_kernel {
+        return Err("Address space doesn't share the kernel's mappings");
+    }
+
+    Ok(crate::arch::without_interrupts(|| {
+        let previous = read_cr3();
+        write_cr3(cr3);
+        let result = f();
+        write_cr3(previous);
+        result
+    }))
+}
+
+/// Allocate the PML4 of a new address space. It starts out as a copy of the kernel's, sharing
+/// every table below it, so the kernel stays mapped in it. Kernel PML4 entries added later won't
+/// show up in it.
+pub fn new_address_space() -> Result<u64, &'static str> {
+    let frame = Frame::alloc_zeroed().ok_or("Out of memory for a page table")?;
+    let pml4 = unsafe { &mut *(frame.addr() as *mut PageTable) };
+    let _tables = PAGE_TABLES.lock();
+    pml4.entries.copy_from_slice(unsafe { &KPML4.entries });
+
+    Ok(frame.into_raw())
+}
+
+/// Free an address space made by `new_address_space`: every table it doesn't share with the
+/// kernel, every frame those still map and the PML4 itself. Whoever shares frames with it (e.g.
+/// shared memory) must have unmapped them first. Fails if it's in use.
+pub fn free_address_space(cr3: u64) -> Result<(), &'static str> {
+    let pml4 = reachable_table(cr3)?;
+    if pml4 == kernel_cr3() || pml4 == crate::arch::current_cr3() {
+        return Err("Address space is in use");
+    }
+
+    let _tables = PAGE_TABLES.lock();
+    let table = unsafe { &*(pml4 as *const PageTable) };
+    let kernel = unsafe { &KPML4 };
+    for (ours, entry) in kernel.entries.iter().zip(&table.entries) {
+        let shared = ours.is_present() && ours.addr() == entry.addr();
+        if entry.is_present() && !shared {
+            free_table(entry.addr(), 3);
+        }
+    }
+    phys::free_frame(pml4);
+
+    Ok(())
+}
+
+/// Free the table at `table` and everything it maps. `level` is 3 for a PDPT, 2 for a page
+/// directory and 1 for a page table.
+fn free_table(table: u64, level: u32) {
+    let entries = unsafe { &(*(table as *const PageTable)).entries };
+    for entry in entries.iter().filter(|entry| entry.is_present()) {
+        if level == 1 || entry.is_huge_page() {
+            // A 4 KiB, 2 MiB or 1 GiB page
+            phys::free_frames(entry.addr(), 1 << (9 * (level - 1)));
+        } else {
+            free_table(entry.addr(), level - 1);
+        }
+    }
+    phys::free_frame(table);
+}
+
+/// Canonical address for the given table indices, the top half sign-extended
+fn canonical(pml4: usize, pdpt: usize, pd: usize, pt: usize) -> u64 {
+    let addr = (pml4 as u64) << 39 | (pdpt as u64) << 30 | (pd as u64) << 21 | (pt as u64) << 12;
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+/// Log every mapping in the active address space, merging neighbouring pages that map
+/// consecutive frames with the same flags. Flags are combined across levels, as in `query`.
+pub fn log_mappings() {
+    /// Flags that say nothing about what the mapping allows
+    const IGNORED: u64 = flags::ACCESSED | flags::DIRTY | flags::HUGE_PAGE;
+
+    let mut run: Option<(u64, u64, u64, u64)> = None;
+    let mut count = 0;
+    let mut add = |virt: u64, phys: u64, size: u64, entry_flags: u64| {
+        let entry_flags = entry_flags & FLAG_MASK & !IGNORED;
+        if let Some((start, start_phys, len, run_flags)) = &mut run
+            && *start + *len == virt
+            && *start_phys + *len == phys
+            && *run_flags == entry_flags
+        {
+            *len += size;
+            return;
+        }
+
+        if let Some(finished) = run.replace((virt, phys, size, entry_flags)) {
+            log_mapping(finished);
+            count += 1;
+        }
+    };
+
+    // Writable and user-accessible only if every level says so, no-execute if any does
+    let restrict = |effective: u64, entry: &PageTableEntry| {
+        let allowed = entry.flags() | !(flags::WRITABLE | flags::USER_ACCESSIBLE);
+        (effective & allowed) | (entry.flags() & flags::NO_EXECUTE)
+    };
+
+    let pml4 = unsafe { &*(crate::arch::current_cr3() as *const PageTable) };
+    for (i, pml4_entry) in pml4.entries.iter().enumerate() {
+        if !pml4_entry.is_present() {
+            continue;
+        }
+        let effective = restrict(flags::WRITABLE | flags::USER_ACCESSIBLE, pml4_entry);
+
+        let pdpt = unsafe { &*(pml4_entry.addr() as *const PageTable) };
+        for (j, pdpt_entry) in pdpt.entries.iter().enumerate() {
+            if !pdpt_entry.is_present() {
+                continue;
+            }
+            let effective = restrict(effective, pdpt_entry);
+            if pdpt_entry.is_huge_page() {
+                add(canonical(i, j, 0, 0), pdpt_entry.addr(), 1 << 30, effective);
+                continue;
+            }
+
+            let pd = unsafe { &*(pdpt_entry.addr() as *const PageTable) };
+            for (k, pd_entry) in pd.entries.iter().enumerate() {
+                if !pd_entry.is_present() {
+                    continue;
+                }
+                let effective = restrict(effective, pd_entry);
+                if pd_entry.is_huge_page() {
+                    add(
+                        canonical(i, j, k, 0),
+                        pd_entry.addr(),
+                        HUGE_PAGE_SIZE,
+                        effective,
+                    );
+                    continue;
+                }
+
+                let pt = unsafe { &*(pd_entry.addr() as *const PageTable) };
+                for (l, pt_entry) in pt.entries.iter().enumerate() {
+                    if pt_entry.is_present() {
+                        let flags = restrict(effective, pt_entry);
+                        add(
+                            canonical(i, j, k, l),
+                            pt_entry.addr(),
+                            PAGE_SIZE as u64,
+                            flags,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(last) = run {
+        log_mapping(last);
+        count += 1;
+    }
+    log::info!(
+        "{} mapped ranges in address space {:#x}",
+        count,
+        crate::arch::current_cr3()
+    );
+}
+
+/// Log one range: writable, executable, user-accessible, global and uncached (or write-through or
+/// write-combining, which use the same bits), `-` where not
+fn log_mapping((virt, phys, len, entry_flags): (u64, u64, u64, u64)) {
+    let flag = |bit: u64, set: char| if entry_flags & bit != 0 { set } else { '-' };
+    let executable = if entry_flags & flags::NO_EXECUTE != 0 {
+        '-'
+    } else {
+        'x'
+    };
+    log::info!(
+        "  {:#018x}-{:#018x} -> {:#x} {}{}{}{}{}",
+        virt,
+        virt + len,
+        phys,
+        flag(flags::WRITABLE, 'w'),
+        executable,
+        flag(flags::USER_ACCESSIBLE, 'u'),
+        flag(flags::GLOBAL, 'g'),
+        flag(flags::WRITE_THROUGH | flags::CACHE_DISABLE, 'c'),
+    );
+}
+
 /// Translate virtual address to physical address
 pub fn translate(virt: u64) -> Option<u64> {
+    query(virt).map(|(phys, _)| phys)
+}
+
+/// Translate a virtual address in the address space whose PML4 is at `cr3`
+pub fn translate_in(cr3: u64, virt: u64) -> Option<u64> {
+    query_in(cr3, virt).map(|(phys, _)| phys)
+}
+
+/// Physical address `virt` maps to, and the flags that actually apply to it. Writable and
+/// user-accessible only count if every level allows them, no-execute if any level sets it. The
+/// other bits come from the entry that maps the page, so `HUGE_PAGE` shows whether it's part of a
+/// larger page.
+pub fn query(virt: u64) -> Option<(u64, u64)> {
+    query_in(kernel_cr3(), virt)
+}
+
+/// `query` in the address space whose PML4 is at `cr3`
+pub fn query_in(cr3: u64, virt: u64) -> Option<(u64, u64)> {
     let indices = VirtualAddress(virt).indices();
+    let pml4 = reachable_table(cr3).ok()? as *const PageTable;
+
+    // Only these are combined across levels, everything else is the leaf's own
+    const COMBINED: u64 = flags::WRITABLE | flags::USER_ACCESSIBLE | flags::NO_EXECUTE;
+
+    // Folds one more level into the combined flags
+    let restrict = |effective: u64, entry: &PageTableEntry| {
+        let allowed = entry.flags() | !(flags::WRITABLE | flags::USER_ACCESSIBLE);
+        (effective & allowed) | (entry.flags() & flags::NO_EXECUTE)
+    };
+    // Combined flags of all levels above plus the leaf's other bits
+    let leaf_flags = |effective: u64, entry: &PageTableEntry| {
+        restrict(effective, entry) | (entry.flags() & !COMBINED)
+    };
 
     unsafe {
-        let pml4_entry = &KPML4[indices.pml4];
+        let pml4_entry = &(*pml4).entries[indices.pml4];
         if !pml4_entry.is_present() {
             return None;
         }
+        let effective = restrict(flags::WRITABLE | flags::USER_ACCESSIBLE, pml4_entry);
 
         let pdpt = pml4_entry.addr() as *const PageTable;
         let pdpt_entry = &(*pdpt).entries[indices.pdpt];
@@ -312,8 +920,9 @@ pub fn translate(virt: u64) -> Option<u64> {
         // Check for 1GB page
         if pdpt_entry.is_huge_page() {
             let phys = pdpt_entry.addr() + (virt & 0x3FFF_FFFF);
-            return Some(phys);
+            return Some((phys, leaf_flags(effective, pdpt_entry)));
         }
+        let effective = restrict(effective, pdpt_entry);
 
         let pd = pdpt_entry.addr() as *const PageTable;
         let pd_entry = &(*pd).entries[indices.pd];
@@ -324,8 +933,9 @@ pub fn translate(virt: u64) -> Option<u64> {
         // Check for 2MB page
         if pd_entry.is_huge_page() {
             let phys = pd_entry.addr() + (virt & 0x1F_FFFF);
-            return Some(phys);
+            return Some((phys, leaf_flags(effective, pd_entry)));
         }
+        let effective = restrict(effective, pd_entry);
 
         let pt = pd_entry.addr() as *const PageTable;
         let pt_entry = &(*pt).entries[indices.pt];
@@ -333,6 +943,168 @@ pub fn translate(virt: u64) -> Option<u64> {
             return None;
         }
 
-        Some(pt_entry.addr() + indices.offset as u64)
+        Some((pt_entry.addr() + indices.offset as u64, leaf_flags(effective, pt_entry)))
     }
 }
+
+/// Replace the flags of the existing mapping of `virt`, keeping the physical address. The page
+/// stays present whatever `new_flags` says, unmap it to get rid of it. A 2 MiB huge page covering
+/// `virt` is split first so only the one 4 KiB page changes.
+pub fn protect(virt: u64, new_flags: u64) -> Result<(), &'static str> {
+    protect_in(kernel_cr3(), virt, new_flags)
+}
+
+/// `protect` in the address space whose PML4 is at `cr3`
+pub fn protect_in(cr3: u64, virt: u64, new_flags: u64) -> Result<(), &'static str> {
+    let indices = VirtualAddress(virt).indices();
+    let _tables = PAGE_TABLES.lock();
+    let pml4 = own_pml4(cr3, indices.pml4)?;
+
+    unsafe {
+        let pml4_entry = &(*pml4).entries[indices.pml4];
+        if !pml4_entry.is_present() {
+            return Err("PML4 entry not present");
+        }
+
+        let pdpt = pml4_entry.addr() as *mut PageTable;
+        let pdpt_entry = &(*pdpt).entries[indices.pdpt];
+        if !pdpt_entry.is_present() {
+            return Err("PDPT entry not present");
+        }
+        if pdpt_entry.is_huge_page() {
+            return Err("1 GiB pages are not supported");
+        }
+
+        let pd = pdpt_entry.addr() as *mut PageTable;
+        let pd_entry = &mut (*pd).entries[indices.pd];
+        if !pd_entry.is_present() {
+            return Err("PD entry not present");
+        }
+
+        if pd_entry.is_huge_page() {
+            split_huge_page(pd_entry)?;
+        }
+
+        let pt = pd_entry.addr() as *mut PageTable;
+        let pt_entry = &mut (*pt).entries[indices.pt];
+        if !pt_entry.is_present() {
+            return Err("PT entry not present");
+        }
+
+        pt_entry.set_flags((new_flags | flags::PRESENT) & !flags::HUGE_PAGE);
+
+        crate::arch::x86_64::invlpg(virt);
+    }
+
+    Ok(())
+}
+
+/// Unused stretch of the higher half the self-test maps its page at
+const SELFTEST_PAGE: u64 = 0xFFFF_FD00_0000_0000;
+
+/// `query` reports what `protect` set, for the same frame, and refuses unmapped pages
+pub fn selftest_protect_query() -> Result<(), &'static str> {
+    const CHANGED: u64 = flags::WRITABLE | flags::NO_EXECUTE;
+
+    let frame = phys::alloc_frame().ok_or("Out of frames")?;
+    if let Err(e) = map_page(SELFTEST_PAGE, frame, flags::WRITABLE | flags::NO_EXECUTE) {
+        phys::free_frame(frame);
+        return Err(e);
+    }
+
+    let round_trip = |set: u64| -> Result<(), &'static str> {
+        protect(SELFTEST_PAGE, set)?;
+        match query(SELFTEST_PAGE) {
+            Some((phys, _)) if phys != frame => Err("Protecting a page moved it"),
+            Some((_, got)) if got & CHANGED != set & CHANGED => {
+                Err("Flags read back don't match the ones set")
+            }
+            Some(_) => Ok(()),
+            None => Err("Protecting a page unmapped it"),
+        }
+    };
+    let result = round_trip(flags::NO_EXECUTE)
+        .and_then(|()| round_trip(flags::WRITABLE))
+        .and_then(|()| round_trip(flags::WRITABLE | flags::NO_EXECUTE));
+
+    let unmapped = unmap_page(SELFTEST_PAGE);
+    phys::free_frame(frame);
+    result?;
+    unmapped?;
+
+    if query(SELFTEST_PAGE).is_some() {
+        return Err("Unmapped page still queries as mapped");
+    }
+    if protect(SELFTEST_PAGE, flags::WRITABLE).is_ok() {
+        return Err("Protected a page that isn't mapped");
+    }
+
+    Ok(())
+}
+
+/// Mapping a 4 KiB page inside a 2 MiB page of the identity map splits it: the page moves to its
+/// new frame and the other 511 stay where they were
+pub fn selftest_split_huge_page() -> Result<(), &'static str> {
+    const PATTERN: u64 = 0xB16B_00B5_0DDB_A115;
+    let huge = HUGE_PAGE_SIZE as usize;
+
+    // A whole 2 MiB of frames, so nothing else is using any page of it while it's moved around
+    let region = crate::mem::heap::alloc_aligned(huge, huge).ok_or("Out of frames")?;
+    let base = region.as_ptr() as u64;
+    let frame = match phys::alloc_frame() {
+        Some(frame) => frame,
+        None => {
+            unsafe { crate::mem::heap::free_aligned(region, huge) };
+            return Err("Out of frames");
+        }
+    };
+
+    let result = check_split(base, frame, PATTERN);
+
+    phys::free_frame(frame);
+    unsafe { crate::mem::heap::free_aligned(region, huge) };
+    result
+}
+
+fn check_split(base: u64, frame: u64, pattern: u64) -> Result<(), &'static str> {
+    let page = base + 7 * PAGE_SIZE as u64;
+
+    let Some((phys, original_flags)) = query(page) else {
+        return Err("Identity map doesn't cover the region");
+    };
+    if phys != page || original_flags & flags::HUGE_PAGE == 0 {
+        return Err("Region isn't a 2 MiB page of the identity map");
+    }
+
+    // Written through the frame's own identity address, before it's mapped anywhere else
+    unsafe { (frame as *mut u64).write_volatile(pattern) };
+    map_page(page, frame, flags::PRESENT | flags::WRITABLE)?;
+
+    let moved = match query(page) {
+        Some((phys, _)) if phys != frame => Err("New mapping didn't replace the huge page"),
+        Some((_, page_flags)) if page_flags & flags::HUGE_PAGE != 0 => {
+            Err("Page is still part of a huge page")
+        }
+        Some(_) if unsafe { (page as *const u64).read_volatile() } != pattern => {
+            Err("Reading the page doesn't see the new frame")
+        }
+        Some(_) => Ok(()),
+        None => Err("Mapped page doesn't translate"),
+    };
+    let neighbours = (base..base + HUGE_PAGE_SIZE)
+        .step_by(PAGE_SIZE)
+        .filter(|&other| other != page)
+        .all(|other| translate(other) == Some(other));
+
+    // Put the identity mapping back, with the flags it had as part of the huge page
+    map_page(page, page, original_flags & !flags::HUGE_PAGE)?;
+    moved?;
+    if !neighbours {
+        return Err("Splitting moved the pages around the new mapping");
+    }
+    if translate(page) != Some(page) {
+        return Err("Identity mapping wasn't restored");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Per-CPU data, reached through the GS segment base.
+//! Each CPU points its GS base at its own `PerCpu` block, whose first field points back at the
+//! block itself, so `current()` is a single `mov reg, gs:[0]` with no locking or CPU lookup.
+//!
+//! That only holds while in the kernel. User code has a GS base of its own, so `swapgs` trades the
+//! two on every crossing: in user mode IA32_GS_BASE is the user's and IA32_KERNEL_GS_BASE holds
+//! the block, in the kernel it's the other way around. `idt::enter_user` swaps on the way out, and
+//! the entry stubs swap back whenever they were entered from user mode.
+
+use crate::arch::x86_64::{gdt, wrmsr};
+use crate::proc::scheduler::Scheduler;
+use crate::proc::thread::Thread;
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// GS base MSR, the per-CPU block while in the kernel
+const IA32_GS_BASE: u32 = 0xC000_0101;
+/// Swapped with the GS base by `swapgs`, the user's GS base while in the kernel
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+pub const MAX_CPUS: usize = 16;
+
+/// Declare the fields of `PerCpu` along with their initial values
+macro_rules! percpu_fields {
+    ($($(#[$attr:meta])* $field:ident: $ty:ty = $init:expr),* $(,)?) => {
+        #[repr(C)]
+        pub struct PerCpu {
+            /// Must stay first: `current()` reads it through `gs:[0]`
+            self_ptr: *const PerCpu,
+            $($(#[$attr])* pub $field: $ty,)*
+        }
+
+        impl PerCpu {
+            const fn new() -> Self {
+                Self {
+                    self_ptr: core::ptr::null(),
+                    $($field: $init,)*
+                }
+            }
+        }
+    };
+}
+
+percpu_fields! {
+    /// Index of this CPU, 0 is the bootstrap processor
+    cpu_id: u32 = 0,
+    /// Thread running on this CPU, null before scheduling starts
+    current_thread: AtomicPtr<Thread> = AtomicPtr::new(core::ptr::null_mut()),
+    /// Threads waiting to run on this CPU
+    run_queue: Mutex<Scheduler> = Mutex::new(Scheduler::new()),
+    /// `preempt_disable` depth, the running thread can only be preempted at 0
+    preempt_count: AtomicU32 = AtomicU32::new(0),
+    /// Set by the timer once the running thread's time slice is used up
+    need_resched: AtomicBool = AtomicBool::new(false),
+    /// Stack the SYSCALL entry stub switches to, the running thread's kernel stack
+    syscall_stack: AtomicU64 = AtomicU64::new(0),
+    /// User RSP saved by the SYSCALL entry stub while it runs on `syscall_stack`
+    user_rsp: u64 = 0,
+}
+
+/// Only written by `init`, before the CPU's GS base points at its slot
+static mut CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// Set once the bootstrap processor's GS base points at its block. It's the only CPU running, so
+/// this covers every caller.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Set up the per-CPU block for `cpu_id` and point this CPU's GS base at it
+pub fn init(cpu_id: u32) {
+    assert!((cpu_id as usize) < MAX_CPUS, "CPU {} exceeds MAX_CPUS", cpu_id);
+
+    let block = unsafe {
+        let block = &mut *(&raw mut CPUS).cast::<PerCpu>().add(cpu_id as usize);
+        block.self_ptr = block;
+        block.cpu_id = cpu_id;
+        block
+            .syscall_stack
+            .store(gdt::kernel_stack_top(), Ordering::Relaxed);
+        block as *const PerCpu as u64
+    };
+
+    wrmsr(IA32_GS_BASE, block);
+    // The user GS base, swapped in by `idt::enter_user`
+    wrmsr(IA32_KERNEL_GS_BASE, 0);
+    READY.store(true, Ordering::Release);
+
+    log::debug!("Per-CPU data for CPU {} at {:#x}", cpu_id, block);
+}
+
+/// This CPU's per-CPU block, `None` before `init`. For code that can run that early, e.g. the heap.
+pub fn try_current() -> Option<&'static PerCpu> {
+    READY.load(Ordering::Acquire).then(current)
+}
+
+/// This CPU's per-CPU block. Only valid after `init` ran on this CPU.
+#[inline(always)]
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[0]",
+            out(reg) ptr,
+            options(nostack, readonly, preserves_flags)
+        );
+        &*ptr
+    }
+}
@@ -0,0 +1,135 @@
+//! Random number source
+//!
+//! Uses the RDRAND instruction when the CPU has it. Otherwise (or if RDRAND keeps failing) falls
+//! back to an xorshift64* generator seeded from the TSC and timing jitter, which is fine for
+//! things like hash seeds and stack canaries but is *not* cryptographically secure.
+
+use crate::arch::x86_64::{cpu, rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Intel recommends retrying RDRAND up to 10 times before assuming the DRNG is broken
+const RDRAND_RETRIES: usize = 10;
+
+/// State of the fallback generator, 0 means it hasn't been seeded yet
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Get a random 64-bit value
+pub fn u64() -> u64 {
+    if cpu::features().rdrand
+        && let Some(value) = rdrand()
+    {
+        return value;
+    }
+
+    xorshift()
+}
+
+/// Fill `buf` with random bytes
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = u64().to_ne_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+
+        // CF is set when a valid random number was returned
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Step the fallback xorshift64* generator (lock-free, so it's safe from interrupt context)
+fn xorshift() -> u64 {
+    let mut current = STATE.load(Ordering::Relaxed);
+
+    loop {
+        let mut x = if current == 0 { seed() } else { current };
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+
+        match STATE.compare_exchange_weak(current, x, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return x.wrapping_mul(0x2545_F491_4F6C_DD1D),
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// Build a seed from the TSC plus the jitter between successive TSC reads
+fn seed() -> u64 {
+    let mut seed = rdtsc();
+
+    for _ in 0..64 {
+        let before = rdtsc();
+        core::hint::spin_loop();
+        let delta = rdtsc().wrapping_sub(before);
+        seed = splitmix(seed ^ delta);
+    }
+
+    // xorshift gets stuck at zero forever
+    if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed }
+}
+
+/// SplitMix64 finalizer, spreads low-entropy input across all 64 bits
+fn splitmix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Rough check that the fallback generator isn't obviously broken: every bit is set about half
+/// the time, the top nibble is spread evenly and no value repeats straight away. Bounds are far
+/// enough out (more than 7 standard deviations) that a working generator never trips them.
+pub fn selftest_fallback() -> Result<(), &'static str> {
+    const SAMPLES: u32 = 4096;
+    const BIT_TOLERANCE: u32 = 256;
+    const BUCKETS: usize = 16;
+    const BUCKET_TOLERANCE: u32 = 128;
+
+    let mut bit_counts = [0u32; 64];
+    let mut buckets = [0u32; BUCKETS];
+    let mut previous = None;
+
+    for _ in 0..SAMPLES {
+        let value = xorshift();
+        if previous == Some(value) {
+            return Err("Same value twice in a row");
+        }
+        previous = Some(value);
+
+        for (bit, count) in bit_counts.iter_mut().enumerate() {
+            *count += (value >> bit) as u32 & 1;
+        }
+        buckets[(value >> 60) as usize] += 1;
+    }
+
+    let biased = |&count: &u32| count.abs_diff(SAMPLES / 2) > BIT_TOLERANCE;
+    if bit_counts.iter().any(biased) {
+        return Err("A bit is biased");
+    }
+
+    let uneven = |&count: &u32| count.abs_diff(SAMPLES / BUCKETS as u32) > BUCKET_TOLERANCE;
+    if buckets.iter().any(uneven) {
+        return Err("Values are unevenly spread");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Snapshots of the general purpose registers, for failure reports outside of an exception.
+
+use core::arch::asm;
+
+/// Register values at the point `capture` was inlined
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// Read the registers as they are in the caller. One of them holds the snapshot's address by
+    /// the time it's stored, so treat the values as a best-effort hint rather than exact state.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut regs = Self::default();
+
+        unsafe {
+            asm!(
+                "mov [{regs} + 0x00], rax",
+                "mov [{regs} + 0x08], rbx",
+                "mov [{regs} + 0x10], rcx",
+                "mov [{regs} + 0x18], rdx",
+                "mov [{regs} + 0x20], rsi",
+                "mov [{regs} + 0x28], rdi",
+                "mov [{regs} + 0x30], rbp",
+                "mov [{regs} + 0x38], rsp",
+                "mov [{regs} + 0x40], r8",
+                "mov [{regs} + 0x48], r9",
+                "mov [{regs} + 0x50], r10",
+                "mov [{regs} + 0x58], r11",
+                "mov [{regs} + 0x60], r12",
+                "mov [{regs} + 0x68], r13",
+                "mov [{regs} + 0x70], r14",
+                "mov [{regs} + 0x78], r15",
+                "lea {tmp}, [rip]",
+                "mov [{regs} + 0x80], {tmp}",
+                "pushfq",
+                "pop {tmp}",
+                "mov [{regs} + 0x88], {tmp}",
+                regs = in(reg) &raw mut regs,
+                tmp = out(reg) _,
+                options(preserves_flags),
+            );
+        }
+
+        regs
+    }
+
+    /// Log every register at error level
+    pub fn log(&self) {
+        log::error!(
+            "  RIP={:#018x}  RFLAGS={:#018x}\n\
+             \x20 RSP={:#018x}  RBP={:#018x}\n\
+             \x20 RAX={:#018x}  RBX={:#018x}  RCX={:#018x}  RDX={:#018x}\n\
+             \x20 RSI={:#018x}  RDI={:#018x}\n\
+             \x20 R8 ={:#018x}  R9 ={:#018x}  R10={:#018x}  R11={:#018x}\n\
+             \x20 R12={:#018x}  R13={:#018x}  R14={:#018x}  R15={:#018x}",
+            self.rip,
+            self.rflags,
+            self.rsp,
+            self.rbp,
+            self.rax,
+            self.rbx,
+            self.rcx,
+            self.rdx,
+            self.rsi,
+            self.rdi,
+            self.r8,
+            self.r9,
+            self.r10,
+            self.r11,
+            self.r12,
+            self.r13,
+            self.r14,
+            self.r15,
+        );
+    }
+}
+
+const _: () = {
+    use core::mem::offset_of;
+
+    // The offsets in `capture`
+    assert!(offset_of!(Registers, rbp) == 0x30);
+    assert!(offset_of!(Registers, r8) == 0x40);
+    assert!(offset_of!(Registers, r15) == 0x78);
+    assert!(offset_of!(Registers, rip) == 0x80);
+    assert!(offset_of!(Registers, rflags) == 0x88);
+};
@@ -1,13 +1,18 @@
 use core::fmt::Write;
 
 use crate::arch::x86_64::{inb, outb};
+use crate::proc::scheduler;
 
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 use log;
 
 // Port base
 
 const COM1: u16 = 0x3F8;
+/// Second port, used by the GDB stub so it doesn't share a line with the log
+pub const COM2: u16 = 0x2F8;
 
 // Register offsets from the port base
 //
@@ -33,6 +38,8 @@ const FCR_ENABLE_14B: u8 = 0xC7; // Enable FIFO, clear Tx/Rx, 14-byte threshold
 const MCR_LOOPBACK: u8 = 0x1E; // RTS + OUT1 + OUT2 + LOOP (bit 4 enables loopback)
 const MCR_NORMAL: u8 = 0x0F; // DTR + RTS + OUT1 + OUT2  (LOOP bit cleared)
 
+const IER_RX_AVAILABLE: u8 = 0x01; // Bit 0: interrupt when received data is available
+
 const LSR_DATA_READY: u8 = 0x01; // Bit 0: received data is available
 const LSR_THR_EMPTY: u8 = 0x20; // Bit 5: transmit-hold register is empty
 
@@ -45,23 +52,34 @@ const LOOPBACK_TEST_BYTE: u8 = 0xAE;
 
 // Implementation
 
+/// Cleared when the self-test fails, after which all output to the port is dropped.
+/// Assumed to work until then, firmware normally leaves the UART usable.
+static USABLE: AtomicBool = AtomicBool::new(true);
+
+/// Whether anything written to serial can be expected to arrive
+pub fn is_usable() -> bool {
+    USABLE.load(Ordering::Relaxed)
+}
+
 pub struct Serial {
     port: u16,
+    /// Last byte sent through `write_string`, used to keep newline translation idempotent
+    last_byte: u8,
 }
 
 impl Serial {
     pub const fn new(port: u16) -> Self {
-        Serial { port }
+        Serial { port, last_byte: 0 }
     }
 
     /// Initialize the port at 115200 baud, 8N1, no interrupts.
-    /// Panics if the loopback self-test fails.
-    pub fn init(&self) {
+    /// Fails if the loopback self-test does, which usually means there's no UART at all.
+    pub fn init(&self) -> Result<(), &'static str> {
         self.disable_interrupts();
         self.set_baud(BAUD_115200);
         self.configure_line(LCR_8N1);
         self.configure_fifo(FCR_ENABLE_14B);
-        self.loopback_test();
+        self.loopback_test()
     }
 
     fn reg(&self, offset: u16) -> u16 {
@@ -72,6 +90,11 @@ impl Serial {
         outb(self.reg(REG_IER), 0x00);
     }
 
+    /// Raise an interrupt whenever a byte arrives. `init` turns it back off.
+    pub fn enable_rx_interrupt(&self) {
+        outb(self.reg(REG_IER), IER_RX_AVAILABLE);
+    }
+
     /// Set baud rate via the divisor latch. `divisor` is `(low_byte, high_byte)`.
     fn set_baud(&self, divisor: (u8, u8)) {
         outb(self.reg(REG_LCR), LCR_DLAB); // Enable divisor latch
@@ -89,22 +112,25 @@ impl Serial {
     }
 
     /// Enable loopback mode, write a test byte, read it back, then restore normal mode.
-    fn loopback_test(&self) {
+    fn loopback_test(&self) -> Result<(), &'static str> {
         outb(self.reg(REG_MCR), MCR_LOOPBACK);
         outb(self.reg(REG_DATA), LOOPBACK_TEST_BYTE);
 
         let result = inb(self.reg(REG_DATA));
+        outb(self.reg(REG_MCR), MCR_NORMAL);
+
         if result != LOOPBACK_TEST_BYTE {
-            panic!(
-                "Serial self-test failed: wrote 0x{:02X}, read 0x{:02X}",
-                LOOPBACK_TEST_BYTE, result
-            );
+            return Err("Serial loopback self-test failed");
         }
 
-        outb(self.reg(REG_MCR), MCR_NORMAL);
+        Ok(())
     }
 
     pub fn write_byte(&self, byte: u8) {
+        if self.port == COM1 && !is_usable() {
+            return;
+        }
+
         while inb(self.reg(REG_LSR)) & LSR_THR_EMPTY == 0 {}
         outb(self.reg(REG_DATA), byte);
     }
@@ -117,13 +143,24 @@ impl Serial {
         }
     }
 
-    pub fn write_string(&self, s: &str) {
-        for byte in s.bytes() {
-            if byte == b'\n' {
-                self.write_byte(b'\r');
-            }
-            self.write_byte(byte);
+    /// Write a string, translating `\n` to `\r\n` for terminals.
+    /// A `\n` that already follows a `\r` (even from a previous call) is left alone.
+    pub fn write_string(&mut self, s: &str) {
+        let mut last_byte = self.last_byte;
+        translate_newlines(&mut last_byte, s, |byte| self.write_byte(byte));
+        self.last_byte = last_byte;
+    }
+}
+
+/// Pass `s` to `emit` a byte at a time with `\n` turned into `\r\n`, unless it follows a `\r`.
+/// `last_byte` is the byte emitted before `s`, and is updated to the last one in it.
+fn translate_newlines(last_byte: &mut u8, s: &str, mut emit: impl FnMut(u8)) {
+    for byte in s.bytes() {
+        if byte == b'\n' && *last_byte != b'\r' {
+            emit(b'\r');
         }
+        emit(byte);
+        *last_byte = byte;
     }
 }
 
@@ -150,19 +187,83 @@ impl core::fmt::Debug for Serial {
 
 pub static SERIAL: Mutex<Serial> = Mutex::new(Serial::new(COM1));
 
+/// Run `f` against COM1 without ever blocking on `SERIAL`.
+///
+/// If the lock is free it is taken as usual. If it's already held (e.g. an interrupt fired while
+/// the interrupted code was mid-write), `f` gets an unlocked handle to the same port instead. The
+/// UART itself is just port I/O, so the worst case is interleaved output rather than a deadlock
+/// on the spin lock.
+pub fn with_port<R>(f: impl FnOnce(&mut Serial) -> R) -> R {
+    match SERIAL.try_lock() {
+        Some(mut guard) => f(&mut guard),
+        None => f(&mut Serial::new(COM1)),
+    }
+}
+
+/// Write straight to COM1 without going through `SERIAL`, for output before `init()` has run.
+/// Firmware normally leaves the UART in a usable state, so this works from the very first
+/// instruction of `_start64`.
+pub fn write_early(s: &str) {
+    Serial::new(COM1).write_string(s);
+}
+
 pub fn init() {
     log::trace!("Initializing serial port COM1 (0x{:03X})...", COM1);
-    SERIAL.lock().init();
+
+    if let Err(e) = SERIAL.lock().init() {
+        USABLE.store(false, Ordering::Relaxed);
+        // Only shows up if there's another sink, i.e. the VGA text buffer
+        log::warn!("{}, serial output disabled", e);
+        return;
+    }
+
     log::debug!("Serial port initialized: 115200 baud, 8N1, FIFO enabled");
 }
 
+/// Backend of `serial_print!`: COM1, or the VGA text buffer if serial isn't usable
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    if is_usable() {
+        // Syscalls print with interrupts disabled, they'd spin forever behind a preempted holder
+        scheduler::preempt_disable();
+        let _ = SERIAL.lock().write_fmt(args);
+        scheduler::preempt_enable();
+    } else {
+        crate::drivers::vga_text::with_writer(|writer| {
+            let _ = writer.write_fmt(args);
+        });
+    }
+}
+
+/// Newline translation never doubles a `\r`, whether it came in the same write or the last one
+pub fn selftest_newlines() -> Result<(), &'static str> {
+    let cases: [(&[&str], &[u8]); 5] = [
+        (&["a\r\nb"], b"a\r\nb"),
+        (&["a\nb"], b"a\r\nb"),
+        (&["a\r", "\nb"], b"a\r\nb"),
+        (&["a", "\nb"], b"a\r\nb"),
+        (&["\n\r\n\n"], b"\r\n\r\n\r\n"),
+    ];
+
+    for (writes, expected) in cases {
+        let mut output = Vec::new();
+        let mut last_byte = 0;
+        for s in writes {
+            translate_newlines(&mut last_byte, s, |byte| output.push(byte));
+        }
+
+        if output != expected {
+            return Err("Newlines translated wrongly");
+        }
+    }
+
+    Ok(())
+}
+
 /// Printing macros (supports `format_args!` syntax, e.g. `serial_println!("Hello, {}!", "world")`)
 #[macro_export]
 macro_rules! serial_print {
-    ($($arg:tt)*) => ({
-        use core::fmt::Write;
-        let _ = write!($crate::arch::x86_64::serial::SERIAL.lock(), $($arg)*);
-    });
+    ($($arg:tt)*) => ($crate::arch::x86_64::serial::_print(format_args!($($arg)*)));
 }
 
 #[macro_export]
@@ -44,7 +44,11 @@ multiboot_magic_saved: dq 0
 multiboot_info_saved:  dq 0
 
 section .bss
-align 16
+; Guard page below the stack, unmapped by the kernel once paging is up so overflows fault
+align 4096
+global boot_stack_guard
+boot_stack_guard:
+    resb 4096
 stack_bottom:
     resb 65536  ; 64KB stack
 stack_top:
@@ -90,6 +94,8 @@ bits 32
 
 global _start
 extern _start64
+extern _bss_start
+extern _bss_end
 
 _start:
     ; Set up stack
@@ -99,6 +105,16 @@ _start:
     mov dword [multiboot_magic_saved], eax
     mov dword [multiboot_info_saved], ebx
 
+    ; Zero .bss before anything lives there. It has to happen here rather than in Rust, since
+    ; .bss also holds the boot stack and the page tables set up below. Multiboot loaders are
+    ; supposed to clear it already, but nothing guarantees every one of them does.
+    cld
+    mov edi, _bss_start
+    mov ecx, _bss_end
+    sub ecx, edi
+    xor eax, eax
+    rep stosb
+
     ; Set up identity paging (map first 4GB using 2MB pages)
 	; This means that virtual addresses will match physical addresses, which simplifies the transition
 	; TODO: Post transition, we can set up more complex paging if needed (e.g. higher half kernel, like in Windows/Linux)
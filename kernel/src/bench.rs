@@ -0,0 +1,301 @@
+//! Microbenchmarks for the memory allocators and screen sync, built with `--features bench`
+//! (`make bench`).
+//!
+//! `run` takes over from `kernel_main` once memory management is up, times the frame allocator,
+//! the heap, `map_page` and syncing a back buffer with the TSC, prints a table over serial and
+//! exits QEMU through the isa-debug-exit device. Nothing else is running yet, so the numbers only include the timer
+//! interrupt's share of noise. Each benchmark does its own bookkeeping allocations up front so
+//! they don't land inside the timed loop.
+
+use crate::arch::x86_64::paging::{self, flags};
+use crate::arch::x86_64::{delay, outl, rdtsc};
+use crate::drivers::screen::{self, Screen};
+use crate::drivers::vga_text;
+use crate::kprintln;
+use crate::mem::{PAGE_SIZE, phys};
+use crate::{BootInfo, FramebufferInfo};
+
+use alloc::alloc::{Layout, alloc, dealloc};
+use alloc::vec::Vec;
+
+/// Frames taken and mapped by the frame and `map_page` benchmarks
+const FRAMES: usize = 4096;
+/// Allocations made at each heap size
+const HEAP_ALLOCATIONS: usize = 1024;
+const HEAP_SIZES: [usize; 6] = [16, 64, 256, 1024, 4096, 65536];
+
+/// Back buffer size the sync benchmark runs at, 1080p in 32-bit colour
+const SYNC_WIDTH: u32 = 1920;
+const SYNC_HEIGHT: u32 = 1080;
+/// Syncs timed of each kind
+const SYNC_ROUNDS: usize = 32;
+/// Rows the dirty-only sync copies, about one line of text
+const SYNC_DIRTY_ROWS: usize = 16;
+
+/// Unused stretch of the higher half that `map_page` is timed against
+const MAP_START: u64 = 0xFFFF_FE00_0000_0000;
+
+/// Port of QEMU's `isa-debug-exit` device. Writing `code` exits QEMU with `(code << 1) | 1`.
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum ExitCode {
+    /// QEMU exits with 33
+    Success = 0x10,
+    /// QEMU exits with 35
+    Failed = 0x11,
+}
+
+/// Run every benchmark, then exit QEMU
+pub fn run(boot_info: &BootInfo) -> ! {
+    kprintln!("Benchmarks (TSC cycles):");
+    kprintln!(
+        "  {:<24} {:>8} {:>12} {:>12}",
+        "name",
+        "ops",
+        "cycles/op",
+        "ops/s"
+    );
+
+    let result = bench_frames()
+        .and_then(|()| bench_heap())
+        .and_then(|()| bench_map_page())
+        .and_then(|()| bench_screen_sync())
+        .and_then(|()| bench_write_combining(&boot_info.framebuffer));
+
+    match result {
+        Ok(()) => exit(ExitCode::Success),
+        Err(e) => {
+            kprintln!("Benchmark failed: {}", e);
+            exit(ExitCode::Failed)
+        }
+    }
+}
+
+/// Cycles taken by `f`
+fn measure(f: impl FnOnce()) -> u64 {
+    let start = rdtsc();
+    f();
+    rdtsc() - start
+}
+
+fn report(name: &str, ops: usize, cycles: u64) {
+    let per_op = cycles / ops.max(1) as u64;
+
+    // Without a calibrated TSC there's no way to turn cycles into time
+    match delay::tsc_per_us() {
+        Some(per_us) => {
+            let per_second = ops as u64 * per_us * 1_000_000 / cycles.max(1);
+            kprintln!(
+                "  {:<24} {:>8} {:>12} {:>12}",
+                name,
+                ops,
+                per_op,
+                per_second
+            );
+        }
+        None => kprintln!("  {:<24} {:>8} {:>12} {:>12}", name, ops, per_op, "?"),
+    }
+}
+
+fn bench_frames() -> Result<(), &'static str> {
+    let mut frames = Vec::with_capacity(FRAMES);
+
+    let cycles = measure(|| {
+        for _ in 0..FRAMES {
+            match phys::alloc_frame() {
+                Some(frame) => frames.push(frame),
+                None => break,
+            }
+        }
+    });
+    if frames.len() < FRAMES {
+        frames.into_iter().for_each(phys::free_frame);
+        return Err("Out of frames");
+    }
+    report("alloc_frame", FRAMES, cycles);
+
+    let cycles = measure(|| frames.iter().for_each(|&frame| phys::free_frame(frame)));
+    report("free_frame", FRAMES, cycles);
+
+    Ok(())
+}
+
+fn bench_heap() -> Result<(), &'static str> {
+    let mut pointers = Vec::with_capacity(HEAP_ALLOCATIONS);
+
+    for size in HEAP_SIZES {
+        let layout = Layout::from_size_align(size, 8).map_err(|_| "Bad heap layout")?;
+
+        let cycles = measure(|| {
+            for _ in 0..HEAP_ALLOCATIONS {
+                pointers.push(unsafe { alloc(layout) });
+            }
+        });
+        let failed = pointers.iter().any(|pointer| pointer.is_null());
+        if !failed {
+            report(
+                &alloc::format!("heap alloc {}", size),
+                HEAP_ALLOCATIONS,
+                cycles,
+            );
+        }
+
+        let cycles = measure(|| {
+            for pointer in pointers.drain(..).filter(|pointer| !pointer.is_null()) {
+                unsafe { dealloc(pointer, layout) };
+            }
+        });
+        if failed {
+            return Err("Out of heap");
+        }
+        report(
+            &alloc::format!("heap free {}", size),
+            HEAP_ALLOCATIONS,
+            cycles,
+        );
+    }
+
+    Ok(())
+}
+
+fn bench_map_page() -> Result<(), &'static str> {
+    let mut frames = Vec::with_capacity(FRAMES);
+    for _ in 0..FRAMES {
+        match phys::alloc_frame() {
+            Some(frame) => frames.push(frame),
+            None => {
+                frames.into_iter().for_each(phys::free_frame);
+                return Err("Out of frames");
+            }
+        }
+    }
+
+    let page = |i: usize| MAP_START + (i * PAGE_SIZE) as u64;
+    let flags = paging::flags::WRITABLE | paging::flags::NO_EXECUTE;
+
+    let mut mapped = 0;
+    let mut result = Ok(());
+    let cycles = measure(|| {
+        for (i, &frame) in frames.iter().enumerate() {
+            if let Err(e) = paging::map_page(page(i), frame, flags) {
+                result = Err(e);
+                break;
+            }
+            mapped += 1;
+        }
+    });
+    if result.is_ok() {
+        report("map_page", FRAMES, cycles);
+    }
+
+    let cycles = measure(|| {
+        for i in 0..mapped {
+            let _ = paging::unmap_page(page(i));
+        }
+    });
+    if result.is_ok() {
+        report("unmap_page", FRAMES, cycles);
+    }
+
+    frames.into_iter().for_each(phys::free_frame);
+    result
+}
+
+/// Sync the whole back buffer, then only a few dirty rows, `SYNC_ROUNDS` times each
+fn time_syncs(screen: &mut Screen) -> (u64, u64) {
+    let full = measure(|| {
+        for _ in 0..SYNC_ROUNDS {
+            screen.mark_all_dirty();
+            screen.sync_dirty();
+        }
+    });
+
+    let dirty = measure(|| {
+        for _ in 0..SYNC_ROUNDS {
+            screen.mark_dirty(0, SYNC_DIRTY_ROWS);
+            screen.sync_dirty();
+        }
+    });
+
+    (full, dirty)
+}
+
+/// Full-frame against dirty-only sync of a 1080p back buffer, into ordinary memory so it runs
+/// whatever the framebuffer's size
+fn bench_screen_sync() -> Result<(), &'static str> {
+    let stride = SYNC_WIDTH * 4;
+    let len = stride as usize * SYNC_HEIGHT as usize;
+
+    let mut target: Vec<u8> = Vec::new();
+    target.try_reserve_exact(len).map_err(|_| "Out of heap")?;
+    target.resize(len, 0);
+
+    let address = target.as_mut_ptr() as usize;
+    let mut screen = Screen::with_back_buffer(address, SYNC_WIDTH, SYNC_HEIGHT, stride, 32)?;
+
+    let (full, dirty) = time_syncs(&mut screen);
+    report("sync 1080p full", SYNC_ROUNDS, full);
+    report("sync 1080p 16 rows", SYNC_ROUNDS, dirty);
+
+    Ok(())
+}
+
+/// Full-frame sync to the real framebuffer with its default caching, then again once it's
+/// remapped write-combining. Skipped without a framebuffer to draw to or without PAT support.
+fn bench_write_combining(framebuffer: &FramebufferInfo) -> Result<(), &'static str> {
+    if vga_text::is_active() || framebuffer.address == 0 {
+        kprintln!("  (no framebuffer, write-combining not measured)");
+        return Ok(());
+    }
+
+    if !paging::write_combining_available() {
+        kprintln!("  (no PAT, write-combining not measured)");
+        return Ok(());
+    }
+
+    let address = screen::map_framebuffer(framebuffer)?;
+    let mut screen = Screen::with_back_buffer(
+        address,
+        framebuffer.width,
+        framebuffer.height,
+        framebuffer.pitch,
+        framebuffer.bpp,
+    )?;
+
+    let mut full_syncs = || {
+        measure(|| {
+            for _ in 0..SYNC_ROUNDS {
+                screen.mark_all_dirty();
+                screen.sync_dirty();
+            }
+        })
+    };
+
+    let default = full_syncs();
+    let len = framebuffer.pitch as u64 * framebuffer.height as u64;
+    paging::update_range_flags(
+        address as u64,
+        len,
+        flags::WRITE_COMBINING,
+        flags::CACHE_DISABLE,
+    )?;
+    let write_combining = full_syncs();
+
+    report("fb sync default", SYNC_ROUNDS, default);
+    report("fb sync write-combining", SYNC_ROUNDS, write_combining);
+
+    Ok(())
+}
+
+fn exit(code: ExitCode) -> ! {
+    outl(DEBUG_EXIT_PORT, code as u32);
+
+    // Not under QEMU, or started without the device
+    kprintln!("No isa-debug-exit device, halting");
+    crate::arch::disable_interrupts();
+    loop {
+        crate::arch::halt();
+    }
+}
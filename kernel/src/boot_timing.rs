@@ -0,0 +1,61 @@
+//! Boot phase timing
+//!
+//! `mark(name)` stamps the TSC at the start of a phase, and each phase runs until the next mark.
+//! Marks go into a fixed table so they can be taken before the heap exists, and `report()` prints
+//! how long each phase took once the TSC has been calibrated.
+
+use crate::arch::x86_64::{delay, rdtsc};
+use spin::Mutex;
+
+const MAX_MARKS: usize = 32;
+
+struct Marks {
+    entries: [(&'static str, u64); MAX_MARKS],
+    count: usize,
+}
+
+static MARKS: Mutex<Marks> = Mutex::new(Marks {
+    entries: [("", 0); MAX_MARKS],
+    count: 0,
+});
+
+/// Start a new phase called `name`, ending the previous one
+pub fn mark(name: &'static str) {
+    let now = rdtsc();
+    let mut marks = MARKS.lock();
+
+    if marks.count == MAX_MARKS {
+        log::warn!("Boot timing table full, dropping mark '{}'", name);
+        return;
+    }
+
+    let i = marks.count;
+    marks.entries[i] = (name, now);
+    marks.count += 1;
+}
+
+/// End the last phase and log a table of how long each one took
+pub fn report() {
+    let now = rdtsc();
+    let marks = MARKS.lock();
+    let entries = &marks.entries[..marks.count];
+
+    let Some(&(_, first)) = entries.first() else {
+        return;
+    };
+
+    // Before calibration there's no way to turn ticks into time, show raw cycles instead
+    let per_us = delay::tsc_per_us();
+    let unit = if per_us.is_some() { "us" } else { "kcycles" };
+    let scale = |ticks: u64| match per_us {
+        Some(per_us) => ticks / per_us,
+        None => ticks / 1000,
+    };
+
+    log::info!("Boot timing ({}):", unit);
+    for (i, &(name, start)) in entries.iter().enumerate() {
+        let end = entries.get(i + 1).map_or(now, |&(_, next)| next);
+        log::info!("  {:<16} {:>10}", name, scale(end - start));
+    }
+    log::info!("  {:<16} {:>10}", "total", scale(now - first));
+}
@@ -9,6 +9,54 @@ static mut MEMORY_MAP_BUFFER: [MemoryMapEntry; 128] = [MemoryMapEntry {
 }; 128];
 static mut MEMORY_MAP_COUNT: usize = 0;
 
+unsafe extern "C" {
+    /// Start and end of the kernel image, from the linker script
+    static _kernel_start: u8;
+    static _kernel_end: u8;
+}
+
+/// Anything claiming to be bigger than this is assumed to be a corrupt multiboot info blob
+const MAX_MULTIBOOT_INFO_SIZE: usize = 1024 * 1024;
+
+/// Every tag starts with a u32 type and a u32 size
+const TAG_HEADER_SIZE: usize = 8;
+
+/// Framebuffer tag up to and including the RGB field positions/sizes we read
+const FRAMEBUFFER_TAG_MIN_SIZE: usize = 38;
+
+/// What the screen is assumed to be until a framebuffer tag says otherwise: VGA text mode
+const TEXT_MODE_FRAMEBUFFER: FramebufferInfo = FramebufferInfo {
+    address: 0xb8000,
+    width: 80,
+    height: 25,
+    pitch: 160,
+    bpp: 16,
+    red_shift: 16,
+    green_shift: 8,
+    blue_shift: 16,
+    red_mask: 0,
+    green_mask: 0,
+    blue_mask: 0,
+};
+
+/// VBE info tag: header, mode and interface fields, controller info and mode info blocks
+const VBE_TAG_SIZE: usize = 16 + 512 + 256;
+
+/// Memory map tag header (type, size, entry_size, entry_version)
+const MMAP_TAG_HEADER_SIZE: usize = 16;
+
+/// Memory map entry (base, length, type, reserved)
+const MMAP_ENTRY_MIN_SIZE: usize = 24;
+
+/// EFI memory map tag header (type, size, descriptor_size, descriptor_version)
+const EFI_MMAP_TAG_HEADER_SIZE: usize = 16;
+
+/// EFI_MEMORY_DESCRIPTOR (type, pad, physical_start, virtual_start, number_of_pages, attribute)
+const EFI_MEMORY_DESCRIPTOR_MIN_SIZE: usize = 40;
+
+/// EFI pages are always 4 KiB, regardless of the kernel's page size
+const EFI_PAGE_SIZE: u64 = 4096;
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct BootInfo {
@@ -23,6 +71,12 @@ pub struct BootInfo {
     pub initrd_end: u64,
     pub cmdline: *const u8,
     pub cmdline_len: usize,
+    /// Physical address of the ACPI RSDP copy handed over by the bootloader (0 if none)
+    pub rsdp: u64,
+    /// Physical address of the EFI system table when booted via UEFI (0 if none)
+    pub efi_system_table: u64,
+    /// Physical address of the VBE controller info block copied by the bootloader (0 if none)
+    pub vbe_info: u64,
 }
 
 #[repr(C)]
@@ -41,6 +95,58 @@ pub struct FramebufferInfo {
     pub blue_mask: u8,
 }
 
+// Lock the layout described to C in kernel/include/bootinfo.h. Anything that trips these needs the
+// header updated too.
+#[cfg(target_pointer_width = "64")]
+const _: () = {
+    use core::mem::{offset_of, size_of};
+
+    assert!(size_of::<BootInfo>() == 136);
+    assert!(offset_of!(BootInfo, magic) == 0);
+    assert!(offset_of!(BootInfo, memory_map) == 8);
+    assert!(offset_of!(BootInfo, memory_map_entries) == 16);
+    assert!(offset_of!(BootInfo, framebuffer) == 24);
+    assert!(offset_of!(BootInfo, arch) == 56);
+    assert!(offset_of!(BootInfo, kernel_start) == 64);
+    assert!(offset_of!(BootInfo, kernel_end) == 72);
+    assert!(offset_of!(BootInfo, initrd_start) == 80);
+    assert!(offset_of!(BootInfo, initrd_end) == 88);
+    assert!(offset_of!(BootInfo, cmdline) == 96);
+    assert!(offset_of!(BootInfo, cmdline_len) == 104);
+    assert!(offset_of!(BootInfo, rsdp) == 112);
+    assert!(offset_of!(BootInfo, efi_system_table) == 120);
+    assert!(offset_of!(BootInfo, vbe_info) == 128);
+
+    assert!(size_of::<FramebufferInfo>() == 32);
+    assert!(offset_of!(FramebufferInfo, address) == 0);
+    assert!(offset_of!(FramebufferInfo, width) == 8);
+    assert!(offset_of!(FramebufferInfo, height) == 12);
+    assert!(offset_of!(FramebufferInfo, pitch) == 16);
+    assert!(offset_of!(FramebufferInfo, bpp) == 20);
+    assert!(offset_of!(FramebufferInfo, red_shift) == 21);
+    assert!(offset_of!(FramebufferInfo, green_shift) == 22);
+    assert!(offset_of!(FramebufferInfo, blue_shift) == 23);
+    assert!(offset_of!(FramebufferInfo, red_mask) == 24);
+    assert!(offset_of!(FramebufferInfo, green_mask) == 25);
+    assert!(offset_of!(FramebufferInfo, blue_mask) == 26);
+
+    assert!(size_of::<MemoryMapEntry>() == 24);
+    assert!(offset_of!(MemoryMapEntry, base) == 0);
+    assert!(offset_of!(MemoryMapEntry, length) == 8);
+    assert!(offset_of!(MemoryMapEntry, mem_type) == 16);
+
+    assert!(size_of::<Architecture>() == 4);
+    assert!(Architecture::X86 as u32 == 0);
+    assert!(Architecture::X86_64 as u32 == 1);
+    assert!(Architecture::Arm32 as u32 == 2);
+    assert!(Architecture::Arm64 as u32 == 3);
+    assert!(Architecture::Unknown as u32 == 255);
+
+    assert!(size_of::<MemoryType>() == 4);
+    assert!(MemoryType::Available as u32 == 1);
+    assert!(MemoryType::PageTable as u32 == 9);
+};
+
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Architecture {
@@ -80,31 +186,168 @@ impl Architecture {
             Architecture::Unknown
         }
     }
+
+    /// Whether pointers on this architecture are 64 bits wide
+    pub fn is_64bit(self) -> bool {
+        self.pointer_width() == 64
+    }
+
+    /// Width of a pointer in bits. For `Unknown`, that's whatever we were compiled for.
+    pub fn pointer_width(self) -> u32 {
+        match self {
+            Architecture::X86_64 | Architecture::Arm64 => 64,
+            Architecture::X86 | Architecture::Arm32 => 32,
+            Architecture::Unknown => usize::BITS,
+        }
+    }
+
+    /// Highest address a pointer can hold
+    pub fn max_address(self) -> u64 {
+        u64::MAX >> (64 - self.pointer_width())
+    }
+}
+
+/// Drop or trim the first `count` memory map entries that reach past what a pointer on `arch` can
+/// address, since memory there can't be used without a wider physical address mapping scheme.
+/// Returns the new count.
+pub(crate) fn clamp_memory_map(count: usize, arch: Architecture) -> usize {
+    if arch.is_64bit() {
+        return count;
+    }
+
+    let max = arch.max_address();
+    let mut kept = 0;
+
+    let original = unsafe { MEMORY_MAP_BUFFER };
+    for mut entry in original.into_iter().take(count) {
+        if entry.base > max {
+            continue;
+        }
+        entry.length = entry.length.min(max - entry.base + 1);
+
+        unsafe { MEMORY_MAP_BUFFER[kept] = entry };
+        kept += 1;
+    }
+
+    if kept < count {
+        log::warn!(
+            "Dropped {} memory map entries beyond the {}-bit address space",
+            count - kept,
+            arch.pointer_width()
+        );
+    }
+
+    kept
+}
+
+/// Sort the first `count` memory map entries by base, merge touching entries of the same type and
+/// add `Reserved` entries for the holes between them, so the map covers every address up to its
+/// highest one. Holes are left out once the buffer can't also fit the remaining entries. Returns
+/// the new count.
+pub(crate) fn fill_memory_map_gaps(count: usize) -> usize {
+    let mut sorted = unsafe { MEMORY_MAP_BUFFER };
+    let entries = &mut sorted[..count];
+
+    // Insertion sort, there's no heap yet and the map is nearly always in order already
+    for i in 1..entries.len() {
+        let mut j = i;
+        while j > 0 && entries[j - 1].base > entries[j].base {
+            entries.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    let capacity = sorted.len();
+    let mut kept = 0;
+    let mut filled = 0;
+    let mut end = 0;
+
+    for (i, entry) in sorted.into_iter().take(count).enumerate() {
+        if entry.length == 0 {
+            continue;
+        }
+
+        let mut pending = [None, Some(entry)];
+        if entry.base > end && kept + (count - i) < capacity {
+            pending[0] = Some(MemoryMapEntry {
+                base: end,
+                length: entry.base - end,
+                mem_type: MemoryType::Reserved,
+            });
+            filled += 1;
+        }
+
+        for entry in pending.into_iter().flatten() {
+            unsafe {
+                if kept > 0 {
+                    let last = MEMORY_MAP_BUFFER[kept - 1];
+                    if last.mem_type == entry.mem_type && last.base + last.length == entry.base {
+                        MEMORY_MAP_BUFFER[kept - 1].length += entry.length;
+                        continue;
+                    }
+                }
+
+                MEMORY_MAP_BUFFER[kept] = entry;
+            }
+            kept += 1;
+        }
+
+        end = end.max(entry.base.saturating_add(entry.length));
+    }
+
+    log::trace!(
+        "Memory map: {} entries after filling {} gaps (was {})",
+        kept,
+        filled,
+        count
+    );
+
+    kept
+}
+
+/// The memory map parsed at boot
+pub(crate) fn parsed_memory_map() -> &'static [MemoryMapEntry] {
+    unsafe {
+        core::slice::from_raw_parts(
+            (&raw const MEMORY_MAP_BUFFER).cast::<MemoryMapEntry>(),
+            MEMORY_MAP_COUNT,
+        )
+    }
 }
 
 impl BootInfo {
     pub fn from_bootloader(multiboot_info: u64) -> Self {
-        let mut framebuffer_addr: u64 = 0xb8000;
-        let mut framebuffer_width: u32 = 80;
-        let mut framebuffer_height: u32 = 25;
-        let mut framebuffer_pitch: u32 = 160;
-        let mut framebuffer_bpp: u8 = 16;
+        let mut framebuffer = TEXT_MODE_FRAMEBUFFER;
 
-        let mut framebuffer_red_shift: u8 = 16;
-        let mut framebuffer_green_shift: u8 = 8;
-        let mut framebuffer_blue_shift: u8 = 16;
+        let mut cmdline: *const u8 = core::ptr::null();
+        let mut cmdline_len: usize = 0;
 
-        let mut framebuffer_red_mask: u8 = 0;
-        let mut framebuffer_green_mask: u8 = 0;
-        let mut framebuffer_blue_mask: u8 = 0;
+        let mut rsdp: u64 = 0;
+        let mut efi_system_table: u64 = 0;
+        let mut vbe_info: u64 = 0;
+
+        let mut initrd_start: u64 = 0;
+        let mut initrd_end: u64 = 0;
+
+        // The legacy memory map is preferred, the EFI one is only used when it's missing
+        let mut legacy_map_found = false;
+        let mut efi_map_tag: Option<(u64, usize)> = None;
 
         if multiboot_info != 0 {
             unsafe {
-                let total_size = *(multiboot_info as *const u32) as usize;
+                let mut total_size = *(multiboot_info as *const u32) as usize;
+                if !(8..=MAX_MULTIBOOT_INFO_SIZE).contains(&total_size) {
+                    log::error!(
+                        "Implausible multiboot info size {:#x}, ignoring boot information",
+                        total_size
+                    );
+                    total_size = 0;
+                }
+
                 let mut addr = multiboot_info + 8; // skip total_size & reserved
                 let end = multiboot_info + total_size as u64;
 
-                while addr < end {
+                while addr + TAG_HEADER_SIZE as u64 <= end {
                     let tag_type = *(addr as *const u32);
                     let tag_size = *((addr + 4) as *const u32) as usize;
 
@@ -112,40 +355,47 @@ impl BootInfo {
                         break; // End tag
                     }
 
-                    // Framebuffer
-                    if tag_type == 8 {
-                        framebuffer_addr = *((addr + 8) as *const u64);
-                        framebuffer_pitch = *((addr + 16) as *const u32);
-                        framebuffer_width = *((addr + 20) as *const u32);
-                        framebuffer_height = *((addr + 24) as *const u32);
-                        framebuffer_bpp = *((addr + 28) as *const u8);
-
-                        let fb_type = *((addr + 29) as *const u8);
-
-                        // framebuffer types:
-                        // - 0: indexed color (palette)
-                        // - 1: RGB (this is what we want since we can write directly to it)
-                        // - 2: EGA text
-
-                        if fb_type != 1 {
-                            panic!("Unsupported framebuffer type");
-                        }
+                    // A tag must at least hold its own header and may not run past the end,
+                    // otherwise we can't trust anything after it either
+                    if tag_size < TAG_HEADER_SIZE || addr + tag_size as u64 > end {
+                        log::error!(
+                            "Malformed multiboot tag (type {}, size {}) at {:#x}, stopping parse",
+                            tag_type,
+                            tag_size,
+                            addr
+                        );
+                        break;
+                    }
 
-                        framebuffer_red_shift = *((addr + 32) as *const u8);
-                        framebuffer_red_mask = *((addr + 33) as *const u8);
+                    // Boot command line (NUL-terminated string)
+                    if tag_type == 1 {
+                        let start = (addr + 8) as *const u8;
+                        let max_len = tag_size.saturating_sub(8);
+                        let mut len = 0;
+                        while len < max_len && *start.add(len) != 0 {
+                            len += 1;
+                        }
 
-                        framebuffer_green_shift = *((addr + 34) as *const u8);
-                        framebuffer_green_mask = *((addr + 35) as *const u8);
+                        cmdline = start;
+                        cmdline_len = len;
+                    }
 
-                        framebuffer_blue_shift = *((addr + 36) as *const u8);
-                        framebuffer_blue_mask = *((addr + 37) as *const u8);
+                    // Framebuffer
+                    if tag_type == 8
+                        && let Some(info) = parse_framebuffer_tag(addr, tag_size)
+                    {
+                        framebuffer = info;
                     }
 
                     // Memory map
-                    if tag_type == 6 {
-                        let entry_size = *((addr + 8) as *const u32) as usize;
+                    if tag_type == 6 && tag_size < MMAP_TAG_HEADER_SIZE {
+                        log::warn!("Truncated memory map tag ({} bytes), ignoring it", tag_size);
+                    } else if tag_type == 6 {
+                        // Clamp so a bogus entry size can't make us read garbage or loop forever
+                        let entry_size =
+                            (*((addr + 8) as *const u32) as usize).max(MMAP_ENTRY_MIN_SIZE);
                         // entry_version is at addr+12, currently unused
-                        let entries_start = addr + 16;
+                        let entries_start = addr + MMAP_TAG_HEADER_SIZE as u64;
                         let entries_end = addr + tag_size as u64;
                         let mut entry_addr = entries_start;
                         let mut count: usize = 0;
@@ -175,10 +425,52 @@ impl BootInfo {
                         }
 
                         MEMORY_MAP_COUNT = count;
+                        legacy_map_found = true;
+                    }
+
+                    // Boot module (mod_start, mod_end, string), the first one is the initrd
+                    if tag_type == 3 && tag_size >= 16 && initrd_end == 0 {
+                        initrd_start = *((addr + 8) as *const u32) as u64;
+                        initrd_end = *((addr + 12) as *const u32) as u64;
+                    }
+
+                    // VBE info: mode, protected mode interface (8 bytes), then the 512 byte
+                    // controller info block and the 256 byte mode info block
+                    if tag_type == 7 && tag_size >= VBE_TAG_SIZE {
+                        vbe_info = addr + 16;
+                    }
+
+                    // EFI system table pointer (32-bit and 64-bit variants)
+                    if tag_type == 11 && tag_size >= 12 {
+                        efi_system_table = *((addr + 8) as *const u32) as u64;
+                    }
+                    if tag_type == 12 && tag_size >= 16 {
+                        efi_system_table = *((addr + 8) as *const u64);
+                    }
+
+                    // ACPI RSDP copies (14 = ACPI 1.0, 15 = ACPI 2.0+), the newer one wins
+                    if tag_type == 14 && rsdp == 0 {
+                        rsdp = addr + TAG_HEADER_SIZE as u64;
+                    }
+                    if tag_type == 15 {
+                        rsdp = addr + TAG_HEADER_SIZE as u64;
+                    }
+
+                    // EFI memory map, parsed after the walk if there was no legacy map
+                    if tag_type == 17 {
+                        efi_map_tag = Some((addr, tag_size));
                     }
 
                     addr += ((tag_size + 7) & !7) as u64; // align to 8 bytes
                 }
+
+                if !legacy_map_found && let Some((tag_addr, tag_size)) = efi_map_tag {
+                    log::debug!("No legacy memory map, synthesizing it from the EFI memory map");
+                    MEMORY_MAP_COUNT = parse_efi_memory_map(tag_addr, tag_size);
+                }
+
+                let count = clamp_memory_map(MEMORY_MAP_COUNT, Architecture::current());
+                MEMORY_MAP_COUNT = fill_memory_map_gaps(count);
             }
         }
 
@@ -186,26 +478,193 @@ impl BootInfo {
             magic: multiboot_info,
             memory_map: unsafe { MEMORY_MAP_BUFFER.as_ptr() },
             memory_map_entries: unsafe { MEMORY_MAP_COUNT },
-            framebuffer: FramebufferInfo {
-                address: framebuffer_addr,
-                width: framebuffer_width,
-                height: framebuffer_height,
-                pitch: framebuffer_pitch,
-                bpp: framebuffer_bpp,
-                red_shift: framebuffer_red_shift,
-                green_shift: framebuffer_green_shift,
-                blue_shift: framebuffer_blue_shift,
-                red_mask: framebuffer_red_mask,
-                green_mask: framebuffer_green_mask,
-                blue_mask: framebuffer_blue_mask,
-            },
+            framebuffer,
             arch: Architecture::current(),
-            kernel_start: 0,
-            kernel_end: 0,
-            initrd_start: 0,
-            initrd_end: 0,
-            cmdline: core::ptr::null(),
-            cmdline_len: 0,
+            kernel_start: &raw const _kernel_start as u64,
+            kernel_end: &raw const _kernel_end as u64,
+            initrd_start,
+            initrd_end,
+            cmdline,
+            cmdline_len,
+            rsdp,
+            efi_system_table,
+            vbe_info,
+        }
+    }
+
+    /// Memory map entries, as parsed from the bootloader
+    pub fn memory_map(&self) -> &[MemoryMapEntry] {
+        if self.memory_map.is_null() {
+            return &[];
+        }
+
+        unsafe { core::slice::from_raw_parts(self.memory_map, self.memory_map_entries) }
+    }
+
+    /// Physical range of the multiboot information structure, which the bootloader leaves in
+    /// available memory and the command line still points into
+    pub fn multiboot_info_range(&self) -> Option<(u64, u64)> {
+        if self.magic == 0 {
+            return None;
+        }
+
+        let size = unsafe { *(self.magic as *const u32) } as u64;
+        Some((self.magic, self.magic + size))
+    }
+
+    /// Kernel command line passed by the bootloader (empty if there was none)
+    pub fn cmdline(&self) -> &str {
+        if self.cmdline.is_null() {
+            return "";
         }
+
+        let bytes = unsafe { core::slice::from_raw_parts(self.cmdline, self.cmdline_len) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+
+    /// Check whether a bare `flag` (e.g. `nocolor`) is present on the command line
+    pub fn cmdline_flag(&self, flag: &str) -> bool {
+        self.cmdline().split_whitespace().any(|word| word == flag)
+    }
+
+    /// Get the value of a `key=value` option on the command line
+    pub fn cmdline_option(&self, key: &str) -> Option<&str> {
+        self.cmdline()
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix(key)?.strip_prefix('='))
+    }
+}
+
+/// Read the framebuffer tag at `addr`. `None` if it's too short to hold the colour layout, the
+/// text mode default is kept then.
+unsafe fn parse_framebuffer_tag(addr: u64, tag_size: usize) -> Option<FramebufferInfo> {
+    if tag_size < FRAMEBUFFER_TAG_MIN_SIZE {
+        log::warn!(
+            "Truncated framebuffer tag ({} bytes), keeping the default text mode",
+            tag_size
+        );
+        return None;
+    }
+
+    unsafe {
+        // framebuffer types:
+        // - 0: indexed color (palette)
+        // - 1: RGB (this is what we want since we can write directly to it)
+        // - 2: EGA text
+        let fb_type = *((addr + 29) as *const u8);
+        crate::kassert!(fb_type == 1, "Unsupported framebuffer type {}", fb_type);
+
+        Some(FramebufferInfo {
+            address: *((addr + 8) as *const u64),
+            pitch: *((addr + 16) as *const u32),
+            width: *((addr + 20) as *const u32),
+            height: *((addr + 24) as *const u32),
+            bpp: *((addr + 28) as *const u8),
+            red_shift: *((addr + 32) as *const u8),
+            red_mask: *((addr + 33) as *const u8),
+            green_shift: *((addr + 34) as *const u8),
+            green_mask: *((addr + 35) as *const u8),
+            blue_shift: *((addr + 36) as *const u8),
+            blue_mask: *((addr + 37) as *const u8),
+        })
+    }
+}
+
+/// Convert the EFI memory map tag at `addr` into `MEMORY_MAP_BUFFER`, returning the entry count.
+///
+/// Boot services memory is free to reuse since GRUB has exited boot services by the time we run,
+/// but loader code/data is kept, as that's where GRUB put us and the boot information.
+unsafe fn parse_efi_memory_map(addr: u64, tag_size: usize) -> usize {
+    if tag_size < EFI_MMAP_TAG_HEADER_SIZE {
+        log::warn!("Truncated EFI memory map tag ({} bytes), ignoring it", tag_size);
+        return 0;
+    }
+
+    unsafe {
+        let descriptor_size =
+            (*((addr + 8) as *const u32) as usize).max(EFI_MEMORY_DESCRIPTOR_MIN_SIZE);
+        let mut desc_addr = addr + EFI_MMAP_TAG_HEADER_SIZE as u64;
+        let end = addr + tag_size as u64;
+        let mut count = 0;
+
+        while desc_addr + descriptor_size as u64 <= end && count < MEMORY_MAP_BUFFER.len() {
+            let efi_type = *(desc_addr as *const u32);
+            let base = *((desc_addr + 8) as *const u64);
+            let pages = *((desc_addr + 24) as *const u64);
+
+            let mem_type = match efi_type {
+                3 | 4 | 7 => MemoryType::Available, // boot services code/data, conventional
+                1 | 2 => MemoryType::Bootloader,    // loader code/data
+                8 => MemoryType::BadMemory,         // unusable
+                9 => MemoryType::AcpiReclaimable,
+                10 => MemoryType::AcpiNvs,
+                _ => MemoryType::Reserved, // runtime services, MMIO, PAL code, ...
+            };
+
+            MEMORY_MAP_BUFFER[count] = MemoryMapEntry {
+                base,
+                length: pages.saturating_mul(EFI_PAGE_SIZE),
+                mem_type,
+            };
+            count += 1;
+            desc_addr += descriptor_size as u64;
+        }
+
+        count
+    }
+}
+
+/// Spare page of the higher half the self-test builds its tags in. The page after it is never
+/// mapped, so reading past the end of a tag placed at the end of the page faults.
+const SELFTEST_PAGE: u64 = 0xFFFF_FD00_0001_0000;
+
+/// A framebuffer tag cut short is rejected without reading past its end, and one that's just
+/// long enough is read in full
+pub fn selftest_truncated_framebuffer() -> Result<(), &'static str> {
+    use crate::arch::x86_64::paging::{self, Mapping};
+    use crate::mem::{PAGE_SIZE, phys::Frame};
+
+    let frame = Frame::alloc_zeroed().ok_or("Out of frames")?;
+    let flags = paging::flags::WRITABLE | paging::flags::NO_EXECUTE;
+    let page = Mapping::new(SELFTEST_PAGE, frame, flags)?;
+    let page_end = page.virt() + PAGE_SIZE as u64;
+
+    // Type, size, address, pitch, width, height, bpp, type, reserved, then the colour layout
+    let mut tag = [0u8; FRAMEBUFFER_TAG_MIN_SIZE];
+    tag[..4].copy_from_slice(&8u32.to_le_bytes());
+    tag[8..16].copy_from_slice(&0xFD00_0000u64.to_le_bytes());
+    tag[16..20].copy_from_slice(&4096u32.to_le_bytes());
+    tag[20..24].copy_from_slice(&1024u32.to_le_bytes());
+    tag[24..28].copy_from_slice(&768u32.to_le_bytes());
+    tag[28] = 32;
+    tag[29] = 1;
+    tag[32..38].copy_from_slice(&[16, 8, 8, 8, 0, 8]);
+
+    // Ends on the last byte of the page, so its fields can be read but nothing after them
+    let write_tag = |len: usize| {
+        let addr = page_end - len as u64;
+        let mut tag = tag;
+        tag[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        unsafe { core::ptr::copy_nonoverlapping(tag.as_ptr(), addr as *mut u8, len) };
+        addr
+    };
+
+    // Cut off in the middle of the colour layout
+    let truncated = FRAMEBUFFER_TAG_MIN_SIZE - 6;
+    if unsafe { parse_framebuffer_tag(write_tag(truncated), truncated) }.is_some() {
+        return Err("Used a truncated framebuffer tag");
+    }
+
+    let full = FRAMEBUFFER_TAG_MIN_SIZE;
+    let info = unsafe { parse_framebuffer_tag(write_tag(full), full) }
+        .ok_or("Rejected a complete framebuffer tag")?;
+    let expected = (0xFD00_0000, 4096, 1024, 768, 32);
+    if (info.address, info.pitch, info.width, info.height, info.bpp) != expected
+        || (info.red_shift, info.green_shift, info.blue_shift) != (16, 8, 0)
+        || (info.red_mask, info.green_mask, info.blue_mask) != (8, 8, 8)
+    {
+        return Err("Misread a framebuffer tag");
     }
+
+    Ok(())
 }
@@ -0,0 +1,44 @@
+//! `kassert!` and `bug!`: fail with the register state and a backtrace, then panic.
+//!
+//! Meant for invariants the kernel can't carry on without. Unlike a bare `panic!`, the report
+//! says what the CPU looked like at the failing line.
+
+use crate::arch::x86_64::{backtrace, registers::Registers};
+use core::fmt;
+
+/// Report a failed check at `file:line` and panic
+#[cold]
+#[inline(never)]
+pub fn fail(regs: &Registers, file: &str, line: u32, message: fmt::Arguments) -> ! {
+    log::error!("BUG at {}:{}: {}", file, line, message);
+    regs.log();
+    backtrace::log_from(regs.rbp);
+
+    panic!("BUG at {}:{}: {}", file, line, message);
+}
+
+/// Panic with the register state if `cond` is false, e.g.
+/// `kassert!(fb_type == 1, "Unsupported framebuffer type {}", fb_type)`
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, "assertion failed: {}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::bug!($($arg)+);
+        }
+    };
+}
+
+/// Panic with the register state, for code that should be unreachable
+#[macro_export]
+macro_rules! bug {
+    () => {
+        $crate::bug!("unreachable code reached")
+    };
+    ($($arg:tt)+) => {{
+        let regs = $crate::arch::x86_64::registers::Registers::capture();
+        $crate::bug::fail(&regs, file!(), line!(), format_args!($($arg)+))
+    }};
+}
@@ -0,0 +1,134 @@
+//! In-memory copy of the kernel log, so it can be read back after it has scrolled off the serial
+//! line (`sys_dmesg`).
+//!
+//! Lines are stored as plain text without colour codes. When the buffer is full the oldest text
+//! is overwritten. Until the heap is up this is a small static buffer, `logbuf=<KiB>` on the
+//! command line moves it to a heap buffer of that size once it is.
+
+use crate::BootInfo;
+use crate::sync::IrqSpinlock;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Size of the buffer used during early boot and without `logbuf=`
+const DEFAULT_SIZE: usize = 16 * 1024;
+
+/// Smallest and largest `logbuf=` accepted, in KiB
+const MIN_KIB: usize = 4;
+const MAX_KIB: usize = 16 * 1024;
+
+struct LogBuffer {
+    early: [u8; DEFAULT_SIZE],
+    heap: Option<Vec<u8>>,
+    /// Bytes written since boot, the next one goes at `written % capacity`
+    written: usize,
+}
+
+impl LogBuffer {
+    fn storage(&mut self) -> &mut [u8] {
+        match &mut self.heap {
+            Some(heap) => heap,
+            None => &mut self.early,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.heap.as_ref().map_or(DEFAULT_SIZE, Vec::len)
+    }
+
+    /// Copy the last `out.len()` bytes (at most what's stored) into `out`, returning how many
+    fn copy_recent(&mut self, out: &mut [u8]) -> usize {
+        let capacity = self.capacity();
+        let written = self.written;
+        let count = out.len().min(written).min(capacity);
+        let storage = self.storage();
+
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = storage[(written - count + i) % capacity];
+        }
+
+        count
+    }
+
+    /// Switch to `heap`, keeping as much of the most recent text as fits
+    fn move_to(&mut self, mut heap: Vec<u8>) {
+        let mut recent = vec![0u8; heap.len()];
+        let count = self.copy_recent(&mut recent);
+
+        // Keep every byte where `written` expects it
+        let start = self.written - count;
+        for (i, &byte) in recent[..count].iter().enumerate() {
+            let position = (start + i) % heap.len();
+            heap[position] = byte;
+        }
+        self.heap = Some(heap);
+    }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let capacity = self.capacity();
+        let written = self.written;
+        let storage = self.storage();
+
+        for (i, &byte) in s.as_bytes().iter().enumerate() {
+            storage[(written + i) % capacity] = byte;
+        }
+        self.written += s.len();
+
+        Ok(())
+    }
+}
+
+static BUFFER: IrqSpinlock<LogBuffer> = IrqSpinlock::new(LogBuffer {
+    early: [0; DEFAULT_SIZE],
+    heap: None,
+    written: 0,
+});
+
+/// Run `f` on the buffer to append a line. The line is dropped if the buffer is busy, since the
+/// holder may be the code this context interrupted.
+pub(crate) fn with_writer(f: impl FnOnce(&mut dyn fmt::Write)) {
+    if let Some(mut buffer) = BUFFER.try_lock() {
+        f(&mut *buffer);
+    }
+}
+
+/// Move the log to a heap buffer of `logbuf=<KiB>` if that is on the command line. Needs the heap.
+pub fn init(boot_info: &BootInfo) {
+    let Some(value) = boot_info.cmdline_option("logbuf") else {
+        return;
+    };
+
+    match value.parse::<usize>() {
+        Ok(kib) if (MIN_KIB..=MAX_KIB).contains(&kib) => {
+            let heap = vec![0u8; kib * 1024];
+            BUFFER.lock().move_to(heap);
+            log::debug!("Kernel log buffer is {} KiB", kib);
+        }
+        _ => log::warn!(
+            "Ignoring invalid logbuf value, keeping {} KiB",
+            DEFAULT_SIZE / 1024
+        ),
+    }
+}
+
+/// The most recent `max` bytes of the log, at most. If older text had to be left out, this starts
+/// at the first full line.
+pub fn recent(max: usize) -> Vec<u8> {
+    let (mut text, truncated) = {
+        let mut buffer = BUFFER.lock();
+        let mut text = vec![0u8; max.min(buffer.capacity())];
+        let count = buffer.copy_recent(&mut text);
+        text.truncate(count);
+        (text, count < buffer.written)
+    };
+
+    if truncated && let Some(newline) = text.iter().position(|&byte| byte == b'\n') {
+        text.drain(..=newline);
+    }
+
+    text
+}
@@ -0,0 +1,443 @@
+//! Minimal ACPI table discovery and fixed-hardware power management.
+//!
+//! Finds the RSDT/XSDT from the RSDP the bootloader handed over (or by scanning the BIOS area
+//! for it) and looks tables up by signature. Tables are read through the identity map, so only
+//! tables below 4 GiB are reachable.
+//!
+//! There's no AML interpreter. The power button is the fixed one in the PM1 event registers, and
+//! shutdown takes the S5 sleep type from a byte scan of the DSDT for the `\_S5` package.
+
+use crate::BootInfo;
+use crate::arch::x86_64::{idt, inw, outb, outw};
+use crate::mem;
+use spin::Once;
+
+/// Highest physical address covered by the identity map
+const IDENTITY_MAPPED_END: u64 = 0x1_0000_0000;
+
+/// Where to scan for the RSDP on legacy BIOS machines
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+
+    // ACPI 2.0+ only
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP, which is all the checksum covers
+const RSDP_V1_SIZE: usize = 20;
+
+/// Header shared by every System Description Table
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// FADT field offsets
+mod fadt {
+    pub const DSDT: usize = 40;
+    pub const SCI_INT: usize = 46;
+    pub const SMI_CMD: usize = 48;
+    pub const ACPI_ENABLE: usize = 52;
+    pub const PM1A_EVT_BLK: usize = 56;
+    pub const PM1B_EVT_BLK: usize = 60;
+    pub const PM1A_CNT_BLK: usize = 64;
+    pub const PM1B_CNT_BLK: usize = 68;
+    pub const PM1_EVT_LEN: usize = 88;
+    /// 64-bit DSDT address, only in ACPI 2.0+ FADTs long enough to hold it
+    pub const X_DSDT: usize = 140;
+}
+
+/// MADT layout: interrupt controller structures follow the local APIC address and flags
+mod madt {
+    pub const ENTRIES: usize = 44;
+    pub const LOCAL_APIC: u8 = 0;
+    pub const LOCAL_X2APIC: u8 = 9;
+    /// Offset of the flags in each entry type, bit 0 is "enabled"
+    pub const LOCAL_APIC_FLAGS: usize = 4;
+    pub const LOCAL_X2APIC_FLAGS: usize = 8;
+    pub const ENABLED: u32 = 1 << 0;
+}
+
+/// PM1 status and enable bit of the fixed power button
+const PWRBTN: u16 = 1 << 8;
+/// PM1 control bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// How long firmware gets to hand the hardware over after ACPI_ENABLE is written
+const ENABLE_TIMEOUT_MS: u64 = 300;
+
+/// Ports emulators power off through when the ACPI way doesn't work: QEMU, Bochs (and older
+/// QEMU), VirtualBox
+const EMULATOR_SHUTDOWN: [(u16, u16); 3] = [(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+
+/// Fixed-hardware registers from the FADT
+struct PowerManagement {
+    /// Global system interrupt of the SCI, an ISA IRQ on PIC systems
+    sci_irq: u16,
+    pm1a_evt: u16,
+    pm1b_evt: u16,
+    pm1_evt_len: u16,
+    pm1a_cnt: u16,
+    pm1b_cnt: u16,
+    /// SLP_TYPa and SLP_TYPb of the S5 (soft off) state
+    s5: Option<(u16, u16)>,
+}
+
+impl PowerManagement {
+    /// Ports of the PM1 event blocks that exist. Each starts with its status register, the enable
+    /// register follows halfway through the block.
+    fn event_blocks(&self) -> impl Iterator<Item = u16> {
+        [self.pm1a_evt, self.pm1b_evt]
+            .into_iter()
+            .filter(|&port| port != 0)
+    }
+}
+
+static POWER: Once<PowerManagement> = Once::new();
+
+/// The root table and whether it's an XSDT (64-bit entries) or an RSDT (32-bit entries)
+struct RootTable {
+    address: u64,
+    extended: bool,
+}
+
+static ROOT: Once<RootTable> = Once::new();
+
+/// Whether `bytes` sum to zero, as every ACPI structure must
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Whether the ACPI 1.0 part of an RSDP at `addr` is readable and has a good checksum
+fn rsdp_ok(addr: u64) -> bool {
+    mem::phys_slice(addr, RSDP_V1_SIZE).is_some_and(checksum_ok)
+}
+
+fn find_rsdp_in_bios_area() -> Option<u64> {
+    (BIOS_AREA_START..BIOS_AREA_END).step_by(16).find(|&addr| {
+        mem::phys_slice(addr, RSDP_V1_SIZE)
+            .is_some_and(|rsdp| rsdp.starts_with(b"RSD PTR ") && checksum_ok(rsdp))
+    })
+}
+
+pub fn init(boot_info: &BootInfo) {
+    let rsdp_addr = match boot_info.rsdp {
+        0 => match find_rsdp_in_bios_area() {
+            Some(addr) => addr,
+            None => {
+                log::warn!("No ACPI RSDP found, ACPI tables unavailable");
+                return;
+            }
+        },
+        addr => addr,
+    };
+
+    if !rsdp_ok(rsdp_addr) {
+        log::warn!("ACPI RSDP at {:#x} has a bad checksum, ignoring it", rsdp_addr);
+        return;
+    }
+
+    let rsdp = mem::phys_read::<Rsdp>(rsdp_addr);
+
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable {
+            address: rsdp.xsdt_address,
+            extended: true,
+        }
+    } else {
+        RootTable {
+            address: rsdp.rsdt_address as u64,
+            extended: false,
+        }
+    };
+
+    if root.address >= IDENTITY_MAPPED_END {
+        log::warn!("ACPI root table at {:#x} is above 4 GiB, ignoring it", root.address);
+        return;
+    }
+
+    log::debug!(
+        "ACPI revision {}: {} at {:#x}",
+        rsdp.revision,
+        if root.extended { "XSDT" } else { "RSDT" },
+        root.address
+    );
+
+    ROOT.call_once(|| root);
+
+    init_power();
+}
+
+/// Length of the table at `addr` from its header, `None` if it's too short to be a table or runs
+/// past the identity map, where it can't be read (or checksummed) in place
+fn table_length(addr: u64) -> Option<usize> {
+    let length = mem::phys_read::<SdtHeader>(addr).length as u64;
+    let fits = length >= size_of::<SdtHeader>() as u64
+        && addr
+            .checked_add(length)
+            .is_some_and(|end| end <= IDENTITY_MAPPED_END);
+
+    fits.then_some(length as usize)
+}
+
+/// The whole table at `addr`, header included, as long as its header says it is
+fn table_bytes(addr: u64) -> Option<&'static [u8]> {
+    mem::phys_slice(addr, table_length(addr)?)
+}
+
+/// Read a `T` `offset` bytes into `bytes`, `None` if it doesn't fit
+fn read_at<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let field = bytes.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(unsafe { core::ptr::read_unaligned(field.as_ptr().cast::<T>()) })
+}
+
+/// Find the table with the given signature (e.g. `b"HPET"`) and return its physical address
+pub fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let root = ROOT.get()?;
+    let Some(root_table) = table_bytes(root.address) else {
+        log::warn!("ACPI root table at {:#x} has a bogus length", root.address);
+        return None;
+    };
+
+    let entry_size = if root.extended { 8 } else { 4 };
+    root_table[size_of::<SdtHeader>()..]
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            if root.extended {
+                read_at::<u64>(entry, 0)
+            } else {
+                read_at::<u32>(entry, 0).map(u64::from)
+            }
+        })
+        .filter(|&addr| addr != 0 && addr < IDENTITY_MAPPED_END)
+        .find(|&addr| {
+            table_bytes(addr)
+                .is_some_and(|table| table.starts_with(signature) && checksum_ok(table))
+        })
+}
+
+/// Number of enabled processors (local APIC and x2APIC entries) in the MADT, `None` without one
+pub fn madt_cpu_count() -> Option<u32> {
+    let table = table_bytes(find_table(b"APIC")?)?;
+
+    // Entries are packed with no alignment, each starts with its type and length
+    let mut count = 0;
+    let mut entry = madt::ENTRIES;
+    while let (Some(kind), Some(length)) = (table.get(entry), table.get(entry + 1)) {
+        let length = *length as usize;
+        let Some(bytes) = table.get(entry..entry + length).filter(|_| length >= 2) else {
+            break;
+        };
+
+        let flags = match *kind {
+            madt::LOCAL_APIC => read_at::<u32>(bytes, madt::LOCAL_APIC_FLAGS),
+            madt::LOCAL_X2APIC => read_at::<u32>(bytes, madt::LOCAL_X2APIC_FLAGS),
+            _ => None,
+        };
+        if flags.is_some_and(|flags| flags & madt::ENABLED != 0) {
+            count += 1;
+        }
+
+        entry += length;
+    }
+
+    Some(count)
+}
+
+/// Read a FADT field. Fields past the end of the table (older, shorter FADTs) read as 0, which
+/// means "not present" for every one we use.
+fn read_fadt<T: Copy + Default>(fadt: &[u8], offset: usize) -> T {
+    read_at(fadt, offset).unwrap_or_default()
+}
+
+/// Switch to ACPI mode, enable the power button event and route the SCI
+fn init_power() {
+    let Some(table) = find_table(b"FACP").and_then(table_bytes) else {
+        log::debug!("No ACPI FADT, power button unavailable");
+        return;
+    };
+
+    let dsdt = match read_fadt::<u64>(table, fadt::X_DSDT) {
+        0 => read_fadt::<u32>(table, fadt::DSDT) as u64,
+        x_dsdt => x_dsdt,
+    };
+
+    let power = PowerManagement {
+        sci_irq: read_fadt::<u16>(table, fadt::SCI_INT),
+        pm1a_evt: read_fadt::<u32>(table, fadt::PM1A_EVT_BLK) as u16,
+        pm1b_evt: read_fadt::<u32>(table, fadt::PM1B_EVT_BLK) as u16,
+        pm1_evt_len: read_fadt::<u8>(table, fadt::PM1_EVT_LEN) as u16,
+        pm1a_cnt: read_fadt::<u32>(table, fadt::PM1A_CNT_BLK) as u16,
+        pm1b_cnt: read_fadt::<u32>(table, fadt::PM1B_CNT_BLK) as u16,
+        s5: find_s5(dsdt),
+    };
+
+    if power.pm1a_evt == 0 || power.pm1a_cnt == 0 || power.pm1_evt_len < 4 {
+        log::warn!("ACPI FADT has no usable PM1 registers, power button unavailable");
+        return;
+    }
+    if power.s5.is_none() {
+        log::debug!("No \\_S5 package in the DSDT, shutdown will only work on emulators");
+    }
+
+    if inw(power.pm1a_cnt) & SCI_EN == 0 {
+        let smi_cmd = read_fadt::<u32>(table, fadt::SMI_CMD) as u16;
+        let acpi_enable = read_fadt::<u8>(table, fadt::ACPI_ENABLE);
+        if smi_cmd == 0 || acpi_enable == 0 {
+            log::warn!("ACPI mode is off and the FADT has no way to turn it on");
+            return;
+        }
+
+        outb(smi_cmd, acpi_enable);
+        let mut waited = 0;
+        while inw(power.pm1a_cnt) & SCI_EN == 0 {
+            if waited == ENABLE_TIMEOUT_MS {
+                log::warn!("Firmware didn't switch to ACPI mode");
+                return;
+            }
+            crate::arch::delay_ms(1);
+            waited += 1;
+        }
+    }
+
+    // The power button is the only event we handle, leave the rest disabled so they can't
+    // raise an SCI nobody clears. Status bits are cleared by writing 1s.
+    for evt in power.event_blocks() {
+        outw(evt, PWRBTN);
+        outw(evt + power.pm1_evt_len / 2, PWRBTN);
+    }
+
+    let sci_irq = power.sci_irq;
+    POWER.call_once(|| power);
+
+    if sci_irq < 16 {
+        idt::unmask_irq(sci_irq as u8);
+        log::debug!("ACPI power button enabled, SCI on IRQ {}", sci_irq);
+    } else {
+        log::warn!("ACPI SCI is on GSI {}, which the PIC can't deliver", sci_irq);
+    }
+}
+
+/// Find SLP_TYPa and SLP_TYPb of `\_S5` in the DSDT at `dsdt`, without interpreting any AML.
+/// Looks for `Name(_S5, Package() { a, b, ... })`, which is how every firmware we know of
+/// defines it.
+fn find_s5(dsdt: u64) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    if dsdt == 0 || dsdt >= IDENTITY_MAPPED_END {
+        return None;
+    }
+
+    let aml = &table_bytes(dsdt)?[size_of::<SdtHeader>()..];
+
+    let name = aml.windows(4).enumerate().position(|(i, window)| {
+        let named = (i >= 1 && aml[i - 1] == NAME_OP)
+            || (i >= 2 && aml[i - 2] == NAME_OP && aml[i - 1] == b'\\');
+        window == b"_S5_" && named
+    })?;
+
+    let mut i = name + 4;
+    if *aml.get(i)? != PACKAGE_OP {
+        return None;
+    }
+
+    // PkgLength: the top two bits of the lead byte count the bytes that follow it
+    let pkg_length_bytes = (*aml.get(i + 1)? >> 6) as usize + 1;
+    // Then NumElements
+    i += 1 + pkg_length_bytes + 1;
+
+    let mut element = || {
+        let value = match *aml.get(i)? {
+            BYTE_PREFIX => {
+                i += 1;
+                *aml.get(i)?
+            }
+            // ZeroOp and OneOp are their own value, anything else is a bare byte
+            value => value,
+        };
+        i += 1;
+        Some(value as u16 & 0b111)
+    };
+
+    Some((element()?, element()?))
+}
+
+/// The IRQ the SCI arrives on, once the power button is set up
+pub fn sci_irq() -> Option<u8> {
+    POWER
+        .get()
+        .map(|power| power.sci_irq)
+        .filter(|&irq| irq < 16)
+        .map(|irq| irq as u8)
+}
+
+/// Handle an SCI: shut down if it was the power button, ignore anything else
+pub fn handle_sci() {
+    let Some(power) = POWER.get() else {
+        return;
+    };
+
+    let mut pressed = false;
+    for evt in power.event_blocks() {
+        if inw(evt) & PWRBTN != 0 {
+            outw(evt, PWRBTN);
+            pressed = true;
+        }
+    }
+
+    if pressed {
+        log::info!("Power button pressed, shutting down");
+        shutdown();
+    }
+}
+
+/// Turn the machine off by entering S5, falling back to emulator-specific ports
+pub fn shutdown() -> ! {
+    crate::arch::disable_interrupts();
+
+    if let Some(power) = POWER.get()
+        && let Some((typ_a, typ_b)) = power.s5
+    {
+        for (cnt, typ) in [(power.pm1a_cnt, typ_a), (power.pm1b_cnt, typ_b)] {
+            if cnt != 0 {
+                let value = inw(cnt) & !(SLP_TYP_MASK | SLP_EN);
+                outw(cnt, value | typ << SLP_TYP_SHIFT | SLP_EN);
+            }
+        }
+    }
+
+    for (port, value) in EMULATOR_SHUTDOWN {
+        outw(port, value);
+    }
+
+    log::error!("Shutdown failed, halting");
+    loop {
+        crate::arch::halt();
+    }
+}
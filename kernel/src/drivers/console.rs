@@ -0,0 +1,358 @@
+//! Text on the framebuffer, drawn with the bitmap font in `font`.
+//!
+//! `draw_text` uses whichever `Font` was picked with `set_font`: the bitmap font enlarged by whole
+//! pixels, or its outline filled by tiny_skia with anti-aliasing at any size. Anti-aliased glyphs
+//! are rasterised once and kept in a cache keyed by character and size.
+//!
+//! The panic screen always uses the plain bitmap font, drawn without allocating or waiting on
+//! locks, since the heap or a lock holder may be what panicked.
+
+use crate::drivers::screen::{self, Rgb, Screen};
+use crate::drivers::{font, vga_text};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use tiny_skia::{Color, FillRule, Paint, Path, PathBuilder, Pixmap, Rect, Transform};
+
+/// Colours the console draws with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub foreground: Rgb,
+    pub background: Rgb,
+    pub panic_foreground: Rgb,
+    pub panic_background: Rgb,
+}
+
+impl Theme {
+    pub const DEFAULT: Self = Self {
+        foreground: Rgb::new(0xCC, 0xCC, 0xCC),
+        background: Rgb::new(0x00, 0x00, 0x00),
+        panic_foreground: Rgb::new(0xFF, 0xFF, 0xFF),
+        panic_background: Rgb::new(0xAA, 0x00, 0x00),
+    };
+}
+
+static THEME: Mutex<Theme> = Mutex::new(Theme::DEFAULT);
+
+/// Blank space around the panic screen's text, in pixels
+const MARGIN: usize = 16;
+
+pub fn set_theme(theme: Theme) {
+    *THEME.lock() = theme;
+}
+
+pub fn theme() -> Theme {
+    *THEME.lock()
+}
+
+/// How many times to enlarge the 8x8 font so it stays readable on large screens
+fn scale_for(screen: &Screen) -> usize {
+    if screen.width >= 1024 { 2 } else { 1 }
+}
+
+/// How `draw_text` draws characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Font {
+    /// The bitmap font, each font pixel drawn as a `scale` by `scale` square
+    Bitmap { scale: usize },
+    /// The bitmap font's outline filled with anti-aliasing, `size` pixels tall. Looks better than
+    /// `Bitmap` at sizes that aren't a multiple of 8, but is slower to draw.
+    Smooth { size: usize },
+}
+
+impl Font {
+    /// Width and height of a character cell in pixels
+    pub fn cell_size(&self) -> (usize, usize) {
+        match *self {
+            Font::Bitmap { scale } => (font::WIDTH * scale, font::HEIGHT * scale),
+            Font::Smooth { size } => ((font::WIDTH * size).div_ceil(font::HEIGHT), size),
+        }
+    }
+}
+
+/// Largest bitmap scale or smooth size `set_font` accepts
+const MAX_SCALE: usize = 16;
+const MAX_SIZE: usize = 128;
+
+/// Font picked with `set_font`, if none was a bitmap scaled for the screen is used
+static FONT: Mutex<Option<Font>> = Mutex::new(None);
+
+/// Pick the font for `draw_text`
+pub fn set_font(font: Font) -> Result<(), &'static str> {
+    match font {
+        Font::Bitmap { scale } if !(1..=MAX_SCALE).contains(&scale) => {
+            return Err("Bitmap font scale out of range");
+        }
+        Font::Smooth { size } if !(1..=MAX_SIZE).contains(&size) => {
+            return Err("Smooth font size out of range");
+        }
+        _ => {}
+    }
+
+    *FONT.lock() = Some(font);
+    // Glyphs at other sizes are unlikely to be needed again
+    GLYPH_CACHE.lock().clear();
+    Ok(())
+}
+
+/// The font `draw_text` uses on `screen`
+pub fn font_for(screen: &Screen) -> Font {
+    FONT.lock().unwrap_or(Font::Bitmap {
+        scale: scale_for(screen),
+    })
+}
+
+/// A rasterised glyph, how much of each pixel the character covers from 0 to 255
+struct Glyph {
+    width: usize,
+    height: usize,
+    coverage: Vec<u8>,
+}
+
+/// Most glyphs kept before the cache is emptied and starts again
+const GLYPH_CACHE_LIMIT: usize = 512;
+
+static GLYPH_CACHE: Mutex<BTreeMap<(char, usize), Glyph>> = Mutex::new(BTreeMap::new());
+
+/// Outline of `c`'s bitmap glyph scaled to `size` pixels tall, top left corner at the origin.
+/// None if there's nothing to fill, like for a space.
+pub(crate) fn glyph_path(c: char, size: f32) -> Option<Path> {
+    let pixel = size / font::HEIGHT as f32;
+
+    // One square per font pixel. Filled as a single path, so squares that touch don't leave a
+    // faint seam between them.
+    let mut builder = PathBuilder::new();
+    for (row, bits) in font::glyph(c).iter().enumerate() {
+        for column in (0..font::WIDTH).filter(|column| bits >> column & 1 != 0) {
+            let square = Rect::from_xywh(column as f32 * pixel, row as f32 * pixel, pixel, pixel)?;
+            builder.push_rect(square);
+        }
+    }
+
+    builder.finish()
+}
+
+/// Fill the outline of `c`'s bitmap glyph scaled to `size` pixels tall. None if tiny_skia couldn't
+/// get a pixmap for it.
+fn rasterise(c: char, size: usize) -> Option<Glyph> {
+    let (width, height) = Font::Smooth { size }.cell_size();
+    let mut pixmap = Pixmap::new(width as u32, height as u32)?;
+
+    if let Some(path) = glyph_path(c, size as f32) {
+        let mut paint = Paint::default();
+        paint.set_color(Color::WHITE);
+        paint.anti_alias = true;
+        pixmap.fill_path(
+            &path,
+            &paint,
+            FillRule::Winding,
+            Transform::identity(),
+            None,
+        );
+    }
+
+    let coverage = pixmap.pixels().iter().map(|pixel| pixel.alpha()).collect();
+    Some(Glyph {
+        width,
+        height,
+        coverage,
+    })
+}
+
+/// The glyph for `c` at `size` from the cache, rasterising it first if it isn't there
+fn cached_glyph(
+    cache: &mut BTreeMap<(char, usize), Glyph>,
+    c: char,
+    size: usize,
+) -> Option<&Glyph> {
+    if !cache.contains_key(&(c, size)) {
+        if cache.len() >= GLYPH_CACHE_LIMIT {
+            cache.clear();
+        }
+        cache.insert((c, size), rasterise(c, size)?);
+    }
+
+    cache.get(&(c, size))
+}
+
+/// Mix `foreground` over `background`, `alpha` out of 255 of the way
+fn blend(foreground: Rgb, background: Rgb, alpha: u8) -> Rgb {
+    let mix = |fg: u8, bg: u8| {
+        let (fg, bg, alpha) = (fg as u32, bg as u32, alpha as u32);
+        ((fg * alpha + bg * (255 - alpha) + 127) / 255) as u8
+    };
+
+    Rgb::new(
+        mix(foreground.r, background.r),
+        mix(foreground.g, background.g),
+        mix(foreground.b, background.b),
+    )
+}
+
+fn draw_glyph(screen: &mut Screen, x: usize, y: usize, glyph: &Glyph, colours: (Rgb, Rgb)) {
+    let (foreground, background) = colours;
+
+    screen.draw_pixels(x, y, glyph.width, glyph.height, |column, row| {
+        blend(
+            foreground,
+            background,
+            glyph.coverage[row * glyph.width + column],
+        )
+    });
+}
+
+/// Draw `text` on one line from (`x`, `y`) in the font from `set_font`, returning the x just past
+/// it. Newlines aren't handled and anything past the edge of the screen is cut off.
+pub fn draw_text(
+    screen: &mut Screen,
+    x: usize,
+    y: usize,
+    text: &str,
+    colours: (Rgb, Rgb),
+) -> usize {
+    let font = font_for(screen);
+    let (cell_width, _) = font.cell_size();
+    let mut x = x;
+
+    match font {
+        Font::Bitmap { scale } => {
+            for c in text.chars() {
+                draw_char(screen, x, y, c, scale, colours);
+                x += cell_width;
+            }
+        }
+        Font::Smooth { size } => {
+            let mut cache = GLYPH_CACHE.lock();
+            for c in text.chars() {
+                match cached_glyph(&mut cache, c, size) {
+                    Some(glyph) => draw_glyph(screen, x, y, glyph, colours),
+                    // Without a glyph, get as close as the bitmap font can
+                    None => draw_char(screen, x, y, c, (size / font::HEIGHT).max(1), colours),
+                }
+                x += cell_width;
+            }
+        }
+    }
+
+    x
+}
+
+/// Draw `c` with its top left corner at (`x`, `y`), `scale` pixels per font pixel
+pub fn draw_char(
+    screen: &mut Screen,
+    x: usize,
+    y: usize,
+    c: char,
+    scale: usize,
+    colours: (Rgb, Rgb),
+) {
+    let (foreground, background) = colours;
+
+    for (row, bits) in font::glyph(c).iter().enumerate() {
+        for column in 0..font::WIDTH {
+            let colour = if bits >> column & 1 != 0 {
+                foreground
+            } else {
+                background
+            };
+            screen.fill_rect(x + column * scale, y + row * scale, scale, scale, colour);
+        }
+    }
+}
+
+/// Draws text left to right from a starting point, wrapping at the right edge (minus `margin`)
+struct TextCursor<'a> {
+    screen: &'a mut Screen,
+    x: usize,
+    y: usize,
+    margin: usize,
+    scale: usize,
+    colours: (Rgb, Rgb),
+}
+
+impl TextCursor<'_> {
+    fn new_line(&mut self) {
+        self.x = self.margin;
+        self.y += (font::HEIGHT + 2) * self.scale;
+    }
+}
+
+impl Write for TextCursor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let cell_width = font::WIDTH * self.scale;
+        let right = (self.screen.width as usize).saturating_sub(self.margin);
+
+        for c in s.chars() {
+            match c {
+                '\n' => self.new_line(),
+                c => {
+                    if self.x + cell_width > right {
+                        self.new_line();
+                    }
+                    if self.y + font::HEIGHT * self.scale > self.screen.height as usize {
+                        return Err(fmt::Error);
+                    }
+
+                    draw_char(self.screen, self.x, self.y, c, self.scale, self.colours);
+                    self.x += cell_width;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Paint the whole screen in the theme's panic colours with the panic message, where it happened
+/// and the return addresses in `backtrace`. Does nothing without a framebuffer.
+pub fn panic_screen(info: &PanicInfo, backtrace: &[u64]) {
+    // In text mode the VGA text writer already shows the panic
+    if vga_text::is_active() {
+        return;
+    }
+
+    // Whoever holds the theme lock isn't coming back
+    let theme = THEME.try_lock().map_or(Theme::DEFAULT, |theme| *theme);
+
+    let mut screen = screen::lock_for_panic();
+    if screen.width == 0 || screen.height == 0 || screen.bits_per_pixel < 8 {
+        return;
+    }
+
+    let (width, height) = (screen.width as usize, screen.height as usize);
+    screen.fill_rect(0, 0, width, height, theme.panic_background);
+
+    let scale = scale_for(&screen);
+    let mut cursor = TextCursor {
+        screen: &mut screen,
+        x: MARGIN,
+        y: MARGIN,
+        margin: MARGIN,
+        scale,
+        colours: (theme.panic_foreground, theme.panic_background),
+    };
+
+    // Running out of room just cuts the text short
+    let _ = writeln!(cursor, "KERNEL PANIC\n");
+    let _ = writeln!(cursor, "{}\n", info.message());
+    if let Some(location) = info.location() {
+        let _ = writeln!(
+            cursor,
+            "at {}:{}:{}\n",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+
+    if !backtrace.is_empty() {
+        let _ = writeln!(cursor, "Backtrace:");
+        for (depth, address) in backtrace.iter().enumerate() {
+            let _ = writeln!(cursor, "  #{:<2} {:#018x}", depth, address);
+        }
+    }
+
+    screen.sync();
+}
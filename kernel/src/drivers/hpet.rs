@@ -0,0 +1,236 @@
+//! HPET, the High Precision Event Timer.
+//!
+//! A free-running main counter ticking at (at least) 10 MHz, plus a set of comparators that can
+//! fire one-shot interrupts. When present it replaces the PIT as the kernel's clock, the PIT's
+//! ~55 ms ticks are far too coarse for delays.
+
+use crate::drivers::acpi;
+use crate::mem::mmio;
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+/// HPET register offsets
+mod regs {
+    pub const CAPABILITIES: usize = 0x000;
+    pub const CONFIG: usize = 0x010;
+    pub const MAIN_COUNTER: usize = 0x0F0;
+
+    pub const fn timer_config(n: usize) -> usize {
+        0x100 + 0x20 * n
+    }
+
+    pub const fn timer_comparator(n: usize) -> usize {
+        0x108 + 0x20 * n
+    }
+}
+
+/// General capabilities: the main counter is 64 bits wide, otherwise only 32
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// General configuration: main counter runs
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+/// Timer configuration bits
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+
+/// Size of the register block
+const HPET_MMIO_SIZE: usize = 1024;
+
+/// Offset of the base address GAS's address field within the ACPI HPET table
+const HPET_TABLE_ADDRESS_OFFSET: u64 = 44;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+static BASE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+/// Length of a counter tick in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// Uptime when the counter was started, so `now_ns` continues where the PIT left off
+static START_NS: AtomicU64 = AtomicU64::new(0);
+static TIMER_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Whether the main counter is 64 bits wide
+static WIDE_COUNTER: AtomicBool = AtomicBool::new(false);
+/// Last value `counter` returned, which a 32-bit counter is extended from
+static LAST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+fn read(offset: usize) -> u64 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::read_volatile(base.add(offset) as *const u64) }
+}
+
+fn write(offset: usize, value: u64) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { core::ptr::write_volatile(base.add(offset) as *mut u64, value) }
+}
+
+/// Whether the HPET was found and is running
+pub fn is_available() -> bool {
+    !BASE.load(Ordering::Relaxed).is_null()
+}
+
+pub fn init() {
+    let Some(table) = acpi::find_table(b"HPET") else {
+        log::debug!("No ACPI HPET table, staying on the PIT");
+        return;
+    };
+
+    let phys =
+        unsafe { core::ptr::read_unaligned((table + HPET_TABLE_ADDRESS_OFFSET) as *const u64) };
+
+    let base = match mmio::map(phys, HPET_MMIO_SIZE) {
+        Ok(base) => base,
+        Err(e) => {
+            log::warn!("Failed to map HPET at {:#x}: {}", phys, e);
+            return;
+        }
+    };
+    BASE.store(base, Ordering::Relaxed);
+
+    let caps = read(regs::CAPABILITIES);
+    let period_fs = caps >> 32;
+    let timers = ((caps >> 8) & 0x1F) + 1;
+
+    // The spec caps the period at 100 ns, anything else means we're not looking at an HPET
+    if period_fs == 0 || period_fs > 100_000_000 {
+        log::warn!("HPET at {:#x} reports a bogus period of {} fs", phys, period_fs);
+        mmio::unmap(base, HPET_MMIO_SIZE);
+        BASE.store(core::ptr::null_mut(), Ordering::Relaxed);
+        return;
+    }
+
+    PERIOD_FS.store(period_fs, Ordering::Relaxed);
+    TIMER_COUNT.store(timers, Ordering::Relaxed);
+    let wide = caps & COUNT_SIZE_CAP != 0;
+    WIDE_COUNTER.store(wide, Ordering::Relaxed);
+    LAST_COUNT.store(0, Ordering::Relaxed);
+
+    // Start from zero with every comparator disabled
+    write(regs::CONFIG, read(regs::CONFIG) & !CONFIG_ENABLE);
+    for n in 0..timers as usize {
+        let config = read(regs::timer_config(n));
+        write(
+            regs::timer_config(n),
+            config & !(TIMER_INT_ENABLE | TIMER_PERIODIC),
+        );
+    }
+    write(regs::MAIN_COUNTER, 0);
+
+    START_NS.store(
+        crate::arch::x86_64::idt::pit_uptime_ms() * 1_000_000,
+        Ordering::Relaxed,
+    );
+    write(regs::CONFIG, read(regs::CONFIG) | CONFIG_ENABLE);
+
+    log::info!(
+        "HPET at {:#x}: {} kHz, {}-bit, {} comparators",
+        phys,
+        1_000_000_000_000 / period_fs,
+        if wide { 64 } else { 32 },
+        timers
+    );
+}
+
+/// Main counter value. A 32-bit counter is extended to 64 bits in software, which only works if
+/// this is called at least once per wraparound (about 7 minutes at 10 MHz). The timer tick reads
+/// the uptime, so it always is.
+pub fn counter() -> u64 {
+    if WIDE_COUNTER.load(Ordering::Relaxed) {
+        return read(regs::MAIN_COUNTER);
+    }
+
+    loop {
+        // Read after `last`, so the counter can't be behind it. If anything else extends a
+        // reading in the meantime, this one may be older than theirs and is taken again.
+        let last = LAST_COUNT.load(Ordering::Relaxed);
+        let low = read(regs::MAIN_COUNTER) & u32::MAX as u64;
+
+        // Same high half as last time, unless the low half has wrapped since
+        let mut count = (last & !(u32::MAX as u64)) | low;
+        if count < last {
+            count += 1 << 32;
+        }
+
+        if LAST_COUNT
+            .compare_exchange(last, count, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            return count;
+        }
+    }
+}
+
+/// Nanoseconds since boot, `None` if there's no HPET
+pub fn now_ns() -> Option<u64> {
+    if !is_available() {
+        return None;
+    }
+
+    let period = PERIOD_FS.load(Ordering::Relaxed) as u128;
+    let elapsed = (counter() as u128 * period / FEMTOS_PER_NANO) as u64;
+
+    Some(START_NS.load(Ordering::Relaxed) + elapsed)
+}
+
+/// Spin for `ns` nanoseconds. Returns false (without waiting) if there's no HPET.
+pub fn sleep_ns(ns: u64) -> bool {
+    if !is_available() {
+        return false;
+    }
+
+    let period = PERIOD_FS.load(Ordering::Relaxed) as u128;
+    let ticks = (ns as u128 * FEMTOS_PER_NANO).div_ceil(period) as u64;
+    let start = counter();
+
+    while counter().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+
+    true
+}
+
+/// Arm comparator `timer` to raise I/O APIC input `route` once, `ns` nanoseconds from now
+pub fn arm_oneshot(timer: usize, ns: u64, route: u8) -> Result<(), &'static str> {
+    if !is_available() {
+        return Err("No HPET");
+    }
+    if timer as u64 >= TIMER_COUNT.load(Ordering::Relaxed) {
+        return Err("No such HPET comparator");
+    }
+
+    let config = read(regs::timer_config(timer));
+
+    // The upper half of the config register lists the I/O APIC inputs this timer can drive
+    if route >= 32 || (config >> 32) & (1 << route) == 0 {
+        return Err("HPET comparator can't be routed to that interrupt");
+    }
+
+    let period = PERIOD_FS.load(Ordering::Relaxed) as u128;
+    let ticks = (ns as u128 * FEMTOS_PER_NANO).div_ceil(period) as u64;
+
+    // A 32-bit comparator only matches the low half of the count
+    if !WIDE_COUNTER.load(Ordering::Relaxed) && ticks > u32::MAX as u64 {
+        return Err("Too far ahead for a 32-bit HPET");
+    }
+
+    let config = (config & !(TIMER_PERIODIC | TIMER_32BIT_MODE | TIMER_ROUTE_MASK))
+        | ((route as u64) << TIMER_ROUTE_SHIFT)
+        | TIMER_INT_ENABLE;
+
+    write(regs::timer_comparator(timer), counter().wrapping_add(ticks));
+    write(regs::timer_config(timer), config);
+
+    Ok(())
+}
+
+/// Stop comparator `timer` from raising further interrupts
+pub fn disarm(timer: usize) {
+    if !is_available() || timer as u64 >= TIMER_COUNT.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let config = read(regs::timer_config(timer));
+    write(regs::timer_config(timer), config & !TIMER_INT_ENABLE);
+}
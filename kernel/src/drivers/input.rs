@@ -0,0 +1,115 @@
+//! Input events from every device, in one queue.
+//!
+//! The keyboard and mouse drivers push into the same ring, so a consumer sees events in the order
+//! they happened across devices. Both push from the PS/2 bottom half, and only one piece of
+//! deferred work runs at a time, which keeps the ring single-producer.
+
+use crate::drivers::keyboard::KeyEvent;
+use crate::drivers::ring::RingBuffer;
+use crate::sync::{IrqSpinlock, WaitQueue};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Events buffered by default before new ones are dropped
+pub const DEFAULT_CAPACITY: usize = 128;
+/// Most events that can ever be buffered
+pub const MAX_CAPACITY: usize = 256;
+
+#[derive(Debug, Copy, Clone)]
+pub enum InputEvent {
+    Key(KeyEvent),
+    Mouse(MouseEvent),
+}
+
+/// Relative mouse movement and the buttons held after it
+#[derive(Debug, Copy, Clone)]
+pub struct MouseEvent {
+    /// Positive is right
+    pub dx: i16,
+    /// Positive is up
+    pub dy: i16,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Filled without locking or allocating, so pushing can never wait on a reader
+static QUEUE: RingBuffer<InputEvent, MAX_CAPACITY> = RingBuffer::new();
+/// Serializes readers, the ring only supports one consumer at a time. Producers never take it.
+/// Waiters take it with interrupts disabled, so its holder mustn't be preempted.
+static READ_LOCK: IrqSpinlock<()> = IrqSpinlock::new(());
+/// Events the IRQ handlers may queue
+static CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_CAPACITY);
+/// Events dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Readers waiting for the next event
+static READABLE: WaitQueue = WaitQueue::new();
+/// Set by `close`, blocking reads that care give up once the queue is empty
+static CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Queue an event, called from the PS/2 bottom half
+pub fn push(event: InputEvent) {
+    let full = QUEUE.len() >= CAPACITY.load(Ordering::Relaxed);
+    if full || QUEUE.push(event).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    READABLE.wake_all();
+}
+
+/// Take the oldest event, if any
+pub fn poll() -> Option<InputEvent> {
+    let _reader = READ_LOCK.lock();
+    QUEUE.pop()
+}
+
+/// Sleep until an event arrives and take it
+pub fn wait() -> InputEvent {
+    READABLE.wait_until(poll)
+}
+
+/// Sleep until an event arrives and take it, or return `None` once the queue is empty after
+/// `close`
+pub fn wait_or_closed() -> Option<InputEvent> {
+    READABLE.wait_until(|| match poll() {
+        Some(event) => Some(Some(event)),
+        None if CLOSED.load(Ordering::Relaxed) => Some(None),
+        None => None,
+    })
+}
+
+/// Stop `wait_or_closed` from blocking, e.g. before shutting down. Events already queued are
+/// still handed out first.
+pub fn close() {
+    CLOSED.store(true, Ordering::Relaxed);
+    READABLE.wake_all();
+}
+
+/// Check if there are any events queued
+pub fn has_event() -> bool {
+    !QUEUE.is_empty()
+}
+
+/// Number of events dropped because the queue was full
+pub fn dropped_count() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Change how many events are buffered, up to `MAX_CAPACITY`. Shrinking below the number
+/// currently queued drops the oldest ones.
+pub fn set_capacity(capacity: usize) -> Result<(), &'static str> {
+    if capacity == 0 || capacity > MAX_CAPACITY {
+        return Err("Input queue capacity must be between 1 and MAX_CAPACITY");
+    }
+
+    CAPACITY.store(capacity, Ordering::Relaxed);
+
+    let _reader = READ_LOCK.lock();
+    while QUEUE.len() > capacity {
+        QUEUE.pop();
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    log::debug!("Input queue capacity set to {} events", capacity);
+
+    Ok(())
+}
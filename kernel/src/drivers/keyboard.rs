@@ -1,9 +1,12 @@
-use alloc::collections::VecDeque;
+use crate::drivers::input::{self, InputEvent};
+use crate::arch::x86_64::idt;
+use crate::drivers::ps2;
+use core::sync::atomic::{AtomicU8, Ordering};
 use spin::Mutex;
 use log;
 
-static KEYBOARD_BUF: Mutex<VecDeque<KeyEvent>> = Mutex::new(VecDeque::new());
-static EXTENDED_KEY: Mutex<bool> = Mutex::new(false);
+/// `DecodeState` of the scancode stream, only touched by `handle_byte`
+static DECODE_STATE: AtomicU8 = AtomicU8::new(DecodeState::Normal as u8);
 
 #[derive(Debug, Copy, Clone)]
 pub struct KeyEvent {
@@ -129,6 +132,10 @@ pub enum KeyCode {
     KeypadDivide,
     KeypadEnter,
     KeypadPeriod,
+
+    // System keys
+    PrintScreen,
+    Pause,
 }
 
 /// Modifier keys
@@ -149,61 +156,129 @@ static MODIFIERS: Mutex<Modifiers> = Mutex::new(Modifiers {
     num_lock: false,
 });
 
-pub fn handle_interrupt() {
-    use crate::arch::x86_64::inb;
-
-    let scancode = inb(0x60);
+/// Position inside a multi-byte scancode sequence
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+enum DecodeState {
+    Normal,
+    /// Got 0xE0, the next byte is an extended key
+    Extended,
+    /// Got 0xE1 (Pause), two more bytes follow
+    Pause1,
+    Pause2,
+}
 
-    if scancode == 0xE0 {
-        *EXTENDED_KEY.lock() = true;
-        return;
+impl DecodeState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Extended,
+            2 => Self::Pause1,
+            3 => Self::Pause2,
+            _ => Self::Normal,
+        }
     }
+}
 
-    let is_extended = {
-        let mut ext = EXTENDED_KEY.lock();
-        let was_ext = *ext;
-        *ext = false;
-        was_ext
-    };
+/// Feed one byte of scancode set 1 through the decoder, returning the next state and the
+/// (keycode, pressed) it completed, if any.
+///
+/// Besides plain E0-prefixed keys this copes with:
+/// - PrintScreen, sent as E0 2A E0 37 and released as E0 B7 E0 AA. The E0 2A / E0 AA (and
+///   E0 36 / E0 B6) halves are fake shifts the keyboard wraps some keys in, they are dropped.
+/// - Pause, sent as E1 1D 45 E1 9D C5 on press with no release of its own. That decodes as a
+///   press followed by a release. With Ctrl held it's E0 46 E0 C6 instead.
+fn decode(state: DecodeState, byte: u8) -> (DecodeState, Option<(KeyCode, bool)>) {
+    let pressed = byte & 0x80 == 0;
+    let code = byte & 0x7F;
+
+    match state {
+        // A new prefix always restarts the sequence, so one garbled byte can't desync the rest
+        _ if byte == 0xE0 => (DecodeState::Extended, None),
+        _ if byte == 0xE1 => (DecodeState::Pause1, None),
 
-    if let Some(event) = handle_scancode(scancode, is_extended) {
-        let mut buf = KEYBOARD_BUF.lock();
-        if buf.len() < 100 {
-            buf.push_back(event);
+        DecodeState::Normal => (DecodeState::Normal, Some((scancode_to_keycode(code), pressed))),
+        DecodeState::Extended => {
+            let keycode = match code {
+                0x2A | 0x36 => return (DecodeState::Normal, None),
+                0x37 => KeyCode::PrintScreen,
+                0x46 => KeyCode::Pause,
+                _ => extended_scancode_to_keycode(code),
+            };
+            (DecodeState::Normal, Some((keycode, pressed)))
         }
+        DecodeState::Pause1 => (DecodeState::Pause2, None),
+        DecodeState::Pause2 => (DecodeState::Normal, Some((KeyCode::Pause, pressed))),
     }
 }
 
-fn handle_scancode(scancode: u8, extended: bool) -> Option<KeyEvent> {
-    let pressed = scancode & 0x80 == 0;
-    let code = scancode & 0x7F;
+/// Decode a byte the keyboard sent, run from the PS/2 bottom half
+pub fn handle_byte(scancode: u8) {
+    let state = DecodeState::from_u8(DECODE_STATE.load(Ordering::Relaxed));
+    let (next, key) = decode(state, scancode);
+    DECODE_STATE.store(next as u8, Ordering::Relaxed);
 
-    let keycode = if extended {
-        extended_scancode_to_keycode(code)
-    } else {
-        scancode_to_keycode(code)
+    let Some((keycode, pressed)) = key else {
+        return;
     };
 
+    input::push(InputEvent::Key(handle_key(scancode, keycode, pressed)));
+}
+
+/// Bits of `HELD_MODIFIERS`
+const LEFT_SHIFT: u8 = 1 << 0;
+const RIGHT_SHIFT: u8 = 1 << 1;
+const LEFT_CTRL: u8 = 1 << 2;
+const RIGHT_CTRL: u8 = 1 << 3;
+const LEFT_ALT: u8 = 1 << 4;
+const RIGHT_ALT: u8 = 1 << 5;
+
+/// Modifier keys currently held down, tracked per side so releasing one Ctrl while the other is
+/// still held doesn't clear `Modifiers::ctrl`
+static HELD_MODIFIERS: AtomicU8 = AtomicU8::new(0);
+
+fn modifier_bit(keycode: KeyCode) -> Option<u8> {
+    match keycode {
+        KeyCode::LeftShift => Some(LEFT_SHIFT),
+        KeyCode::RightShift => Some(RIGHT_SHIFT),
+        KeyCode::LeftCtrl => Some(LEFT_CTRL),
+        KeyCode::RightCtrl => Some(RIGHT_CTRL),
+        KeyCode::LeftAlt => Some(LEFT_ALT),
+        KeyCode::RightAlt => Some(RIGHT_ALT),
+        _ => None,
+    }
+}
+
+/// Update the modifier state for a decoded key and build its event
+fn handle_key(scancode: u8, keycode: KeyCode, pressed: bool) -> KeyEvent {
     {
         let mut mods = MODIFIERS.lock();
         match keycode {
-            KeyCode::LeftShift | KeyCode::RightShift => mods.shift = pressed,
-            KeyCode::LeftCtrl | KeyCode::RightCtrl => mods.ctrl = pressed,
-            KeyCode::LeftAlt | KeyCode::RightAlt => mods.alt = pressed,
             KeyCode::CapsLock if pressed => mods.caps_lock = !mods.caps_lock,
             KeyCode::NumLock if pressed => mods.num_lock = !mods.num_lock,
             _ => {}
         }
+
+        if let Some(bit) = modifier_bit(keycode) {
+            let held = if pressed {
+                HELD_MODIFIERS.fetch_or(bit, Ordering::Relaxed) | bit
+            } else {
+                HELD_MODIFIERS.fetch_and(!bit, Ordering::Relaxed) & !bit
+            };
+
+            mods.shift = held & (LEFT_SHIFT | RIGHT_SHIFT) != 0;
+            mods.ctrl = held & (LEFT_CTRL | RIGHT_CTRL) != 0;
+            mods.alt = held & (LEFT_ALT | RIGHT_ALT) != 0;
+        }
     }
 
     let modifiers = *MODIFIERS.lock();
 
-    Some(KeyEvent {
+    KeyEvent {
         scancode,
         keycode,
         modifiers,
         pressed,
-    })
+    }
 }
 
 /// Convert extended scancode (after 0xE0) to keycode
@@ -690,9 +765,279 @@ pub fn keyevent_to_char(event: &KeyEvent) -> Option<char> {
     Some(c)
 }
 
-/// Read key event from buffer (blocking)
+/// Human readable name of a key, e.g. for logging or a key binding UI
+pub fn keycode_name(keycode: KeyCode) -> &'static str {
+    // No wildcard arm, so a new KeyCode variant won't compile until it has a name
+    match keycode {
+        KeyCode::Unknown => "Unknown",
+        KeyCode::A => "A",
+        KeyCode::B => "B",
+        KeyCode::C => "C",
+        KeyCode::D => "D",
+        KeyCode::E => "E",
+        KeyCode::F => "F",
+        KeyCode::G => "G",
+        KeyCode::H => "H",
+        KeyCode::I => "I",
+        KeyCode::J => "J",
+        KeyCode::K => "K",
+        KeyCode::L => "L",
+        KeyCode::M => "M",
+        KeyCode::N => "N",
+        KeyCode::O => "O",
+        KeyCode::P => "P",
+        KeyCode::Q => "Q",
+        KeyCode::R => "R",
+        KeyCode::S => "S",
+        KeyCode::T => "T",
+        KeyCode::U => "U",
+        KeyCode::V => "V",
+        KeyCode::W => "W",
+        KeyCode::X => "X",
+        KeyCode::Y => "Y",
+        KeyCode::Z => "Z",
+        KeyCode::Key0 => "0",
+        KeyCode::Key1 => "1",
+        KeyCode::Key2 => "2",
+        KeyCode::Key3 => "3",
+        KeyCode::Key4 => "4",
+        KeyCode::Key5 => "5",
+        KeyCode::Key6 => "6",
+        KeyCode::Key7 => "7",
+        KeyCode::Key8 => "8",
+        KeyCode::Key9 => "9",
+        KeyCode::F1 => "F1",
+        KeyCode::F2 => "F2",
+        KeyCode::F3 => "F3",
+        KeyCode::F4 => "F4",
+        KeyCode::F5 => "F5",
+        KeyCode::F6 => "F6",
+        KeyCode::F7 => "F7",
+        KeyCode::F8 => "F8",
+        KeyCode::F9 => "F9",
+        KeyCode::F10 => "F10",
+        KeyCode::F11 => "F11",
+        KeyCode::F12 => "F12",
+        KeyCode::Escape => "Escape",
+        KeyCode::Tab => "Tab",
+        KeyCode::CapsLock => "Caps Lock",
+        KeyCode::LeftShift => "Left Shift",
+        KeyCode::RightShift => "Right Shift",
+        KeyCode::LeftCtrl => "Left Ctrl",
+        KeyCode::RightCtrl => "Right Ctrl",
+        KeyCode::LeftAlt => "Left Alt",
+        KeyCode::RightAlt => "Right Alt",
+        KeyCode::Space => "Space",
+        KeyCode::Enter => "Enter",
+        KeyCode::Backspace => "Backspace",
+        KeyCode::Delete => "Delete",
+        KeyCode::Insert => "Insert",
+        KeyCode::Home => "Home",
+        KeyCode::End => "End",
+        KeyCode::PageUp => "Page Up",
+        KeyCode::PageDown => "Page Down",
+        KeyCode::Up => "Up",
+        KeyCode::Down => "Down",
+        KeyCode::Left => "Left",
+        KeyCode::Right => "Right",
+        KeyCode::Minus => "Minus",
+        KeyCode::Equals => "Equals",
+        KeyCode::LeftBracket => "Left Bracket",
+        KeyCode::RightBracket => "Right Bracket",
+        KeyCode::Backslash => "Backslash",
+        KeyCode::Semicolon => "Semicolon",
+        KeyCode::Quote => "Quote",
+        KeyCode::Grave => "Grave",
+        KeyCode::Comma => "Comma",
+        KeyCode::Period => "Period",
+        KeyCode::Slash => "Slash",
+        KeyCode::NumLock => "Num Lock",
+        KeyCode::ScrollLock => "Scroll Lock",
+        KeyCode::Keypad0 => "Keypad 0",
+        KeyCode::Keypad1 => "Keypad 1",
+        KeyCode::Keypad2 => "Keypad 2",
+        KeyCode::Keypad3 => "Keypad 3",
+        KeyCode::Keypad4 => "Keypad 4",
+        KeyCode::Keypad5 => "Keypad 5",
+        KeyCode::Keypad6 => "Keypad 6",
+        KeyCode::Keypad7 => "Keypad 7",
+        KeyCode::Keypad8 => "Keypad 8",
+        KeyCode::Keypad9 => "Keypad 9",
+        KeyCode::KeypadPlus => "Keypad Plus",
+        KeyCode::KeypadMinus => "Keypad Minus",
+        KeyCode::KeypadMultiply => "Keypad Multiply",
+        KeyCode::KeypadDivide => "Keypad Divide",
+        KeyCode::KeypadEnter => "Keypad Enter",
+        KeyCode::KeypadPeriod => "Keypad Period",
+        KeyCode::PrintScreen => "Print Screen",
+        KeyCode::Pause => "Pause",
+    }
+}
+
+/// USB HID usage ID (keyboard/keypad page 0x07) of a key, so input from other sources (a USB
+/// keyboard, a remote input protocol, ...) can share one keycode table
+pub fn keycode_to_hid(keycode: KeyCode) -> Option<u8> {
+    let usage = match keycode {
+        KeyCode::Unknown => return None,
+        KeyCode::A => 0x04,
+        KeyCode::B => 0x05,
+        KeyCode::C => 0x06,
+        KeyCode::D => 0x07,
+        KeyCode::E => 0x08,
+        KeyCode::F => 0x09,
+        KeyCode::G => 0x0A,
+        KeyCode::H => 0x0B,
+        KeyCode::I => 0x0C,
+        KeyCode::J => 0x0D,
+        KeyCode::K => 0x0E,
+        KeyCode::L => 0x0F,
+        KeyCode::M => 0x10,
+        KeyCode::N => 0x11,
+        KeyCode::O => 0x12,
+        KeyCode::P => 0x13,
+        KeyCode::Q => 0x14,
+        KeyCode::R => 0x15,
+        KeyCode::S => 0x16,
+        KeyCode::T => 0x17,
+        KeyCode::U => 0x18,
+        KeyCode::V => 0x19,
+        KeyCode::W => 0x1A,
+        KeyCode::X => 0x1B,
+        KeyCode::Y => 0x1C,
+        KeyCode::Z => 0x1D,
+        KeyCode::Key0 => 0x27,
+        KeyCode::Key1 => 0x1E,
+        KeyCode::Key2 => 0x1F,
+        KeyCode::Key3 => 0x20,
+        KeyCode::Key4 => 0x21,
+        KeyCode::Key5 => 0x22,
+        KeyCode::Key6 => 0x23,
+        KeyCode::Key7 => 0x24,
+        KeyCode::Key8 => 0x25,
+        KeyCode::Key9 => 0x26,
+        KeyCode::F1 => 0x3A,
+        KeyCode::F2 => 0x3B,
+        KeyCode::F3 => 0x3C,
+        KeyCode::F4 => 0x3D,
+        KeyCode::F5 => 0x3E,
+        KeyCode::F6 => 0x3F,
+        KeyCode::F7 => 0x40,
+        KeyCode::F8 => 0x41,
+        KeyCode::F9 => 0x42,
+        KeyCode::F10 => 0x43,
+        KeyCode::F11 => 0x44,
+        KeyCode::F12 => 0x45,
+        KeyCode::Escape => 0x29,
+        KeyCode::Tab => 0x2B,
+        KeyCode::CapsLock => 0x39,
+        KeyCode::LeftShift => 0xE1,
+        KeyCode::RightShift => 0xE5,
+        KeyCode::LeftCtrl => 0xE0,
+        KeyCode::RightCtrl => 0xE4,
+        KeyCode::LeftAlt => 0xE2,
+        KeyCode::RightAlt => 0xE6,
+        KeyCode::Space => 0x2C,
+        KeyCode::Enter => 0x28,
+        KeyCode::Backspace => 0x2A,
+        KeyCode::Delete => 0x4C,
+        KeyCode::Insert => 0x49,
+        KeyCode::Home => 0x4A,
+        KeyCode::End => 0x4D,
+        KeyCode::PageUp => 0x4B,
+        KeyCode::PageDown => 0x4E,
+        KeyCode::Up => 0x52,
+        KeyCode::Down => 0x51,
+        KeyCode::Left => 0x50,
+        KeyCode::Right => 0x4F,
+        KeyCode::Minus => 0x2D,
+        KeyCode::Equals => 0x2E,
+        KeyCode::LeftBracket => 0x2F,
+        KeyCode::RightBracket => 0x30,
+        KeyCode::Backslash => 0x31,
+        KeyCode::Semicolon => 0x33,
+        KeyCode::Quote => 0x34,
+        KeyCode::Grave => 0x35,
+        KeyCode::Comma => 0x36,
+        KeyCode::Period => 0x37,
+        KeyCode::Slash => 0x38,
+        KeyCode::NumLock => 0x53,
+        KeyCode::ScrollLock => 0x47,
+        KeyCode::Keypad0 => 0x62,
+        KeyCode::Keypad1 => 0x59,
+        KeyCode::Keypad2 => 0x5A,
+        KeyCode::Keypad3 => 0x5B,
+        KeyCode::Keypad4 => 0x5C,
+        KeyCode::Keypad5 => 0x5D,
+        KeyCode::Keypad6 => 0x5E,
+        KeyCode::Keypad7 => 0x5F,
+        KeyCode::Keypad8 => 0x60,
+        KeyCode::Keypad9 => 0x61,
+        KeyCode::KeypadPlus => 0x57,
+        KeyCode::KeypadMinus => 0x56,
+        KeyCode::KeypadMultiply => 0x55,
+        KeyCode::KeypadDivide => 0x54,
+        KeyCode::KeypadEnter => 0x58,
+        KeyCode::KeypadPeriod => 0x63,
+        KeyCode::PrintScreen => 0x46,
+        KeyCode::Pause => 0x48,
+    };
+
+    Some(usage)
+}
+
+/// Read the next key event from the input queue (non-blocking).
+/// Mouse events queued ahead of it are discarded, so code that handles both should use
+/// `input::poll` instead.
 pub fn read_key() -> Option<KeyEvent> {
-    KEYBOARD_BUF.lock().pop_front()
+    while let Some(event) = input::poll() {
+        if let InputEvent::Key(key) = event {
+            return Some(key);
+        }
+    }
+    None
+}
+
+/// Sleep until the next key event arrives and take it. Mouse events are discarded, as with
+/// `read_key`.
+pub fn wait_key() -> KeyEvent {
+    loop {
+        if let InputEvent::Key(key) = input::wait() {
+            return key;
+        }
+    }
+}
+
+/// Iterator over key events, made by `events` and `try_events`. Mouse events in between are
+/// discarded, as with `read_key`.
+pub struct KeyEvents {
+    blocking: bool,
+}
+
+impl Iterator for KeyEvents {
+    type Item = KeyEvent;
+
+    fn next(&mut self) -> Option<KeyEvent> {
+        if !self.blocking {
+            return read_key();
+        }
+
+        loop {
+            if let InputEvent::Key(key) = input::wait_or_closed()? {
+                return Some(key);
+            }
+        }
+    }
+}
+
+/// Key events as they arrive. Sleeps while none are queued, and only ends once the input queue
+/// is closed (`input::close`).
+pub fn events() -> KeyEvents {
+    KeyEvents { blocking: true }
+}
+
+/// Key events already queued, ends as soon as there are none left
+pub fn try_events() -> KeyEvents {
+    KeyEvents { blocking: false }
 }
 
 /// Read character from keyboard (blocking)
@@ -714,11 +1059,178 @@ pub fn get_char() -> Option<char> {
     None
 }
 
-/// Check if there are any key events in the buffer
+/// Check if there are any input events queued, which may be mouse events rather than keys
 pub fn has_key() -> bool {
-    !KEYBOARD_BUF.lock().is_empty()
+    input::has_event()
 }
 
+/// Keyboard command: set the lock LEDs from the following data byte
+const SET_LEDS: u8 = 0xED;
+
+/// Turn the Scroll/Num/Caps Lock LEDs on or off
+pub fn set_leds(scroll_lock: bool, num_lock: bool, caps_lock: bool) -> Result<(), &'static str> {
+    let leds = scroll_lock as u8 | (num_lock as u8) << 1 | (caps_lock as u8) << 2;
+
+    ps2::with_controller(|| {
+        ps2::send_device(ps2::Port::First, SET_LEDS)?;
+        ps2::send_device(ps2::Port::First, leds)
+    })
+}
+
+/// IRQ line of the first PS/2 port
+const IRQ: u8 = 1;
+
+/// Start taking keyboard interrupts, the controller has already been set up by `ps2::init`
 pub fn init() {
-    log::debug!("Keyboard driver initialized (stub - no hardware initialization yet)");
+    if !ps2::has_port(ps2::Port::First) {
+        log::debug!("No first PS/2 port, keyboard unavailable");
+        return;
+    }
+
+    idt::unmask_irq(IRQ);
+    log::debug!("Keyboard driver initialized");
+}
+
+/// Run `bytes` through a fresh decoder, checking it produces `expected` and ends up back outside
+/// any sequence
+fn check_sequence(bytes: &[u8], expected: &[(KeyCode, bool)]) -> Result<(), &'static str> {
+    let mut state = DecodeState::Normal;
+    let mut keys = expected.iter();
+
+    for &byte in bytes {
+        let (next, key) = decode(state, byte);
+        state = next;
+        if let Some(key) = key
+            && keys.next() != Some(&key)
+        {
+            return Err("Decoded the wrong key");
+        }
+    }
+
+    if keys.next().is_some() {
+        return Err("Sequence decoded to too few keys");
+    }
+    if state != DecodeState::Normal {
+        return Err("Decoder left in the middle of a sequence");
+    }
+    Ok(())
+}
+
+/// Run `bytes` through the decoder and `handle_key`, as if the keyboard had sent them, checking
+/// `(ctrl, alt)` of the modifiers reported after each decoded key matches `expected`. The live
+/// modifier state is put back afterwards, so keys really held during the test aren't lost.
+fn check_modifiers(bytes: &[u8], expected: &[(bool, bool)]) -> Result<(), &'static str> {
+    let saved_modifiers = *MODIFIERS.lock();
+    let saved_held = HELD_MODIFIERS.swap(0, Ordering::Relaxed);
+    {
+        let mut mods = MODIFIERS.lock();
+        mods.shift = false;
+        mods.ctrl = false;
+        mods.alt = false;
+    }
+
+    let mut state = DecodeState::Normal;
+    let mut expected = expected.iter();
+    let mut result = Ok(());
+
+    for &byte in bytes {
+        let (next, key) = decode(state, byte);
+        state = next;
+
+        if let Some((keycode, pressed)) = key {
+            let mods = handle_key(byte, keycode, pressed).modifiers;
+            if expected.next() != Some(&(mods.ctrl, mods.alt)) {
+                result = Err("Wrong modifiers held after a key");
+                break;
+            }
+        }
+    }
+
+    if result.is_ok() && expected.next().is_some() {
+        result = Err("Sequence decoded to too few keys");
+    }
+
+    *MODIFIERS.lock() = saved_modifiers;
+    HELD_MODIFIERS.store(saved_held, Ordering::Relaxed);
+    result
+}
+
+/// PrintScreen and Pause, whose sequences don't follow the usual E0 prefix and break bit
+pub fn selftest_special_keys() -> Result<(), &'static str> {
+    use KeyCode::{Pause, PrintScreen};
+
+    check_sequence(
+        &[0xE0, 0x2A, 0xE0, 0x37, 0xE0, 0xB7, 0xE0, 0xAA],
+        &[(PrintScreen, true), (PrintScreen, false)],
+    )?;
+    check_sequence(
+        &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5],
+        &[(Pause, true), (Pause, false)],
+    )?;
+    // Ctrl+Pause (Break) comes as an ordinary extended key
+    check_sequence(&[0xE0, 0x46, 0xE0, 0xC6], &[(Pause, true), (Pause, false)])?;
+    // Keys on either side still decode normally
+    check_sequence(
+        &[0x1E, 0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5, 0x9E],
+        &[
+            (KeyCode::A, true),
+            (Pause, true),
+            (Pause, false),
+            (KeyCode::A, false),
+        ],
+    )
+}
+
+/// Extended keys press and release through the E0 prefix, and aren't mistaken for the keys that
+/// share their scancode without it (LeftCtrl, LeftAlt and the keypad)
+pub fn selftest_extended_keys() -> Result<(), &'static str> {
+    let keys = [
+        (0x1D, KeyCode::RightCtrl),
+        (0x38, KeyCode::RightAlt),
+        (0x48, KeyCode::Up),
+        (0x50, KeyCode::Down),
+        (0x4B, KeyCode::Left),
+        (0x4D, KeyCode::Right),
+    ];
+
+    for (code, keycode) in keys {
+        check_sequence(
+            &[0xE0, code, 0xE0, code | 0x80],
+            &[(keycode, true), (keycode, false)],
+        )?;
+    }
+
+    // Held together, released in the opposite order
+    check_sequence(
+        &[0xE0, 0x1D, 0xE0, 0x38, 0xE0, 0xB8, 0xE0, 0x9D],
+        &[
+            (KeyCode::RightCtrl, true),
+            (KeyCode::RightAlt, true),
+            (KeyCode::RightAlt, false),
+            (KeyCode::RightCtrl, false),
+        ],
+    )?;
+    check_sequence(
+        &[0x1D, 0xE0, 0x1D, 0x9D, 0xE0, 0x9D],
+        &[
+            (KeyCode::LeftCtrl, true),
+            (KeyCode::RightCtrl, true),
+            (KeyCode::LeftCtrl, false),
+            (KeyCode::RightCtrl, false),
+        ],
+    )?;
+
+    // Releasing one side of a modifier leaves it held until the other side goes up too
+    check_modifiers(
+        &[0x1D, 0xE0, 0x1D, 0x9D, 0xE0, 0x9D],
+        &[(true, false), (true, false), (true, false), (false, false)],
+    )?;
+    check_modifiers(
+        &[0x38, 0xE0, 0x38, 0xB8, 0xE0, 0xB8],
+        &[(false, true), (false, true), (false, true), (false, false)],
+    )?;
+    check_modifiers(
+        &[0xE0, 0x1D, 0xE0, 0x38, 0xE0, 0xB8, 0xE0, 0x9D],
+        &[(true, false), (true, true), (true, false), (false, false)],
+    )
 }
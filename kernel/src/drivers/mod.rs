@@ -1,13 +1,29 @@
+pub mod acpi;
+pub mod console;
+pub mod font;
+pub mod hpet;
+pub mod input;
 pub mod keyboard;
+pub mod mouse;
+pub mod ps2;
+pub mod ring;
 pub mod screen;
+pub mod vbe;
+pub mod vga_text;
 
 use crate::BootInfo;
 
 pub fn init(boot_info: &BootInfo) {
     log::trace!("Initializing drivers...");
 
+    acpi::init(boot_info);
+    hpet::init();
+
+    ps2::init();
+
     log::trace!("Initializing keyboard driver...");
     keyboard::init();
+    mouse::init();
 
     log::trace!("Initializing screen driver...");
     screen::init(boot_info);
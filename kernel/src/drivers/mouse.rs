@@ -0,0 +1,94 @@
+//! PS/2 mouse on the second controller port, standard 3-byte packets (no scroll wheel).
+
+use crate::arch::x86_64::idt;
+use crate::drivers::input::{self, InputEvent, MouseEvent};
+use crate::drivers::ps2::{self, Port};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+/// IRQ line of the second PS/2 port
+const IRQ: u8 = 12;
+
+/// Mouse commands
+const SET_DEFAULTS: u8 = 0xF6;
+const ENABLE_REPORTING: u8 = 0xF4;
+
+/// Bits of the first packet byte
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Bytes of the packet being assembled, packed little-endian. Only touched by `handle_byte`.
+static PACKET: AtomicU32 = AtomicU32::new(0);
+static PACKET_LEN: AtomicU8 = AtomicU8::new(0);
+
+/// Add a byte the mouse sent to the current packet, run from the PS/2 bottom half
+pub fn handle_byte(byte: u8) {
+    let len = PACKET_LEN.load(Ordering::Relaxed);
+
+    // Bit 3 of the first byte is always set, if it isn't we're out of step with the packets
+    if len == 0 && byte & ALWAYS_ONE == 0 {
+        return;
+    }
+
+    let packet = if len == 0 {
+        byte as u32
+    } else {
+        PACKET.load(Ordering::Relaxed) | (byte as u32) << (len * 8)
+    };
+
+    if len < 2 {
+        PACKET.store(packet, Ordering::Relaxed);
+        PACKET_LEN.store(len + 1, Ordering::Relaxed);
+        return;
+    }
+
+    PACKET_LEN.store(0, Ordering::Relaxed);
+    if let Some(event) = decode_packet(packet.to_le_bytes()) {
+        input::push(InputEvent::Mouse(event));
+    }
+}
+
+/// Turn a complete packet into an event. Packets that overflowed carry junk movement and are
+/// dropped.
+fn decode_packet([flags, x, y, _]: [u8; 4]) -> Option<MouseEvent> {
+    if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+        return None;
+    }
+
+    // Movement is 9-bit two's complement, with the sign bit in the flags byte
+    let dx = x as i16 - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+    let dy = y as i16 - if flags & Y_SIGN != 0 { 0x100 } else { 0 };
+
+    Some(MouseEvent {
+        dx,
+        dy,
+        left: flags & LEFT_BUTTON != 0,
+        right: flags & RIGHT_BUTTON != 0,
+        middle: flags & MIDDLE_BUTTON != 0,
+    })
+}
+
+pub fn init() {
+    if !ps2::has_port(Port::Second) {
+        log::debug!("No second PS/2 port, mouse unavailable");
+        return;
+    }
+
+    let result = ps2::with_controller(|| {
+        ps2::send_device(Port::Second, SET_DEFAULTS)?;
+        ps2::send_device(Port::Second, ENABLE_REPORTING)
+    });
+
+    match result {
+        Ok(()) => {
+            idt::unmask_irq(IRQ);
+            log::debug!("PS/2 mouse enabled");
+        }
+        Err(e) => log::warn!("Failed to enable PS/2 mouse: {}", e),
+    }
+}
@@ -0,0 +1,279 @@
+//! The 8042 PS/2 controller, shared by the keyboard (first port) and mouse (second port).
+//!
+//! Everything that talks to the controller or a device behind it goes through here, so the two
+//! drivers can't interleave their command bytes. Multi-byte exchanges run under
+//! `with_controller`, which also keeps the IRQ handlers from swallowing the replies.
+//!
+//! The IRQ handler only reads the byte out of the controller. Decoding happens in a bottom half
+//! (see `workqueue`), which hands the bytes to the keyboard and mouse drivers in the order they
+//! arrived.
+
+use crate::arch::{self, x86_64::inb, x86_64::outb};
+use crate::drivers::ring::RingBuffer;
+use crate::drivers::{keyboard, mouse};
+use crate::workqueue;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+pub const DATA_PORT: u16 = 0x60;
+/// Status register when read, command register when written
+pub const STATUS_PORT: u16 = 0x64;
+pub const COMMAND_PORT: u16 = 0x64;
+
+/// Status register bits
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// Controller commands
+pub mod cmd {
+    pub const READ_CONFIG: u8 = 0x20;
+    pub const WRITE_CONFIG: u8 = 0x60;
+    pub const DISABLE_SECOND_PORT: u8 = 0xA7;
+    pub const ENABLE_SECOND_PORT: u8 = 0xA8;
+    pub const TEST_SECOND_PORT: u8 = 0xA9;
+    pub const SELF_TEST: u8 = 0xAA;
+    pub const TEST_FIRST_PORT: u8 = 0xAB;
+    pub const DISABLE_FIRST_PORT: u8 = 0xAD;
+    pub const ENABLE_FIRST_PORT: u8 = 0xAE;
+    pub const WRITE_SECOND_PORT: u8 = 0xD4;
+    pub const PULSE_RESET: u8 = 0xFE;
+}
+
+/// Controller configuration byte bits
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// Replies to controller tests
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// Device replies
+const DEVICE_ACK: u8 = 0xFA;
+const DEVICE_RESEND: u8 = 0xFE;
+const DEVICE_RETRIES: usize = 3;
+
+/// How long to poll the status register before giving up
+const TIMEOUT_US: u64 = 10_000;
+const POLL_INTERVAL_US: u64 = 10;
+
+/// One of the controller's two device ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    /// Usually the keyboard
+    First,
+    /// Usually the mouse
+    Second,
+}
+
+static CONTROLLER: Mutex<()> = Mutex::new(());
+
+static FIRST_PORT: AtomicBool = AtomicBool::new(false);
+static SECOND_PORT: AtomicBool = AtomicBool::new(false);
+
+/// Bytes read by the IRQ handlers and not decoded yet. Both handlers run as interrupt gates and
+/// can't interrupt each other, and only the bottom half pops, so it has one producer and one
+/// consumer.
+static RECEIVED: RingBuffer<(Port, u8), 64> = RingBuffer::new();
+/// Bytes dropped because the bottom half fell behind
+static OVERRUNS: AtomicU64 = AtomicU64::new(0);
+
+/// Run `f` with exclusive access to the controller and interrupts disabled, so neither the other
+/// driver nor an IRQ handler reads bytes meant for us
+pub fn with_controller<R>(f: impl FnOnce() -> R) -> R {
+    arch::without_interrupts(|| {
+        let _controller = CONTROLLER.lock();
+        f()
+    })
+}
+
+fn poll(mut ready: impl FnMut(u8) -> bool, what: &'static str) -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT_US / POLL_INTERVAL_US {
+        if ready(inb(STATUS_PORT)) {
+            return Ok(());
+        }
+        arch::delay_us(POLL_INTERVAL_US);
+    }
+
+    Err(what)
+}
+
+/// Wait until the controller has taken the last byte we wrote
+pub fn wait_input_clear() -> Result<(), &'static str> {
+    poll(
+        |status| status & STATUS_INPUT_FULL == 0,
+        "PS/2 controller input buffer stayed full",
+    )
+}
+
+/// Wait until there's a byte to read
+pub fn wait_output_full() -> Result<(), &'static str> {
+    poll(
+        |status| status & STATUS_OUTPUT_FULL != 0,
+        "PS/2 controller output buffer stayed empty",
+    )
+}
+
+pub fn write_command(command: u8) -> Result<(), &'static str> {
+    wait_input_clear()?;
+    outb(COMMAND_PORT, command);
+    Ok(())
+}
+
+pub fn write_data(data: u8) -> Result<(), &'static str> {
+    wait_input_clear()?;
+    outb(DATA_PORT, data);
+    Ok(())
+}
+
+pub fn read_data() -> Result<u8, &'static str> {
+    wait_output_full()?;
+    Ok(inb(DATA_PORT))
+}
+
+/// Read the byte that caused a PS/2 IRQ, it's already waiting so there's nothing to poll
+#[inline]
+pub fn read_data_irq() -> u8 {
+    inb(DATA_PORT)
+}
+
+/// IRQ handler for either port: take the byte and leave decoding to `process_received`
+pub fn handle_interrupt(port: Port) {
+    let byte = read_data_irq();
+    if RECEIVED.push((port, byte)).is_err() {
+        OVERRUNS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // With the work queue full, the next byte's interrupt tries again
+    let _ = workqueue::schedule(process_received);
+}
+
+/// Bottom half of `handle_interrupt`
+fn process_received() {
+    while let Some((port, byte)) = RECEIVED.pop() {
+        match port {
+            Port::First => keyboard::handle_byte(byte),
+            Port::Second => mouse::handle_byte(byte),
+        }
+    }
+}
+
+/// Bytes dropped because they came in faster than they were decoded
+pub fn overrun_count() -> u64 {
+    OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Throw away any bytes sitting in the output buffer
+pub fn flush_output() {
+    while inb(STATUS_PORT) & STATUS_OUTPUT_FULL != 0 {
+        inb(DATA_PORT);
+    }
+}
+
+/// Whether `port` exists and passed its self test
+pub fn has_port(port: Port) -> bool {
+    match port {
+        Port::First => FIRST_PORT.load(Ordering::Relaxed),
+        Port::Second => SECOND_PORT.load(Ordering::Relaxed),
+    }
+}
+
+fn read_config() -> Result<u8, &'static str> {
+    write_command(cmd::READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), &'static str> {
+    write_command(cmd::WRITE_CONFIG)?;
+    write_data(config)
+}
+
+fn test_port(command: u8) -> Result<bool, &'static str> {
+    write_command(command)?;
+    Ok(read_data()? == PORT_TEST_PASSED)
+}
+
+/// Reset the controller to a known state: self test it, find out whether it has a second port,
+/// test both ports and enable the ones that work along with their IRQs
+fn init_controller() -> Result<(bool, bool), &'static str> {
+    // Keep the devices quiet while we poke at the controller
+    write_command(cmd::DISABLE_FIRST_PORT)?;
+    write_command(cmd::DISABLE_SECOND_PORT)?;
+    flush_output();
+
+    // Translation stays on: the keyboard driver decodes scancode set 1, which is what the
+    // controller translates the keyboard's native set 2 into
+    let mut config = read_config()?;
+    config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+    config |= CONFIG_TRANSLATION;
+    write_config(config)?;
+
+    write_command(cmd::SELF_TEST)?;
+    if read_data()? != SELF_TEST_PASSED {
+        return Err("PS/2 controller failed its self test");
+    }
+    // Some controllers reset themselves during the self test
+    write_config(config)?;
+
+    // With the second port disabled its clock bit reads as set, enabling it must clear the bit
+    // if the port actually exists
+    let mut dual = false;
+    if config & CONFIG_SECOND_CLOCK_DISABLED != 0 {
+        write_command(cmd::ENABLE_SECOND_PORT)?;
+        dual = read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        write_command(cmd::DISABLE_SECOND_PORT)?;
+    }
+
+    let first = test_port(cmd::TEST_FIRST_PORT)?;
+    let second = dual && test_port(cmd::TEST_SECOND_PORT)?;
+
+    // The clock bits mirror the enable commands, so they have to agree or writing the config
+    // back would disable the port again
+    if first {
+        write_command(cmd::ENABLE_FIRST_PORT)?;
+        config = (config | CONFIG_FIRST_IRQ) & !CONFIG_FIRST_CLOCK_DISABLED;
+    }
+    if second {
+        write_command(cmd::ENABLE_SECOND_PORT)?;
+        config = (config | CONFIG_SECOND_IRQ) & !CONFIG_SECOND_CLOCK_DISABLED;
+    }
+    write_config(config)?;
+
+    Ok((first, second))
+}
+
+pub fn init() {
+    match with_controller(init_controller) {
+        Ok((first, second)) => {
+            FIRST_PORT.store(first, Ordering::Relaxed);
+            SECOND_PORT.store(second, Ordering::Relaxed);
+            log::info!(
+                "PS/2 controller: first port {}, second port {}",
+                if first { "ok" } else { "missing" },
+                if second { "ok" } else { "missing" },
+            );
+        }
+        Err(e) => log::warn!("{}, PS/2 devices unavailable", e),
+    }
+}
+
+/// Send a byte to the device on `port` and wait for it to acknowledge, resending if asked to.
+/// Must be called inside `with_controller`.
+pub fn send_device(port: Port, byte: u8) -> Result<(), &'static str> {
+    for _ in 0..DEVICE_RETRIES {
+        if port == Port::Second {
+            write_command(cmd::WRITE_SECOND_PORT)?;
+        }
+        write_data(byte)?;
+
+        match read_data()? {
+            DEVICE_ACK => return Ok(()),
+            DEVICE_RESEND => continue,
+            _ => return Err("PS/2 device sent an unexpected reply"),
+        }
+    }
+
+    Err("PS/2 device kept asking for a resend")
+}
@@ -0,0 +1,79 @@
+//! Fixed-size single-producer single-consumer ring buffer.
+//!
+//! Meant for handing data from an interrupt handler to the rest of the kernel: neither side takes
+//! a lock or allocates, so the producer can't deadlock against code it interrupted.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Ring buffer of `N` slots, `N` must be a power of two.
+/// Only one context may push and only one may pop at a time.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Next slot to read, only advanced by the consumer
+    head: AtomicUsize,
+    /// Next slot to write, only advanced by the producer
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    const MASK: usize = {
+        assert!(N.is_power_of_two(), "RingBuffer size must be a power of two");
+        N - 1
+    };
+
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append a value, handing it back if the buffer is full. Producer side only.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) >= N {
+            return Err(value);
+        }
+
+        unsafe { (*self.slots[tail & Self::MASK].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Take the oldest value. Consumer side only.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*self.slots[head & Self::MASK].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
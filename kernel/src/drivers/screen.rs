@@ -1,18 +1,51 @@
-use crate::BootInfo;
+use crate::{BootInfo, FramebufferInfo};
+use crate::arch;
+use crate::arch::x86_64::{idt, percpu};
+use crate::drivers::{console, font, vbe, vga_text};
+use crate::mem::mmio;
+use crate::proc::scheduler;
+use crate::sync::{IrqSpinlock, IrqSpinlockGuard};
 use derivative::Derivative;
 use spin::Mutex;
 
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A colour with 8 bits per channel, converted to the framebuffer's format when drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
 
 // TODO: Support more than default RGB
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Screen {
     address: usize,
+    /// Physical address of the framebuffer, for remapping it after a mode change
+    phys_address: u64,
 
     #[derivative(Debug = "ignore")]
     buffer: Vec<u8>,
 
+    /// No back buffer could be allocated, drawing goes straight to the framebuffer
+    direct: bool,
+
+    /// Rows `[start, end)` of the back buffer changed since the last sync
+    dirty: Option<(usize, usize)>,
+
+    /// VBE controller info from the bootloader, 0 if there was none
+    vbe_info: u64,
+
     // metadata
     pub width: u32,
     pub height: u32,
@@ -32,7 +65,11 @@ impl Screen {
     pub const fn new() -> Self {
         Self {
             address: 0,
+            phys_address: 0,
             buffer: Vec::new(),
+            direct: false,
+            dirty: None,
+            vbe_info: 0,
             width: 0,
             height: 0,
             bits_per_pixel: 0,
@@ -46,15 +83,19 @@ impl Screen {
         }
     }
 
-    pub fn init(&mut self, boot_info: &BootInfo) {
-        let info = boot_info.framebuffer;
-        let address = info.address as usize;
+    /// Set up the screen from the bootloader's framebuffer info and allocate the back buffer.
+    /// `address` is where the framebuffer is mapped, see `map_framebuffer`.
+    /// On error the metadata is still valid, only the back buffer is missing.
+    pub fn init(&mut self, boot_info: &BootInfo, address: usize) -> Result<(), &'static str> {
+        self.vbe_info = boot_info.vbe_info;
+        self.configure(&boot_info.framebuffer, address)
+    }
 
+    /// Take the geometry and pixel format from `info` and allocate a fresh back buffer for it,
+    /// dropping the old one first. `address` is where the framebuffer is mapped.
+    fn configure(&mut self, info: &FramebufferInfo, address: usize) -> Result<(), &'static str> {
         self.address = address;
-
-        // calculate new buffer size
-        let buffer_size = (info.width as usize) * (info.height as usize) * (info.bpp as usize) / 8;
-        self.buffer.resize(buffer_size, 0);
+        self.phys_address = info.address;
 
         self.width = info.width;
         self.height = info.height;
@@ -76,40 +117,651 @@ impl Screen {
             self.green_mask,
             self.blue_mask,
         );
+
+        // High resolutions need a back buffer of tens of MiB, which the heap may not be able to
+        // provide this early, so don't let the allocation failure abort the boot
+        self.buffer = Vec::new();
+        self.direct = false;
+        self.dirty = None;
+        let buffer_size = (info.width as usize) * (info.height as usize) * (info.bpp as usize) / 8;
+        self.buffer
+            .try_reserve_exact(buffer_size)
+            .map_err(|_| "Failed to allocate screen back buffer")?;
+        self.buffer.resize(buffer_size, 0);
+
+        Ok(())
+    }
+
+    /// A screen with a zeroed back buffer of `width` by `height` pixels, `bpp` bits each, that
+    /// syncs to `address`, where rows are `stride` bytes apart. For timing syncs without touching
+    /// `SCREEN`.
+    pub fn with_back_buffer(
+        address: usize,
+        width: u32,
+        height: u32,
+        stride: u32,
+        bpp: u8,
+    ) -> Result<Self, &'static str> {
+        let mut screen = Self {
+            address,
+            width,
+            height,
+            stride,
+            bits_per_pixel: bpp,
+            ..Self::new()
+        };
+
+        let buffer_size = screen.row_bytes() * height as usize;
+        screen
+            .buffer
+            .try_reserve_exact(buffer_size)
+            .map_err(|_| "Failed to allocate screen back buffer")?;
+        screen.buffer.resize(buffer_size, 0);
+
+        Ok(screen)
+    }
+
+    /// Whether drawing goes straight to the framebuffer instead of a back buffer
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Mark rows `[y_start, y_end)` as changed, so the next `sync_dirty` copies them
+    pub fn mark_dirty(&mut self, y_start: usize, y_end: usize) {
+        let y_end = y_end.min(self.height as usize);
+        if y_start >= y_end {
+            return;
+        }
+
+        self.dirty = Some(match self.dirty {
+            Some((start, end)) => (start.min(y_start), end.max(y_end)),
+            None => (y_start, y_end),
+        });
+    }
+
+    /// Mark the whole screen as changed
+    pub fn mark_all_dirty(&mut self) {
+        self.mark_dirty(0, self.height as usize);
     }
 
+    /// Copy the whole back buffer to the framebuffer, honouring the framebuffer's stride.
     pub fn sync(&self) {
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                self.buffer.as_ptr(),
-                self.address as *mut u8,
-                self.buffer.len(),
-            );
+        self.sync_rows(0, self.height as usize);
+    }
+
+    /// Copy only the rows changed since the last sync to the framebuffer
+    pub fn sync_dirty(&mut self) {
+        if let Some((start, end)) = self.dirty.take() {
+            self.sync_rows(start, end);
+        }
+    }
+
+    fn sync_rows(&self, y_start: usize, y_end: usize) {
+        // Without a back buffer everything is already on screen
+        if self.direct || y_start >= y_end {
+            return;
+        }
+
+        let row_bytes = self.row_bytes();
+        let stride = self.stride as usize;
+
+        // Fast path: rows are tightly packed, so the span is one contiguous copy
+        if stride == row_bytes {
+            let offset = y_start * row_bytes;
+            let len = (y_end - y_start) * row_bytes;
+            unsafe {
+                copy_fast(
+                    (self.address + offset) as *mut u8,
+                    self.buffer[offset..offset + len].as_ptr(),
+                    len,
+                );
+            }
+            return;
+        }
+
+        let rows = self.buffer.chunks_exact(row_bytes).enumerate();
+        for (y, row) in rows.skip(y_start).take(y_end - y_start) {
+            unsafe {
+                copy_fast((self.address + y * stride) as *mut u8, row.as_ptr(), row_bytes);
+            }
         }
     }
 
+    /// Bytes in one row of the (tightly packed) back buffer
+    fn row_bytes(&self) -> usize {
+        self.width as usize * self.bits_per_pixel as usize / 8
+    }
+
+    /// Bytes between rows of what `buffer_mut` returns
+    fn pitch(&self) -> usize {
+        if self.direct { self.stride as usize } else { self.row_bytes() }
+    }
+
+    /// Get the buffer to draw into: the back buffer, or the framebuffer itself in direct mode
+    /// (where rows are `stride` bytes apart rather than tightly packed).
+    /// Anything may be drawn through it, so the whole screen is marked dirty.
     pub fn get_buffer(&mut self) -> &mut [u8] {
+        self.mark_all_dirty();
+        self.buffer_mut()
+    }
+
+    /// Pixels of row `y` as last drawn: from the back buffer, or the framebuffer in direct mode
+    pub fn row(&self, y: usize) -> &[u8] {
+        let row_bytes = self.row_bytes();
+
+        if self.direct {
+            let start = self.address + y * self.stride as usize;
+            return unsafe { core::slice::from_raw_parts(start as *const u8, row_bytes) };
+        }
+
+        &self.buffer[y * row_bytes..(y + 1) * row_bytes]
+    }
+
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        if self.direct {
+            let len = self.stride as usize * self.height as usize;
+            return unsafe { core::slice::from_raw_parts_mut(self.address as *mut u8, len) };
+        }
+
         &mut self.buffer
     }
 
+    /// `colour` as a pixel value in the framebuffer's format
+    pub fn encode(&self, colour: Rgb) -> u32 {
+        // The masks are channel sizes in bits
+        let channel = |value: u8, shift: u8, size: u8| {
+            ((value as u32) >> (8 - size.min(8))) << shift
+        };
+
+        channel(colour.r, self.red_shift, self.red_mask)
+            | channel(colour.g, self.green_shift, self.green_mask)
+            | channel(colour.b, self.blue_shift, self.blue_mask)
+    }
+
+    /// Fill a rectangle, clipped to the screen
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, colour: Rgb) {
+        let bytes = self.bits_per_pixel as usize / 8;
+        if bytes == 0 {
+            return;
+        }
+
+        let pitch = self.pitch();
+        let x_end = x.saturating_add(width).min(self.width as usize);
+        let y_end = y.saturating_add(height).min(self.height as usize);
+        let value = self.encode(colour).to_le_bytes();
+
+        let buffer = self.buffer_mut();
+        for row in y..y_end {
+            for column in x..x_end {
+                let offset = row * pitch + column * bytes;
+                buffer[offset..offset + bytes].copy_from_slice(&value[..bytes]);
+            }
+        }
+
+        self.mark_dirty(y, y_end);
+    }
+
+    /// Draw a `width` by `height` block of pixels with its top left corner at (`x`, `y`), clipped
+    /// to the screen. `pixel(column, row)` gives the colour at that offset into the block.
+    pub fn draw_pixels(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        mut pixel: impl FnMut(usize, usize) -> Rgb,
+    ) {
+        let bytes = self.bits_per_pixel as usize / 8;
+        if bytes == 0 {
+            return;
+        }
+
+        let pitch = self.pitch();
+        let x_end = x.saturating_add(width).min(self.width as usize);
+        let y_end = y.saturating_add(height).min(self.height as usize);
+
+        for row in y..y_end {
+            for column in x..x_end {
+                let value = self.encode(pixel(column - x, row - y)).to_le_bytes();
+                let offset = row * pitch + column * bytes;
+                self.buffer_mut()[offset..offset + bytes].copy_from_slice(&value[..bytes]);
+            }
+        }
+
+        self.mark_dirty(y, y_end);
+    }
+
     pub fn write(&mut self, data: &[u8]) {
-        let buffer = self.get_buffer();
+        let row_bytes = if self.direct { self.stride as usize } else { self.row_bytes() };
+
+        let buffer = self.buffer_mut();
         let len = data.len().min(buffer.len());
 
         buffer[..len].copy_from_slice(&data[..len]);
+
+        self.mark_dirty(0, len.div_ceil(row_bytes.max(1)));
+    }
+}
+
+/// Copy `len` bytes eight at a time with `rep movsq`, finishing any tail that isn't a multiple
+/// of 8 with `rep movsb`. Framebuffer rows are at least 4-byte aligned in practice, which keeps
+/// the quadword stores from splitting across too many bus transactions.
+///
+/// # Safety
+/// `src` and `dst` must be valid for `len` bytes and must not overlap.
+unsafe fn copy_fast(dst: *mut u8, src: *const u8, len: usize) {
+    unsafe {
+        core::arch::asm!(
+            "rep movsq",
+            "mov rcx, {tail}",
+            "rep movsb",
+            tail = in(reg) len % 8,
+            inout("rcx") len / 8 => _,
+            inout("rdi") dst => _,
+            inout("rsi") src => _,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// `copy_fast` copies exactly what `copy_nonoverlapping` does for every length short of two
+/// quadwords, from and to every alignment, and leaves the bytes around the destination alone
+pub fn selftest_copy_fast() -> Result<(), &'static str> {
+    const GUARD: u8 = 0xA5;
+
+    let source: [u8; 32] = core::array::from_fn(|i| i as u8 + 1);
+    for len in 1..=15 {
+        for src_offset in 0..8 {
+            for dst_offset in 0..8 {
+                let mut expected = [GUARD; 32];
+                let mut got = [GUARD; 32];
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        source[src_offset..].as_ptr(),
+                        expected[dst_offset..].as_mut_ptr(),
+                        len,
+                    );
+                    copy_fast(
+                        got[dst_offset..].as_mut_ptr(),
+                        source[src_offset..].as_ptr(),
+                        len,
+                    );
+                }
+
+                if got != expected {
+                    log::error!(
+                        "copy_fast of {} bytes from offset {} to offset {} gave {:02x?}",
+                        len,
+                        src_offset,
+                        dst_offset,
+                        got
+                    );
+                    return Err("copy_fast doesn't match copy_nonoverlapping");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The screen, shared by everything that draws.
+///
+/// Locking it disables interrupts, so interrupt handlers (and the panic handler) can draw without
+/// deadlocking against the code they interrupted. In return, the lock must only be held for one
+/// frame or one piece of text at a time: draw, sync, release. Don't take it in one function and
+/// then call something that may block or log heavily while holding it.
+pub static SCREEN: IrqSpinlock<Screen> = IrqSpinlock::new(Screen::new());
+
+/// Find a virtual address the framebuffer can be reached through.
+/// The identity map only covers the low 4 GiB, and UEFI firmware likes to put VRAM above that,
+/// in which case the framebuffer gets its own mapping in the MMIO window.
+pub fn map_framebuffer(info: &FramebufferInfo) -> Result<usize, &'static str> {
+    use crate::arch::paging;
+    use crate::mem::{PAGE_SIZE, mmio, page_align_down};
+
+    let len = info.pitch as u64 * info.height as u64;
+    if len == 0 {
+        return Err("Framebuffer has no size");
+    }
+
+    let start = page_align_down(info.address);
+    let end = info.address.checked_add(len).ok_or("Framebuffer range overflows")?;
+    let identity_mapped =
+        (start..end).step_by(PAGE_SIZE).all(|page| paging::translate(page) == Some(page));
+
+    if identity_mapped {
+        return Ok(info.address as usize);
+    }
+
+    let virt = mmio::map(info.address, len as usize)?;
+    log::debug!(
+        "Framebuffer at {:#x} is outside the identity map, mapped at {:p}",
+        info.address,
+        virt
+    );
+
+    Ok(virt as usize)
+}
+
+/// Remap the framebuffer write-combining, so the CPU can batch writes to VRAM into bursts
+/// instead of doing an uncached bus transaction per store
+fn map_write_combining(screen: &Screen) {
+    use crate::arch::paging::{self, flags};
+    use crate::arch::x86_64::rdtsc;
+
+    if !paging::write_combining_available() {
+        log::debug!("Write-combining unavailable, framebuffer keeps default caching");
+        return;
     }
+
+    let len = screen.stride as u64 * screen.height as u64;
+
+    let start = rdtsc();
+    screen.sync();
+    let before = rdtsc() - start;
+
+    if let Err(e) = paging::update_range_flags(
+        screen.address as u64,
+        len,
+        flags::WRITE_COMBINING,
+        flags::CACHE_DISABLE,
+    ) {
+        log::warn!("Failed to map framebuffer write-combining: {}", e);
+        return;
+    }
+
+    let start = rdtsc();
+    screen.sync();
+    let after = rdtsc() - start;
+
+    log::debug!(
+        "Framebuffer mapped write-combining, full sync took {} kcycles (was {} kcycles)",
+        after / 1000,
+        before / 1000
+    );
+}
+
+/// Compare a full-frame sync against a dirty-only sync of a text-line sized span
+fn benchmark_sync(screen: &mut Screen) {
+    use crate::arch::x86_64::rdtsc;
+
+    if screen.direct {
+        return;
+    }
+
+    const DIRTY_ROWS: usize = 16;
+
+    let start = rdtsc();
+    screen.sync();
+    let full = rdtsc() - start;
+
+    screen.mark_dirty(0, DIRTY_ROWS);
+    let start = rdtsc();
+    screen.sync_dirty();
+    let dirty = rdtsc() - start;
+
+    log::debug!(
+        "Screen sync at {}x{}: full frame {} kcycles, {} dirty rows {} kcycles",
+        screen.width,
+        screen.height,
+        full / 1000,
+        DIRTY_ROWS,
+        dirty / 1000
+    );
+}
+
+/// What the screen is cleared to at init and behind the boot splash
+static CLEAR_COLOUR: Mutex<Rgb> = Mutex::new(Rgb::new(0x00, 0x00, 0x00));
+
+/// Set the colour the screen is cleared to. Takes effect at the next clear, so call it before
+/// `init` to change what the bootloader's picture is replaced with.
+pub fn set_clear_color(r: u8, g: u8, b: u8) {
+    *CLEAR_COLOUR.lock() = Rgb::new(r, g, b);
 }
 
-pub static SCREEN: Mutex<Screen> = Mutex::new(Screen::new());
+pub fn clear_color() -> Rgb {
+    *CLEAR_COLOUR.lock()
+}
+
+/// Parse `clearcolor=RRGGBB` (hex, optionally starting with `#`) from the command line
+fn configure_clear_color(boot_info: &BootInfo) {
+    let Some(value) = boot_info.cmdline_option("clearcolor") else {
+        return;
+    };
+
+    let hex = value.trim_start_matches('#');
+    match u32::from_str_radix(hex, 16) {
+        Ok(rgb) if hex.len() == 6 => {
+            set_clear_color((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+        }
+        _ => log::warn!("Ignoring invalid clearcolor value {:?}", value),
+    }
+}
 
 pub fn init(boot_info: &BootInfo) {
+    configure_clear_color(boot_info);
+
     let mut screen = SCREEN.lock();
-    screen.init(boot_info);
+
+    if vga_text::is_active() {
+        log::info!("Display is in VGA text mode, no framebuffer to draw to");
+        return;
+    }
+
+    // Nothing can be drawn without a mapping, leave the screen zero-sized
+    let address = match map_framebuffer(&boot_info.framebuffer) {
+        Ok(address) => address,
+        Err(e) => {
+            log::error!("Screen disabled: {}", e);
+            return;
+        }
+    };
+    log::info!(
+        "Framebuffer {:#x} mapped at {:#x}",
+        boot_info.framebuffer.address,
+        address
+    );
+
+    match screen.init(boot_info, address) {
+        Ok(()) => log::info!(
+            "Screen {}x{}: double buffered ({} KiB back buffer)",
+            screen.width,
+            screen.height,
+            screen.buffer.len() / 1024
+        ),
+        Err(e) => {
+            screen.direct = true;
+            log::warn!(
+                "Screen {}x{}: {}, drawing directly to the framebuffer",
+                screen.width,
+                screen.height,
+                e
+            );
+        }
+    }
+
+    map_write_combining(&screen);
+    benchmark_sync(&mut screen);
+
+    // Replace whatever the bootloader left on screen
+    let (width, height) = (screen.width as usize, screen.height as usize);
+    screen.fill_rect(0, 0, width, height, clear_color());
+    screen.sync_dirty();
+
+    // Everything drawn directly is already on screen, there's nothing to flush
+    if !screen.direct {
+        start_flusher();
+    }
 }
 
+fn start_flusher() {
+    match scheduler::spawn_kernel(flush_thread, FLUSHER_STACK_SIZE) {
+        Ok(tid) => log::debug!("Screen flusher running as thread {}", tid),
+        Err(e) => log::warn!("No screen flusher ({}), callers must sync themselves", e),
+    }
+}
+
+/// Clear the screen and draw a logo with the kernel version under it. Does nothing in VGA text
+/// mode or without a framebuffer, returns whether the splash was drawn.
+pub fn boot_splash() -> bool {
+    if vga_text::is_active() {
+        return false;
+    }
+
+    let background = clear_color();
+    let foreground = console::theme().foreground;
+    let colours = (foreground, background);
+
+    let mut screen = SCREEN.lock();
+    let (width, height) = (screen.width as usize, screen.height as usize);
+    if width == 0 || height == 0 {
+        return false;
+    }
+    screen.fill_rect(0, 0, width, height, background);
+
+    // The logo is the name in large letters inside a box, a little above the middle
+    const NAME: &str = "viceOS";
+    let scale = (width / 160).clamp(1, 8);
+    let padding = font::HEIGHT * scale;
+    let logo_width = NAME.len() * font::WIDTH * scale + 2 * padding;
+    let logo_height = font::HEIGHT * scale + 2 * padding;
+    let logo_x = width.saturating_sub(logo_width) / 2;
+    let logo_y = (height / 2).saturating_sub(logo_height);
+
+    screen.fill_rect(logo_x, logo_y, logo_width, logo_height, foreground);
+    screen.fill_rect(
+        logo_x + scale,
+        logo_y + scale,
+        logo_width.saturating_sub(2 * scale),
+        logo_height.saturating_sub(2 * scale),
+        background,
+    );
+    for (i, c) in NAME.chars().enumerate() {
+        let x = logo_x + padding + i * font::WIDTH * scale;
+        console::draw_char(&mut screen, x, logo_y + padding, c, scale, colours);
+    }
+
+    let version = concat!("version ", env!("CARGO_PKG_VERSION"));
+    let (cell_width, cell_height) = console::font_for(&screen).cell_size();
+    let version_x = width.saturating_sub(version.len() * cell_width) / 2;
+    let version_y = logo_y + logo_height + cell_height;
+    console::draw_text(&mut screen, version_x, version_y, version, colours);
+
+    drop(screen);
+    request_redraw();
+    true
+}
+
+/// Time between flushes, ~60 Hz
+const FRAME_INTERVAL_MS: u64 = 16;
+const FLUSHER_STACK_SIZE: usize = 16 * 1024;
+
+/// Set by `request_redraw` to flush before the next frame is due
+static REDRAW_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Copy the dirty rows to the framebuffer every frame, or as soon as a redraw is requested.
+///
+/// Scheduling is cooperative, so this only runs when drawing code yields. Drawers hold the screen
+/// lock for a whole frame, so a flush never catches one half drawn.
+fn flush_thread() {
+    let mut next_frame = idt::uptime_ms();
+
+    loop {
+        let now = idt::uptime_ms();
+        if REDRAW_REQUESTED.swap(false, Ordering::Relaxed) || now >= next_frame {
+            SCREEN.lock().sync_dirty();
+            next_frame = now + FRAME_INTERVAL_MS;
+        }
+
+        scheduler::yield_now();
+
+        // Alone on this CPU, sleep until the next timer tick rather than spinning
+        if arch::without_interrupts(|| percpu::current().run_queue.lock().ready_count()) == 0 {
+            arch::halt();
+        }
+    }
+}
+
+/// Have the flusher present what's been drawn the next time it runs, instead of waiting for the
+/// next frame
+pub fn request_redraw() {
+    REDRAW_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Append `data` to `out` as padded base64
+fn base64_encode(data: &[u8], out: &mut String) {
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+/// Write what's currently drawn to serial, so it can be reconstructed on the host.
+///
+/// The output is a header line with the geometry and pixel format, then one base64 line per row
+/// (so a mangled line only costs one row), between BEGIN/END markers. The screen and serial locks
+/// are only held for one row at a time, so drawing and logging carry on during the dump, at the
+/// cost of rows possibly coming from different frames. Mind that a 1080p dump is ~11 MB of text,
+/// which takes a while at 115200 baud.
+pub fn dump() {
+    use crate::arch::x86_64::serial;
+    use core::fmt::Write;
+
+    let (width, height, bpp, format) = {
+        let screen = SCREEN.lock();
+        let format = alloc::format!(
+            "red={}:{} green={}:{} blue={}:{}",
+            screen.red_shift,
+            screen.red_mask,
+            screen.green_shift,
+            screen.green_mask,
+            screen.blue_shift,
+            screen.blue_mask
+        );
+        (screen.width, screen.height, screen.bits_per_pixel, format)
+    };
+
+    serial::with_port(|ser| {
+        let _ = write!(
+            ser,
+            "-----BEGIN VICEOS SCREEN-----\nwidth={} height={} bpp={} {}\n",
+            width, height, bpp, format
+        );
+    });
+
+    let mut line = String::new();
+    for y in 0..height as usize {
+        line.clear();
+        base64_encode(SCREEN.lock().row(y), &mut line);
+        line.push('\n');
+
+        serial::with_port(|ser| ser.write_string(&line));
+    }
+
+    serial::with_port(|ser| ser.write_string("-----END VICEOS SCREEN-----\n"));
+}
+
+/// Copy everything drawn since the last sync to the framebuffer right away. Not needed when the
+/// flusher thread is running, unless the caller won't yield for a while.
 pub fn sync() {
-    let screen = SCREEN.lock();
-    screen.sync();
+    let mut screen = SCREEN.lock();
+    screen.sync_dirty();
 }
 
 pub fn write(data: &[u8]) {
@@ -117,10 +769,143 @@ pub fn write(data: &[u8]) {
     screen.write(data);
 }
 
-pub fn get_buffer() -> spin::MutexGuard<'static, Screen> {
+pub fn get_buffer() -> IrqSpinlockGuard<'static, Screen> {
     SCREEN.lock()
 }
 
+/// Get the screen from the panic handler. If the lock is held, whoever holds it was interrupted
+/// by the panic and won't finish, so it's broken rather than waited on. The drawing it was doing
+/// may be half done, which is fine for a screen that's about to show a panic message.
+pub fn lock_for_panic() -> IrqSpinlockGuard<'static, Screen> {
+    if let Some(screen) = SCREEN.try_lock() {
+        return screen;
+    }
+
+    unsafe { SCREEN.force_unlock() };
+    SCREEN.lock()
+}
+
+/// VBE mode numbers the video card offers, from the info block the bootloader fetched.
+/// Without real mode the kernel can't ask the BIOS about each mode, so only the numbers are known.
+pub fn available_modes() -> Result<Vec<u16>, &'static str> {
+    let vbe_info = SCREEN.lock().vbe_info;
+    let (version, modes) = vbe::modes(vbe_info)?;
+
+    log::debug!(
+        "VBE {}.{}: {} modes available",
+        version >> 8,
+        version & 0xFF,
+        modes.len()
+    );
+
+    Ok(modes)
+}
+
+/// Switch to a `width`x`height` mode with `bpp` (16, 24 or 32) bits per pixel.
+/// Only the Bochs/QEMU display adapter can do this, through its DISPI registers. Elsewhere
+/// changing modes means calling the VBE BIOS, which needs a real-mode trampoline the kernel
+/// doesn't have yet, so pick the resolution in the bootloader instead (the framebuffer tag in
+/// boot_stub.asm or the GRUB config). The framebuffer is remapped and the back buffer
+/// reallocated for the new mode, which starts out cleared.
+pub fn set_mode(width: u32, height: u32, bpp: u8) -> Result<(), &'static str> {
+    let mut screen = SCREEN.lock();
+    let old_mode = (screen.width, screen.height, screen.bits_per_pixel);
+    if old_mode == (width, height, bpp) {
+        return Ok(());
+    }
+
+    if screen.address == 0 {
+        return Err("No framebuffer, the display mode can't be changed");
+    }
+
+    if !matches!(bpp, 16 | 24 | 32) {
+        return Err("Only 16, 24 and 32 bits per pixel are supported");
+    }
+
+    if !vbe::dispi_present() {
+        return Err("Changing the VBE mode needs real-mode BIOS calls, which aren't supported yet");
+    }
+
+    let pitch = match vbe::dispi_set_mode(width, height, bpp) {
+        Ok(pitch) => pitch,
+        Err(e) => {
+            restore_mode(&mut screen, old_mode);
+            return Err(e);
+        }
+    };
+
+    // DISPI modes are little-endian BGR(X), or 5:6:5 at 16 bits
+    let (red_shift, green_shift, red_mask, green_mask, blue_mask) = match bpp {
+        16 => (11, 5, 5, 6, 5),
+        _ => (16, 8, 8, 8, 8),
+    };
+    let info = FramebufferInfo {
+        address: screen.phys_address,
+        width,
+        height,
+        pitch,
+        bpp,
+        red_shift,
+        green_shift,
+        blue_shift: 0,
+        red_mask,
+        green_mask,
+        blue_mask,
+    };
+
+    let old_address = screen.address;
+    let old_len = screen.stride as usize * screen.height as usize;
+    let address = match map_framebuffer(&info) {
+        Ok(address) => address,
+        Err(e) => {
+            restore_mode(&mut screen, old_mode);
+            return Err(e);
+        }
+    };
+    if old_address != screen.phys_address as usize {
+        mmio::unmap(old_address as *mut u8, old_len);
+    }
+
+    let was_direct = screen.direct;
+    if let Err(e) = screen.configure(&info, address) {
+        screen.direct = true;
+        log::warn!(
+            "Screen {}x{}: {}, drawing directly to the framebuffer",
+            width,
+            height,
+            e
+        );
+    }
+    map_write_combining(&screen);
+
+    screen.fill_rect(0, 0, width as usize, height as usize, clear_color());
+    screen.sync_dirty();
+
+    if was_direct && !screen.direct {
+        start_flusher();
+    }
+
+    log::info!("Display mode set to {}x{}x{}", width, height, bpp);
+    Ok(())
+}
+
+/// Put the adapter back in the mode `screen` is still configured for, after a failed switch
+fn restore_mode(screen: &mut Screen, (width, height, bpp): (u32, u32, u8)) {
+    if let Err(e) = vbe::dispi_set_mode(width, height, bpp) {
+        log::error!(
+            "Failed to restore display mode {}x{}x{}: {}",
+            width,
+            height,
+            bpp,
+            e
+        );
+        return;
+    }
+
+    screen.mark_all_dirty();
+    screen.sync_dirty();
+}
+
 pub fn get_info() -> (u32, u32) {
     let screen = SCREEN.lock();
     (screen.width, screen.height)
@@ -0,0 +1,132 @@
+//! VESA BIOS Extensions controller info, as copied by the bootloader.
+//!
+//! Only the information the bootloader already fetched is available. Asking the BIOS for details
+//! of a mode (4F01h) or switching to it (4F02h) needs real mode, which the kernel can't get back
+//! to yet. On the Bochs/QEMU display adapter the mode can still be changed through its DISPI
+//! registers, see `dispi_set_mode`.
+
+use crate::arch::x86_64::{inw, outw};
+use alloc::vec::Vec;
+
+const SIGNATURE: &[u8; 4] = b"VESA";
+
+/// Offsets into the controller info block
+const VERSION_OFFSET: u64 = 4;
+const MODE_LIST_OFFSET: u64 = 14;
+
+/// Terminator of the mode list
+const MODE_LIST_END: u16 = 0xFFFF;
+/// Real-mode pointers can't reach past 1 MiB (plus the HMA)
+const REAL_MODE_LIMIT: u64 = 0x10FFF0;
+/// Stop walking a mode list that has lost its terminator
+const MAX_MODES: usize = 256;
+
+/// VBE version and mode numbers of the controller info block at `info` (physical address)
+pub fn modes(info: u64) -> Result<(u16, Vec<u16>), &'static str> {
+    if info == 0 {
+        return Err("Bootloader provided no VBE information");
+    }
+
+    let (signature, version, mode_ptr) = unsafe {
+        (
+            *(info as *const [u8; 4]),
+            core::ptr::read_unaligned((info + VERSION_OFFSET) as *const u16),
+            core::ptr::read_unaligned((info + MODE_LIST_OFFSET) as *const u32),
+        )
+    };
+
+    if &signature != SIGNATURE {
+        return Err("VBE controller info has a bad signature");
+    }
+
+    // The mode list is a segment:offset far pointer into BIOS (or bootloader) memory, which is
+    // still identity mapped
+    let list = ((mode_ptr >> 16) as u64) * 16 + (mode_ptr & 0xFFFF) as u64;
+    if list == 0 || list + 2 > REAL_MODE_LIMIT {
+        return Err("VBE mode list pointer is invalid");
+    }
+
+    let mut modes = Vec::new();
+    for i in 0..MAX_MODES as u64 {
+        let addr = list + i * 2;
+        if addr + 2 > REAL_MODE_LIMIT {
+            break;
+        }
+
+        let mode = unsafe { core::ptr::read_unaligned(addr as *const u16) };
+        if mode == MODE_LIST_END {
+            break;
+        }
+        modes.push(mode);
+    }
+
+    Ok((version, modes))
+}
+
+/// Bochs DISPI register index and data ports
+const DISPI_INDEX_PORT: u16 = 0x1CE;
+const DISPI_DATA_PORT: u16 = 0x1CF;
+
+/// DISPI registers
+const DISPI_INDEX_ID: u16 = 0;
+const DISPI_INDEX_XRES: u16 = 1;
+const DISPI_INDEX_YRES: u16 = 2;
+const DISPI_INDEX_BPP: u16 = 3;
+const DISPI_INDEX_ENABLE: u16 = 4;
+const DISPI_INDEX_VIRT_WIDTH: u16 = 6;
+
+/// Range of IDs the adapter reports, one per interface revision
+const DISPI_ID_FIRST: u16 = 0xB0C0;
+const DISPI_ID_LAST: u16 = 0xB0C5;
+
+const DISPI_DISABLED: u16 = 0x00;
+const DISPI_ENABLED: u16 = 0x01;
+/// Map VRAM as a linear framebuffer instead of through banks
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+fn dispi_read(index: u16) -> u16 {
+    outw(DISPI_INDEX_PORT, index);
+    inw(DISPI_DATA_PORT)
+}
+
+fn dispi_write(index: u16, value: u16) {
+    outw(DISPI_INDEX_PORT, index);
+    outw(DISPI_DATA_PORT, value);
+}
+
+/// Whether the display is a Bochs/QEMU adapter whose mode can be set through DISPI
+pub fn dispi_present() -> bool {
+    (DISPI_ID_FIRST..=DISPI_ID_LAST).contains(&dispi_read(DISPI_INDEX_ID))
+}
+
+/// Switch the Bochs/QEMU adapter to `width`x`height` at `bpp` bits per pixel, keeping the linear
+/// framebuffer where it was. Returns the new pitch in bytes. The adapter clamps modes that don't
+/// fit in its VRAM, so what it ended up with is read back and anything else is an error.
+pub fn dispi_set_mode(width: u32, height: u32, bpp: u8) -> Result<u32, &'static str> {
+    if !dispi_present() {
+        return Err("No Bochs DISPI adapter");
+    }
+
+    let (Ok(xres), Ok(yres)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err("Mode is too large for DISPI");
+    };
+
+    dispi_write(DISPI_INDEX_ENABLE, DISPI_DISABLED);
+    dispi_write(DISPI_INDEX_XRES, xres);
+    dispi_write(DISPI_INDEX_YRES, yres);
+    dispi_write(DISPI_INDEX_BPP, bpp as u16);
+    dispi_write(DISPI_INDEX_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+
+    let set = (
+        dispi_read(DISPI_INDEX_XRES),
+        dispi_read(DISPI_INDEX_YRES),
+        dispi_read(DISPI_INDEX_BPP),
+    );
+    if set != (xres, yres, bpp as u16) {
+        return Err("Display adapter rejected the mode");
+    }
+
+    // Rows are the virtual width apart, which the adapter resets to the new width
+    let bytes_per_pixel = (bpp as u32).div_ceil(8);
+    Ok(dispi_read(DISPI_INDEX_VIRT_WIDTH) as u32 * bytes_per_pixel)
+}
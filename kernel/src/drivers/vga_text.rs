@@ -0,0 +1,148 @@
+//! Output to the 80x25 VGA text buffer, the last resort when serial doesn't work.
+//!
+//! Only used when the bootloader left the display in text mode (no framebuffer tag), otherwise
+//! 0xB8000 isn't what's on screen.
+
+use crate::BootInfo;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const BUFFER: u64 = 0xB8000;
+const WIDTH: usize = 80;
+const HEIGHT: usize = 25;
+
+/// Light grey on black
+const DEFAULT_ATTRIBUTE: u8 = 0x07;
+/// White on red
+pub const PANIC_ATTRIBUTE: u8 = 0x4F;
+
+/// Whether the display is in text mode
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub struct TextWriter {
+    column: usize,
+    row: usize,
+    attribute: u8,
+    /// Inside an ANSI escape sequence (log colours), which is skipped
+    in_escape: bool,
+}
+
+impl TextWriter {
+    const fn new() -> Self {
+        Self {
+            column: 0,
+            row: 0,
+            attribute: DEFAULT_ATTRIBUTE,
+            in_escape: false,
+        }
+    }
+
+    fn cell(row: usize, column: usize) -> *mut u16 {
+        (BUFFER as *mut u16).wrapping_add(row * WIDTH + column)
+    }
+
+    pub fn set_attribute(&mut self, attribute: u8) {
+        self.attribute = attribute;
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        if self.in_escape {
+            self.in_escape = !byte.is_ascii_alphabetic();
+            return;
+        }
+
+        match byte {
+            0x1B => self.in_escape = true,
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            byte => {
+                if self.column == WIDTH {
+                    self.new_line();
+                }
+
+                // Anything outside printable ASCII shows up as a block
+                let byte = if (0x20..0x7F).contains(&byte) { byte } else { 0xFE };
+                let value = (self.attribute as u16) << 8 | byte as u16;
+                unsafe { Self::cell(self.row, self.column).write_volatile(value) };
+                self.column += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < HEIGHT {
+            self.row += 1;
+            return;
+        }
+
+        // Scroll everything up a line and blank the last one
+        unsafe {
+            core::ptr::copy(Self::cell(1, 0), Self::cell(0, 0), (HEIGHT - 1) * WIDTH);
+        }
+        self.clear_row(HEIGHT - 1);
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        let blank = (self.attribute as u16) << 8 | b' ' as u16;
+        for column in 0..WIDTH {
+            unsafe { Self::cell(row, column).write_volatile(blank) };
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for row in 0..HEIGHT {
+            self.clear_row(row);
+        }
+        self.row = 0;
+        self.column = 0;
+    }
+}
+
+impl Write for TextWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+static WRITER: Mutex<TextWriter> = Mutex::new(TextWriter::new());
+
+/// Check the bootloader left the display in text mode
+pub fn init(boot_info: &BootInfo) {
+    let fb = &boot_info.framebuffer;
+    let text_mode = fb.address == BUFFER && fb.width == WIDTH as u32 && fb.height == HEIGHT as u32;
+    ACTIVE.store(text_mode, Ordering::Relaxed);
+}
+
+/// Whether text written here ends up on screen
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Run `f` against the text writer. Output is dropped if the display isn't in text mode, or if the
+/// writer is busy (e.g. an interrupt fired mid-write), since the cursor can't be shared.
+pub fn with_writer(f: impl FnOnce(&mut TextWriter)) {
+    if !is_active() {
+        return;
+    }
+
+    if let Some(mut writer) = WRITER.try_lock() {
+        f(&mut writer);
+    }
+}
+
+/// Get the writer for the panic handler, breaking the lock if whoever held it was interrupted
+pub fn with_writer_for_panic(f: impl FnOnce(&mut TextWriter)) {
+    if !is_active() {
+        return;
+    }
+
+    if WRITER.is_locked() {
+        unsafe { WRITER.force_unlock() };
+    }
+    f(&mut WRITER.lock());
+}
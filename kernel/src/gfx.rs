@@ -0,0 +1,146 @@
+//! Anti-aliased drawing with tiny_skia, onto a canvas that is copied to the screen in one go.
+//!
+//! A `Canvas` is a tiny_skia pixmap, always 32-bit RGBA whatever the framebuffer uses. Shapes are
+//! drawn into it with screen coordinates in pixels, and `present` converts the result to the
+//! framebuffer's pixel format and copies it into the screen's back buffer. Nothing shows until
+//! then, and the flusher puts it on screen at the next frame.
+
+use crate::drivers::console;
+use crate::drivers::font;
+use crate::drivers::screen::{self, Rgb};
+
+use tiny_skia::{
+    Color, FillRule, Paint, PathBuilder, Pixmap, PremultipliedColorU8, Rect, Stroke, Transform,
+};
+
+pub struct Canvas {
+    pixmap: Pixmap,
+}
+
+impl Canvas {
+    /// A canvas the size of the screen, cleared to black
+    pub fn new() -> Result<Self, &'static str> {
+        let (width, height) = screen::get_info();
+        if width == 0 || height == 0 {
+            return Err("No screen to draw to");
+        }
+
+        Self::with_size(width, height)
+    }
+
+    /// A `width` by `height` canvas, cleared to black
+    pub fn with_size(width: u32, height: u32) -> Result<Self, &'static str> {
+        let mut pixmap = Pixmap::new(width, height).ok_or("Failed to allocate canvas")?;
+        pixmap.fill(Color::BLACK);
+        Ok(Self { pixmap })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.pixmap.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.pixmap.height()
+    }
+
+    /// Paint the whole canvas in `colour`
+    pub fn clear(&mut self, colour: Rgb) {
+        self.pixmap.fill(to_color(colour));
+    }
+
+    /// Fill a rectangle. Edges that don't fall on pixel boundaries are anti-aliased.
+    pub fn fill_rect(&mut self, x: f32, y: f32, width: f32, height: f32, colour: Rgb) {
+        // Empty or not finite, so nothing to draw
+        let Some(rect) = Rect::from_xywh(x, y, width, height) else {
+            return;
+        };
+
+        self.pixmap
+            .fill_rect(rect, &paint(colour), Transform::identity(), None);
+    }
+
+    /// Draw a line `width` pixels wide between two points
+    pub fn draw_line(&mut self, from: (f32, f32), to: (f32, f32), width: f32, colour: Rgb) {
+        let mut builder = PathBuilder::new();
+        builder.move_to(from.0, from.1);
+        builder.line_to(to.0, to.1);
+        let Some(path) = builder.finish() else {
+            return;
+        };
+
+        let stroke = Stroke {
+            width,
+            ..Stroke::default()
+        };
+        self.pixmap
+            .stroke_path(&path, &paint(colour), &stroke, Transform::identity(), None);
+    }
+
+    /// Fill a circle of `radius` pixels around (`x`, `y`)
+    pub fn draw_circle(&mut self, x: f32, y: f32, radius: f32, colour: Rgb) {
+        let Some(path) = PathBuilder::from_circle(x, y, radius) else {
+            return;
+        };
+
+        self.pixmap.fill_path(
+            &path,
+            &paint(colour),
+            FillRule::Winding,
+            Transform::identity(),
+            None,
+        );
+    }
+
+    /// Draw `text` on one line with its top left corner at (`x`, `y`), in the console's bitmap font
+    /// scaled to `size` pixels tall. Returns the x just past the end of the text.
+    pub fn draw_text(&mut self, x: f32, y: f32, text: &str, size: f32, colour: Rgb) -> f32 {
+        let advance = size * font::WIDTH as f32 / font::HEIGHT as f32;
+        let paint = paint(colour);
+        let mut x = x;
+
+        for c in text.chars() {
+            if let Some(path) = console::glyph_path(c, size) {
+                self.pixmap.fill_path(
+                    &path,
+                    &paint,
+                    FillRule::Winding,
+                    Transform::from_translate(x, y),
+                    None,
+                );
+            }
+            x += advance;
+        }
+
+        x
+    }
+
+    /// Copy the canvas into the screen's back buffer with its top left corner at the screen's,
+    /// cutting off anything that doesn't fit
+    pub fn present(&self) {
+        let width = self.pixmap.width() as usize;
+        let height = self.pixmap.height() as usize;
+        let pixels = self.pixmap.pixels();
+
+        let mut screen = screen::get_buffer();
+        screen.draw_pixels(0, 0, width, height, |column, row| {
+            to_rgb(pixels[row * width + column])
+        });
+    }
+}
+
+fn paint(colour: Rgb) -> Paint<'static> {
+    let mut paint = Paint::default();
+    paint.set_color(to_color(colour));
+    paint.anti_alias = true;
+    paint
+}
+
+fn to_color(colour: Rgb) -> Color {
+    Color::from_rgba8(colour.r, colour.g, colour.b, 0xFF)
+}
+
+/// A canvas pixel as the colour it shows over black, the screen has no alpha to keep
+fn to_rgb(pixel: PremultipliedColorU8) -> Rgb {
+    // Premultiplied, so the channels are already scaled by the pixel's alpha
+    Rgb::new(pixel.red(), pixel.green(), pixel.blue())
+}
@@ -9,20 +9,31 @@
 extern crate alloc;
 
 mod arch;
+#[cfg(feature = "bench")]
+mod bench;
+mod boot_timing;
 mod bootinfo;
+mod bug;
+mod dmesg;
 mod drivers;
+mod gfx;
 mod logging;
 mod mem;
 mod proc;
+mod selftest;
+mod stack_protector;
+mod sync;
+mod syscall;
+mod test_render;
+mod timer;
+mod watchdog;
+mod workqueue;
 
 pub use bootinfo::{BootInfo, FramebufferInfo};
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use log::LevelFilter;
 
-use crate::drivers::screen::SCREEN;
-
-use libm::{cos, sin};
-
 const KERNEL_BANNER: &str = r#"
          oo                    .88888.  .d88888b  
                               d8'   `8b 88.    "' 
@@ -34,85 +45,196 @@ dP   .dP dP .d8888b. .d8888b. 88     88 `Y88888b.
    Welcome to viceOS, a hobby OS written in Rust!
 "#;
 
+/// How long the boot splash stays up before the render loop draws over it, unless a key is pressed
+const SPLASH_MS: u64 = 2000;
+
+/// Lives in .bss and is never written, so it reads as zero only if the boot stub cleared .bss
+static BSS_CANARY: AtomicU64 = AtomicU64::new(0);
+
 #[unsafe(no_mangle)]
 pub extern "C" fn _start64(multiboot_info: u64) -> ! {
-    logging::init(LevelFilter::Trace).expect("Failed to initialize logger");
+    boot_timing::mark("early");
+    stack_protector::init();
+    logging::init(LevelFilter::Info).expect("Failed to initialize logger");
+    assert_eq!(BSS_CANARY.load(Ordering::Relaxed), 0, ".bss was not zeroed at boot");
 
     let boot_info = BootInfo::from_bootloader(multiboot_info);
+    drivers::vga_text::init(&boot_info);
+    logging::configure(&boot_info);
+
+    boot_timing::mark("arch");
     arch::init(&boot_info);
 
     log::trace!("Entering kernel main");
     kernel_main(&boot_info);
 }
 
+// The benchmarks never return, leaving the rest of the boot unreachable
+#[cfg_attr(feature = "bench", allow(unreachable_code))]
 pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    boot_timing::mark("mem");
     mem::init(boot_info);
-    drivers::init(boot_info);
-
-    kprintln!("{}", KERNEL_BANNER);
-
-    let pid = proc::manager::get_manager().create_process();
-    let proc = proc::manager::get_process(pid).unwrap();
-    log::trace!("Test proc: {:#?}", proc);
+    dmesg::init(boot_info);
+    boot_timing::mark("arch late");
+    arch::init_late();
 
-    let mut screen = SCREEN.lock();
+    // Benchmarks run before any threads are started, so nothing competes with them
+    #[cfg(feature = "bench")]
+    bench::run(boot_info);
 
-    let screen_width = screen.width;
-    let screen_height = screen.height;
+    workqueue::init();
+    boot_timing::mark("drivers");
+    drivers::init(boot_info);
+    boot_timing::mark("watchdog");
+    watchdog::init(boot_info);
+    boot_timing::report();
 
-    let midx = screen.width as f64 / 2.0;
-    let midy = screen.height as f64 / 2.0;
+    if boot_info.cmdline_flag("selftest") {
+        selftest::run();
+    }
 
-    let mut counter: u64 = 0;
+    print_banner(boot_info);
+    if !boot_info.cmdline_flag("nosplash") && drivers::screen::boot_splash() {
+        hold_splash();
+    }
 
-    loop {
-        use tiny_skia::*;
+    let created = proc::manager::manager().create_process();
+    match created {
+        Ok(handle) => {
+            proc::manager::with_process(handle, |proc| log::trace!("Test proc: {:#?}", proc));
+        }
+        Err(e) => log::warn!("Failed to create test process: {}", e),
+    }
 
-        let mut pixmap = PixmapMut::from_bytes(
-            screen.get_buffer(),
-            screen_width as u32,
-            screen_height as u32,
-        )
-        .unwrap();
+    // `vmmap=<pid>` dumps a process's page tables, for debugging address space setup
+    if let Some(value) = boot_info.cmdline_option("vmmap") {
+        match value.parse() {
+            Ok(pid) => {
+                if let Err(e) = proc::manager::log_mappings(pid) {
+                    log::warn!("Can't dump the mappings of PID {}: {}", pid, e);
+                }
+            }
+            Err(_) => log::warn!("Ignoring invalid vmmap value {:?}", value),
+        }
+    }
 
-        pixmap.fill(Color::WHITE);
+    // `irqstats` logs how often each interrupt vector fired during boot
+    if boot_info.cmdline_flag("irqstats") {
+        arch::x86_64::idt::log_stats();
+    }
 
-        let mut pb = PathBuilder::new();
+    test_render::test_render_loop();
 
-        let x = midx + 100.0 * cos((counter as f32 * 0.01).into());
-        let y = midy + 100.0 * sin((counter as f32 * 0.01).into());
+    log::info!("Render loop exited, halting");
 
-        pb.push_circle(x as f32, y as f32, 100.0);
+    // From here on the boot thread is the idle thread, keep letting kernel threads (like the
+    // screen flusher) run
+    if let Err(e) = proc::set_priority(0, proc::Priority::Idle) {
+        log::warn!("Failed to lower the boot thread's priority: {}", e);
+    }
+    loop {
+        // Idling isn't a hang, the watchdog only has to catch threads that never give the CPU up
+        watchdog::kick();
+        proc::scheduler::yield_now();
+        arch::halt();
+    }
+}
 
-        counter = counter.wrapping_add(1);
+/// Leave the boot splash on screen for `SPLASH_MS`, or until a key is pressed
+fn hold_splash() {
+    let until = arch::x86_64::idt::uptime_ms().saturating_add(SPLASH_MS);
+    while arch::x86_64::idt::uptime_ms() < until {
+        if drivers::keyboard::read_key().is_some() {
+            break;
+        }
+        proc::scheduler::sleep_ms(10);
+    }
+}
 
-        let path = pb.finish().unwrap();
+/// Print the banner along with a summary of the machine we booted on
+pub fn print_banner(boot_info: &BootInfo) {
+    use arch::x86_64::cpu;
 
-        let mut paint = Paint::default();
-        paint.set_color_rgba8(0, 255, 0, 255);
+    kprintln!("{}", KERNEL_BANNER);
 
-        pixmap.fill_path(
-            &path,
-            &paint,
-            FillRule::Winding,
-            Transform::identity(),
-            None,
+    let identity = cpu::identity();
+    let features = cpu::features();
+    kprintln!("  CPU:    {} ({})", identity.brand(), identity.vendor());
+
+    let flags = [
+        ("tsc", features.tsc),
+        ("pat", features.pat),
+        ("apic", features.apic),
+        ("x2apic", features.x2apic),
+        ("rdrand", features.rdrand),
+        ("rdseed", features.rdseed),
+    ];
+    serial_print!("  Flags: ");
+    for (name, _) in flags.iter().filter(|(_, present)| *present) {
+        serial_print!(" {}", name);
+    }
+    kprintln!();
+
+    let topology = cpu::topology();
+    kprintln!(
+        "  CPUs:   {} logical, {} cores x {} threads per package",
+        topology.logical_cpus,
+        topology.cores_per_package,
+        topology.threads_per_core
+    );
+    if !topology.is_consistent() {
+        log::warn!(
+            "The MADT lists {} CPUs, not a multiple of the {} per package CPUID reports",
+            topology.madt_cpus.unwrap_or(0),
+            topology.logical_per_package
         );
-
-        screen.sync();
     }
 
-    /*loop {
-        arch::halt();
-    }*/
+    let memory = mem::stats();
+    kprintln!(
+        "  Memory: {} MiB total, {} MiB available ({} memory map entries)",
+        memory.total_memory / 1024 / 1024,
+        memory.available_memory / 1024 / 1024,
+        boot_info.memory_map_entries
+    );
+
+    let fb = &boot_info.framebuffer;
+    kprintln!(
+        "  Screen: {}x{}, {} bpp, RGB{}{}{} at shifts {}/{}/{}",
+        fb.width,
+        fb.height,
+        fb.bpp,
+        fb.red_mask,
+        fb.green_mask,
+        fb.blue_mask,
+        fb.red_shift,
+        fb.green_shift,
+        fb.blue_shift
+    );
+    kprintln!();
 }
 
 // Reason for not test is because
 // LSP screams about it!
 #[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    log::error!("Kernel panic: {}", _info);
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    log::error!("Kernel panic: {}", info);
+
+    // The log only falls back to the VGA text buffer without serial, make sure the panic is on
+    // screen either way
+    if arch::x86_64::serial::is_usable() {
+        drivers::vga_text::with_writer_for_panic(|writer| {
+            use core::fmt::Write;
+            writer.set_attribute(drivers::vga_text::PANIC_ATTRIBUTE);
+            let _ = writeln!(writer, "\nKernel panic: {}", info);
+        });
+    }
+
+    // Paint the panic over the framebuffer too, for anyone not watching serial
+    let mut frames = [0; 16];
+    let depth = arch::x86_64::backtrace::collect_current(&mut frames);
+    drivers::console::panic_screen(info, &frames[..depth]);
 
     loop {
         arch::halt();
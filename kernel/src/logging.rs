@@ -1,10 +1,17 @@
+use crate::BootInfo;
+use crate::arch;
+use crate::arch::x86_64::serial;
+use crate::dmesg;
+use crate::drivers::vga_text;
 use core::fmt::Write;
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
 
 #[derive(Default)]
 pub struct SerialLogger {
     log_level_int: AtomicU8,
+    colours: AtomicBool,
+    verbose: AtomicBool,
 }
 
 // Table of log levels corresponding ANSI colour codes
@@ -48,21 +55,8 @@ impl SerialLogger {
         let col = LOG_LEVEL_COLOURS.get(level_int).unwrap_or(&"\x1b[0m");
         col
     }
-}
 
-impl log::Log for SerialLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.get_log_level()
-    }
-
-    fn log(&self, record: &Record) {
-        if !self.enabled(record.metadata()) {
-            return;
-        }
-
-        // use SERIAL
-        use crate::arch::x86_64::serial::SERIAL;
-        let mut ser = SERIAL.lock();
+    fn write_record(&self, ser: &mut (impl Write + ?Sized), record: &Record, colours: bool) {
         const RESET_COLOUR: &str = "\x1b[0m";
 
         let max_level_len: i32 = 5;
@@ -74,17 +68,51 @@ impl log::Log for SerialLogger {
             let _ = ser.write_str(" ");
         }
 
-        let colour = self.get_log_colour(record.level());
+        let (colour, reset) = if colours {
+            (self.get_log_colour(record.level()), RESET_COLOUR)
+        } else {
+            ("", "")
+        };
 
         let _ = write!(
             ser,
-            "{}[{}] - {}: {}{}\n",
+            "{}[{}] - {}: {}",
             colour,
             level_str,
             record.target(),
             record.args(),
-            RESET_COLOUR,
         );
+
+        if self.verbose.load(Ordering::Relaxed)
+            && let (Some(file), Some(line)) = (record.file(), record.line())
+        {
+            let _ = write!(ser, " ({}:{})", file, line);
+        }
+
+        let _ = writeln!(ser, "{}", reset);
+    }
+}
+
+impl log::Log for SerialLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.get_log_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Never block on the serial lock here: an interrupt handler that logs while the code it
+        // interrupted holds the lock would otherwise spin forever.
+        let colours = self.colours.load(Ordering::Relaxed);
+        if serial::is_usable() {
+            serial::with_port(|ser| self.write_record(ser, record, colours));
+        } else {
+            vga_text::with_writer(|writer| self.write_record(writer, record, colours));
+        }
+
+        dmesg::with_writer(|buffer| self.write_record(buffer, record, false));
     }
 
     fn flush(&self) {}
@@ -92,6 +120,8 @@ impl log::Log for SerialLogger {
 
 static LOGGER: SerialLogger = SerialLogger {
     log_level_int: AtomicU8::new(LevelFilter::Info as u8),
+    colours: AtomicBool::new(true),
+    verbose: AtomicBool::new(false),
 };
 
 pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
@@ -100,3 +130,64 @@ pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
 
     Ok(())
 }
+
+/// Change the log level after `init`
+pub fn set_level(level: LevelFilter) {
+    LOGGER.set_log_level(level);
+}
+
+/// Apply the logging options from the kernel command line:
+/// `loglevel=<off|error|warn|info|debug|trace>`, `nocolor` and `logverbose`
+pub fn configure(boot_info: &BootInfo) {
+    if boot_info.cmdline_flag("nocolor") {
+        set_colours(false);
+    }
+    if boot_info.cmdline_flag("logverbose") {
+        set_verbose(true);
+    }
+
+    if let Some(value) = boot_info.cmdline_option("loglevel") {
+        match value.parse::<LevelFilter>() {
+            Ok(level) => set_level(level),
+            Err(_) => log::warn!(
+                "Unknown loglevel '{}', keeping {}",
+                value,
+                LOGGER.get_log_level()
+            ),
+        }
+    }
+}
+
+/// Enable or disable ANSI colour codes in log output (on by default). Turn this off when the
+/// serial output is going somewhere that doesn't interpret escape sequences, like a log file.
+pub fn set_colours(enabled: bool) {
+    LOGGER.colours.store(enabled, Ordering::Relaxed);
+}
+
+/// Append the `(file:line)` a record was logged from to every line (off by default)
+pub fn set_verbose(enabled: bool) {
+    LOGGER.verbose.store(enabled, Ordering::Relaxed);
+}
+
+/// Logging while `serial::SERIAL` is held, as an interrupt handler would if it fired mid-write,
+/// falls back to an unlocked handle instead of spinning on the lock. A regression hangs here
+/// rather than failing.
+pub fn selftest_log_under_serial_lock() -> Result<(), &'static str> {
+    let guard = serial::SERIAL.lock();
+
+    let wrote = arch::without_interrupts(|| {
+        log::info!("Logging with the serial lock held");
+        serial::with_port(|_| true)
+    });
+
+    let still_held = serial::SERIAL.try_lock().is_none();
+    drop(guard);
+
+    if !wrote {
+        return Err("Serial port unavailable while the lock was held");
+    }
+    if !still_held {
+        return Err("Serial lock released by the fallback");
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Physically contiguous buffers for devices that do DMA.
+//!
+//! Physical memory is identity mapped, so a buffer's virtual and physical addresses are the same
+//! today. Drivers should still hand `phys()` to the device and use `virt()` from the CPU so they
+//! keep working once that stops being true.
+
+use crate::arch::paging::{self, flags};
+use crate::mem::{PAGE_SIZE, heap, phys};
+
+/// ISA DMA controllers can't cross a 64 KiB boundary in a single transfer
+pub const ISA_DMA_BOUNDARY: u64 = 64 * 1024;
+
+/// A zeroed, physically contiguous, cache-disabled buffer. The frames are freed on drop.
+pub struct DmaBuffer {
+    virt: *mut u8,
+    phys: u64,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Pointer for CPU access
+    pub fn virt(&self) -> *mut u8 {
+        self.virt
+    }
+
+    /// Physical address to put in device descriptors
+    pub fn phys(&self) -> u64 {
+        self.phys
+    }
+
+    /// Length in bytes, rounded up to whole pages
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt, self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Err(e) =
+            paging::update_range_flags(self.virt as u64, self.len as u64, 0, flags::CACHE_DISABLE)
+        {
+            log::warn!("DMA buffer at {:#x}: failed to restore caching: {}", self.phys, e);
+        }
+
+        phys::free_frames(self.phys, self.len / PAGE_SIZE);
+    }
+}
+
+unsafe impl Send for DmaBuffer {}
+
+/// Allocate a DMA buffer of at least `size` bytes
+pub fn alloc(size: usize) -> Result<DmaBuffer, &'static str> {
+    alloc_within(size, None)
+}
+
+/// Allocate a DMA buffer of at least `size` bytes that doesn't cross a multiple of `boundary`
+/// (a power of two), e.g. `ISA_DMA_BOUNDARY`
+pub fn alloc_within(size: usize, boundary: Option<u64>) -> Result<DmaBuffer, &'static str> {
+    if size == 0 {
+        return Err("DMA buffer size must be non-zero");
+    }
+
+    let pages = size.div_ceil(PAGE_SIZE);
+    let len = pages * PAGE_SIZE;
+
+    let phys = match boundary {
+        Some(boundary) => {
+            if !boundary.is_power_of_two() || (len as u64) > boundary {
+                return Err("DMA buffer can't fit within the requested boundary");
+            }
+
+            // Starting on a boundary guarantees a buffer no larger than it doesn't cross one
+            heap::alloc_aligned(len, (boundary as usize).max(PAGE_SIZE))
+                .ok_or("Out of physical memory for DMA buffer")?
+                .as_ptr() as u64
+        }
+        None => phys::alloc_frames(pages).ok_or("Out of physical memory for DMA buffer")?,
+    };
+
+    if let Err(e) = paging::update_range_flags(phys, len as u64, flags::CACHE_DISABLE, 0) {
+        phys::free_frames(phys, pages);
+        return Err(e);
+    }
+
+    let virt = phys as *mut u8;
+    unsafe { core::ptr::write_bytes(virt, 0, len) };
+
+    log::trace!("DMA buffer allocated: {:#x}, {} pages", phys, pages);
+
+    Ok(DmaBuffer { virt, phys, len })
+}
@@ -1,17 +1,34 @@
-use crate::mem::{PAGE_SIZE, phys};
+use crate::BootInfo;
+use crate::arch::paging::{self, Mapping, flags};
+use crate::mem::PAGE_SIZE;
+use crate::mem::phys::{self, Frame};
+use crate::proc::scheduler;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use linked_list_allocator::LockedHeap;
 use spin::Mutex;
 
-const HEAP_START: u64 = 0x0000_0000_0200_0000; // 32 MiB, past the kernel and bootloader
-const INITIAL_HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB initial heap
+/// Start of the heap's virtual window. Nothing else maps anything in the 512 GiB under this PML4
+/// entry, so the heap can always grow into it whatever frames back it.
+const HEAP_START: u64 = 0xFFFF_FC00_0000_0000;
+/// The heap never leaves the PML4 entry it starts in. Its page directory pointer table is made
+/// when the heap is set up, before any address space copies the kernel's PML4.
+const HEAP_WINDOW_SIZE: usize = 512 * 1024 * 1024 * 1024;
+const DEFAULT_INITIAL_HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB initial heap
 const EXTEND_CHUNK_SIZE: usize = 4 * 1024 * 1024; // grow by 4 MiB at a time (minimum)
-const MAX_HEAP_SIZE: usize = 512 * 1024 * 1024; // 512 MiB hard cap
+const DEFAULT_MAX_HEAP_SIZE: usize = 512 * 1024 * 1024; // 512 MiB hard cap
 
 /// Heap allocator that automatically extends itself when an allocation fails.
+///
+/// The heap has a virtual window of its own in the higher half. Pages are backed by whatever
+/// frames the frame allocator hands out as the heap grows, so nothing is held back for it.
 struct AutoExtendHeap {
     inner: LockedHeap,
+    /// Start of the heap, fixed by `init`
+    start: AtomicU64,
+    /// Size the heap may grow to
+    max_size: AtomicUsize,
     /// Tracks the current end of the mapped heap region.
     heap_end: Mutex<u64>,
 }
@@ -20,90 +37,102 @@ impl AutoExtendHeap {
     const fn new() -> Self {
         Self {
             inner: LockedHeap::empty(),
-            heap_end: Mutex::new(HEAP_START),
+            start: AtomicU64::new(0),
+            max_size: AtomicUsize::new(0),
+            heap_end: Mutex::new(0),
         }
     }
 
-    fn init(&self) {
-        let mut heap_end = self.heap_end.lock();
-        let num_pages = (INITIAL_HEAP_SIZE + PAGE_SIZE - 1) / PAGE_SIZE;
+    fn init(&self, start: u64, initial_size: usize, max_size: usize) {
+        self.start.store(start, Ordering::Relaxed);
+        self.max_size.store(max_size, Ordering::Relaxed);
 
-        for i in 0..num_pages {
-            let phys = phys::alloc_frame().expect("Failed to allocate frame for initial heap");
-            let virt = HEAP_START + (i * PAGE_SIZE) as u64;
-            use crate::arch::paging::{self, flags};
-            paging::map_page(virt, phys, flags::PRESENT | flags::WRITABLE)
-                .expect("Failed to map heap page");
-        }
+        let mut heap_end = self.heap_end.lock();
+        *heap_end = start;
 
-        let mapped = (num_pages * PAGE_SIZE) as u64;
-        *heap_end = HEAP_START + mapped;
+        let num_pages = initial_size.div_ceil(PAGE_SIZE);
+        assert!(
+            Self::map_pages(start, num_pages),
+            "Failed to map the initial heap"
+        );
+        *heap_end = start + (num_pages * PAGE_SIZE) as u64;
 
         unsafe {
             self.inner
                 .lock()
-                .init(HEAP_START as *mut u8, num_pages * PAGE_SIZE);
+                .init(start as *mut u8, num_pages * PAGE_SIZE);
         }
 
         log::trace!(
             "Heap initialized at {:#x}, size {} KiB",
-            HEAP_START,
+            start,
             (num_pages * PAGE_SIZE) / 1024
         );
     }
 
+    /// Back `num_pages` pages from `virt` on with fresh frames. Either all of them are mapped or,
+    /// if a frame or page table runs out partway, none are and every frame is given back.
+    fn map_pages(virt: u64, num_pages: usize) -> bool {
+        let page = |i: usize| virt + (i * PAGE_SIZE) as u64;
+
+        for i in 0..num_pages {
+            let mapped = Frame::alloc()
+                .ok_or("Out of frames")
+                .and_then(|frame| Mapping::new(page(i), frame, flags::PRESENT | flags::WRITABLE));
+
+            match mapped {
+                Ok(mapping) => {
+                    mapping.into_raw();
+                }
+                Err(e) => {
+                    log::warn!("Failed to back heap page {:#x}: {}", page(i), e);
+                    // Dropping them unmaps the pages mapped so far and frees their frames
+                    (0..i).for_each(|j| drop(unsafe { Mapping::from_raw(page(j)) }));
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
     /// Map more pages into the heap and tell the inner allocator about them.
     /// Extends by at least `min_bytes` (rounded up to pages), but at least
-    /// `EXTEND_CHUNK_SIZE` so we don't thrash on many small extensions.
+    /// `EXTEND_CHUNK_SIZE` so we don't thrash on many small extensions. If memory is too short
+    /// for a whole chunk, only `min_bytes` is tried.
     fn try_extend(&self, min_bytes: usize) -> bool {
+        // Page tables for the new pages come from the pool, fill it before taking any heap lock
+        paging::refill_table_pool();
+
         let mut heap_end = self.heap_end.lock();
-        let current_size = (*heap_end - HEAP_START) as usize;
+        let start = self.start.load(Ordering::Relaxed);
+        let max_size = self.max_size.load(Ordering::Relaxed);
+        let current_size = (*heap_end - start) as usize;
 
-        if current_size >= MAX_HEAP_SIZE {
+        if current_size >= max_size {
             log::warn!(
                 "Heap has reached maximum size ({} MiB)",
-                MAX_HEAP_SIZE / 1024 / 1024
+                max_size / 1024 / 1024
             );
             return false;
         }
 
-        let want = min_bytes.max(EXTEND_CHUNK_SIZE);
-        let capped = want.min(MAX_HEAP_SIZE - current_size);
-        let num_pages = (capped + PAGE_SIZE - 1) / PAGE_SIZE;
-
-        let mut mapped_pages = 0usize;
-        for i in 0..num_pages {
-            let frame = match phys::alloc_frame() {
-                Some(f) => f,
-                None => {
-                    log::warn!(
-                        "Heap extension stopped early: out of physical frames after {} pages",
-                        i
-                    );
-                    break;
-                }
-            };
-
-            let virt = *heap_end + (i * PAGE_SIZE) as u64;
-            use crate::arch::paging::{self, flags};
-            match paging::map_page(virt, frame, flags::PRESENT | flags::WRITABLE) {
-                Ok(_) => mapped_pages += 1,
-                Err(_) => {
-                    phys::free_frame(frame);
-                    log::warn!(
-                        "Heap extension stopped early: failed to map virt {:#x}",
-                        virt
-                    );
-                    break;
-                }
-            }
-        }
+        let room = max_size - current_size;
+        let needed = min_bytes.min(room).div_ceil(PAGE_SIZE);
+        let chunk = min_bytes
+            .max(EXTEND_CHUNK_SIZE)
+            .min(room)
+            .div_ceil(PAGE_SIZE);
 
-        if mapped_pages == 0 {
+        let grown = if Self::map_pages(*heap_end, chunk) {
+            chunk
+        } else if needed < chunk && Self::map_pages(*heap_end, needed) {
+            needed
+        } else {
             return false;
-        }
+        };
 
-        let added = mapped_pages * PAGE_SIZE;
+        let added = grown * PAGE_SIZE;
         unsafe {
             self.inner.lock().extend(added);
         }
@@ -112,16 +141,87 @@ impl AutoExtendHeap {
         log::debug!(
             "Heap extended by {} KiB (total: {} KiB / {} MiB max)",
             added / 1024,
-            (*heap_end - HEAP_START) as usize / 1024,
-            MAX_HEAP_SIZE / 1024 / 1024,
+            (*heap_end - start) as usize / 1024,
+            max_size / 1024 / 1024,
         );
 
         true
     }
 }
 
+/// Allocate `size` bytes aligned to `align` (a power of two, at least `PAGE_SIZE`) straight from
+/// physical frames. Physical memory is identity mapped, so the frames are usable as-is and we
+/// skip the first-fit search, which scans (and may fail to find) a suitably aligned free block.
+///
+/// Any frames before the aligned start and past the end are returned to the frame allocator.
+pub fn alloc_aligned(size: usize, align: usize) -> Option<NonNull<u8>> {
+    if size == 0 || !align.is_power_of_two() || align < PAGE_SIZE {
+        return None;
+    }
+
+    let pages = size.div_ceil(PAGE_SIZE);
+    let slack = align / PAGE_SIZE - 1;
+
+    let base = phys::alloc_frames(pages + slack)?;
+    let start = (base + align as u64 - 1) & !(align as u64 - 1);
+
+    let leading = ((start - base) as usize) / PAGE_SIZE;
+    let trailing = slack - leading;
+
+    if leading > 0 {
+        phys::free_frames(base, leading);
+    }
+    if trailing > 0 {
+        phys::free_frames(start + (pages * PAGE_SIZE) as u64, trailing);
+    }
+
+    NonNull::new(start as *mut u8)
+}
+
+/// Free memory returned by `alloc_aligned`
+///
+/// # Safety
+/// `ptr` and `size` must come from a previous `alloc_aligned` call.
+pub unsafe fn free_aligned(ptr: NonNull<u8>, size: usize) {
+    phys::free_frames(ptr.as_ptr() as u64, size.div_ceil(PAGE_SIZE));
+}
+
 unsafe impl GlobalAlloc for AutoExtendHeap {
+    // Allocating with interrupts disabled is fine, so the heap's locks must never be held by a
+    // preempted thread
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        scheduler::preempt_disable();
+        let ptr = unsafe { self.alloc_unpreempted(layout) };
+        scheduler::preempt_enable();
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        scheduler::preempt_disable();
+        unsafe { self.dealloc_unpreempted(ptr, layout) };
+        scheduler::preempt_enable();
+    }
+}
+
+impl AutoExtendHeap {
+    unsafe fn alloc_unpreempted(&self, layout: Layout) -> *mut u8 {
+        // Nothing to allocate, any non-null pointer with the right alignment will do
+        if layout.size() == 0 {
+            return core::ptr::without_provenance_mut(layout.align());
+        }
+
+        // Page-aligned (DMA buffers, page tables, ...) requests bypass the linked list
+        if layout.align() >= PAGE_SIZE {
+            return alloc_aligned(layout.size(), layout.align())
+                .map_or(core::ptr::null_mut(), NonNull::as_ptr);
+        }
+
+        // Could never fit, even after growing the heap as far as it goes. Only the linked list is
+        // limited by that, page-aligned requests come straight from the frame allocator.
+        if layout.size() > self.max_size.load(Ordering::Relaxed) {
+            return core::ptr::null_mut();
+        }
+
         let ptr = self
             .inner
             .lock()
@@ -145,7 +245,17 @@ unsafe impl GlobalAlloc for AutoExtendHeap {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_unpreempted(&self, ptr: *mut u8, layout: Layout) {
+        // Came from the zero-size case in `alloc_unpreempted`, not the heap
+        if layout.size() == 0 {
+            return;
+        }
+
+        if layout.align() >= PAGE_SIZE {
+            unsafe { free_aligned(NonNull::new_unchecked(ptr), layout.size()) };
+            return;
+        }
+
         unsafe {
             self.inner
                 .lock()
@@ -157,8 +267,81 @@ unsafe impl GlobalAlloc for AutoExtendHeap {
 #[global_allocator]
 static ALLOCATOR: AutoExtendHeap = AutoExtendHeap::new();
 
-pub fn init() {
-    ALLOCATOR.init();
+/// Read a size in MiB from the command line
+fn size_option(boot_info: &BootInfo, key: &str, default: usize) -> usize {
+    match boot_info.cmdline_option(key).map(str::parse::<usize>) {
+        None => default,
+        Some(Ok(mib)) if mib > 0 => mib * 1024 * 1024,
+        Some(_) => {
+            log::warn!(
+                "Ignoring invalid {} value, using {} MiB",
+                key,
+                default / 1024 / 1024
+            );
+            default
+        }
+    }
+}
+
+/// Map the heap's initial size at the start of its window.
+///
+/// `heapsize=<MiB>` and `heapmax=<MiB>` on the command line set the initial and maximum size.
+/// Frames are only taken as the heap grows, so the maximum is a limit rather than a reservation.
+pub fn init(boot_info: &BootInfo) {
+    let initial_size = size_option(boot_info, "heapsize", DEFAULT_INITIAL_HEAP_SIZE);
+    let max_size = size_option(boot_info, "heapmax", DEFAULT_MAX_HEAP_SIZE)
+        .max(initial_size)
+        .min(HEAP_WINDOW_SIZE);
+
+    log::debug!(
+        "Heap at {:#x}: {} MiB initial, up to {} MiB",
+        HEAP_START,
+        initial_size / 1024 / 1024,
+        max_size / 1024 / 1024
+    );
+
+    ALLOCATOR.init(HEAP_START, initial_size, max_size);
+}
+
+/// Zero-sized and oversized requests are answered without touching the heap. Checked on a heap
+/// that was never initialised, so its maximum size is 0 and anything that reached the linked list
+/// would fail.
+pub fn selftest_edge_cases() -> Result<(), &'static str> {
+    let heap = AutoExtendHeap::new();
+
+    let empty = Layout::from_size_align(0, 64).map_err(|_| "Bad layout")?;
+    let ptr = unsafe { heap.alloc_unpreempted(empty) };
+    if ptr.is_null() || !ptr.addr().is_multiple_of(64) {
+        return Err("Zero-sized allocation isn't a non-null pointer with the right alignment");
+    }
+    unsafe { heap.dealloc_unpreempted(ptr, empty) };
+
+    let oversized = Layout::from_size_align(64, 8).map_err(|_| "Bad layout")?;
+    if !unsafe { heap.alloc_unpreempted(oversized) }.is_null() {
+        return Err("Allocation bigger than the heap can grow succeeded");
+    }
+
+    // Served by the frame allocator, however small the heap is. Aligning past a page has it
+    // trim the slack frames on either side of the aligned run.
+    for align in [PAGE_SIZE, 64 * 1024] {
+        let aligned = Layout::from_size_align(2 * PAGE_SIZE, align).map_err(|_| "Bad layout")?;
+        let free_before = phys::free_frames_count();
+        let ptr = unsafe { heap.alloc_unpreempted(aligned) };
+        if ptr.is_null() {
+            return Err("Page-aligned allocation bigger than the heap was refused");
+        }
+
+        let misaligned = !ptr.addr().is_multiple_of(align);
+        unsafe { heap.dealloc_unpreempted(ptr, aligned) };
+        if misaligned {
+            return Err("Page-aligned allocation isn't aligned as asked");
+        }
+        if phys::free_frames_count() != free_before {
+            return Err("Page-aligned allocation leaked frames");
+        }
+    }
+
+    Ok(())
 }
 
 /// Get heap statistics: (free, used)
@@ -169,5 +352,5 @@ pub fn heap_stats() -> (usize, usize) {
 
 /// Get current mapped heap size in bytes
 pub fn heap_size() -> usize {
-    (*ALLOCATOR.heap_end.lock() - HEAP_START) as usize
+    (*ALLOCATOR.heap_end.lock() - ALLOCATOR.start.load(Ordering::Relaxed)) as usize
 }
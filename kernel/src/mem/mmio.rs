@@ -0,0 +1,123 @@
+//! Virtual address space for device memory.
+//!
+//! Device registers (APIC, IOAPIC, PCI BARs, ...) get mapped into a dedicated window in the
+//! higher half, uncached, instead of being reached through the identity map. The window sits far
+//! away from the heap and the identity-mapped low 4 GiB (which holds the framebuffer), so device
+//! mappings can never alias either of them.
+
+use crate::arch::paging::{self, flags};
+use crate::mem::{PAGE_SIZE, page_align_down, page_align_up};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Start of the MMIO window
+const MMIO_START: u64 = 0xFFFF_FF00_0000_0000;
+/// Size of the MMIO window
+const MMIO_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// Hands out page ranges inside the MMIO window
+struct MmioArena {
+    /// Everything from here to the end of the window has never been handed out
+    next: u64,
+    /// Ranges given back by `unmap`, as (start, length in bytes)
+    free: Vec<(u64, u64)>,
+}
+
+impl MmioArena {
+    const fn new() -> Self {
+        Self {
+            next: MMIO_START,
+            free: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self, len: u64) -> Option<u64> {
+        // Reuse a freed range first, splitting it if it's bigger than needed
+        if let Some(i) = self.free.iter().position(|&(_, free_len)| free_len >= len) {
+            let (start, free_len) = self.free[i];
+            if free_len == len {
+                self.free.swap_remove(i);
+            } else {
+                self.free[i] = (start + len, free_len - len);
+            }
+            return Some(start);
+        }
+
+        if self.next + len > MMIO_START + MMIO_SIZE {
+            return None;
+        }
+
+        let start = self.next;
+        self.next += len;
+        Some(start)
+    }
+
+    fn free(&mut self, start: u64, len: u64) {
+        self.free.push((start, len));
+    }
+}
+
+static ARENA: Mutex<MmioArena> = Mutex::new(MmioArena::new());
+
+/// Map `size` bytes of device memory at physical address `phys`, uncached.
+/// `phys` doesn't have to be page aligned, the returned pointer points at the same offset.
+pub fn map(phys: u64, size: usize) -> Result<*mut u8, &'static str> {
+    if size == 0 {
+        return Err("MMIO mapping size must be non-zero");
+    }
+
+    let phys_start = page_align_down(phys);
+    let phys_end = page_align_up(phys.checked_add(size as u64).ok_or("MMIO range overflows")?);
+    let len = phys_end - phys_start;
+
+    let virt_start = ARENA
+        .lock()
+        .alloc(len)
+        .ok_or("MMIO window exhausted")?;
+
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        let result = paging::map_page(
+            virt_start + offset,
+            phys_start + offset,
+            flags::PRESENT | flags::WRITABLE | flags::CACHE_DISABLE | flags::WRITE_THROUGH,
+        );
+
+        if let Err(e) = result {
+            for undo in (0..offset).step_by(PAGE_SIZE) {
+                let _ = paging::unmap_page(virt_start + undo);
+            }
+            ARENA.lock().free(virt_start, len);
+            return Err(e);
+        }
+    }
+
+    log::trace!(
+        "MMIO {:#x}..{:#x} mapped at {:#x}",
+        phys_start,
+        phys_end,
+        virt_start
+    );
+
+    Ok((virt_start + (phys - phys_start)) as *mut u8)
+}
+
+/// Undo a mapping made by `map`, `ptr` and `size` must be what was passed to / returned by it
+pub fn unmap(ptr: *mut u8, size: usize) {
+    let virt = ptr as u64;
+    if !(MMIO_START..MMIO_START + MMIO_SIZE).contains(&virt) {
+        log::warn!("Attempted to unmap {:#x}, which is outside the MMIO window", virt);
+        return;
+    }
+
+    let virt_start = page_align_down(virt);
+    let len = page_align_up(virt + size as u64) - virt_start;
+
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        if let Err(e) = paging::unmap_page(virt_start + offset) {
+            log::warn!("Failed to unmap MMIO page {:#x}: {}", virt_start + offset, e);
+        }
+    }
+
+    ARENA.lock().free(virt_start, len);
+}
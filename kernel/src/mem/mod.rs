@@ -1,13 +1,43 @@
+//! Memory management.
+//!
+//! Lock ordering, outermost first:
+//! 1. `heap_end` in `heap`, held while the heap grows
+//! 2. `PAGE_TABLES` in `paging`, held by every page-table edit
+//! 3. The page-table frame pool in `paging`
+//! 4. `FRAME_ALLOCATOR` in `phys`
+//!
+//! The frame allocator never allocates from the heap or edits page tables, so it's safe to take
+//! from anywhere. Page-table edits take frames from the pool rather than the frame allocator, and
+//! the heap refills the pool before taking its own locks, so growing the heap never re-enters
+//! either of them. Nothing may allocate from the heap while `PAGE_TABLES` is held, growing the
+//! heap maps pages and would take it again.
+
+pub mod dma;
 pub mod heap;
+pub mod mmio;
 pub mod phys;
+pub mod shm;
 pub mod virt;
 
 use crate::BootInfo;
+use crate::arch::paging::{self, flags};
+use crate::sync::IrqSpinlock;
 use spin::Mutex;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_SHIFT: usize = 12;
 
+/// Physical memory below this is identity mapped and can be accessed directly
+const IDENTITY_MAPPED_END: u64 = 0x1_0000_0000;
+/// Physical addresses are at most 52 bits wide
+const PHYS_ADDR_LIMIT: u64 = 1 << 52;
+
+/// Virtual pages `phys_read`/`phys_write` map other physical memory into, just past the MMIO
+/// window. Two, so a value can straddle a page boundary.
+const SCRATCH_START: u64 = 0xFFFF_FF00_4000_0000;
+const SCRATCH_PAGES: u64 = 2;
+static SCRATCH: IrqSpinlock<()> = IrqSpinlock::new(());
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemoryType {
@@ -35,6 +65,10 @@ pub struct MemoryMapEntry {
 /// it lets us track how much memory we have, how much is used, and how many pages are free/used
 /// this is essential for the kernel to manage memory effectively and to provide information to
 /// user-space applications about available resources.
+///
+/// The totals come from the memory map, the usage figures are taken live from the frame
+/// allocator, see `stats()`.
+#[derive(Debug, Clone, Copy)]
 pub struct MemoryStats {
     pub total_memory: u64,
     pub available_memory: u64,
@@ -66,8 +100,115 @@ pub fn init(boot_info: &BootInfo) {
     }
 
     phys::init(boot_info);
-    heap::init();
+    let pooled = crate::arch::paging::refill_table_pool();
+    log::trace!("Page-table frame pool holds {} frames", pooled);
+    heap::init(boot_info);
     log::info!("Heap initialized: {} KiB", heap::heap_size() / 1024);
+
+    log_vmstat();
+}
+
+/// Log page-table usage, see `paging::stats()`
+pub fn log_vmstat() {
+    let stats = crate::arch::paging::stats();
+    log::debug!(
+        "Page tables: {} frames, mapping {} 4 KiB, {} 2 MiB and {} 1 GiB pages",
+        stats.table_frames,
+        stats.mapped_pages,
+        stats.huge_pages,
+        stats.giant_pages
+    );
+}
+
+/// Snapshot of memory usage, with page counts read from the frame allocator at the time of the
+/// call
+pub fn stats() -> MemoryStats {
+    let mut stats = *MEMORY_STATS.lock();
+
+    let (_, _, free) = phys::stats();
+    let available_pages = stats.available_memory / PAGE_SIZE as u64;
+
+    stats.free_pages = free as u64;
+    stats.used_pages = available_pages.saturating_sub(stats.free_pages);
+    stats.used_memory = stats.used_pages * PAGE_SIZE as u64;
+
+    stats
+}
+
+/// Log the memory map as the kernel sees it, holes filled in as reserved
+pub fn dump_map() {
+    let map = crate::bootinfo::parsed_memory_map();
+    log::info!("Memory map ({} entries):", map.len());
+
+    for entry in map {
+        log::info!(
+            "  {:#014x}..{:#014x} {:>9} KiB  {:?}",
+            entry.base,
+            entry.base.saturating_add(entry.length),
+            entry.length / 1024,
+            entry.mem_type
+        );
+    }
+}
+
+/// Run `f` with a pointer through which the `T` at physical address `phys` can be accessed.
+/// Memory outside the identity map is mapped into the scratch window for the duration.
+fn with_phys<T, R>(phys: u64, f: impl FnOnce(*mut T) -> R) -> R {
+    assert!(
+        phys.is_multiple_of(align_of::<T>() as u64),
+        "Misaligned physical access at {:#x}",
+        phys
+    );
+
+    let end = phys
+        .checked_add(size_of::<T>() as u64)
+        .filter(|&end| end <= PHYS_ADDR_LIMIT)
+        .unwrap_or_else(|| panic!("Physical access at {:#x} is out of range", phys));
+
+    if end <= IDENTITY_MAPPED_END {
+        return f(phys as *mut T);
+    }
+
+    let first = page_align_down(phys);
+    let pages = (page_align_up(end) - first) / PAGE_SIZE as u64;
+    assert!(pages <= SCRATCH_PAGES, "Physical access at {:#x} is too large", phys);
+
+    let _scratch = SCRATCH.lock();
+    for i in 0..pages {
+        let offset = i * PAGE_SIZE as u64;
+        paging::map_page(SCRATCH_START + offset, first + offset, flags::PRESENT | flags::WRITABLE)
+            .expect("Failed to map the physical access window");
+    }
+
+    let result = f((SCRATCH_START + (phys - first)) as *mut T);
+
+    for i in 0..pages {
+        let _ = paging::unmap_page(SCRATCH_START + i * PAGE_SIZE as u64);
+    }
+
+    result
+}
+
+/// The `len` bytes at physical address `phys`, read in place through the identity map. `None` if
+/// any of them are outside it (or at address 0), `phys_read` can still reach those a value at a
+/// time. Only for memory that stays put, like firmware tables.
+pub fn phys_slice(phys: u64, len: usize) -> Option<&'static [u8]> {
+    let end = phys.checked_add(len as u64)?;
+    if phys == 0 || end > IDENTITY_MAPPED_END {
+        return None;
+    }
+
+    Some(unsafe { core::slice::from_raw_parts(phys as *const u8, len) })
+}
+
+/// Read the `T` at physical address `phys`, which must be aligned for `T`
+pub fn phys_read<T: Copy>(phys: u64) -> T {
+    with_phys(phys, |ptr: *mut T| unsafe { ptr.read_volatile() })
+}
+
+/// Write `value` to physical address `phys`, which must be aligned for `T`
+pub fn phys_write<T: Copy>(phys: u64, value: T) {
+    with_phys(phys, |ptr: *mut T| unsafe { ptr.write_volatile(value) })
 }
 
 fn parse_mem_map(boot_info: &BootInfo) {
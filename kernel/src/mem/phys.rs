@@ -1,5 +1,8 @@
 use crate::BootInfo;
+use crate::arch::x86_64::rand;
 use crate::mem::{MemoryType, PAGE_SIZE, page_align_down, page_align_up};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use spin::Mutex;
 
 // TODO: Why not make this bigger? We can support more than 4 GiB of RAM, but we need to make sure
@@ -10,6 +13,10 @@ const MAX_PAGES: usize = MAX_PHYS_MEM / PAGE_SIZE;
 
 const BITMAP_SIZE: usize = MAX_PAGES / 8; // 1 bit per page
 
+/// Pages below 1 MiB. They hold the IVT and BIOS data, and are the only memory real-mode code (SMP
+/// trampolines) and legacy DMA can reach, so they're kept out of general allocation.
+const LOW_PAGES: usize = 0x100000 / PAGE_SIZE;
+
 /// The frame allocator allocates and deallocates physical memory frames (pages). It uses a bitmap
 /// to track which frames are free or used.
 ///
@@ -20,18 +27,21 @@ const BITMAP_SIZE: usize = MAX_PAGES / 8; // 1 bit per page
 /// A frame is a region of physical memory that is typically the size of a page (4 KiB).
 pub struct FrameAllocator {
     bitmap: [u8; BITMAP_SIZE],
+    /// Free pages below 1 MiB, only handed out by `alloc_low`. One bit per page, set if free.
+    low_free: [u64; LOW_PAGES / 64],
+    /// Pages below 1 MiB the memory map said were usable, the only ones that may be freed into the
+    /// low pool. The rest hold the IVT, the BDA/EBDA or ROMs.
+    low_usable: [u64; LOW_PAGES / 64],
     first_free: usize,
-    total_pages: usize,
-    free_pages: usize,
 }
 
 impl FrameAllocator {
     pub const fn new() -> Self {
         Self {
             bitmap: [0; BITMAP_SIZE],
+            low_free: [0; LOW_PAGES / 64],
+            low_usable: [0; LOW_PAGES / 64],
             first_free: 0,
-            total_pages: 0,
-            free_pages: 0,
         }
     }
 
@@ -47,8 +57,8 @@ impl FrameAllocator {
         if boot_info.memory_map.is_null() || boot_info.memory_map_entries == 0 {
             log::warn!("No memory map provided, assuming all memory is available");
 
-            self.total_pages = MAX_PAGES;
-            self.free_pages = MAX_PAGES;
+            TOTAL_PAGES.store(MAX_PAGES, Ordering::Relaxed);
+            FREE_PAGES.store(MAX_PAGES, Ordering::Relaxed);
 
             return;
         } else {
@@ -70,12 +80,23 @@ impl FrameAllocator {
             }
         }
 
+        // Move usable memory below 1 MiB into the low pool. Page 0 (the IVT) is never handed out,
+        // not least because its address is null.
+        for page in 1..LOW_PAGES {
+            if !self.is_allocated(page) {
+                self.mark_allocated(page);
+                self.low_free[page / 64] |= 1 << (page % 64);
+                self.low_usable[page / 64] |= 1 << (page % 64);
+            }
+        }
+        self.mark_allocated(0);
+
         log::debug!(
             "Frame allocator initialized: {} pages ({} MiB) total, {} pages ({} MiB) free",
-            self.total_pages,
-            (self.total_pages * PAGE_SIZE) / 1024 / 1024,
-            self.free_pages,
-            (self.free_pages * PAGE_SIZE) / 1024 / 1024,
+            self.total_count(),
+            (self.total_count() * PAGE_SIZE) / 1024 / 1024,
+            self.free_count(),
+            (self.free_count() * PAGE_SIZE) / 1024 / 1024,
         );
     }
 
@@ -92,8 +113,8 @@ impl FrameAllocator {
         }
 
         self.bitmap[byte] &= !(1 << bit);
-        self.free_pages += 1;
-        self.total_pages = self.total_pages.max(page + 1);
+        FREE_PAGES.fetch_add(1, Ordering::Relaxed);
+        TOTAL_PAGES.fetch_max(page + 1, Ordering::Relaxed);
     }
 
     fn mark_allocated(&mut self, page: usize) {
@@ -109,7 +130,7 @@ impl FrameAllocator {
         }
 
         self.bitmap[byte] |= 1 << bit;
-        self.free_pages -= 1;
+        FREE_PAGES.fetch_sub(1, Ordering::Relaxed);
     }
 
     fn is_allocated(&self, page: usize) -> bool {
@@ -126,7 +147,7 @@ impl FrameAllocator {
     /// Allocate a single page and return its physical address. Returns None if no free pages are
     /// available.
     pub fn alloc(&mut self) -> Option<u64> {
-        for page in self.first_free..self.total_pages {
+        for page in self.first_free..self.total_count() {
             if !self.is_allocated(page) {
                 self.mark_allocated(page);
                 self.first_free = page + 1;
@@ -145,43 +166,106 @@ impl FrameAllocator {
 
         log::warn!(
             "Physical frame allocator out of memory: total={} pages, free={} pages",
-            self.total_pages,
-            self.free_pages
+            self.total_count(),
+            self.free_count()
         );
         None // No free pages
     }
 
     pub fn alloc_contiguous(&mut self, num_pages: usize) -> Option<u64> {
-        if num_pages == 0 || num_pages > self.free_pages {
+        let total_pages = self.total_count();
+        if num_pages == 0 || num_pages > self.free_count() || num_pages > total_pages {
             return None;
         }
 
-        for start_page in self.first_free..=self.total_pages - num_pages {
-            let mut found = true;
+        let last_start = total_pages - num_pages;
 
-            for page in start_page..start_page + num_pages {
-                if self.is_allocated(page) {
-                    found = false;
-                    break;
-                }
-            }
+        // Search from first_free up, then wrap around to pick up runs freed below it
+        let start_page = self
+            .find_free_run(self.first_free, last_start, num_pages)
+            .or_else(|| {
+                let wrap_end = self.first_free.saturating_sub(1).min(last_start);
+                self.find_free_run(0, wrap_end, num_pages)
+            })?;
 
-            if found {
-                for page in start_page..start_page + num_pages {
-                    self.mark_allocated(page);
-                }
-                self.first_free = start_page + num_pages;
-                return Some((start_page * PAGE_SIZE) as u64);
+        for page in start_page..start_page + num_pages {
+            self.mark_allocated(page);
+        }
+        self.first_free = start_page + num_pages;
+
+        Some((start_page * PAGE_SIZE) as u64)
+    }
+
+    /// Find `num_pages` free pages in a row, starting somewhere in `[from, to]`
+    fn find_free_run(&self, from: usize, to: usize, num_pages: usize) -> Option<usize> {
+        let mut start_page = from;
+
+        while start_page <= to {
+            // Skip past the last allocated page in the candidate run, no run can start before it
+            match (start_page..start_page + num_pages).rfind(|&page| self.is_allocated(page)) {
+                Some(page) => start_page = page + 1,
+                None => return Some(start_page),
             }
         }
 
         None // No contiguous block of free pages found
     }
 
+    /// Allocate a page below 1 MiB
+    pub fn alloc_low(&mut self) -> Option<u64> {
+        let (word, bits) = self
+            .low_free
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != 0)?;
+
+        let bit = bits.trailing_zeros() as usize;
+        *bits &= !(1 << bit);
+
+        Some(((word * 64 + bit) * PAGE_SIZE) as u64)
+    }
+
+    /// Return a page below 1 MiB to the low pool
+    fn free_low(&mut self, page: usize) {
+        let (word, bit) = (page / 64, page % 64);
+        if self.low_usable[word] & (1 << bit) == 0 {
+            log::warn!(
+                "Ignoring free of low frame {:#x}, it was never usable memory",
+                page * PAGE_SIZE
+            );
+            return;
+        }
+        if self.low_free[word] & (1 << bit) != 0 {
+            log::warn!("Low frame {:#x} was already free (double free?)", page * PAGE_SIZE);
+            return;
+        }
+        scrub(page);
+        self.low_free[word] |= 1 << bit;
+    }
+
+    /// Mark every frame overlapping `start..end` as allocated
+    fn reserve_range(&mut self, start: u64, end: u64) {
+        let first = page_align_down(start) as usize / PAGE_SIZE;
+        let last = page_align_up(end) as usize / PAGE_SIZE;
+
+        for page in first..last.min(MAX_PAGES) {
+            if page < LOW_PAGES {
+                self.low_free[page / 64] &= !(1 << (page % 64));
+            }
+            self.mark_allocated(page);
+        }
+    }
+
     pub fn free(&mut self, addr: u64) {
         let page = (addr as usize) / PAGE_SIZE;
 
+        if page < LOW_PAGES {
+            self.free_low(page);
+            return;
+        }
+
         if page < MAX_PAGES && self.is_allocated(page) {
+            scrub(page);
             self.mark_free(page);
             if page < self.first_free {
                 self.first_free = page; // Update first_free to the lowest free page
@@ -199,43 +283,144 @@ impl FrameAllocator {
 
     pub fn free_contiguous(&mut self, addr: u64, num_pages: usize) {
         let start_page = (addr as usize) / PAGE_SIZE;
+        let end_page = start_page.saturating_add(num_pages);
 
-        for i in 0..num_pages {
-            let page = start_page + i;
-            if page < MAX_PAGES {
+        if end_page > MAX_PAGES {
+            log::warn!(
+                "Attempted to free out-of-bounds pages {:#x}..{:#x}",
+                addr,
+                (end_page * PAGE_SIZE) as u64
+            );
+        }
+
+        // Only pages that are actually allocated get freed (and counted), anything else means
+        // the caller's bookkeeping is off, e.g. a double free
+        let mut already_free = 0;
+        for page in start_page..end_page.min(MAX_PAGES) {
+            if page < LOW_PAGES {
+                self.free_low(page);
+            } else if self.is_allocated(page) {
+                scrub(page);
                 self.mark_free(page);
             } else {
-                log::warn!(
-                    "Attempted to free out-of-bounds page at address {:#x}",
-                    (page * PAGE_SIZE) as u64
-                );
+                already_free += 1;
             }
         }
 
+        if already_free > 0 {
+            log::warn!(
+                "free_contiguous({:#x}, {}): {} pages were already free (double free?)",
+                addr,
+                num_pages,
+                already_free
+            );
+        }
+
         if start_page < self.first_free {
             self.first_free = start_page; // Update first_free to the lowest free page
         }
     }
 
     pub fn free_count(&self) -> usize {
-        self.free_pages
+        FREE_PAGES.load(Ordering::Relaxed)
     }
 
     pub fn total_count(&self) -> usize {
-        self.total_pages
+        TOTAL_PAGES.load(Ordering::Relaxed)
+    }
+
+    /// Free pages according to the bitmap, which `free_count` should always agree with
+    fn count_free_bits(&self) -> usize {
+        (0..self.total_count())
+            .filter(|&page| !self.is_allocated(page))
+            .count()
     }
 }
 
 static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
 
+/// Pages up to the highest usable one, and how many of them are free. Kept next to the bitmap by
+/// `FrameAllocator`, so they can be read without taking its lock, e.g. from the panic handler.
+static TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+static FREE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Scrub every frame as it's freed (`zerofree` on the command line).
+///
+/// Freed frames otherwise keep whatever their last owner left in them. Frames headed for user
+/// space are always zeroed on allocation (`alloc_user_frame`), which is enough to stop leaking
+/// data between processes. Zeroing on free also scrubs kernel data as soon as it's released, at
+/// the cost of a 4 KiB memset on every free, including frames that are about to be overwritten
+/// anyway.
+static ZERO_ON_FREE: AtomicBool = AtomicBool::new(false);
+
 pub fn init(boot_info: &BootInfo) {
-    FRAME_ALLOCATOR.lock().init(boot_info);
+    {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        allocator.init(boot_info);
+
+        // The bootloader reports these as available memory, but we're still using them
+        let in_use = [
+            (
+                "kernel",
+                Some((boot_info.kernel_start, boot_info.kernel_end)),
+            ),
+            (
+                "initrd",
+                Some((boot_info.initrd_start, boot_info.initrd_end)),
+            ),
+            ("boot info", boot_info.multiboot_info_range()),
+        ];
+        for (name, range) in in_use {
+            if let Some((start, end)) = range.filter(|&(start, end)| start < end) {
+                allocator.reserve_range(start, end);
+                log::debug!("Reserved {} frames {:#x}..{:#x}", name, start, end);
+            }
+        }
+    }
+
+    if boot_info.cmdline_flag("zerofree") {
+        ZERO_ON_FREE.store(true, Ordering::Relaxed);
+        log::info!("Freed frames will be zeroed");
+    }
+}
+
+/// Fill `count` frames starting at `addr` with zeroes, through the identity mapping
+fn zero_frames(addr: u64, count: usize) {
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, count * PAGE_SIZE) };
+}
+
+/// Zero a page the allocator is taking back, if `zerofree` asked for it. Only called once the page
+/// is known to be allocated, so a bogus free never wipes memory that isn't the caller's, and
+/// before it's marked free, after which it may already be handed out again.
+fn scrub(page: usize) {
+    if ZERO_ON_FREE.load(Ordering::Relaxed) {
+        zero_frames((page * PAGE_SIZE) as u64, 1);
+    }
 }
 
 pub fn alloc_frame() -> Option<u64> {
     FRAME_ALLOCATOR.lock().alloc()
 }
 
+/// Allocate a frame filled with zeroes
+pub fn alloc_zeroed_frame() -> Option<u64> {
+    let frame = alloc_frame()?;
+    zero_frames(frame, 1);
+    Some(frame)
+}
+
+/// Allocate a frame that will be mapped into user space. It is always zeroed, so it can't leak
+/// the kernel's or another process's data.
+pub fn alloc_user_frame() -> Option<u64> {
+    alloc_zeroed_frame()
+}
+
+/// Allocate a frame below 1 MiB, for code that has to run in or DMA to real-mode memory (e.g. the
+/// SMP trampoline). `alloc_frame` never returns these.
+pub fn alloc_low_frame() -> Option<u64> {
+    FRAME_ALLOCATOR.lock().alloc_low()
+}
+
 pub fn alloc_frames(count: usize) -> Option<u64> {
     FRAME_ALLOCATOR.lock().alloc_contiguous(count)
 }
@@ -248,20 +433,260 @@ pub fn free_frames(addr: u64, count: usize) {
     FRAME_ALLOCATOR.lock().free_contiguous(addr, count);
 }
 
+/// An owned physical frame, given back to the allocator when dropped.
+///
+/// Use `leak` for frames that are never freed, and `into_raw`/`from_raw` to pass ownership
+/// through code that only deals in addresses (page tables, device registers, ...).
+#[derive(Debug)]
+pub struct Frame {
+    addr: u64,
+}
+
+impl Frame {
+    pub fn alloc() -> Option<Self> {
+        alloc_frame().map(|addr| Self { addr })
+    }
+
+    pub fn alloc_zeroed() -> Option<Self> {
+        alloc_zeroed_frame().map(|addr| Self { addr })
+    }
+
+    /// Take ownership of a frame allocated elsewhere
+    ///
+    /// # Safety
+    /// `addr` must be an allocated frame that nothing else will free.
+    pub unsafe fn from_raw(addr: u64) -> Self {
+        Self { addr }
+    }
+
+    /// Give up ownership without freeing, the caller becomes responsible for the frame
+    pub fn into_raw(self) -> u64 {
+        core::mem::ManuallyDrop::new(self).addr
+    }
+
+    /// Keep the frame allocated forever
+    pub fn leak(self) -> u64 {
+        self.into_raw()
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        free_frame(self.addr);
+    }
+}
+
 pub fn free_frames_count() -> usize {
-    FRAME_ALLOCATOR.lock().free_count()
+    FREE_PAGES.load(Ordering::Relaxed)
 }
 
 pub fn total_frames_count() -> usize {
-    FRAME_ALLOCATOR.lock().total_count()
+    TOTAL_PAGES.load(Ordering::Relaxed)
 }
 
+/// Total, used and free page counts. Doesn't take the allocator lock, so it's safe to call from
+/// interrupt handlers and the panic path, but the counts may be from either side of an
+/// allocation running on another CPU.
 pub fn stats() -> (usize, usize, usize) {
+    let total = total_frames_count();
+    let free = free_frames_count();
+    let used = total.saturating_sub(free);
+
+    (total, used, free)
+}
+
+/// Whether the page counts kept next to the bitmap still match it
+fn counts_match_bitmap() -> bool {
     let allocator = FRAME_ALLOCATOR.lock();
+    allocator.count_free_bits() == allocator.free_count()
+}
 
-    let total = allocator.total_count();
-    let free = allocator.free_count();
-    let used = total - free;
+/// Random single and contiguous allocations and frees leave the free count matching the bitmap,
+/// and back where it started once everything is freed
+pub fn selftest_counts() -> Result<(), &'static str> {
+    const ROUNDS: usize = 2000;
+    const MAX_RUN: u64 = 16;
 
-    (total, used, free)
+    if !counts_match_bitmap() {
+        return Err("Free count doesn't match the bitmap to begin with");
+    }
+
+    let mut held: Vec<(u64, usize)> = Vec::new();
+    held.try_reserve(ROUNDS).map_err(|_| "Out of memory")?;
+    let free_before = free_frames_count();
+
+    for round in 0..ROUNDS {
+        let random = rand::u64();
+        if random % 3 == 0 && !held.is_empty() {
+            let (addr, count) = held.swap_remove((random / 3) as usize % held.len());
+            free_frames(addr, count);
+        } else {
+            let count = (random / 3 % MAX_RUN) as usize + 1;
+            let addr = if count == 1 {
+                alloc_frame()
+            } else {
+                alloc_frames(count)
+            };
+            if let Some(addr) = addr {
+                held.push((addr, count));
+            }
+        }
+
+        if round % 100 == 0 && !counts_match_bitmap() {
+            for (addr, count) in held {
+                free_frames(addr, count);
+            }
+            return Err("Free count drifted from the bitmap");
+        }
+    }
+
+    for (addr, count) in held {
+        free_frames(addr, count);
+    }
+    if !counts_match_bitmap() {
+        return Err("Free count doesn't match the bitmap after freeing everything");
+    }
+    if free_frames_count() != free_before {
+        return Err("Not every frame came back");
+    }
+
+    Ok(())
+}
+
+/// `alloc_frame` and `alloc_frames` stay above 1 MiB, even when asked for a lot of memory, while
+/// `alloc_low_frame` stays below it and frees of low pages that were never usable are ignored
+pub fn selftest_low_memory() -> Result<(), &'static str> {
+    const ROUNDS: usize = 512;
+    let low_end = (LOW_PAGES * PAGE_SIZE) as u64;
+
+    let mut held: Vec<(u64, usize)> = Vec::new();
+    held.try_reserve(2 * ROUNDS).map_err(|_| "Out of memory")?;
+
+    let mut result = Ok(());
+    for _ in 0..ROUNDS {
+        let singles = alloc_frame().map(|addr| (addr, 1));
+        let runs = alloc_frames(8).map(|addr| (addr, 8));
+        for (addr, count) in singles.into_iter().chain(runs) {
+            held.push((addr, count));
+            if addr < low_end {
+                result = Err("Normal allocation returned memory below 1 MiB");
+            }
+        }
+    }
+
+    for (addr, count) in held {
+        free_frames(addr, count);
+    }
+    result?;
+
+    // Not every machine has usable memory down there, so only check what comes back
+    if let Some(addr) = alloc_low_frame() {
+        free_frame(addr);
+        if addr >= low_end {
+            return Err("Low allocation returned memory above 1 MiB");
+        }
+    }
+
+    // The IVT and anything else the memory map didn't offer (the BIOS ROM is normally the top
+    // page) must never end up in the low pool
+    let is_set = |bits: &[u64], page: usize| bits[page / 64] & (1 << (page % 64)) != 0;
+    let rom = {
+        let allocator = FRAME_ALLOCATOR.lock();
+        (1..LOW_PAGES).rfind(|&page| !is_set(&allocator.low_usable, page))
+    };
+    let unusable = [Some(0), rom];
+    for page in unusable.into_iter().flatten() {
+        free_frame((page * PAGE_SIZE) as u64);
+    }
+    let allocator = FRAME_ALLOCATOR.lock();
+    if unusable
+        .into_iter()
+        .flatten()
+        .any(|page| is_set(&allocator.low_free, page))
+    {
+        return Err("Freeing an unusable page put it in the low pool");
+    }
+
+    Ok(())
+}
+
+/// Oversized contiguous requests fail cleanly, and contiguous allocation still works, without
+/// handing out frames that are in use, once memory is fragmented
+pub fn selftest_contiguous() -> Result<(), &'static str> {
+    const FRAGMENTS: usize = 64;
+
+    if alloc_frames(total_frames_count() + 1).is_some() || alloc_frames(usize::MAX).is_some() {
+        return Err("Allocated more frames than exist");
+    }
+    if alloc_frames(0).is_some() {
+        return Err("Allocated an empty run");
+    }
+
+    let mut kept = Vec::new();
+    kept.try_reserve(FRAGMENTS).map_err(|_| "Out of memory")?;
+    kept.extend((0..FRAGMENTS).map_while(|_| alloc_frame()));
+
+    // Free every other frame, so the holes left are a single page each
+    let mut i = 0;
+    kept.retain(|&frame| {
+        i += 1;
+        if i % 2 == 0 {
+            return true;
+        }
+        free_frame(frame);
+        false
+    });
+
+    let mut result = Ok(());
+    for count in [2, 8, FRAGMENTS] {
+        let Some(run) = alloc_frames(count) else {
+            result = Err("Contiguous allocation failed after fragmentation");
+            break;
+        };
+        let run_end = run + (count * PAGE_SIZE) as u64;
+        if kept.iter().any(|frame| (run..run_end).contains(frame)) {
+            result = Err("Contiguous run overlaps frames still in use");
+        }
+        free_frames(run, count);
+    }
+
+    for frame in kept {
+        free_frame(frame);
+    }
+    result
+}
+
+/// Freeing a contiguous block twice, whole or in part, only counts its frames once
+pub fn selftest_double_free() -> Result<(), &'static str> {
+    const COUNT: usize = 8;
+
+    let free_before = free_frames_count();
+    let run = alloc_frames(COUNT).ok_or("Out of frames")?;
+    if free_frames_count() != free_before - COUNT {
+        free_frames(run, COUNT);
+        return Err("Allocating a block counted the wrong number of frames");
+    }
+
+    free_frames(run, COUNT);
+    free_frames(run, COUNT);
+    if free_frames_count() != free_before {
+        return Err("Freeing a block twice counted it twice");
+    }
+
+    // Only the second half is still allocated when the whole block is freed
+    let run = alloc_frames(COUNT).ok_or("Out of frames")?;
+    free_frames(run, COUNT / 2);
+    free_frames(run, COUNT);
+    if free_frames_count() != free_before {
+        return Err("Freeing a partly freed block miscounted it");
+    }
+
+    if !counts_match_bitmap() {
+        return Err("Free count doesn't match the bitmap");
+    }
+    Ok(())
 }
@@ -0,0 +1,190 @@
+//! Shared memory regions: frames that can be mapped into several processes at once.
+//!
+//! A region is reference counted. The handle a process holds and every mapping of the region each
+//! keep it alive, and its frames are freed once the last of them goes away.
+
+use crate::arch::paging::{self, flags};
+use crate::mem::PAGE_SIZE;
+use crate::mem::phys::{self, Frame};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Largest region that can be created
+pub const MAX_SHM_SIZE: usize = 64 * 1024 * 1024;
+
+/// Physical frames backing a shared region, freed when the region is dropped
+#[derive(Debug)]
+pub struct SharedMemory {
+    frames: Vec<Frame>,
+}
+
+impl SharedMemory {
+    /// Allocate a zeroed region of at least `size` bytes
+    pub fn new(size: usize) -> Result<Arc<Self>, &'static str> {
+        if size == 0 || size > MAX_SHM_SIZE {
+            return Err("Shared memory size out of range");
+        }
+
+        let mut frames = Vec::new();
+        frames
+            .try_reserve_exact(size.div_ceil(PAGE_SIZE))
+            .map_err(|_| "Out of memory for shared memory frame list")?;
+
+        for _ in 0..size.div_ceil(PAGE_SIZE) {
+            // Frames already allocated are freed when `frames` is dropped
+            let addr = phys::alloc_user_frame().ok_or("Out of physical memory")?;
+            frames.push(unsafe { Frame::from_raw(addr) });
+        }
+
+        Ok(Arc::new(Self { frames }))
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+}
+
+/// Whether no page of `[addr, addr + size)` is mapped in the address space whose PML4 is at `cr3`
+/// (the identity-mapped low 4 GiB counts as mapped)
+pub fn is_unmapped(cr3: u64, addr: u64, size: usize) -> bool {
+    let Some(end) = addr.checked_add(size as u64) else {
+        return false;
+    };
+
+    (addr..end)
+        .step_by(PAGE_SIZE)
+        .all(|page| paging::translate_in(cr3, page).is_none())
+}
+
+/// A shared region mapped into an address space, unmapped when dropped
+#[derive(Debug)]
+pub struct ShmMapping {
+    region: Arc<SharedMemory>,
+    /// PML4 of the address space it's mapped into
+    cr3: u64,
+    addr: u64,
+}
+
+impl ShmMapping {
+    /// Map all of `region` at `addr` in the address space whose PML4 is at `cr3`. `addr` must be
+    /// page aligned and not mapped yet. Nothing is left mapped on failure.
+    pub fn new(
+        region: Arc<SharedMemory>,
+        cr3: u64,
+        addr: u64,
+        writable: bool,
+    ) -> Result<Self, &'static str> {
+        if !addr.is_multiple_of(PAGE_SIZE as u64) {
+            return Err("Shared memory address must be page aligned");
+        }
+
+        if !is_unmapped(cr3, addr, region.size()) {
+            return Err("Shared memory range is already mapped");
+        }
+
+        let mut page_flags = flags::PRESENT | flags::USER_ACCESSIBLE;
+        if writable {
+            page_flags |= flags::WRITABLE;
+        }
+
+        // The mapping only exists once every page is in, dropping a partial one unmaps too much
+        for (i, frame) in region.frames.iter().enumerate() {
+            let virt = addr + (i * PAGE_SIZE) as u64;
+            if let Err(e) = paging::map_page_in(cr3, virt, frame.addr(), page_flags) {
+                for mapped in (addr..virt).step_by(PAGE_SIZE) {
+                    let _ = paging::unmap_page_in(cr3, mapped);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(Self { region, cr3, addr })
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn region(&self) -> &Arc<SharedMemory> {
+        &self.region
+    }
+}
+
+impl Drop for ShmMapping {
+    fn drop(&mut self) {
+        // Only the pages go, the frames belong to the region
+        for i in 0..self.region.frames.len() {
+            let virt = self.addr + (i * PAGE_SIZE) as u64;
+            if let Err(e) = paging::unmap_page_in(self.cr3, virt) {
+                log::warn!("Failed to unmap shared memory page {:#x}: {}", virt, e);
+            }
+        }
+    }
+}
+
+/// A region mapped into two address spaces shows a write through one mapping in the other, and
+/// its frames stay allocated until the last mapping of it is gone.
+pub fn selftest_shared_writes() -> Result<(), &'static str> {
+    // Page tables are taken from the pool, keep it full at both ends so only leaks show
+    paging::refill_table_pool();
+    let (_, used_before, _) = phys::stats();
+
+    let first = paging::new_address_space()?;
+    let second = match paging::new_address_space() {
+        Ok(cr3) => cr3,
+        Err(e) => {
+            let _ = paging::free_address_space(first);
+            return Err(e);
+        }
+    };
+
+    // The mappings are gone by the time it returns, so the address spaces only hold page tables
+    let result = check_shared_writes(first, second);
+    let freed = paging::free_address_space(first).and(paging::free_address_space(second));
+    result.and(freed)?;
+
+    paging::refill_table_pool();
+    let (_, used_after, _) = phys::stats();
+    if used_after != used_before {
+        log::error!("{} frames in use before, {} after", used_before, used_after);
+        return Err("Shared memory leaked frames");
+    }
+
+    Ok(())
+}
+
+fn check_shared_writes(first: u64, second: u64) -> Result<(), &'static str> {
+    /// First address past the bottom 512 GiB, which every address space shares with the kernel
+    const ADDR: u64 = 0x0000_0080_0000_0000;
+    const PAGES: usize = 2;
+    const PATTERN: u64 = 0x5EED_CAFE_F00D_D00D;
+
+    // In the last page, so it's the region's frames in order that both sides agree on
+    let target = (ADDR + (PAGES * PAGE_SIZE) as u64 - 8) as *mut u64;
+    let read = |cr3: u64| paging::with_address_space(cr3, || unsafe { target.read_volatile() });
+
+    let region = SharedMemory::new(PAGES * PAGE_SIZE)?;
+    let writer = ShmMapping::new(region.clone(), first, ADDR, true)?;
+    let reader = ShmMapping::new(region, second, ADDR, false)?;
+
+    paging::with_address_space(first, || unsafe { target.write_volatile(PATTERN) })?;
+    if read(second)? != PATTERN {
+        return Err("A write through one mapping didn't show through the other");
+    }
+
+    let (_, used_mapped, _) = phys::stats();
+    drop(writer);
+    let (_, used_unmapped, _) = phys::stats();
+    if used_unmapped != used_mapped || read(second)? != PATTERN {
+        return Err("Dropping one mapping freed the region under the other");
+    }
+
+    drop(reader);
+    let (_, used_dropped, _) = phys::stats();
+    if used_dropped + PAGES != used_mapped {
+        return Err("Dropping the last mapping didn't free the region");
+    }
+
+    Ok(())
+}
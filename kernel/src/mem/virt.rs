@@ -1,5 +1,8 @@
 //use crate::mm::{PAGE_SIZE, physical};
 
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy)]
 pub struct VmRegion {
     pub start: u64,
     pub end: u64,
@@ -19,3 +22,44 @@ bitflags::bitflags! {
         const MMIO = 1 << 7;
     }
 }
+
+/// Set and clear `flags` on the parts of `regions` inside `[start, end)`. Regions straddling
+/// either edge are split so that only the covered part changes.
+pub fn update_region_flags(
+    regions: &mut Vec<VmRegion>,
+    start: u64,
+    end: u64,
+    set: VmFlags,
+    clear: VmFlags,
+) {
+    let mut updated = Vec::with_capacity(regions.len() + 2);
+
+    for region in regions.drain(..) {
+        if region.end <= start || region.start >= end {
+            updated.push(region);
+            continue;
+        }
+
+        if region.start < start {
+            updated.push(VmRegion {
+                end: start,
+                ..region
+            });
+        }
+
+        updated.push(VmRegion {
+            start: region.start.max(start),
+            end: region.end.min(end),
+            flags: (region.flags | set) - clear,
+        });
+
+        if region.end > end {
+            updated.push(VmRegion {
+                start: end,
+                ..region
+            });
+        }
+    }
+
+    *regions = updated;
+}
@@ -1,3 +1,7 @@
+use crate::arch::x86_64::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR};
+
+/// Saved register state of a thread that isn't running. The field offsets are hard-coded in
+/// `switch_context`.
 #[repr(C)]
 pub struct Context {
     r15: u64,
@@ -25,3 +29,90 @@ pub struct Context {
 
     cr3: u64,
 }
+
+/// Only the reserved bit, threads are switched to with interrupts disabled
+const INITIAL_RFLAGS: u64 = 0x2;
+
+impl Context {
+    /// All zeroes, filled in by the first `switch_context` away from the thread
+    pub const fn empty() -> Self {
+        Self {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rsi: 0,
+            rdi: 0,
+            rbp: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            rip: 0,
+            rsp: 0,
+            rflags: 0,
+            cs: 0,
+            ss: 0,
+            cr3: 0,
+        }
+    }
+
+    /// A kernel thread that starts at `rip` with `arg` as its first argument, on the stack whose
+    /// top is `stack_top`
+    pub fn new_kernel(rip: u64, stack_top: u64, arg: u64) -> Self {
+        // Enter as if `call`ed: the stack is 16-byte aligned before the (null) return address
+        let rsp = (stack_top & !0xF) - 8;
+        unsafe { (rsp as *mut u64).write(0) };
+
+        Self {
+            rip,
+            rsp,
+            rdi: arg,
+            rflags: INITIAL_RFLAGS,
+            cs: KERNEL_CODE_SELECTOR as u64,
+            ss: KERNEL_DATA_SELECTOR as u64,
+            ..Self::empty()
+        }
+    }
+}
+
+/// Save the callee-saved registers, stack and flags in `old` and resume `new`. Returns once
+/// something switches back to `old`.
+///
+/// # Safety
+/// `new` must have been filled in by `Context::new_kernel` or an earlier switch away from it, and
+/// interrupts must be disabled.
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch_context(old: *mut Context, new: *const Context) {
+    core::arch::naked_asm!(
+        "mov [rdi + 0x00], r15",
+        "mov [rdi + 0x08], r14",
+        "mov [rdi + 0x10], r13",
+        "mov [rdi + 0x18], r12",
+        "mov [rdi + 0x50], rbp",
+        "mov [rdi + 0x68], rbx",
+        "lea rax, [rip + 2f]",
+        "mov [rdi + 0x78], rax",
+        "mov [rdi + 0x80], rsp",
+        "pushfq",
+        "pop qword ptr [rdi + 0x88]",
+        "mov r15, [rsi + 0x00]",
+        "mov r14, [rsi + 0x08]",
+        "mov r13, [rsi + 0x10]",
+        "mov r12, [rsi + 0x18]",
+        "mov rbp, [rsi + 0x50]",
+        "mov rbx, [rsi + 0x68]",
+        // Only used by new threads, it's their entry point's argument
+        "mov rdi, [rsi + 0x48]",
+        "push qword ptr [rsi + 0x88]",
+        "popfq",
+        "mov rsp, [rsi + 0x80]",
+        "jmp qword ptr [rsi + 0x78]",
+        "2:",
+        "ret",
+    );
+}
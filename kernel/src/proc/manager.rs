@@ -1,13 +1,31 @@
+use crate::arch::x86_64::{paging, percpu};
+use crate::mem::phys;
 use crate::proc::process::{Pid, Process};
+use crate::proc::scheduler;
 
 use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use spin::{Mutex, MutexGuard};
 
 const MAX_PROCESSES: usize = 1024;
 
+/// A reference to a process that can tell when its PID has since been given to another process.
+/// Each PID slot has a generation that is bumped whenever the PID is handed out, so a handle
+/// whose generation doesn't match the slot's refers to a process that no longer exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessHandle {
+    pub pid: Pid,
+    pub generation: u32,
+}
+
 // bitfield to track used pids
 pub struct Manager {
     pub processes: Vec<Process>,
     process_bitmap: [u64; MAX_PROCESSES / 64],
+    generations: [u32; MAX_PROCESSES],
+    /// Where the search for a free PID starts. PIDs are handed out round-robin rather than
+    /// lowest-first, so a freed PID isn't reused until all the others have been.
+    next_pid: usize,
 }
 
 impl Manager {
@@ -15,6 +33,8 @@ impl Manager {
         let mut instance = Self {
             processes: Vec::new(),
             process_bitmap: [0; MAX_PROCESSES / 64],
+            generations: [0; MAX_PROCESSES],
+            next_pid: 1,
         };
 
         // reserve PID 0 for the kernel process
@@ -23,36 +43,177 @@ impl Manager {
         instance
     }
 
-    // TODO: don't take in cr3, allocate it auto
-    pub fn create_process(&mut self) -> Pid {
-        for (i, bitmap) in self.process_bitmap.iter_mut().enumerate() {
-            if *bitmap != u64::MAX {
-                for j in 0..64 {
-                    let bit = 1 << j;
+    fn is_used(&self, pid: usize) -> bool {
+        self.process_bitmap[pid / 64] & (1 << (pid % 64)) != 0
+    }
+
+    /// Make a process with an address space of its own. Fails once every PID is in use, e.g. for
+    /// `sys_fork` to report `EAGAIN`, or when there's no memory for the page tables.
+    pub fn create_process(&mut self) -> Result<ProcessHandle, &'static str> {
+        let slot = (0..MAX_PROCESSES)
+            .map(|i| (self.next_pid + i) % MAX_PROCESSES)
+            .find(|&pid| !self.is_used(pid))
+            .ok_or("No more PIDs available")?;
+        let cr3 = paging::new_address_space()?;
 
-                    if (*bitmap & bit) == 0 {
-                        *bitmap |= bit;
-                        let pid = (i * 64 + j) as Pid;
+        self.process_bitmap[slot / 64] |= 1 << (slot % 64);
+        self.generations[slot] = self.generations[slot].wrapping_add(1);
+        self.next_pid = (slot + 1) % MAX_PROCESSES;
 
-                        self.processes.push(Process::new(pid));
+        let pid = slot as Pid;
+        self.processes.push(Process::new(pid, cr3));
 
-                        log::trace!("Created process with PID {}", pid);
-                        return pid;
-                    }
-                }
-            }
+        log::trace!("Created process with PID {}", pid);
+
+        Ok(ProcessHandle {
+            pid,
+            generation: self.generations[slot],
+        })
+    }
+
+    /// Give `pid` back, for the next process after every other free one
+    fn release_pid(&mut self, pid: Pid) {
+        let slot = pid as usize;
+        self.process_bitmap[slot / 64] &= !(1 << (slot % 64));
+    }
+
+    /// Whether `handle` still refers to the process it was created for
+    pub fn is_current(&self, handle: ProcessHandle) -> bool {
+        let slot = handle.pid as usize;
+        slot < MAX_PROCESSES && self.is_used(slot) && self.generations[slot] == handle.generation
+    }
+}
+
+static MANAGER: Mutex<Manager> = Mutex::new(Manager::new());
+
+/// Lock the process manager. Don't hold it across anything that blocks, e.g. reading a pipe:
+/// take what's needed out of the process (files are reference counted) and let go first.
+pub fn manager() -> MutexGuard<'static, Manager> {
+    MANAGER.lock()
+}
+
+/// Run `f` on a process, returning `None` if it's gone (even if its PID has been reused since)
+pub fn with_process<R>(handle: ProcessHandle, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let mut manager = manager();
+    if !manager.is_current(handle) {
+        return None;
+    }
+
+    manager.processes.iter_mut().find(|p| p.pid == handle.pid).map(f)
+}
+
+/// Tear a process down: free its threads (their kernel stacks included), unmap its shared memory,
+/// close its files and free its page tables and the memory they map. Its PID is only released
+/// once everything else is gone. A thread can't destroy its own process.
+///
+/// Done here rather than in `Drop`, which would run with the manager locked and can't pick the
+/// order things are freed in.
+pub fn destroy_process(handle: ProcessHandle) -> Result<(), &'static str> {
+    let current_pid = with_current_process(|process| process.pid);
+
+    let mut process = {
+        let mut manager = manager();
+        if !manager.is_current(handle) {
+            return Err("No such process");
+        }
+        if current_pid == Some(handle.pid) {
+            return Err("Can't destroy the running process");
         }
 
-        panic!("No more PIDs available");
+        let index = manager.processes.iter().position(|p| p.pid == handle.pid);
+        manager.processes.swap_remove(index.ok_or("No such process")?)
+    };
+
+    for &tid in &process.threads {
+        if let Err(e) = scheduler::remove_thread(tid) {
+            log::warn!("PID {}: failed to remove thread {}: {}", process.pid, tid, e);
+        }
+    }
+
+    // Shared memory first, its frames belong to the region and mustn't be freed with the tables
+    process.shm_mappings.clear();
+    process.files.clear();
+
+    if process.cr3 != 0
+        && let Err(e) = paging::free_address_space(process.cr3)
+    {
+        log::warn!("PID {}: failed to free the address space: {}", process.pid, e);
     }
+
+    manager().release_pid(process.pid);
+    log::trace!("Destroyed process with PID {}", process.pid);
+
+    Ok(())
 }
 
-static mut MANAGER: Manager = Manager::new();
+/// Log the mappings in `pid`'s address space, PID 0 being the kernel's
+pub fn log_mappings(pid: Pid) -> Result<(), &'static str> {
+    let cr3 = match pid {
+        0 => paging::kernel_cr3(),
+        _ => {
+            let manager = manager();
+            let process = manager.processes.iter().find(|p| p.pid == pid);
+            process.ok_or("No such process")?.cr3
+        }
+    };
+    if cr3 == 0 {
+        return Err("Process has no address space of its own yet");
+    }
 
-pub fn get_manager() -> &'static mut Manager {
-    unsafe { &mut MANAGER }
+    log::info!("Address space of PID {}:", pid);
+    paging::with_address_space(cr3, paging::log_mappings)
 }
 
-pub fn get_process(pid: Pid) -> Option<&'static Process> {
-    get_manager().processes.iter().find(|p| p.pid == pid)
+/// Run `f` on the process the running thread belongs to, `None` while no thread is running
+pub fn with_current_process<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let thread = percpu::current().current_thread.load(Ordering::Relaxed);
+    let pid = unsafe { thread.as_ref() }?.parent_pid;
+
+    manager().processes.iter_mut().find(|p| p.pid == pid).map(f)
+}
+
+/// Creating and destroying processes gives back every frame they took. Each one gets a user page
+/// mapped outside the region shared with the kernel, so its own page tables are freed too.
+pub fn selftest_destroy_leaks() -> Result<(), &'static str> {
+    const ROUNDS: usize = 100;
+    /// First address past the bottom 512 GiB, which every process shares with the kernel
+    const PAGE: u64 = 0x0000_0080_0000_0000;
+
+    let round = || -> Result<(), &'static str> {
+        let handle = manager().create_process()?;
+        let cr3 = with_process(handle, |process| process.cr3).ok_or("Process vanished")?;
+        let mapped = match phys::alloc_user_frame() {
+            Some(frame) => {
+                let flags = paging::flags::WRITABLE | paging::flags::USER_ACCESSIBLE;
+                paging::map_page_in(cr3, PAGE, frame, flags).inspect_err(|_| {
+                    phys::free_frame(frame);
+                })
+            }
+            None => Err("Out of frames"),
+        };
+        destroy_process(handle)?;
+        mapped
+    };
+
+    // The first round may grow the manager's list and refill the page table pool, which are
+    // kept afterwards
+    round()?;
+
+    let (_, used_before, _) = phys::stats();
+    for _ in 0..ROUNDS {
+        round()?;
+    }
+    let (_, used_after, _) = phys::stats();
+
+    if used_after != used_before {
+        log::error!(
+            "{} frames in use before, {} after {} processes",
+            used_before,
+            used_after,
+            ROUNDS
+        );
+        return Err("Destroying processes leaked frames");
+    }
+
+    Ok(())
 }
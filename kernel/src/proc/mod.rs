@@ -1,5 +1,9 @@
 pub mod context;
 pub mod manager;
+pub mod pipe;
 pub mod process;
 pub mod scheduler;
 pub mod thread;
+
+pub use scheduler::set_priority;
+pub use thread::Priority;
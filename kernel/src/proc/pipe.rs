@@ -0,0 +1,128 @@
+//! Anonymous pipes: a bounded in-kernel byte buffer with a read end and a write end.
+//!
+//! Readers sleep while the pipe is empty and writers while it's full. Once every write end is
+//! closed, reads drain what's left and then return 0 (end of file). Once every read end is closed,
+//! writes fail.
+
+use crate::sync::{IrqSpinlock, WaitQueue};
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// Bytes a pipe buffers before writers have to wait
+pub const PIPE_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+#[derive(Debug)]
+struct Pipe {
+    /// Taken with interrupts disabled while checking whether a waiter can go on, so whoever holds
+    /// it mustn't be preempted
+    state: IrqSpinlock<PipeState>,
+    /// Woken when data is written or the last writer goes away
+    readable: WaitQueue,
+    /// Woken when data is read or the last reader goes away
+    writable: WaitQueue,
+}
+
+/// Read end of a pipe, closed when dropped
+#[derive(Debug)]
+pub struct PipeReader(Arc<Pipe>);
+
+/// Write end of a pipe, closed when dropped
+#[derive(Debug)]
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Create a pipe, returning its read and write ends
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: IrqSpinlock::new(PipeState {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl PipeReader {
+    /// Read up to `dst.len()` bytes, sleeping while the pipe is empty. Returns 0 once the pipe is
+    /// empty and every write end has been closed.
+    pub fn read(&self, dst: &mut [u8]) -> usize {
+        if dst.is_empty() {
+            return 0;
+        }
+
+        let count = self.0.readable.wait_until(|| {
+            let mut state = self.0.state.lock();
+            if state.buffer.is_empty() {
+                return (state.writers == 0).then_some(0);
+            }
+
+            let count = dst.len().min(state.buffer.len());
+            for (dst, byte) in dst.iter_mut().zip(state.buffer.drain(..count)) {
+                *dst = byte;
+            }
+            Some(count)
+        });
+
+        self.0.writable.wake_all();
+        count
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.state.lock().readers -= 1;
+        self.0.writable.wake_all();
+    }
+}
+
+impl PipeWriter {
+    /// Write all of `src`, sleeping whenever the pipe is full. Fails if every read end has been
+    /// closed, returning how much was written before that happened.
+    pub fn write(&self, src: &[u8]) -> Result<usize, usize> {
+        let mut written = 0;
+
+        while written < src.len() {
+            let count = self.0.writable.wait_until(|| {
+                let mut state = self.0.state.lock();
+                if state.readers == 0 {
+                    return Some(None);
+                }
+
+                let space = PIPE_CAPACITY - state.buffer.len();
+                if space == 0 {
+                    return None;
+                }
+
+                let count = space.min(src.len() - written);
+                state.buffer.extend(&src[written..written + count]);
+                Some(Some(count))
+            });
+
+            let Some(count) = count else {
+                return Err(written);
+            };
+            written += count;
+            self.0.readable.wake_all();
+        }
+
+        Ok(written)
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.state.lock().writers -= 1;
+        self.0.readable.wake_all();
+    }
+}
@@ -1,20 +1,47 @@
+use crate::mem::shm::{SharedMemory, ShmMapping};
+use crate::mem::virt::VmRegion;
+use crate::proc::pipe::{PipeReader, PipeWriter};
 use crate::proc::thread::Tid;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 pub type Pid = u64;
 
+/// Descriptors 0 to 2 are the console (stdin, stdout, stderr), files are numbered after them
+pub const FIRST_FILE_FD: usize = 3;
+/// Most files a process can have open at once
+pub const MAX_FILES: usize = 64;
+
+/// Something a file descriptor refers to. Dropping it closes it.
+#[derive(Debug)]
+pub enum File {
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    SharedMemory(Arc<SharedMemory>),
+}
+
 #[derive(Debug)]
 pub struct Process {
     pub pid: Pid,
     pub cr3: u64,
 
     pub threads: Vec<Tid>,
+
+    /// Open files, indexed by file descriptor minus `FIRST_FILE_FD`. Shared so a syscall can keep
+    /// using a file after letting go of the process manager, closing it only drops this reference.
+    pub files: Vec<Option<Arc<File>>>,
+
+    /// Shared memory mapped into this process, unmapped when the process goes away
+    pub shm_mappings: Vec<ShmMapping>,
+
+    /// What the user half of the address space holds, for `sys_mprotect` and page faults
+    pub vm_regions: Vec<VmRegion>,
 }
 
 impl Process {
-    pub fn new(pid: Pid) -> Self {
+    /// A process with nothing in it yet, running in the address space whose PML4 is at `cr3`
+    pub fn new(pid: Pid, cr3: u64) -> Self {
         // TODO: required steps for making a process:
-        // - allocate a page directory (cr3) (pml4, pdpt, pd, pt)
         // - set up the page tables to map the process's memory (code, data, stack)
         // - create a main thread for the process and add it to the threads vector
 
@@ -22,8 +49,37 @@ impl Process {
 
         Self {
             pid,
-            cr3: 0, // TODO: allocate a real page directory
+            cr3,
             threads: Vec::new(),
+            files: Vec::new(),
+            shm_mappings: Vec::new(),
+            vm_regions: Vec::new(),
         }
     }
+
+    /// Give `file` the lowest free descriptor
+    pub fn add_file(&mut self, file: File) -> Result<usize, &'static str> {
+        let index = match self.files.iter().position(Option::is_none) {
+            Some(index) => index,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err("Too many open files"),
+        };
+
+        self.files[index] = Some(Arc::new(file));
+        Ok(index + FIRST_FILE_FD)
+    }
+
+    /// The file open as `fd`
+    pub fn file(&self, fd: usize) -> Option<Arc<File>> {
+        self.files.get(fd.checked_sub(FIRST_FILE_FD)?)?.clone()
+    }
+
+    /// Take `fd` out of the table, the file is closed once the result and any other references
+    /// to it are dropped
+    pub fn remove_file(&mut self, fd: usize) -> Option<Arc<File>> {
+        self.files.get_mut(fd.checked_sub(FIRST_FILE_FD)?)?.take()
+    }
 }
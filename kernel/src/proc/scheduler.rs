@@ -1,5 +1,627 @@
-use crate::proc::thread::{Thread, Tid};
+//! Preemptive round-robin scheduling of kernel threads.
+//!
+//! Each CPU has a run queue in its per-CPU block, split into one queue per priority level. The
+//! highest level with a thread ready always goes first. The running thread isn't queued, it's owned
+//! through `current_thread`. Threads run until they call `yield_now`, `sleep_ms` or `exit_thread`,
+//! block on a `WaitQueue`, or until their time slice runs out, at which point the timer interrupt
+//! switches away from them on its way out. Sleeping and blocked threads stay queued, but are
+//! skipped until they're due or woken.
+//!
+//! A thread is never preempted between `preempt_disable` and `preempt_enable`, a tick that lands
+//! there is only acted on once the section ends. `IrqSpinlock` holds such a section for as long as
+//! it's locked. Anything holding a plain spin lock that code with interrupts disabled also takes
+//! (the heap, say) has to be in one too, otherwise that code could spin forever on a lock whose
+//! holder can't run.
+//!
+//! Every state change goes through `Thread::set_state`, which refuses transitions that make no
+//! sense (e.g. running a dead thread).
 
+use crate::arch;
+use crate::arch::x86_64::{gdt, idt, percpu};
+use crate::proc::context::switch_context;
+use crate::proc::thread::{Priority, Thread, ThreadState, Tid};
+use crate::sync::IrqSpinlock;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Smallest kernel stack `spawn_kernel` accepts
+pub const MIN_KERNEL_STACK_SIZE: usize = 4096;
+
+/// Timer ticks a thread runs for before it's preempted, about 110ms at the PIT's rate
+pub const TIME_SLICE_TICKS: u32 = 2;
+
+pub struct Scheduler {
+    /// Threads that are ready, or sleeping until their `wake_at`, indexed by priority
+    ready: [VecDeque<Box<Thread>>; Priority::COUNT],
+    /// Threads that have exited. A thread can't free the stack it's running on, so whichever one
+    /// runs next does. Boxed because the exiting thread saves its context after it's queued here.
+    #[allow(clippy::vec_box)]
+    dead: Vec<Box<Thread>>,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Self {
+            ready: [const { VecDeque::new() }; Priority::COUNT],
+            dead: Vec::new(),
+        }
+    }
+
+    /// Number of threads waiting to run, not counting sleeping ones or the idle level, whose
+    /// threads would only halt anyway
+    pub fn ready_count(&self) -> usize {
+        self.ready[Priority::Idle as usize + 1..]
+            .iter()
+            .flatten()
+            .filter(|thread| thread.state() == ThreadState::Ready)
+            .count()
+    }
+
+    fn push(&mut self, thread: Box<Thread>) {
+        self.ready[thread.priority as usize].push_back(thread);
+    }
+
+    /// Make room for every thread on this CPU in each level, so requeueing one never allocates.
+    /// Preemption requeues from the timer interrupt, where the frame allocator behind a growing
+    /// heap may be held by the interrupted thread.
+    fn reserve(&mut self) {
+        // Everything queued plus the running thread
+        let threads = self.ready.iter().map(VecDeque::len).sum::<usize>() + 1;
+        for level in &mut self.ready {
+            level.reserve(threads - level.len());
+        }
+    }
+
+    /// Take the first thread that can run at `now_ms` from the highest level that has one, down to
+    /// `min`, marked as running
+    fn pop_runnable(&mut self, now_ms: u64, min: Priority) -> Option<Box<Thread>> {
+        let mut thread = self
+            .ready
+            .iter_mut()
+            .rev()
+            .take(Priority::COUNT - min as usize)
+            .find_map(|level| {
+                let index = level
+                    .iter_mut()
+                    .position(|thread| thread.make_runnable(now_ms))?;
+                level.remove(index)
+            })?;
+        thread.set_state(ThreadState::Running);
+        thread.time_slice = TIME_SLICE_TICKS;
+        Some(thread)
+    }
+
+    /// Whether a thread above `priority` can run at `now_ms`, waking it if it was sleeping and its
+    /// time has come
+    fn runnable_above(&mut self, now_ms: u64, priority: Priority) -> bool {
+        self.ready[priority as usize + 1..]
+            .iter_mut()
+            .flatten()
+            .any(|thread| thread.make_runnable(now_ms))
+    }
+}
+
+/// The running thread, created for the boot thread the first time it's needed
+fn current_thread() -> *mut Thread {
+    let cpu = percpu::current();
+    let current = cpu.current_thread.load(Ordering::Relaxed);
+    if !current.is_null() {
+        return current;
+    }
+
+    let boot = Box::into_raw(Box::new(Thread::boot()));
+    cpu.current_thread.store(boot, Ordering::Relaxed);
+    boot
+}
+
+/// Start a kernel thread running `entry` on a new stack of `stack_size` bytes. It's queued behind
+/// the threads already waiting and first runs when the current thread yields. Returning from
+/// `entry` exits the thread.
+pub fn spawn_kernel(entry: fn(), stack_size: usize) -> Result<Tid, &'static str> {
+    if stack_size < MIN_KERNEL_STACK_SIZE {
+        return Err("Kernel stack is too small");
+    }
+
+    let thread = Box::new(Thread::new_kernel(
+        kernel_thread_start as *const () as u64,
+        entry as usize as u64,
+        stack_size,
+    )?);
+    let tid = thread.tid;
+
+    arch::without_interrupts(|| {
+        let mut queue = percpu::current().run_queue.lock();
+        queue.push(thread);
+        queue.reserve();
+    });
+
+    log::trace!("Spawned kernel thread {}", tid);
+
+    Ok(tid)
+}
+
+/// First code a new kernel thread runs, `switch_context` passes the entry point in RDI
+extern "C" fn kernel_thread_start(entry: usize) -> ! {
+    finish_switch();
+    arch::enable_interrupts();
+
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    entry();
+
+    exit_thread();
+}
+
+/// Let the next ready thread of at least this thread's priority run, if there is one. Returns
+/// when this thread is scheduled again.
+pub fn yield_now() {
+    // The depth is per CPU, the next thread would inherit it
+    crate::kassert!(preempt_count() == 0, "Yielded with preemption disabled");
+
+    arch::without_interrupts(|| {
+        switch_away();
+    });
+}
+
+/// Switch to the next thread that may run after the current one, which is queued again. Returns
+/// false straight away if there's nothing to switch to, otherwise true once this thread is
+/// scheduled again. Interrupts must be disabled.
+fn switch_away() -> bool {
+    let current = current_thread();
+    let thread = unsafe { &mut *current };
+    let now = idt::uptime_ms();
+    percpu::current().need_resched.store(false, Ordering::Relaxed);
+
+    // A thread that's going to wait lets anything run, otherwise lower levels have to wait
+    let min = match thread.state() {
+        ThreadState::Sleeping | ThreadState::Blocked => Priority::Idle,
+        _ => thread.priority,
+    };
+
+    let next = {
+        let mut queue = percpu::current().run_queue.lock();
+        let Some(next) = queue.pop_runnable(now, min) else {
+            return false;
+        };
+
+        // A waiting thread is queued as it is and skipped until it's due or woken
+        match thread.state() {
+            ThreadState::Running => thread.set_state(ThreadState::Ready),
+            ThreadState::Sleeping | ThreadState::Blocked => {}
+            state => crate::bug!("Thread {} yielded while {:?}", thread.tid, state),
+        }
+
+        queue.push(unsafe { Box::from_raw(current) });
+        next
+    };
+
+    // The queue lock must be dropped before switching, the next thread will want it
+    unsafe { switch_to(current, Box::into_raw(next)) };
+
+    finish_switch();
+    true
+}
+
+/// Park the running thread on the wait queue at address `queue` until `wake_blocked` is called
+/// for it, running other threads in the meantime, or halting if there are none. Interrupts must
+/// be disabled, so a wakeup can't slip in between the caller's last check of its condition and
+/// the thread being marked blocked. They're disabled again on return.
+pub fn block_current(queue: usize) {
+    crate::kassert!(preempt_count() == 0, "Blocked with preemption disabled");
+
+    let current = current_thread();
+    unsafe {
+        (*current).blocked_on = queue;
+        (*current).set_state(ThreadState::Blocked);
+    }
+
+    while unsafe { (*current).state() } == ThreadState::Blocked {
+        if !switch_away() {
+            // Nothing else can run, so the wakeup has to come from an interrupt
+            arch::enable_interrupts_and_halt();
+            arch::disable_interrupts();
+        }
+    }
+
+    unsafe { (*current).blocked_on = 0 };
+}
+
+/// Wake every thread parked on the wait queue at address `queue` by `block_current`. They run
+/// again once the scheduler picks them. Never allocates, so it's safe in interrupt handlers.
+pub fn wake_blocked(queue: usize) {
+    let Some(cpu) = percpu::try_current() else {
+        return;
+    };
+    let waiting =
+        |thread: &Thread| thread.state() == ThreadState::Blocked && thread.blocked_on == queue;
+
+    arch::without_interrupts(|| {
+        // Blocked with nothing else to run, so still on the CPU
+        let current = cpu.current_thread.load(Ordering::Relaxed);
+        if let Some(current) = unsafe { current.as_mut() }
+            && waiting(current)
+        {
+            current.set_state(ThreadState::Running);
+        }
+
+        let mut woken = false;
+        let mut run_queue = cpu.run_queue.lock();
+        for thread in run_queue.ready.iter_mut().flatten() {
+            if waiting(thread) {
+                thread.set_state(ThreadState::Ready);
+                woken = true;
+            }
+        }
+
+        // A woken thread may well be more urgent than the running one
+        if woken {
+            request_resched();
+        }
+    });
+}
+
+/// End the calling thread. Its stack is freed once another thread is running.
+pub fn exit_thread() -> ! {
+    arch::disable_interrupts();
+
+    let current = current_thread();
+    let tid = unsafe { (*current).tid };
+    if tid == 0 {
+        crate::bug!("The boot thread can't exit");
+    }
+
+    unsafe { (*current).set_state(ThreadState::Dead) };
+
+    // The boot thread never exits, so there's always something else to run, eventually
+    let next = loop {
+        let mut queue = percpu::current().run_queue.lock();
+        if let Some(next) = queue.pop_runnable(idt::uptime_ms(), Priority::Idle) {
+            queue.dead.push(unsafe { Box::from_raw(current) });
+            break next;
+        }
+        drop(queue);
+
+        // Everything else is asleep
+        arch::enable_interrupts_and_halt();
+        arch::disable_interrupts();
+    };
+
+    log::trace!("Kernel thread {} exited", tid);
+
+    unsafe { switch_to(current, Box::into_raw(next)) };
+
+    unreachable!("Switched back to an exited thread");
+}
+
+/// Put the calling thread to sleep for at least `ms` milliseconds, letting other threads run in
+/// the meantime. With nothing else to run the CPU halts until the next interrupt, so interrupts
+/// must be enabled.
+pub fn sleep_ms(ms: u64) {
+    let wake_at = idt::uptime_ms().saturating_add(ms);
+    arch::without_interrupts(|| {
+        let thread = unsafe { &mut *current_thread() };
+        thread.wake_at = wake_at;
+        thread.set_state(ThreadState::Sleeping);
+    });
+
+    loop {
+        yield_now();
+
+        // Being switched back to means the scheduler found this thread due and woke it, but when
+        // there was nothing to switch to it's still asleep here
+        let awake = arch::without_interrupts(|| {
+            let thread = unsafe { &mut *current_thread() };
+            if thread.state() == ThreadState::Sleeping && idt::uptime_ms() >= wake_at {
+                thread.set_state(ThreadState::Running);
+            }
+            thread.state() == ThreadState::Running
+        });
+        if awake {
+            return;
+        }
+
+        if arch::without_interrupts(|| percpu::current().run_queue.lock().ready_count()) == 0 {
+            arch::halt();
+        }
+    }
+}
+
+/// Keep the running thread from being preempted until the matching `preempt_enable`. Calls nest,
+/// only the outermost `preempt_enable` lets it be preempted again.
+pub fn preempt_disable() {
+    if let Some(cpu) = percpu::try_current() {
+        cpu.preempt_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Undo a `preempt_disable`. If that was the outermost one and the time slice ran out in the
+/// meantime, the switch the timer had to put off happens here, unless interrupts are disabled
+/// (e.g. in an interrupt handler), in which case it waits for the next tick.
+pub fn preempt_enable() {
+    let Some(cpu) = percpu::try_current() else {
+        return;
+    };
+
+    let depth = cpu.preempt_count.fetch_sub(1, Ordering::Relaxed);
+    crate::kassert!(depth != 0, "preempt_enable without preempt_disable");
+
+    if depth == 1 && arch::interrupts_enabled() {
+        preempt_if_needed();
+    }
+}
+
+/// Current `preempt_disable` depth on this CPU
+pub fn preempt_count() -> u32 {
+    percpu::try_current().map_or(0, |cpu| cpu.preempt_count.load(Ordering::Relaxed))
+}
+
+/// Timer interrupt: use up a tick of the running thread's slice, asking for a reschedule once
+/// it's gone, or straight away if a thread of higher priority is ready
+pub fn tick() {
+    let cpu = percpu::current();
+    let Some(thread) = (unsafe { cpu.current_thread.load(Ordering::Relaxed).as_mut() }) else {
+        return;
+    };
+
+    thread.time_slice = thread.time_slice.saturating_sub(1);
+    if thread.time_slice == 0 {
+        thread.time_slice = TIME_SLICE_TICKS;
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+
+    // Waking up or being spawned doesn't preempt anything, so a more urgent thread would
+    // otherwise wait out the rest of the slice
+    let mut queue = cpu.run_queue.lock();
+    if queue.runnable_above(idt::uptime_ms(), thread.priority) {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Have the running thread give up the CPU at the next preemption point, as if its time slice had
+/// run out, e.g. because a thread that was waiting for work now has some
+pub fn request_resched() {
+    if let Some(cpu) = percpu::try_current() {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Runs on the way out of every interrupt, after the EOI. Switches to another
+/// thread if the running one's slice is up and it isn't in a `preempt_disable` section, returning
+/// once it's scheduled again. Its registers stay saved in the interrupt frame on its stack.
+pub fn preempt_from_irq() {
+    preempt_if_needed();
+}
+
+/// Switch away from the running thread if the timer asked for it and nothing forbids it
+fn preempt_if_needed() {
+    let cpu = percpu::current();
+    if cpu.preempt_count.load(Ordering::Relaxed) != 0 || !cpu.need_resched.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // Blocked, sleeping or exiting threads give the CPU up on their own, they only halt (with
+    // interrupts enabled) when there's nothing else to run
+    let thread = cpu.current_thread.load(Ordering::Relaxed);
+    if unsafe { thread.as_ref() }.is_none_or(|thread| thread.state() != ThreadState::Running) {
+        return;
+    }
+
+    yield_now();
+}
+
+/// Move thread `tid` to `priority`. Only threads on this CPU can be found. The new level counts
+/// from the next time the scheduler picks a thread.
+pub fn set_priority(tid: Tid, priority: Priority) -> Result<(), &'static str> {
+    arch::without_interrupts(|| {
+        let current = unsafe { &mut *current_thread() };
+        if current.tid == tid {
+            current.priority = priority;
+            return Ok(());
+        }
+
+        let mut queue = percpu::current().run_queue.lock();
+        let mut thread = queue
+            .ready
+            .iter_mut()
+            .find_map(|level| {
+                let index = level.iter().position(|thread| thread.tid == tid)?;
+                level.remove(index)
+            })
+            .ok_or("No such thread")?;
+
+        thread.priority = priority;
+        queue.push(thread);
+        queue.reserve();
+        Ok(())
+    })
+}
+
+/// Take thread `tid` off this CPU's run queue and free it, stack and all, without it running
+/// again. The running thread can't be removed, it has to `exit_thread`.
+pub fn remove_thread(tid: Tid) -> Result<(), &'static str> {
+    let thread = arch::without_interrupts(|| {
+        if unsafe { (*current_thread()).tid } == tid {
+            return Err("Can't remove the running thread");
+        }
+
+        let mut queue = percpu::current().run_queue.lock();
+        queue
+            .ready
+            .iter_mut()
+            .find_map(|level| {
+                let index = level.iter().position(|thread| thread.tid == tid)?;
+                level.remove(index)
+            })
+            .ok_or("No such thread")
+    })?;
+
+    drop(thread);
+    Ok(())
+}
+
+/// The threads on this CPU and their states, the running one first
+pub fn thread_states() -> Vec<(Tid, ThreadState)> {
+    arch::without_interrupts(|| {
+        let current = unsafe { &*current_thread() };
+        let queue = percpu::current().run_queue.lock();
+
+        core::iter::once(current)
+            .chain(queue.ready.iter().flatten().map(|thread| &**thread))
+            .map(|thread| (thread.tid, thread.state()))
+            .collect()
+    })
+}
+
+/// Make `next` the running thread and switch to it, saving `current`'s context for when it's
+/// switched back to. Interrupts must be disabled and the run queue unlocked.
+unsafe fn switch_to(current: *mut Thread, next: *mut Thread) {
+    let cpu = percpu::current();
+    cpu.current_thread.store(next, Ordering::Relaxed);
+
+    // Entering the kernel from user mode lands on the thread's own stack, so a thread can be
+    // switched away from in the middle of a syscall
+    let stack = unsafe { (*next).kernel_stack_top() }.unwrap_or_else(gdt::kernel_stack_top);
+    gdt::set_kernel_stack(stack);
+    cpu.syscall_stack.store(stack, Ordering::Relaxed);
+
+    unsafe {
+        // The FS base belongs to the CPU, not the thread
+        (*next).load_tls();
+        switch_context(&mut (*current).context, &(*next).context);
+    }
+}
+
+/// Runs on the new thread after every switch: free the threads that have exited
+fn finish_switch() {
+    let dead = core::mem::take(&mut percpu::current().run_queue.lock().dead);
+    drop(dead);
+}
+
+/// Tick the high priority self-test thread first ran on, `u64::MAX` until it has
+static HIGH_RAN_AT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn high_priority_thread() {
+    HIGH_RAN_AT.store(idt::ticks(), Ordering::Relaxed);
+}
+
+/// A high priority thread that becomes ready while a normal one is busy runs on the next tick,
+/// without waiting for the normal one to yield or use up its slice
+pub fn selftest_priority_preemption() -> Result<(), &'static str> {
+    if arch::without_interrupts(|| unsafe { (*current_thread()).priority }) != Priority::Normal {
+        return Err("Has to run on a normal priority thread");
+    }
+
+    HIGH_RAN_AT.store(u64::MAX, Ordering::Relaxed);
+    let start = arch::without_interrupts(|| {
+        let tid = spawn_kernel(high_priority_thread, MIN_KERNEL_STACK_SIZE)?;
+        set_priority(tid, Priority::High)?;
+        Ok(idt::ticks())
+    })?;
+
+    // Busy, never yielding, so only preemption lets the other thread in
+    while HIGH_RAN_AT.load(Ordering::Relaxed) == u64::MAX && idt::ticks() < start + 10 {
+        core::hint::spin_loop();
+    }
+
+    match HIGH_RAN_AT.load(Ordering::Relaxed) {
+        u64::MAX => Err("High priority thread never ran"),
+        ran_at if ran_at > start + 1 => Err("High priority thread waited past the next tick"),
+        _ => Ok(()),
+    }
+}
+
+/// Progress made by each of the self-test's busy threads, and how many of them have finished
+static BUSY_COUNTS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+static BUSY_STOP: AtomicBool = AtomicBool::new(false);
+static BUSY_DONE: AtomicU64 = AtomicU64::new(0);
+
+fn busy_thread(index: usize) {
+    while !BUSY_STOP.load(Ordering::Relaxed) {
+        BUSY_COUNTS[index].fetch_add(1, Ordering::Relaxed);
+    }
+    BUSY_DONE.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Two threads that never yield both get to run, because the timer preempts them
+pub fn selftest_time_slicing() -> Result<(), &'static str> {
+    BUSY_STOP.store(false, Ordering::Relaxed);
+    BUSY_DONE.store(0, Ordering::Relaxed);
+    for count in &BUSY_COUNTS {
+        count.store(0, Ordering::Relaxed);
+    }
+
+    spawn_kernel(|| busy_thread(0), MIN_KERNEL_STACK_SIZE)?;
+    if let Err(e) = spawn_kernel(|| busy_thread(1), MIN_KERNEL_STACK_SIZE) {
+        BUSY_STOP.store(true, Ordering::Relaxed);
+        return Err(e);
+    }
+
+    // A few slices' worth, so each thread is preempted at least once
+    sleep_ms(500);
+    let counts = BUSY_COUNTS
+        .each_ref()
+        .map(|count| count.load(Ordering::Relaxed));
+
+    BUSY_STOP.store(true, Ordering::Relaxed);
+    while BUSY_DONE.load(Ordering::Relaxed) < 2 {
+        yield_now();
+    }
+
+    if counts.contains(&0) {
+        return Err("A thread that never yields kept the other from running");
+    }
+    Ok(())
+}
+
+/// Counter the self-test's threads share, and how many of them have returned
+static SHARED_COUNTER: IrqSpinlock<u64> = IrqSpinlock::new(0);
+static SHARED_DONE: AtomicU64 = AtomicU64::new(0);
+
+/// Increments each self-test thread makes
+const SHARED_INCREMENTS: u64 = 10_000;
+
+fn counting_thread() {
+    for i in 0..SHARED_INCREMENTS {
+        *SHARED_COUNTER.lock() += 1;
+        if i % 1000 == 0 {
+            yield_now();
+        }
+    }
+    SHARED_DONE.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Two spawned threads both run to completion, exit by returning, and don't lose each other's
+/// increments
+pub fn selftest_spawned_threads() -> Result<(), &'static str> {
+    *SHARED_COUNTER.lock() = 0;
+    SHARED_DONE.store(0, Ordering::Relaxed);
+
+    // A first thread left on its own if the second can't be spawned still finishes harmlessly
+    let first = spawn_kernel(counting_thread, MIN_KERNEL_STACK_SIZE)?;
+    let second = spawn_kernel(counting_thread, MIN_KERNEL_STACK_SIZE)?;
+
+    // Returning from the entry point exits the thread, so both leave the run queue
+    let queued = || {
+        thread_states()
+            .iter()
+            .any(|&(tid, _)| tid == first || tid == second)
+    };
+    let start = idt::ticks();
+    while queued() && idt::ticks() < start + 100 {
+        yield_now();
+    }
+
+    if queued() {
+        return Err("Spawned threads didn't exit");
+    }
+    if SHARED_DONE.load(Ordering::Relaxed) != 2 {
+        return Err("Spawned thread exited without finishing");
+    }
+    if *SHARED_COUNTER.lock() != 2 * SHARED_INCREMENTS {
+        return Err("Increments were lost");
+    }
 
-pub struct Scheduler {}
+    Ok(())
+}
@@ -1,14 +1,193 @@
 use crate::proc::context::Context;
 use crate::proc::process::Pid;
 
+use alloc::alloc::{Layout, alloc, dealloc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
 pub type Tid = u64;
 
+/// Alignment of kernel stacks
+const STACK_ALIGN: usize = 16;
+
+/// TID 0 is the boot thread, spawned threads count up from 1
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+/// Where a thread is in its life, kept up to date by the scheduler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// In a run queue, waiting for a CPU
+    Ready,
+    /// On a CPU right now
+    Running,
+    /// Waiting on a `WaitQueue`
+    Blocked,
+    /// Waiting for `wake_at`
+    Sleeping,
+    /// Exited, its stack is freed once another thread runs
+    Dead,
+}
+
+impl ThreadState {
+    /// Whether a thread may go straight from `self` to `next`. A blocked or sleeping thread goes
+    /// back to `Running` without passing through `Ready` when it was waiting on its own CPU.
+    pub const fn can_become(self, next: Self) -> bool {
+        use ThreadState::*;
+
+        matches!(
+            (self, next),
+            (Ready, Running)
+                | (Running, Ready | Blocked | Sleeping | Dead)
+                | (Blocked | Sleeping, Ready | Running)
+        )
+    }
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Ready => "ready",
+            Self::Running => "running",
+            Self::Blocked => "blocked",
+            Self::Sleeping => "sleeping",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+/// How urgently a thread wants the CPU. The scheduler always runs a thread from the highest level
+/// with one ready, round-robin within the level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Only runs when nothing else can
+    Idle = 0,
+    Normal = 1,
+    /// Driver workers and the like, which shouldn't wait behind busy normal threads
+    High = 2,
+}
+
+impl Priority {
+    /// Number of levels, one ready queue each
+    pub const COUNT: usize = 3;
+}
+
 pub struct Thread {
     pub tid: Tid,
 
+    /// Changed through `scheduler::set_priority`, which moves the thread between queues
+    pub priority: Priority,
+
+    /// Only changed through `set_state`
+    state: ThreadState,
+    /// Milliseconds since boot at which a `Sleeping` thread may run again
+    pub wake_at: u64,
+    /// Address of the `WaitQueue` a `Blocked` thread waits on
+    pub blocked_on: usize,
+    /// Timer ticks left before the thread is preempted, refilled whenever it's picked to run
+    pub time_slice: u32,
+
     pub context: Context,
     pub parent_pid: Pid,
 
     // heap allocated kernel stack for syscalls
     pub kernel_stack: *mut u8,
+    /// Size of `kernel_stack`, 0 if the thread doesn't own it
+    pub kernel_stack_size: usize,
+
+    /// User thread pointer (FS base), set through `sys_set_tls`
+    pub tls_base: u64,
+}
+
+impl Thread {
+    /// The thread the kernel booted on. Its stack isn't ours to free, and its context is filled
+    /// in the first time it's switched away from.
+    pub fn boot() -> Self {
+        Self {
+            tid: 0,
+            priority: Priority::Normal,
+            state: ThreadState::Running,
+            wake_at: 0,
+            blocked_on: 0,
+            time_slice: 0,
+            context: Context::empty(),
+            parent_pid: 0,
+            kernel_stack: core::ptr::null_mut(),
+            kernel_stack_size: 0,
+            tls_base: 0,
+        }
+    }
+
+    /// A kernel thread with a fresh stack of `stack_size` bytes, starting at `rip` with `arg` as
+    /// its first argument
+    pub fn new_kernel(rip: u64, arg: u64, stack_size: usize) -> Result<Self, &'static str> {
+        let layout = Layout::from_size_align(stack_size, STACK_ALIGN)
+            .map_err(|_| "Invalid kernel stack size")?;
+        let stack = unsafe { alloc(layout) };
+        if stack.is_null() {
+            return Err("Out of memory for kernel stack");
+        }
+
+        let stack_top = stack as u64 + stack_size as u64;
+
+        Ok(Self {
+            tid: NEXT_TID.fetch_add(1, Ordering::Relaxed),
+            priority: Priority::Normal,
+            state: ThreadState::Ready,
+            wake_at: 0,
+            blocked_on: 0,
+            time_slice: 0,
+            context: Context::new_kernel(rip, stack_top, arg),
+            parent_pid: 0,
+            kernel_stack: stack,
+            kernel_stack_size: stack_size,
+            tls_base: 0,
+        })
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    /// Move to `next`, which must be reachable from the current state
+    pub fn set_state(&mut self, next: ThreadState) {
+        crate::kassert!(
+            self.state.can_become(next),
+            "Thread {} can't go from {:?} to {:?}",
+            self.tid,
+            self.state,
+            next
+        );
+        self.state = next;
+    }
+
+    /// Whether the scheduler may pick this thread, waking it if it was sleeping and its time has
+    /// come
+    pub fn make_runnable(&mut self, now_ms: u64) -> bool {
+        match self.state {
+            ThreadState::Ready => true,
+            ThreadState::Sleeping if now_ms >= self.wake_at => {
+                self.set_state(ThreadState::Ready);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Top of the stack the thread enters the kernel on, `None` for the boot thread, which doesn't
+    /// own its stack
+    pub fn kernel_stack_top(&self) -> Option<u64> {
+        (self.kernel_stack_size != 0)
+            .then(|| self.kernel_stack as u64 + self.kernel_stack_size as u64)
+    }
+
+    /// Restore this thread's FS base, must be done whenever switching to it
+    pub fn load_tls(&self) {
+        crate::arch::x86_64::set_fs_base(self.tls_base);
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if self.kernel_stack_size != 0 {
+            let layout = Layout::from_size_align(self.kernel_stack_size, STACK_ALIGN).unwrap();
+            unsafe { dealloc(self.kernel_stack, layout) };
+        }
+    }
 }
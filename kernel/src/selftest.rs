@@ -0,0 +1,124 @@
+//! Checks run at boot when `selftest` is on the command line.
+//!
+//! The kernel only builds for bare metal, so `cargo test` can't reach it. The behaviour a unit
+//! test would normally pin down is checked here instead, once memory management, the scheduler
+//! and the drivers are up. Each check logs whether it passed and a summary follows, nothing is
+//! run unless asked for.
+
+use crate::arch::x86_64::{paging, rand, serial};
+use crate::bootinfo;
+use crate::drivers::{keyboard, screen};
+use crate::logging;
+use crate::mem::{heap, phys, shm};
+use crate::proc::{manager, scheduler};
+use crate::timer;
+
+struct Check {
+    name: &'static str,
+    run: fn() -> Result<(), &'static str>,
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "heap edge cases",
+        run: heap::selftest_edge_cases,
+    },
+    Check {
+        name: "timer deadlines",
+        run: timer::selftest_deadlines,
+    },
+    Check {
+        name: "process teardown",
+        run: manager::selftest_destroy_leaks,
+    },
+    Check {
+        name: "frame counts",
+        run: phys::selftest_counts,
+    },
+    Check {
+        name: "low memory",
+        run: phys::selftest_low_memory,
+    },
+    Check {
+        name: "contiguous frames",
+        run: phys::selftest_contiguous,
+    },
+    Check {
+        name: "double free",
+        run: phys::selftest_double_free,
+    },
+    Check {
+        name: "fallback PRNG",
+        run: rand::selftest_fallback,
+    },
+    Check {
+        name: "truncated framebuffer tag",
+        run: bootinfo::selftest_truncated_framebuffer,
+    },
+    Check {
+        name: "serial newlines",
+        run: serial::selftest_newlines,
+    },
+    Check {
+        name: "logging under serial lock",
+        run: logging::selftest_log_under_serial_lock,
+    },
+    Check {
+        name: "priority preemption",
+        run: scheduler::selftest_priority_preemption,
+    },
+    Check {
+        name: "time slicing",
+        run: scheduler::selftest_time_slicing,
+    },
+    Check {
+        name: "protect and query",
+        run: paging::selftest_protect_query,
+    },
+    Check {
+        name: "huge page split",
+        run: paging::selftest_split_huge_page,
+    },
+    Check {
+        name: "PrintScreen and Pause",
+        run: keyboard::selftest_special_keys,
+    },
+    Check {
+        name: "extended keys",
+        run: keyboard::selftest_extended_keys,
+    },
+    Check {
+        name: "spawned threads",
+        run: scheduler::selftest_spawned_threads,
+    },
+    Check {
+        name: "shared memory",
+        run: shm::selftest_shared_writes,
+    },
+    Check {
+        name: "screen copy",
+        run: screen::selftest_copy_fast,
+    },
+];
+
+/// Run every check and log the results
+pub fn run() {
+    log::info!("Running {} self-tests", CHECKS.len());
+
+    let mut failed = 0;
+    for check in CHECKS {
+        match (check.run)() {
+            Ok(()) => log::info!("  {}: ok", check.name),
+            Err(e) => {
+                log::error!("  {}: FAILED: {}", check.name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == 0 {
+        log::info!("All {} self-tests passed", CHECKS.len());
+    } else {
+        log::error!("{} of {} self-tests failed", failed, CHECKS.len());
+    }
+}
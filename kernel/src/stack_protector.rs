@@ -0,0 +1,33 @@
+//! Support for the compiler's stack smashing protection (`-Z stack-protector`)
+//!
+//! Protected functions copy `__stack_chk_guard` into their frame on entry and compare it on exit.
+//! If a buffer overflow clobbered the copy, the comparison fails and `__stack_chk_fail` is called
+//! instead of returning through a corrupted return address.
+//!
+//! The protector is opt-in: add `-Z stack-protector=strong` to the rustflags in
+//! `.cargo/config.toml` (ideally together with `-Z build-std` so core/alloc are protected too).
+
+use crate::arch::{self, x86_64::backtrace};
+
+/// Canary value checked by protected functions, randomized during boot by `init()`
+#[unsafe(no_mangle)]
+pub static mut __stack_chk_guard: u64 = 0x595E_9FBD_94FD_A766;
+
+/// Randomize the canary.
+///
+/// Any protected frame live on the stack while the guard changes would fail its check on return,
+/// so this must be inlined straight into `_start64`, which never returns.
+#[inline(always)]
+pub fn init() {
+    let guard = arch::rand::u64();
+    unsafe {
+        core::ptr::write_volatile(&raw mut __stack_chk_guard, guard);
+    }
+}
+
+/// Called by protected functions whose canary was overwritten
+#[unsafe(no_mangle)]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    backtrace::log_current();
+    panic!("Stack smashing detected: stack canary was overwritten");
+}
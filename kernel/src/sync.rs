@@ -0,0 +1,184 @@
+//! Locks that are safe to share with interrupt handlers.
+//!
+//! A plain spin lock deadlocks if an interrupt handler tries to take it while the code it
+//! interrupted holds it on the same CPU. `IrqSpinlock` disables interrupts for as long as it is
+//! held, so that can't happen. It also disables preemption, so a thread is never switched away
+//! from while holding one, even once the guard turns interrupts back on before dropping.
+//!
+//! `WaitQueue` is what code that has to block for another party (e.g. a pipe reader waiting for a
+//! writer) waits on, giving the CPU to other threads in the meantime.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch;
+use crate::proc::scheduler;
+use crate::workqueue;
+
+/// Spin lock that keeps interrupts disabled while held
+pub struct IrqSpinlock<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// Disable interrupts and take the lock. Interrupts are restored to their previous state when
+    /// the guard is dropped.
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        scheduler::preempt_disable();
+        let irqs_enabled = arch::interrupts_enabled();
+        arch::disable_interrupts();
+
+        IrqSpinlockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            irqs_enabled,
+        }
+    }
+
+    /// Take the lock only if it's free
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        scheduler::preempt_disable();
+        let irqs_enabled = arch::interrupts_enabled();
+        arch::disable_interrupts();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard {
+                guard: ManuallyDrop::new(guard),
+                irqs_enabled,
+            }),
+            None => {
+                if irqs_enabled {
+                    arch::enable_interrupts();
+                }
+                scheduler::preempt_enable();
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Release the lock without a guard
+    ///
+    /// # Safety
+    /// Whoever holds it must never touch the data again, in practice this is only for the panic
+    /// path, where the holder is not coming back.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() };
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for IrqSpinlock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    irqs_enabled: bool,
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock first, an interrupt taken right after enabling may want the lock
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        if self.irqs_enabled {
+            arch::enable_interrupts();
+        }
+
+        // Last, a switch put off while the lock was held may happen here
+        scheduler::preempt_enable();
+    }
+}
+
+/// Something threads can wait on until another party makes progress.
+///
+/// A waiter is parked as `Blocked` and other threads get the CPU until a waker calls `wake_all`,
+/// then it checks its condition again. Waiting with preemption disabled (e.g. with an
+/// `IrqSpinlock` held) isn't allowed, nothing else could run to wake the waiter.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    /// Threads parked on the queue. Threads find their queue by its address, which also needs
+    /// this to be there: zero-sized statics can share one.
+    waiters: AtomicUsize,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sleep until `ready` returns `Some`, and return its value. `ready` is called with
+    /// interrupts disabled, so a wakeup from an interrupt handler can't slip in between the check
+    /// and going to sleep. Any deferred work (`workqueue`) that's waiting is run before parking,
+    /// it may well be what we're waiting for.
+    pub fn wait_until<T>(&self, mut ready: impl FnMut() -> Option<T>) -> T {
+        crate::kassert!(
+            scheduler::preempt_count() == 0,
+            "Waited with preemption disabled"
+        );
+        let irqs_enabled = arch::interrupts_enabled();
+        // Work is already running on a thread that was switched away from halfway through it
+        let mut work_busy = false;
+
+        loop {
+            arch::disable_interrupts();
+            if let Some(value) = ready() {
+                if irqs_enabled {
+                    arch::enable_interrupts();
+                }
+                return value;
+            }
+
+            // E.g. input decoded by a bottom half. If another thread is in the middle of running
+            // work, park instead so that it can finish.
+            if workqueue::has_pending() && !work_busy {
+                arch::enable_interrupts();
+                work_busy = !workqueue::run_pending();
+                continue;
+            }
+
+            self.waiters.fetch_add(1, Ordering::Relaxed);
+            scheduler::block_current(self.address());
+            self.waiters.fetch_sub(1, Ordering::Relaxed);
+            work_busy = false;
+        }
+    }
+
+    /// Let every waiter re-check its condition. Safe in interrupt handlers.
+    pub fn wake_all(&self) {
+        if self.waiters.load(Ordering::Relaxed) != 0 {
+            scheduler::wake_blocked(self.address());
+        }
+    }
+
+    fn address(&self) -> usize {
+        self as *const Self as usize
+    }
+}
@@ -0,0 +1,16 @@
+//! `sys_dmesg`: read back the kernel log
+
+use crate::dmesg;
+use crate::syscall::{SyscallResult, user};
+
+/// Copy the most recent kernel log text, at most `len` bytes, to the user buffer at `buf`.
+/// Returns the number of bytes written, the text always starts at the beginning of a line.
+pub fn sys_dmesg(buf: u64, len: u64) -> SyscallResult {
+    let len = len as usize;
+    user::validate_write(buf, len)?;
+
+    let text = dmesg::recent(len);
+    user::copy_to_user(buf, &text)?;
+
+    Ok(text.len() as u64)
+}
@@ -0,0 +1,127 @@
+//! File descriptor syscalls: `sys_pipe`, `sys_write` and `sys_close`
+//!
+//! Descriptors 0 to 2 are always the console. Everything else lives in the calling process's
+//! file table.
+
+use crate::proc::manager;
+use crate::proc::pipe;
+use crate::proc::process::File;
+use crate::syscall::{
+    SyscallResult,
+    errno::{EBADF, EMFILE, EPIPE, ESRCH},
+    user,
+};
+
+use alloc::sync::Arc;
+
+/// Bytes moved through the kernel per copy
+const CHUNK_SIZE: usize = 512;
+
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// The file the calling process has open as `fd`
+fn current_file(fd: u64) -> Result<Arc<File>, i64> {
+    manager::with_current_process(|process| process.file(fd as usize))
+        .flatten()
+        .ok_or(EBADF)
+}
+
+/// Create a pipe and write its read and write descriptors to the `[i32; 2]` at `fds`
+pub fn sys_pipe(fds: u64) -> SyscallResult {
+    user::validate_write(fds, 2 * size_of::<i32>())?;
+
+    let (reader, writer) = pipe::pipe();
+    let (read_fd, write_fd) = manager::with_current_process(|process| {
+        let read_fd = process
+            .add_file(File::PipeReader(reader))
+            .map_err(|_| EMFILE)?;
+        match process.add_file(File::PipeWriter(writer)) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(_) => {
+                process.remove_file(read_fd);
+                Err(EMFILE)
+            }
+        }
+    })
+    .ok_or(ESRCH)??;
+
+    let mut bytes = [0u8; 2 * size_of::<i32>()];
+    bytes[..4].copy_from_slice(&(read_fd as i32).to_ne_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as i32).to_ne_bytes());
+    user::copy_to_user(fds, &bytes)?;
+
+    Ok(0)
+}
+
+/// Write `len` bytes from the user buffer at `buf` to `fd`, returning how many were written
+pub fn sys_write(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let len = len as usize;
+    user::validate_read(buf, len)?;
+
+    let file = match fd {
+        STDOUT | STDERR => None,
+        _ => Some(current_file(fd)?),
+    };
+
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut written = 0;
+    while written < len {
+        let count = (len - written).min(CHUNK_SIZE);
+        user::copy_from_user(&mut chunk[..count], buf + written as u64)?;
+
+        match file.as_deref() {
+            None => write_console(&chunk[..count]),
+            Some(File::PipeWriter(writer)) => {
+                if let Err(partial) = writer.write(&chunk[..count]) {
+                    // Report what got through, the error shows up on the next write
+                    return match written + partial {
+                        0 => Err(EPIPE),
+                        total => Ok(total as u64),
+                    };
+                }
+            }
+            Some(_) => return Err(EBADF),
+        }
+
+        written += count;
+    }
+
+    Ok(written as u64)
+}
+
+/// Print to the console, invalid UTF-8 comes out as replacement characters
+fn write_console(bytes: &[u8]) {
+    for chunk in bytes.utf8_chunks() {
+        crate::serial_print!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            crate::serial_print!("\u{FFFD}");
+        }
+    }
+}
+
+/// Read from a file descriptor past the console ones into the user buffer at `buf`
+pub fn read_file(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let file = current_file(fd)?;
+    let File::PipeReader(reader) = &*file else {
+        return Err(EBADF);
+    };
+
+    let len = (len as usize).min(CHUNK_SIZE);
+    user::validate_write(buf, len)?;
+
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let count = reader.read(&mut chunk[..len]);
+    user::copy_to_user(buf, &chunk[..count])?;
+
+    Ok(count as u64)
+}
+
+/// Close `fd`. Closing the last write end of a pipe makes reads at the other end return 0.
+pub fn sys_close(fd: u64) -> SyscallResult {
+    manager::with_current_process(|process| process.remove_file(fd as usize))
+        .flatten()
+        .ok_or(EBADF)?;
+
+    Ok(0)
+}
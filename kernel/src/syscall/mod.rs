@@ -0,0 +1,73 @@
+//! System call dispatch
+//!
+//! User programs enter the kernel through `syscall` (or the slower `int 0x80`) with the syscall
+//! number in RAX and up to five arguments in RDI, RSI, RDX, R10 and R8 (the same registers Linux
+//! uses, so existing toolchains can be pointed at us with minimal effort). The return value is
+//! placed back in RAX; negative values are `-errno`. `syscall` clobbers RCX and R11.
+
+pub mod dmesg;
+pub mod file;
+pub mod mprotect;
+pub mod read;
+pub mod shm;
+pub mod sysinfo;
+pub mod tls;
+pub mod user;
+
+/// Syscall numbers
+pub mod nr {
+    pub const SYSINFO: u64 = 0;
+    pub const SET_TLS: u64 = 1;
+    pub const READ: u64 = 2;
+    pub const SET_CONSOLE_MODE: u64 = 3;
+    pub const WRITE: u64 = 4;
+    pub const PIPE: u64 = 5;
+    pub const CLOSE: u64 = 6;
+    pub const SHM_CREATE: u64 = 7;
+    pub const SHM_MAP: u64 = 8;
+    pub const MPROTECT: u64 = 9;
+    pub const DMESG: u64 = 10;
+}
+
+/// Error numbers returned (negated) from syscalls
+pub mod errno {
+    pub const ESRCH: i64 = 3;
+    pub const EBADF: i64 = 9;
+    pub const EAGAIN: i64 = 11;
+    pub const ENOMEM: i64 = 12;
+    pub const EFAULT: i64 = 14;
+    pub const EEXIST: i64 = 17;
+    pub const EINVAL: i64 = 22;
+    pub const EMFILE: i64 = 24;
+    pub const EPIPE: i64 = 32;
+    pub const ENOSYS: i64 = 38;
+}
+
+/// Result type used by the individual syscall implementations, the error is a positive errno
+pub type SyscallResult = Result<u64, i64>;
+
+/// Route a syscall to its implementation and encode the result for RAX
+pub fn dispatch(num: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> i64 {
+    let result = match num {
+        nr::SYSINFO => sysinfo::sys_sysinfo(a1, a2),
+        nr::SET_TLS => tls::sys_set_tls(a1),
+        nr::READ => read::sys_read(a1, a2, a3),
+        nr::SET_CONSOLE_MODE => read::sys_set_console_mode(a1),
+        nr::WRITE => file::sys_write(a1, a2, a3),
+        nr::PIPE => file::sys_pipe(a1),
+        nr::CLOSE => file::sys_close(a1),
+        nr::SHM_CREATE => shm::sys_shm_create(a1),
+        nr::SHM_MAP => shm::sys_shm_map(a1, a2, a3),
+        nr::MPROTECT => mprotect::sys_mprotect(a1, a2, a3),
+        nr::DMESG => dmesg::sys_dmesg(a1, a2),
+        _ => {
+            log::debug!("Unknown syscall {}", num);
+            Err(errno::ENOSYS)
+        }
+    };
+
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -errno,
+    }
+}
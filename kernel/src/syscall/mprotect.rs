@@ -0,0 +1,92 @@
+//! `sys_mprotect`: change the protection of memory that is already mapped
+
+use crate::arch::paging::{self, flags};
+use crate::mem::virt::{self, VmFlags};
+use crate::mem::{PAGE_SIZE, page_align_up};
+use crate::proc::manager;
+use crate::proc::process::Process;
+use crate::syscall::{
+    SyscallResult,
+    errno::{EINVAL, ENOMEM, ESRCH},
+    user::USER_END,
+};
+
+/// Protection bits for `sys_mprotect`, numbered like Linux's `PROT_*`
+pub mod prot {
+    pub const READ: u64 = 1 << 0;
+    pub const WRITE: u64 = 1 << 1;
+    pub const EXEC: u64 = 1 << 2;
+}
+
+/// Change the protection of `[addr, addr + len)` to `prot`. `addr` must be page aligned, `len` is
+/// rounded up to whole pages, and every page in the range must be a user mapping in the calling
+/// process's address space, otherwise nothing changes.
+///
+/// Mapped pages are always readable and, as no-execute isn't enabled, executable, so `prot` must
+/// include `READ` and `EXEC` is accepted but has no effect yet.
+pub fn sys_mprotect(addr: u64, len: u64, prot: u64) -> SyscallResult {
+    // Nothing in here blocks, so the whole change happens under the manager lock
+    manager::with_current_process(|process| mprotect(process, addr, len, prot))
+        .unwrap_or(Err(ESRCH))
+}
+
+fn mprotect(process: &mut Process, addr: u64, len: u64, prot: u64) -> SyscallResult {
+    if prot & !(prot::READ | prot::WRITE | prot::EXEC) != 0 || prot & prot::READ == 0 {
+        return Err(EINVAL);
+    }
+    if !addr.is_multiple_of(PAGE_SIZE as u64) {
+        return Err(EINVAL);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let end = addr
+        .checked_add(len)
+        .filter(|end| end.checked_add(PAGE_SIZE as u64).is_some())
+        .map(page_align_up)
+        .ok_or(ENOMEM)?;
+    if end > USER_END {
+        return Err(ENOMEM);
+    }
+
+    // Check the whole range before touching any of it
+    let cr3 = process.cr3;
+    for page in (addr..end).step_by(PAGE_SIZE) {
+        match paging::query_in(cr3, page) {
+            Some((_, page_flags)) if page_flags & flags::USER_ACCESSIBLE != 0 => {}
+            _ => return Err(ENOMEM),
+        }
+    }
+
+    let writable = prot & prot::WRITE != 0;
+    for page in (addr..end).step_by(PAGE_SIZE) {
+        let Some((_, page_flags)) = paging::query_in(cr3, page) else {
+            return Err(ENOMEM);
+        };
+
+        let mut new_flags = page_flags & !(flags::WRITABLE | flags::HUGE_PAGE);
+        if writable {
+            new_flags |= flags::WRITABLE;
+        }
+
+        // User pages are never huge, so there's nothing to split and this can't fail halfway
+        paging::protect_in(cr3, page, new_flags).map_err(|_| ENOMEM)?;
+    }
+
+    let mut set = VmFlags::READ;
+    let mut clear = VmFlags::empty();
+    if writable {
+        set |= VmFlags::WRITE;
+    } else {
+        clear |= VmFlags::WRITE;
+    }
+    if prot & prot::EXEC != 0 {
+        set |= VmFlags::EXECUTE;
+    } else {
+        clear |= VmFlags::EXECUTE;
+    }
+    virt::update_region_flags(&mut process.vm_regions, addr, end, set, clear);
+
+    Ok(0)
+}
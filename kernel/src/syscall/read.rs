@@ -0,0 +1,208 @@
+//! `sys_read`: read from standard input, which is the keyboard, or from a pipe
+//!
+//! In line mode (the default) typed characters are echoed and collected until Enter, with
+//! Backspace editing, and only finished lines are handed out. In raw mode every character is
+//! returned as soon as it's typed, without echo. `sys_set_console_mode` switches between them.
+
+use crate::drivers::keyboard;
+use crate::sync::WaitQueue;
+use crate::syscall::{
+    SyscallResult,
+    errno::{EBADF, EINVAL},
+    file, user,
+};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// File descriptor of standard input
+pub const STDIN: u64 = 0;
+
+/// Console modes for `sys_set_console_mode`
+pub mod mode {
+    pub const LINE: u64 = 0;
+    pub const RAW: u64 = 1;
+}
+
+/// Longest line that can be typed, further characters are ignored until Enter
+const LINE_CAPACITY: usize = 256;
+/// Bytes the longest character takes in UTF-8
+const MAX_CHAR_LEN: usize = 4;
+
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
+/// The line being typed, or finished input (a line, or raw characters) that hasn't been read in
+/// full yet
+struct LineBuffer {
+    bytes: [u8; LINE_CAPACITY],
+    len: usize,
+    /// Enter was pressed (or raw input was collected), the bytes can be handed out
+    complete: bool,
+    /// How much of a complete line has already been read
+    consumed: usize,
+}
+
+impl LineBuffer {
+    /// Apply a typed character, echoing it
+    fn edit(&mut self, c: char) {
+        match c {
+            '\x08' => {
+                // Characters are all ASCII or multi-byte UTF-8, drop the whole last one
+                let Some(last) = core::str::from_utf8(&self.bytes[..self.len])
+                    .ok()
+                    .and_then(|line| line.chars().next_back())
+                else {
+                    return;
+                };
+                self.len -= last.len_utf8();
+                crate::serial_print!("\x08 \x08");
+            }
+            '\n' => {
+                // Always room for the newline, the line is cut short if it's full
+                self.len = self.len.min(LINE_CAPACITY - 1);
+                self.bytes[self.len] = b'\n';
+                self.len += 1;
+                self.complete = true;
+                crate::serial_print!("\n");
+            }
+            c => {
+                // Keep the last byte free for the newline
+                if self.len + c.len_utf8() < LINE_CAPACITY && self.push(c) {
+                    crate::serial_print!("{}", c);
+                }
+            }
+        }
+    }
+
+    /// Append a character, returning false if there's no room for it
+    fn push(&mut self, c: char) -> bool {
+        if self.len + c.len_utf8() > LINE_CAPACITY {
+            return false;
+        }
+
+        c.encode_utf8(&mut self.bytes[self.len..]);
+        self.len += c.len_utf8();
+        true
+    }
+
+    /// Copy out as much of the complete line as fits in `dst`
+    fn take(&mut self, dst: &mut [u8]) -> usize {
+        let count = dst.len().min(self.len - self.consumed);
+        dst[..count].copy_from_slice(&self.bytes[self.consumed..self.consumed + count]);
+        self.consumed += count;
+
+        if self.consumed == self.len {
+            self.len = 0;
+            self.consumed = 0;
+            self.complete = false;
+        }
+
+        count
+    }
+}
+
+/// Also serializes readers, so two of them can't take turns at the same line. Its holder waits
+/// for input with it held, so it's only taken through `with_line`, which doesn't spin.
+static LINE: Mutex<LineBuffer> = Mutex::new(LineBuffer {
+    bytes: [0; LINE_CAPACITY],
+    len: 0,
+    complete: false,
+    consumed: 0,
+});
+/// Readers waiting for another reader to let go of `LINE`
+static LINE_FREE: WaitQueue = WaitQueue::new();
+
+/// Run `f` on the line buffer, once no other reader is using it
+fn with_line<R>(f: impl FnOnce(&mut LineBuffer) -> R) -> R {
+    let mut line = LINE_FREE.wait_until(|| LINE.try_lock());
+    let result = f(&mut line);
+
+    drop(line);
+    LINE_FREE.wake_all();
+    result
+}
+
+/// Sleep until a key that produces a character is pressed
+fn wait_char() -> char {
+    loop {
+        if let Some(c) = keyboard::keyevent_to_char(&keyboard::wait_key()) {
+            return c;
+        }
+    }
+}
+
+/// Read up to `len` bytes from `fd` into the user buffer at `buf`, sleeping until input is
+/// available. Returns the number of bytes read, 0 at the end of a pipe.
+pub fn sys_read(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    match fd {
+        STDIN => {}
+        file::STDOUT | file::STDERR => return Err(EBADF),
+        fd => return file::read_file(fd, buf, len),
+    }
+
+    // A line can't be handed out in pieces bigger than it is, so don't bother with larger reads
+    let len = (len as usize).min(LINE_CAPACITY);
+    if len == 0 {
+        return Ok(0);
+    }
+
+    // Fail bad buffers before sleeping, not after the user has typed something
+    user::validate_write(buf, len)?;
+
+    let mut bytes = [0u8; LINE_CAPACITY];
+    let count = if RAW_MODE.load(Ordering::Relaxed) {
+        read_raw(&mut bytes[..len])
+    } else {
+        read_line(&mut bytes[..len])
+    };
+
+    user::copy_to_user(buf, &bytes[..count])?;
+
+    Ok(count as u64)
+}
+
+/// Wait for one character, then take whatever else has already been typed. Anything that doesn't
+/// fit in `dst` is kept for the next read.
+fn read_raw(dst: &mut [u8]) -> usize {
+    with_line(|line| {
+        if !line.complete {
+            // Only take a character when it's sure to fit, one that didn't would be lost
+            let has_room = |line: &LineBuffer| line.len + MAX_CHAR_LEN <= LINE_CAPACITY;
+            if has_room(line) {
+                line.push(wait_char());
+            }
+            while has_room(line)
+                && let Some(c) = keyboard::get_char()
+            {
+                line.push(c);
+            }
+            line.complete = true;
+        }
+
+        line.take(dst)
+    })
+}
+
+/// Wait for a complete line and hand out as much of it as fits
+fn read_line(dst: &mut [u8]) -> usize {
+    with_line(|line| {
+        while !line.complete {
+            line.edit(wait_char());
+        }
+
+        line.take(dst)
+    })
+}
+
+/// Switch standard input between `mode::LINE` and `mode::RAW`
+pub fn sys_set_console_mode(new_mode: u64) -> SyscallResult {
+    let raw = match new_mode {
+        mode::LINE => false,
+        mode::RAW => true,
+        _ => return Err(EINVAL),
+    };
+
+    RAW_MODE.store(raw, Ordering::Relaxed);
+
+    Ok(0)
+}
@@ -0,0 +1,87 @@
+//! `sys_shm_create` and `sys_shm_map`: shared memory between processes
+//!
+//! A region is created behind a file descriptor, which is the handle. Only a process holding the
+//! handle can map the region, so sharing it means passing the descriptor on.
+
+use crate::mem::PAGE_SIZE;
+use crate::mem::shm::{self, MAX_SHM_SIZE, SharedMemory, ShmMapping};
+use crate::mem::virt::{VmFlags, VmRegion};
+use crate::proc::manager;
+use crate::proc::process::File;
+use crate::syscall::{
+    SyscallResult,
+    errno::{EBADF, EEXIST, EINVAL, EMFILE, ENOMEM, ESRCH},
+    user::USER_END,
+};
+
+/// Flags for `sys_shm_map`
+pub mod map_flags {
+    /// Map the region read-only
+    pub const READ_ONLY: u64 = 1 << 0;
+}
+
+/// Create a zeroed shared region of `size` bytes (rounded up to whole pages), returning its
+/// handle
+pub fn sys_shm_create(size: u64) -> SyscallResult {
+    if size == 0 || size > MAX_SHM_SIZE as u64 {
+        return Err(EINVAL);
+    }
+
+    let region = SharedMemory::new(size as usize).map_err(|_| ENOMEM)?;
+    let handle = manager::with_current_process(|process| {
+        process
+            .add_file(File::SharedMemory(region))
+            .map_err(|_| EMFILE)
+    })
+    .ok_or(ESRCH)??;
+
+    Ok(handle as u64)
+}
+
+/// Map the region behind `handle` at `addr` in the calling process, returning its size. The range
+/// must be page aligned, in the user half and not mapped already. The bottom 512 GiB shares the
+/// kernel's tables (the identity map lives there), so mapping anything there fails.
+pub fn sys_shm_map(handle: u64, addr: u64, flags: u64) -> SyscallResult {
+    if flags & !map_flags::READ_ONLY != 0 {
+        return Err(EINVAL);
+    }
+
+    let (file, cr3) =
+        manager::with_current_process(|process| (process.file(handle as usize), process.cr3))
+            .ok_or(ESRCH)?;
+    let file = file.ok_or(EBADF)?;
+    let File::SharedMemory(region) = &*file else {
+        return Err(EBADF);
+    };
+
+    let size = region.size() as u64;
+    if addr == 0 || !addr.is_multiple_of(PAGE_SIZE as u64) {
+        return Err(EINVAL);
+    }
+    if addr.checked_add(size).is_none_or(|end| end > USER_END) {
+        return Err(EINVAL);
+    }
+    if !shm::is_unmapped(cr3, addr, size as usize) {
+        return Err(EEXIST);
+    }
+
+    let writable = flags & map_flags::READ_ONLY == 0;
+    let mapping = ShmMapping::new(region.clone(), cr3, addr, writable).map_err(|_| ENOMEM)?;
+
+    let mut region_flags = VmFlags::READ | VmFlags::USER | VmFlags::SHARED;
+    if writable {
+        region_flags |= VmFlags::WRITE;
+    }
+
+    manager::with_current_process(|process| {
+        process.shm_mappings.push(mapping);
+        process.vm_regions.push(VmRegion {
+            start: addr,
+            end: addr + size,
+            flags: region_flags,
+        });
+    })
+    .ok_or(ESRCH)?;
+
+    Ok(size)
+}
@@ -0,0 +1,75 @@
+//! `sys_sysinfo`: system-wide memory, uptime and process statistics
+
+use crate::arch::x86_64::idt;
+use crate::mem::{self, PAGE_SIZE, heap};
+use crate::proc::manager;
+use crate::syscall::{SyscallResult, errno::EINVAL, user};
+
+use core::mem::size_of;
+
+/// Layout version of `SysInfo`, bumped whenever fields are appended
+pub const SYSINFO_VERSION: u32 = 1;
+
+/// Snapshot of system statistics handed to user space.
+///
+/// New fields must only ever be appended. The caller passes the size of its own copy of the
+/// struct, the kernel writes at most that many bytes and reports its own `size`, so old binaries
+/// keep working against newer kernels and vice versa.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SysInfo {
+    /// Size of this struct as known by the kernel
+    pub size: u32,
+    pub version: u32,
+
+    // Physical memory, in bytes
+    pub total_memory: u64,
+    pub free_memory: u64,
+    pub used_memory: u64,
+
+    // Kernel heap, in bytes
+    pub heap_free: u64,
+    pub heap_used: u64,
+
+    pub uptime_ms: u64,
+    pub process_count: u64,
+}
+
+impl SysInfo {
+    pub fn collect() -> Self {
+        let memory = mem::stats();
+        let (heap_free, heap_used) = heap::heap_stats();
+
+        Self {
+            size: size_of::<Self>() as u32,
+            version: SYSINFO_VERSION,
+            total_memory: memory.total_memory,
+            free_memory: memory.free_pages * PAGE_SIZE as u64,
+            used_memory: memory.used_memory,
+            heap_free: heap_free as u64,
+            heap_used: heap_used as u64,
+            uptime_ms: idt::uptime_ms(),
+            process_count: manager::manager().processes.len() as u64,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) }
+    }
+}
+
+/// Fill the user buffer at `buf` (of `len` bytes) with a `SysInfo`.
+/// Returns the number of bytes written.
+pub fn sys_sysinfo(buf: u64, len: u64) -> SyscallResult {
+    // Must at least be able to hold the size/version header
+    if len < 8 {
+        return Err(EINVAL);
+    }
+
+    let info = SysInfo::collect();
+    let count = (len as usize).min(size_of::<SysInfo>());
+
+    user::copy_to_user(buf, &info.as_bytes()[..count])?;
+
+    Ok(count as u64)
+}
@@ -0,0 +1,23 @@
+//! `sys_set_tls`: set the calling thread's thread pointer (FS base)
+
+use crate::arch::x86_64::{percpu, set_fs_base};
+use crate::syscall::{SyscallResult, errno::EINVAL, user::USER_END};
+
+use core::sync::atomic::Ordering;
+
+/// Point FS at `addr` for the calling thread. The value is saved in the thread so it survives
+/// context switches. Pointing it into the kernel half is refused, it must be a user address.
+pub fn sys_set_tls(addr: u64) -> SyscallResult {
+    if addr >= USER_END {
+        return Err(EINVAL);
+    }
+
+    let thread = percpu::current().current_thread.load(Ordering::Relaxed);
+    if let Some(thread) = unsafe { thread.as_mut() } {
+        thread.tls_base = addr;
+    }
+
+    set_fs_base(addr);
+
+    Ok(0)
+}
@@ -0,0 +1,75 @@
+//! Helpers for moving data across the user/kernel boundary
+//!
+//! Every pointer handed to us by user space must be checked before it is dereferenced: it has to
+//! sit entirely in the lower (user) half of the address space and every page it touches has to be
+//! mapped user-accessible (and writable, for the kernel to write to it), otherwise a malicious or
+//! buggy program could get the kernel to read or write memory on its behalf. The kernel's own
+//! mappings in the user half, like the identity-mapped low 4 GiB, aren't user-accessible.
+
+use crate::arch::paging::{self, flags};
+use crate::mem::{PAGE_SIZE, page_align_down};
+use crate::syscall::errno::EFAULT;
+
+/// First address that is no longer part of the user half (start of the non-canonical hole)
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Check that user code may read `[addr, addr + len)`
+pub fn validate_read(addr: u64, len: usize) -> Result<(), i64> {
+    validate_range(addr, len, flags::USER_ACCESSIBLE)
+}
+
+/// Check that user code may write `[addr, addr + len)`
+pub fn validate_write(addr: u64, len: usize) -> Result<(), i64> {
+    validate_range(addr, len, flags::USER_ACCESSIBLE | flags::WRITABLE)
+}
+
+/// Check that `[addr, addr + len)` is in the user half and every page of it is mapped with all of
+/// `required` in the active address space, the one of the process that made the syscall
+fn validate_range(addr: u64, len: usize, required: u64) -> Result<(), i64> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    if addr == 0 {
+        return Err(EFAULT);
+    }
+
+    let end = addr.checked_add(len as u64).ok_or(EFAULT)?;
+    if end > USER_END {
+        return Err(EFAULT);
+    }
+
+    let cr3 = crate::arch::current_cr3();
+    let mut page = page_align_down(addr);
+    while page < end {
+        match paging::query_in(cr3, page) {
+            Some((_, page_flags)) if page_flags & required == required => {}
+            _ => return Err(EFAULT),
+        }
+        page += PAGE_SIZE as u64;
+    }
+
+    Ok(())
+}
+
+/// Copy `src` into user memory at `dst`
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), i64> {
+    validate_write(dst, src.len())?;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len());
+    }
+
+    Ok(())
+}
+
+/// Copy user memory at `src` into `dst`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), i64> {
+    validate_read(src, dst.len())?;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len());
+    }
+
+    Ok(())
+}
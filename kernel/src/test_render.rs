@@ -0,0 +1,48 @@
+use crate::drivers::keyboard::{self, KeyCode};
+use crate::drivers::screen::Rgb;
+use crate::gfx::Canvas;
+use crate::proc::scheduler;
+use crate::watchdog;
+
+use libm::{cos, sin};
+
+/// Spin a green circle around the middle of the screen until Escape is pressed.
+pub fn test_render_loop() {
+    let mut canvas = match Canvas::new() {
+        Ok(canvas) => canvas,
+        Err(e) => {
+            log::warn!("{}, skipping the render loop", e);
+            return;
+        }
+    };
+
+    let midx = canvas.width() as f64 / 2.0;
+    let midy = canvas.height() as f64 / 2.0;
+
+    let mut counter: u64 = 0;
+
+    loop {
+        watchdog::kick();
+
+        // Drain every pending event so non-character keys (like Escape) aren't lost
+        for key in keyboard::try_events() {
+            if key.pressed && key.keycode == KeyCode::Escape {
+                log::debug!("Escape pressed, leaving render loop");
+                return;
+            }
+        }
+
+        canvas.clear(Rgb::new(0xFF, 0xFF, 0xFF));
+
+        let x = midx + 100.0 * cos((counter as f32 * 0.01).into());
+        let y = midy + 100.0 * sin((counter as f32 * 0.01).into());
+        canvas.draw_circle(x as f32, y as f32, 100.0, Rgb::new(0, 0xFF, 0));
+
+        counter = counter.wrapping_add(1);
+
+        // The screen lock is only held while copying the frame over, and the flusher presents it
+        // once we yield
+        canvas.present();
+        scheduler::yield_now();
+    }
+}
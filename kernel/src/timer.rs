@@ -0,0 +1,170 @@
+//! Callbacks run by the timer interrupt once their deadline has passed
+//!
+//! `after` runs a callback once, `every` keeps running it at an interval until it's cancelled.
+//! Deadlines are counted in timer ticks, so they are only as precise as the tick (about 55 ms with
+//! the PIT at its power-on rate) and always round up to at least one tick.
+//!
+//! Callbacks run in interrupt context, with interrupts disabled. Keep them short, and don't
+//! allocate, block or register timers from one. Anything longer should be passed on with
+//! `workqueue::schedule`.
+
+use crate::arch;
+use crate::arch::x86_64::idt;
+use crate::sync::IrqSpinlock;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Identifies a registered timer, for `cancel`
+pub type TimerId = u64;
+
+struct Timer {
+    id: TimerId,
+    /// Tick at or after which the callback runs
+    deadline: u64,
+    /// Ticks between runs, 0 for a one-shot
+    period: u64,
+    callback: fn(),
+}
+
+/// Pending timers, soonest deadline first
+static TIMERS: IrqSpinlock<Vec<Timer>> = IrqSpinlock::new(Vec::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// IRQ line of the PIT
+const IRQ: u8 = 0;
+
+/// Start taking timer interrupts. The PIT is left at its power-on rate. The scheduler tick in the
+/// handler needs the per-CPU block, so this has to come after it's set up.
+pub fn init() {
+    idt::unmask_irq(IRQ);
+}
+
+/// Run `callback` once, `ms` milliseconds from now
+pub fn after(ms: u64, callback: fn()) -> Result<TimerId, &'static str> {
+    add(idt::ms_to_ticks(ms), 0, callback)
+}
+
+/// Run `callback` every `ms` milliseconds, starting `ms` from now. Like any deadline, the period
+/// is at least one tick, so `every(0, ...)` runs on every tick.
+pub fn every(ms: u64, callback: fn()) -> Result<TimerId, &'static str> {
+    // A period of 0 would make it a one-shot
+    let period = idt::ms_to_ticks(ms).max(1);
+    add(period, period, callback)
+}
+
+fn add(delay: u64, period: u64, callback: fn()) -> Result<TimerId, &'static str> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut timers = TIMERS.lock();
+    timers
+        .try_reserve(1)
+        .map_err(|_| "Out of memory for timers")?;
+
+    let timer = Timer {
+        id,
+        deadline: idt::ticks() + delay.max(1),
+        period,
+        callback,
+    };
+    insert(&mut timers, timer);
+
+    Ok(id)
+}
+
+/// Insert keeping the list sorted, after any timers with the same deadline
+fn insert(timers: &mut Vec<Timer>, timer: Timer) {
+    let index = timers.partition_point(|other| other.deadline <= timer.deadline);
+    timers.insert(index, timer);
+}
+
+/// Stop a timer from running again. Returns false if it already fired (for a one-shot) or was
+/// cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    match timers.iter().position(|timer| timer.id == id) {
+        Some(index) => {
+            timers.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run every callback whose deadline is at or before `now`, called from the timer interrupt
+pub fn run_due(now: u64) {
+    loop {
+        let callback = {
+            let mut timers = TIMERS.lock();
+            if timers.first().is_none_or(|timer| timer.deadline > now) {
+                return;
+            }
+
+            // Only ever shrinks or reuses the slot it frees, so this never allocates
+            let mut timer = timers.remove(0);
+            let callback = timer.callback;
+            if timer.period != 0 {
+                // A late tick skips the runs it missed rather than firing them back to back
+                timer.deadline = (timer.deadline + timer.period).max(now + 1);
+                insert(&mut timers, timer);
+            }
+            callback
+        };
+
+        callback();
+    }
+}
+
+/// Number of timers waiting to run
+pub fn pending() -> usize {
+    TIMERS.lock().len()
+}
+
+/// Runs of the self-test callbacks, and the tick the last one ran at
+static TEST_RUNS: AtomicU64 = AtomicU64::new(0);
+static TEST_RAN_AT: AtomicU64 = AtomicU64::new(0);
+
+fn test_callback() {
+    TEST_RUNS.fetch_add(1, Ordering::Relaxed);
+    TEST_RAN_AT.store(idt::ticks(), Ordering::Relaxed);
+}
+
+/// Wait with interrupts on until the tick count reaches `tick`
+fn wait_for_tick(tick: u64) {
+    while idt::ticks() < tick {
+        arch::halt();
+    }
+}
+
+/// A one-shot runs once, no earlier than its deadline, and a zero-length period still repeats
+pub fn selftest_deadlines() -> Result<(), &'static str> {
+    const DELAY_MS: u64 = 150;
+
+    TEST_RUNS.store(0, Ordering::Relaxed);
+    let start = idt::ticks();
+    let delay = idt::ms_to_ticks(DELAY_MS);
+    after(DELAY_MS, test_callback)?;
+
+    // Long enough for a one-shot that wrongly repeated to run again
+    wait_for_tick(start + delay + 3);
+    match TEST_RUNS.load(Ordering::Relaxed) {
+        0 => return Err("One-shot never ran"),
+        1 => {}
+        _ => return Err("One-shot ran more than once"),
+    }
+    if TEST_RAN_AT.load(Ordering::Relaxed) < start + delay {
+        return Err("One-shot ran before its deadline");
+    }
+
+    TEST_RUNS.store(0, Ordering::Relaxed);
+    let start = idt::ticks();
+    let id = every(0, test_callback)?;
+    wait_for_tick(start + 4);
+    cancel(id);
+    if TEST_RUNS.load(Ordering::Relaxed) < 2 {
+        return Err("Timer with a zero-length period only ran once");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! Software watchdog driven by the timer interrupt
+//!
+//! Enabled with `watchdog=<seconds>` on the kernel command line. Once armed, long-running loops
+//! must call `kick()` at least once per timeout period. If they don't, the timer interrupt
+//! assumes the kernel is hung, dumps where it was and reboots the machine.
+
+use crate::BootInfo;
+use crate::arch::x86_64::idt;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Timeout in milliseconds, 0 means the watchdog is disabled
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Uptime (in milliseconds) of the last kick
+static LAST_KICK_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn init(boot_info: &BootInfo) {
+    let Some(value) = boot_info.cmdline_option("watchdog") else {
+        return;
+    };
+
+    match value.parse::<u64>() {
+        Ok(0) => log::debug!("Watchdog disabled by command line"),
+        Ok(secs) => enable(secs),
+        Err(_) => log::warn!("Ignoring invalid watchdog timeout {:?}", value),
+    }
+}
+
+/// Arm the watchdog with a timeout of `secs` seconds
+pub fn enable(secs: u64) {
+    kick();
+    TIMEOUT_MS.store(secs.saturating_mul(1000), Ordering::Relaxed);
+    log::info!("Watchdog armed: {} s timeout", secs);
+}
+
+pub fn disable() {
+    TIMEOUT_MS.store(0, Ordering::Relaxed);
+}
+
+/// Tell the watchdog we're still making progress
+pub fn kick() {
+    LAST_KICK_MS.store(idt::uptime_ms(), Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt, returns true if the watchdog has expired
+pub fn expired() -> bool {
+    let timeout = TIMEOUT_MS.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return false;
+    }
+
+    let elapsed = idt::uptime_ms().saturating_sub(LAST_KICK_MS.load(Ordering::Relaxed));
+    elapsed > timeout
+}
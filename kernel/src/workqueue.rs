@@ -0,0 +1,145 @@
+//! Work deferred out of interrupt handlers (bottom halves)
+//!
+//! An interrupt handler should only do what can't wait, like reading a byte out of a device before
+//! the next one overwrites it, and `schedule` the rest. Scheduled work runs with interrupts
+//! enabled and never inside an interrupt: on the worker thread, or on a thread about to wait in a
+//! `WaitQueue`, as the work may be what it's waiting for. So work can take locks and allocate like
+//! any other thread code, but it mustn't wait on a `WaitQueue` itself.
+//!
+//! Work items are plain functions, so scheduling one never allocates. A function that is still
+//! waiting to run isn't queued twice, a handler can schedule its bottom half on every interrupt and
+//! have one run drain everything that arrived in the meantime. Only one item runs at a time.
+
+use crate::arch;
+use crate::arch::x86_64::percpu;
+use crate::proc::scheduler;
+use crate::sync::IrqSpinlock;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Most distinct functions that can be waiting at once
+const CAPACITY: usize = 32;
+const WORKER_STACK_SIZE: usize = 16 * 1024;
+
+/// Functions waiting to run, oldest first
+struct Queue {
+    items: [Option<fn()>; CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    fn contains(&self, work: fn()) -> bool {
+        (0..self.len).any(|i| {
+            self.items[(self.head + i) % CAPACITY]
+                .is_some_and(|item| core::ptr::fn_addr_eq(item, work))
+        })
+    }
+
+    fn push(&mut self, work: fn()) -> Result<(), &'static str> {
+        if self.len == CAPACITY {
+            return Err("Work queue full");
+        }
+
+        self.items[(self.head + self.len) % CAPACITY] = Some(work);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<fn()> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % CAPACITY;
+        self.len -= 1;
+        work
+    }
+}
+
+static QUEUE: IrqSpinlock<Queue> = IrqSpinlock::new(Queue {
+    items: [None; CAPACITY],
+    head: 0,
+    len: 0,
+});
+
+/// Set while something is running work, so items never run concurrently or nested
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Work dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Run `work` soon, with interrupts enabled. Safe to call from interrupt handlers. Does nothing if
+/// `work` is already waiting to run.
+pub fn schedule(work: fn()) -> Result<(), &'static str> {
+    {
+        let mut queue = QUEUE.lock();
+        if queue.contains(work) {
+            return Ok(());
+        }
+
+        if let Err(e) = queue.push(work) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+    }
+
+    // Get the worker going at the next chance rather than at the end of the time slice
+    scheduler::request_resched();
+    Ok(())
+}
+
+/// Whether any work is waiting to run
+pub fn has_pending() -> bool {
+    QUEUE.lock().len != 0
+}
+
+/// Number of work items dropped because the queue was full
+pub fn dropped_count() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Run every waiting item, including ones scheduled while doing so. Interrupts must be enabled.
+/// Returns false without running anything if work is already running, further up the stack or on
+/// a thread that was preempted in the middle of it. That thread has to be let run to finish.
+pub fn run_pending() -> bool {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+
+    loop {
+        // The lock is dropped at the end of the statement, before the work runs
+        let Some(work) = QUEUE.lock().pop() else {
+            break;
+        };
+        work();
+    }
+
+    RUNNING.store(false, Ordering::Release);
+    true
+}
+
+/// Start the worker thread. Until it's running, work only runs from waiting threads.
+pub fn init() {
+    match scheduler::spawn_kernel(worker_thread, WORKER_STACK_SIZE) {
+        Ok(tid) => log::debug!("Work queue running as thread {}", tid),
+        Err(e) => log::warn!("No work queue thread ({}), deferred work may be delayed", e),
+    }
+}
+
+fn worker_thread() {
+    loop {
+        run_pending();
+        scheduler::yield_now();
+
+        // Sleep until the next interrupt when there's nothing to do. Checked with interrupts off, so
+        // work scheduled in between still wakes us.
+        arch::disable_interrupts();
+        let idle = !has_pending() && percpu::current().run_queue.lock().ready_count() == 0;
+        if idle {
+            arch::enable_interrupts_and_halt();
+        } else {
+            arch::enable_interrupts();
+        }
+    }
+}